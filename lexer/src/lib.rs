@@ -0,0 +1,2046 @@
+// letterbox-lang-lexer
+// The Letterbox lexer, shared by letterbox-lang and the lb! macro.
+
+use std::fmt;
+use std::ops::Range;
+use logos::{Filter, Logos, Lexer};
+
+/// A Logos-derived enum that can split a Letterbox program
+/// into individual tokens AND parse out their arguments.
+/// With the `serde` feature, commands can be serialized and deserialized.
+/// Deserializing fails with a message like `M: Invalid op Z` for a command that couldn't have been lexed,
+/// such as one with a variable that isn't a letter, an op its command doesn't have,
+/// or an [LbToken::Error].
+#[derive(Logos, Debug, PartialEq, Clone)]
+// deserializing goes through the impl below, which checks the command
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(remote = "Self"))]
+// the extras are set while lexing the body of a conditional,
+// whose else branch can't be taken by a conditional in the body, as in `IaIbPc:Pd`
+#[logos(extras = bool)]
+pub enum LbToken {
+    /// Save a value into a variable.
+    /// The number may have a fraction and an exponent,
+    /// or be written in hexadecimal (`0x`) or binary (`0b`).
+    /// Numbers too big for an f64, like `Sa1e400`, are not recognized.
+    /// 
+    /// Usage: `Sa4`, `Sa-1.5e-7`, `Sa0xFF`, `Sa0b1010`
+    #[regex(r"S[a-zA-Z]\-?[0-9]+(\.[0-9]+)?([eE][+\-]?[0-9]+)?", save_number)]
+    #[regex(r"S[a-zA-Z]\-?0x[0-9a-fA-F]+", save_radix_number)]
+    #[regex(r"S[a-zA-Z]\-?0b[01]+", save_radix_number)]
+    SaveNumber((char, f64)),
+
+    /// Save a value into a variable.
+    /// Escapes: `\'` quote, `\\` backslash, `\n` newline, `\t` tab, `\r` carriage return.
+    /// A backslash before any other character is kept as-is.
+    /// 
+    /// Usage: `Sa'hello'`
+    #[regex(r"S[a-zA-Z]'([^'\\]|\\.)*'", save_str)]
+    SaveStr((char, String)),
+
+    /// Save a list into a variable. Items are numbers, strings, and lists,
+    /// written as they would be saved on their own and separated by whitespace.
+    /// 
+    /// Usage: `Sa[1 2 3]`, `Sa['one' -2.5 [3 []]]`, `Sa[]`
+    #[regex(r"S[a-zA-Z]\[", save_list)]
+    SaveList((char, Vec<ListItem>)),
+
+    /// Copy the value of a variable into another.
+    /// 
+    /// Usage: `Cab`
+    #[regex(r"C[a-zA-Z][a-zA-Z]", copy)]
+    Copy((char, char)),
+
+    /// Append the value of variable b to variable a.
+    /// If a is not a string, it becomes a string.
+    /// The type of the appended variable is not affected.
+    /// 
+    /// Usage: `Aab`
+    #[regex(r"A[a-zA-Z][a-zA-Z]", copy)]
+    Append((char, char)),
+
+    /// Print the value of the given variable, without a newline.
+    /// 
+    /// Usage: `Pa`
+    #[regex(r"P[a-zA-Z]", single_var_arg)]
+    PrintVar(char),
+
+    /// Print the given string directly, without a newline. Doesn't save it into storage.
+    /// Escapes: `\'` quote, `\\` backslash, `\n` newline, `\t` tab, `\r` carriage return.
+    /// A backslash before any other character is kept as-is.
+    /// 
+    /// Usage: `P'hello world'`
+    #[regex(r"P'([^'\\]|\\.)*'", print_str)]
+    PrintStr(String),
+
+    /// Print the value of the given variable, followed by a newline.
+    /// 
+    /// Usage: `Oa`
+    #[regex(r"O[a-zA-Z]", single_var_arg)]
+    PrintLineVar(char),
+
+    /// Print the given string directly, followed by a newline.
+    /// Escapes: `\'` quote, `\\` backslash, `\n` newline, `\t` tab, `\r` carriage return.
+    /// A backslash before any other character is kept as-is.
+    /// 
+    /// Usage: `O'hello world'`
+    #[regex(r"O'([^'\\]|\\.)*'", print_str)]
+    PrintLineStr(String),
+
+    /// Performs a mathematical operation on b and c, storing the result in a.
+    /// 
+    /// Ops: `A` add, `S` subtract, `M` multiply, `D` divide,
+    /// `Q` integer division (rounds toward zero, so it pairs with `R`),
+    /// `R` remainder (takes the sign of b), `O` modulo (takes the sign of c),
+    /// `P` power, `I` minimum, `X` maximum, `T` arctangent of b/c (atan2, in radians),
+    /// `E` equal to, `N` not equal to, `G` greater than, `L` less than,
+    /// `H` greater than or equal to, `W` less than or equal to.
+    /// Comparisons store `true` or `false`, and bools used as operands count as 1 and 0.
+    /// If only one operand of `I` or `X` is NaN, the other is the result.
+    /// `D`, `Q`, `R`, and `O` fail if c is zero.
+    /// Ops that are also unary, like `A`, take a third variable whenever a letter follows,
+    /// so a space is needed before a command that follows their unary form, as in `MAab Pc`.
+    /// 
+    /// Usage: `MAabc`
+    // only ops that take three variables, so a unary op can be followed by a command, like MFabPc
+    #[regex(r"M[ASMDQEGLROPIXTNHW][a-zA-Z][a-zA-Z][a-zA-Z]", math_op)]
+    MathOp((char, char, char, char)),
+
+    /// Performs a mathematical operation on b, storing the result in a.
+    /// 
+    /// Ops: `F` floor, `C` ceiling, `R` round (halves away from zero),
+    /// `A` absolute value, `N` sign (-1, 0, or 1),
+    /// `S` sine, `O` cosine, `T` tangent (all in radians),
+    /// `Q` square root, `L` natural logarithm, `D` base 10 logarithm.
+    /// 
+    /// Usage: `MFab`
+    #[regex(r"M[A-Z][a-zA-Z][a-zA-Z]", unary_math_op)]
+    UnaryMathOp((char, char, char)),
+
+    /// Performs a boolean operation on b and c, storing the result (`true` or `false`) in a.
+    /// 
+    /// Ops: `E` equal truthiness, `A` and, `O` or, `X` xor,
+    /// `S` same value: text is compared by content and numbers and bools by value,
+    /// while values of different kinds, like a bool and a number, are never the same.
+    /// 
+    /// Bitwise ops store numbers. They truncate b and c to 64-bit integers, and fail if either is out of range:
+    /// `&` and, `|` or, `^` xor, `<` shift b left by c bits, `>` shift b right by c bits.
+    /// Shift amounts must be between 0 and 63.
+    /// 
+    /// Usage: `BXabc`, `B&abc`
+    #[regex(r"B[EAOXS][a-zA-Z][a-zA-Z][a-zA-Z]", bool_op)]
+    #[regex(r"B[&|^<>][a-zA-Z][a-zA-Z][a-zA-Z]", bool_op)]
+    BoolOp((char, char, char, char)),
+
+    /// Performs a boolean operation on b, storing the result (`true` or `false`) in a.
+    /// Unlike `Na`, b is not changed.
+    /// 
+    /// Ops: `N` not.
+    /// 
+    /// Usage: `BNab`
+    #[regex(r"B[A-Z][a-zA-Z][a-zA-Z]", unary_bool_op)]
+    UnaryBoolOp((char, char, char)),
+
+    /// Performs a text operation, storing the result in the first variable.
+    /// Numbers used as text are converted to strings first.
+    /// Indexes count characters, not bytes. Fractional indexes are truncated,
+    /// and indexes out of range are clamped.
+    /// 
+    /// Ops:
+    /// - `S` substring: `TSdsil` stores the l characters of s starting at index i in d.
+    /// - `P` split piece: `TPdscn` splits s on the single character in c,
+    ///   and stores piece n in d. Consecutive delimiters produce empty pieces,
+    ///   and a piece out of range is an empty string.
+    /// - `K` split count: `TKdsc` stores the number of pieces `TP` would split s into in d.
+    /// - `N` parse number: `TNdsf` parses s, ignoring surrounding whitespace, as a number into d.
+    ///   Stores `true` in f if it succeeds, or stores 0 in d and `false` in f if it fails.
+    /// - `F` format: `TFdnp` stores number n as text in d, with p decimal places.
+    ///   p is truncated and clamped between 0 and 100.
+    /// - `U` uppercase: `TUds` stores the uppercase form of s in d.
+    /// - `L` lowercase: `TLds` stores the lowercase form of s in d.
+    /// - `C` character: `TCdsi` stores the character at index i of s in d,
+    ///   or an empty string if i is out of range.
+    /// - `I` index of: `TIdhn` stores the index of the first occurrence of n in h in d,
+    ///   or -1 if there is none. An empty n is found at index 0.
+    /// - `R` replace: `TRdsfr` stores s with every occurrence of f replaced by r in d.
+    ///   Matches are replaced from left to right without overlapping.
+    ///   If f is empty, s is stored unchanged.
+    /// - `T` trim: `TTds` stores s without leading or trailing whitespace in d.
+    /// - `B` trim beginning: `TBds` stores s without leading whitespace in d.
+    /// - `E` trim end: `TEds` stores s without trailing whitespace in d.
+    /// - `O` ordinal: `TOds` stores the Unicode code point of the first character of s in d.
+    ///   Fails if s is empty.
+    /// - `H` character from code: `THdn` stores the character with Unicode code point n in d.
+    ///   Fails if n is not a valid code point.
+    /// 
+    /// Usage: `TSdsil`
+    #[regex(r"T[A-Z]", text_op)]
+    TextOp((char, String)),
+
+    /// Performs a list operation. Indexes start at 0, and fractional indexes are truncated.
+    /// Reading or writing an index outside the list fails.
+    /// 
+    /// Ops:
+    /// - `N` new: `VNl` stores an empty list in l.
+    /// - `S` set: `VSliv` sets element i of list l to the value of v.
+    /// - `G` get: `VGdli` stores element i of list l in d.
+    /// - `A` append: `VAlv` adds the value of v to the end of list l.
+    /// - `L` length: `VLdl` stores the number of elements in list l in d.
+    /// - `P` split: `VPlsc` splits s on the single character in c, and stores the pieces in l.
+    ///   Consecutive delimiters produce empty pieces, as with `TP`.
+    /// 
+    /// Usage: `VSliv`
+    #[regex(r"V[A-Z]", list_op)]
+    ListOp((char, String)),
+
+    /// Performs a map operation. Keys are text, and numbers used as keys
+    /// are converted to strings first.
+    /// 
+    /// Ops:
+    /// - `N` new: `HNm` stores an empty map in m.
+    /// - `S` set: `HSmkv` stores the value of v under key k in map m,
+    ///   replacing any value already there.
+    /// - `G` get: `HGdmk` stores the value under key k in map m in d.
+    ///   Fails if the key is not in the map.
+    /// - `C` contains: `HCdmk` stores `true` in d if key k is in map m, or `false` if not.
+    /// - `R` remove: `HRmk` removes key k and its value from map m, if it is there.
+    /// - `L` length: `HLdm` stores the number of keys in map m in d.
+    /// 
+    /// Usage: `HSmkv`
+    #[regex(r"H[A-Z]", map_op)]
+    MapOp((char, String)),
+
+    /// Performs command X, a times.
+    /// X can be a single command or a [LbToken::Block].
+    /// Only the first command after the condition is the body, so `LaPbPc` repeats `Pb` and then runs `Pc` once.
+    /// Any command can be the body, including ones with numbers or strings, like `LaSb5` or `LaP'hi there'`.
+    /// 
+    /// Usage: `LaX`, `La[ X Y ]`
+    #[regex(r"L[a-zA-Z]", base_loop)]
+    Loop((char, Box<LbToken>)),
+
+    /// Performs command X a fixed number of times, written as a number.
+    /// A fraction is truncated, and a count of zero or less doesn't perform X at all.
+    /// X follows the count as it follows the condition of [LbToken::Loop].
+    /// 
+    /// Usage: `L5X`, `L3[ X Y ]`
+    #[regex(r"L\-?[0-9]+(\.[0-9]+)?", count_loop)]
+    Repeat((usize, Box<LbToken>)),
+
+    /// If a is nonzero, perform command X.
+    /// Otherwise, perform the optional else command Y.
+    /// The condition is only read once.
+    /// A conditional directly in the body of another leaves the else command to it,
+    /// so in `IaIbPc:Pd` the `:Pd` belongs to `Ia`.
+    /// 
+    /// Usage: `IaX`, `Ia[ X Y ]`, `IaX:Y`
+    #[regex(r"I[a-zA-Z]", base_if)]
+    IfStatement((char, Box<LbToken>, Option<Box<LbToken>>)),
+
+    /// If a IS EQUAL TO ZERO, perform command X.
+    /// Otherwise, perform the optional else command Y.
+    /// The condition is only read once.
+    /// 
+    /// Usage: `UaX`, `Ua[ X Y ]`, `UaX:Y`
+    #[regex(r"U[a-zA-Z]", base_if)]
+    Unless((char, Box<LbToken>, Option<Box<LbToken>>)),
+
+    /// While a is nonzero, repeat command X
+    /// 
+    /// Usage: `WaX`, `Wa[ X Y ]`
+    #[regex(r"W[a-zA-Z]", base_loop)]
+    WhileLoop((char, Box<LbToken>)),
+
+    /// Reset variable a to 0.
+    /// 
+    /// Usage: `Ra`, `RA`
+    #[regex(r"R[a-zA-Z]", single_var_arg)]
+    ResetVar(char),
+
+    /// Reset all variables in the active bank, and empty the stack.
+    /// This used to be written `RA`, which now resets variable A.
+    /// 
+    /// Usage: `R*`
+    #[regex(r"R\*")]
+    ResetAll,
+
+    /// Reset all variables in every bank, and empty the stack.
+    /// The active bank stays the same.
+    /// 
+    /// Usage: `R#`
+    #[regex(r"R#")]
+    ResetAllBanks,
+
+    /// Switches to bank n of variables, from 0 to 9.
+    /// Every bank has its own 52 variables, and commands after this one use those of bank n.
+    /// Programs start in bank 0, and the stack is shared by all banks.
+    /// 
+    /// Usage: `#1`
+    #[regex(r"#[0-9]", bank)]
+    SwitchBank(u8),
+
+    /// Gets nth input and stores it in variable a as type X (N or S)
+    /// 
+    /// Usage: `GXa1`
+    #[regex(r"G[A-Z][a-zA-Z][0-9]+", get_input)]
+    GetInput((char, char, f64)),
+
+    /// Gets the input at the index stored in variable b,
+    /// and stores it in variable a as type X (N or S).
+    /// Fractional indexes are truncated.
+    /// 
+    /// Usage: `GXab`
+    #[regex(r"G[A-Z][a-zA-Z][a-zA-Z]", get_input_at)]
+    GetInputAt((char, char, char)),
+
+    /// Stores the number of inputs available to `GNa1` and `GSab` in variable a.
+    /// 
+    /// Usage: `G#a`
+    #[regex(r"G#[a-zA-Z]", op_var_arg)]
+    InputCount(char),
+
+    /// Asks the host for the next input while running,
+    /// and stores it in variable a as type X (N or S).
+    /// Fails if the host has no more input.
+    /// 
+    /// Usage: `GQaX`
+    #[regex(r"GQ[a-zA-Z][NS]", query_input)]
+    QueryInput((char, char)),
+
+    /// If a is nonzero, set it to 0, else set it to 1.
+    /// 
+    /// Usage: `Na`
+    #[regex(r"N[a-zA-Z]", single_var_arg)]
+    Negate(char),
+
+    /// Pushes the value of variable a onto the stack.
+    /// 
+    /// Usage: `K+a`
+    #[regex(r"K\+[a-zA-Z]", op_var_arg)]
+    Push(char),
+
+    /// Removes the value on top of the stack and stores it in variable a.
+    /// Fails if the stack is empty.
+    /// 
+    /// Usage: `K-a`
+    #[regex(r"K-[a-zA-Z]", op_var_arg)]
+    Pop(char),
+
+    /// Stores the number of values on the stack in variable a.
+    /// 
+    /// Usage: `K#a`
+    #[regex(r"K#[a-zA-Z]", op_var_arg)]
+    StackDepth(char),
+
+    /// Store a random number between 0 (inclusive) and 1 (exclusive) in variable a.
+    /// 
+    /// Usage: `Da`
+    #[regex(r"D[a-zA-Z]", single_var_arg)]
+    Random(char),
+
+    /// Pauses the program for the number of milliseconds in variable a.
+    /// Negative and NaN durations don't pause at all.
+    /// 
+    /// Usage: `Qa`
+    #[regex(r"Q[a-zA-Z]", single_var_arg)]
+    Sleep(char),
+
+    /// Stores the number of milliseconds since the program started in variable a.
+    /// 
+    /// Usage: `Ea`
+    #[regex(r"E[a-zA-Z]", single_var_arg)]
+    Elapsed(char),
+
+    /// Fails the program if a is false (0, an empty list, or an empty map).
+    /// The error starts with `Z: Assertion failed` and gives the position of the command,
+    /// so failed assertions can be told apart from other errors.
+    /// 
+    /// Usage: `Za`
+    #[regex(r"Z[a-zA-Z]", single_var_arg)]
+    Assert(char),
+
+    /// Fails the program if a and b are not the same value, as in `BSdab`.
+    /// The error gives both values, as well as the position of the command
+    /// as in [LbToken::Assert].
+    /// 
+    /// Usage: `Zab`
+    #[regex(r"Z[a-zA-Z][a-zA-Z]", copy)]
+    AssertSame((char, char)),
+
+    /// Finishes the program immediately.
+    /// 
+    /// Usage: `F`
+    #[regex(r"F")]
+    Finish,
+
+    /// Finishes the program immediately, reporting the number in variable a
+    /// to the host as the program's exit status.
+    /// 
+    /// Usage: `Fa`
+    #[regex(r"F[a-zA-Z]", single_var_arg)]
+    FinishWithStatus(char),
+
+    /// Marks a place in the program that jumps can go to.
+    /// Labels only count at the top level of a program, not inside blocks,
+    /// and each label can only be defined once.
+    /// Directly after an `I` or `U` command, a colon starts an else branch instead,
+    /// so separate the label with whitespace.
+    /// 
+    /// Usage: `:a`
+    #[regex(r":[a-z]", single_var_arg)]
+    Label(char),
+
+    /// Continues the program from label a.
+    /// Jumps can be made from inside blocks and loops, which stop immediately.
+    /// 
+    /// Usage: `Ja`
+    #[regex(r"J[a-z]", single_var_arg)]
+    Jump(char),
+
+    /// If a is nonzero, continues the program from label b.
+    /// 
+    /// Usage: `JIab`
+    #[regex(r"JI[a-zA-Z][a-z]", jump_if)]
+    JumpIf((char, char)),
+
+    /// Executes a string value as a Letterbox program.
+    /// Replaces any number of parameters with different variables,
+    /// so `Xzacbd` runs z with every use of variable a replaced by c and b replaced by d.
+    /// If the program returns a value with `Ya`, it is stored in the
+    /// optional destination variable after `>`. If it doesn't return,
+    /// the destination is left untouched.
+    /// 
+    /// Parameters and their variables can be either case, so the letters after `Xz`
+    /// are read in pairs up to the `>` or the end of the command.
+    /// A command that follows needs a space before it, as in `Xzab Pc`,
+    /// since `XzabPc` passes P as a parameter instead.
+    /// 
+    /// Usage: `Xzacbd`, `XzaB`, `Xzacbd>r`
+    #[regex(r"X[a-zA-Z]([a-zA-Z][a-zA-Z])*(>[a-zA-Z])?", execute_var)]
+    Execute((char, String, Option<char>)),
+
+    /// Executes a string literal as a Letterbox program, like [LbToken::Execute].
+    /// Quotes inside the program must be escaped as `\'`.
+    /// 
+    /// Usage: `X'Pa'`, `X'Pa'acbd>r`
+    #[regex(r"X'([^'\\]|\\.)*'([a-zA-Z][a-zA-Z])*(>[a-zA-Z])?", execute_str)]
+    ExecuteStr((String, String, Option<char>)),
+
+    /// Stops an executed program, returning the value of variable a
+    /// to the destination of the `Xz>r` command that ran it.
+    /// 
+    /// Usage: `Ya`
+    #[regex(r"Y[a-zA-Z]", single_var_arg)]
+    Return(char),
+
+    /// A sequence of commands executed in order.
+    /// Blocks can be nested, and are most useful as the body of
+    /// a loop or conditional.
+    /// An unterminated block lexes as a single [LbToken::Error]
+    /// spanning from its opening bracket to the end of the program.
+    /// 
+    /// Usage: `[ X Y ]`
+    #[regex(r"\[", block)]
+    Block(Vec<LbToken>),
+
+    /// Unrecognized character(s).
+    /// An unterminated block comment lexes as a single error
+    /// spanning from its opening `!{` to the end of the program.
+    #[error]
+    // skip comments, which run to the end of the line
+    #[regex(r"!([^{\n\r][^\n\r]*)?", logos::skip)]
+    // skip block comments like !{ this }!, which can be nested
+    #[regex(r"!\{", block_comment)]
+    // skip whitespace
+    #[regex(r"[ \t\n\f\r]+", logos::skip)]
+    Error,
+}
+
+/// An item of a list literal, like the `1`, `'two'`, and `[3]` in `Sa[1 'two' [3]]`.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ListItem {
+    Number(f64),
+    Text(String),
+    List(Vec<ListItem>),
+}
+
+// Parser methods!
+
+/// The largest integer that an f64 can represent exactly (2^53).
+const MAX_EXACT_INT: u64 = 1 << 53;
+
+fn save_number(lex: &mut Lexer<LbToken>) -> Option<(char, f64)> {
+    let token = lex.slice();
+    let var_name = token.chars().nth(1)?;
+    // numbers too big for an f64 would become infinity, which can't be written back as source
+    let num = token[2..].parse::<f64>().ok().filter(|num| num.is_finite())?;
+    Some((var_name, num))
+}
+
+fn bank(lex: &mut Lexer<LbToken>) -> Option<u8> {
+    lex.slice()[1..].parse().ok()
+}
+
+fn save_radix_number(lex: &mut Lexer<LbToken>) -> Option<(char, f64)> {
+    let token = lex.slice();
+    let var_name = token.chars().nth(1)?;
+    Some((var_name, parse_radix_number(&token[2..])?))
+}
+
+/// Parses a hexadecimal (`0x`) or binary (`0b`) number, which may be negative.
+fn parse_radix_number(literal: &str) -> Option<f64> {
+    let (negative, literal) = match literal.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, literal),
+    };
+    let radix = match literal.get(..2)? {
+        "0x" => 16,
+        "0b" => 2,
+        _ => return None,
+    };
+    let num = u64::from_str_radix(&literal[2..], radix).ok()?;
+    // reject values that an f64 can't hold exactly
+    if num > MAX_EXACT_INT {
+        return None;
+    }
+    let num = num as f64;
+    Some(if negative { -num } else { num })
+}
+
+fn save_str(lex: &mut Lexer<LbToken>) -> Option<(char, String)> {
+    let token = lex.slice();
+    let var_name = token.chars().nth(1)?;
+    let my_str = unquote(&token[2..]);
+    Some((var_name, my_str))
+}
+
+fn save_list(lex: &mut Lexer<LbToken>) -> Option<(char, Vec<ListItem>)> {
+    let var_name = lex.slice().chars().nth(1)?;
+    let remainder = lex.remainder();
+    let Some(end) = find_block_end(remainder) else {
+        // consume the rest of the program so the error covers the open list
+        lex.bump(remainder.len());
+        return None;
+    };
+    let items = list_items(&remainder[..end]);
+    lex.bump(end + 1);
+    Some((var_name, items?))
+}
+
+fn copy(lex: &mut Lexer<LbToken>) -> Option<(char, char)> {
+    let token = lex.slice();
+    let var_name_1 = token.chars().nth(1)?;
+    let var_name_2 = token.chars().nth(2)?;
+    Some((var_name_1, var_name_2))
+}
+
+fn single_var_arg(lex: &mut Lexer<LbToken>) -> Option<char> {
+    let token = lex.slice();
+    token.chars().nth(1)
+}
+
+fn print_str(lex: &mut Lexer<LbToken>) -> Option<String> {
+    let token = lex.slice();
+    let my_str = unquote(&token[1..]);
+    Some(my_str)
+}
+
+fn math_op(lex: &mut Lexer<LbToken>) -> Option<(char, char, char, char)> {
+    let token = lex.slice();
+    let valid_ops = MATH_OPS;
+    let args: Vec<char> = token[1..].chars().collect();
+    // must have exactly one op and three vars
+    if args.len() != 4 {
+        return None;
+    }
+    // op must be valid
+    if !valid_ops.contains(args[0]) {
+        return None;
+    }
+    Some((args[0], args[1], args[2], args[3]))
+}
+
+fn unary_bool_op(lex: &mut Lexer<LbToken>) -> Option<(char, char, char)> {
+    let token = lex.slice();
+    let valid_ops = UNARY_BOOL_OPS;
+    let args: Vec<char> = token[1..].chars().collect();
+    // must have exactly one op and two vars
+    if args.len() != 3 || extra_var(lex) {
+        return None;
+    }
+    // op must be valid
+    if !valid_ops.contains(args[0]) {
+        return None;
+    }
+    Some((args[0], args[1], args[2]))
+}
+
+fn unary_math_op(lex: &mut Lexer<LbToken>) -> Option<(char, char, char)> {
+    let token = lex.slice();
+    let valid_ops = UNARY_MATH_OPS;
+    let args: Vec<char> = token[1..].chars().collect();
+    // must have exactly one op and two vars
+    if args.len() != 3 || extra_var(lex) {
+        return None;
+    }
+    // op must be valid
+    if !valid_ops.contains(args[0]) {
+        return None;
+    }
+    Some((args[0], args[1], args[2]))
+}
+
+fn bool_op(lex: &mut Lexer<LbToken>) -> Option<(char, char, char, char)> {
+    let token = lex.slice();
+    let valid_ops = BOOL_OPS;
+    let args: Vec<char> = token[1..].chars().collect();
+    // must have exactly one op and three vars
+    if args.len() != 4 {
+        return None;
+    }
+    // op must be valid
+    if !valid_ops.contains(args[0]) {
+        return None;
+    }
+    Some((args[0], args[1], args[2], args[3]))
+}
+
+/// Consumes a lowercase letter directly following a unary op, like the `c` in `MFabc`,
+/// and returns whether there was one, in which case the op was given too many variables.
+/// An uppercase letter is left alone, since it starts the next command, like the `P` in `MFabPc`.
+fn extra_var(lex: &mut Lexer<LbToken>) -> bool {
+    let extra = lex.remainder().starts_with(|c: char| c.is_ascii_lowercase());
+    if extra {
+        lex.bump(1);
+    }
+    extra
+}
+
+fn text_op(lex: &mut Lexer<LbToken>) -> Option<(char, String)> {
+    let op = lex.slice().chars().nth(1)?;
+    op_args(lex, op_arity('T', op))
+}
+
+fn list_op(lex: &mut Lexer<LbToken>) -> Option<(char, String)> {
+    let op = lex.slice().chars().nth(1)?;
+    op_args(lex, op_arity('V', op))
+}
+
+fn map_op(lex: &mut Lexer<LbToken>) -> Option<(char, String)> {
+    let op = lex.slice().chars().nth(1)?;
+    op_args(lex, op_arity('H', op))
+}
+
+fn base_loop(lex: &mut Lexer<LbToken>) -> Option<(char, Box<LbToken>)> {
+    let condition = lex.slice().chars().nth(1)?;
+    // an else branch after the body is still left for any conditional this loop is in
+    let subcommand = branch(lex, lex.extras)?;
+    Some((condition, Box::new(subcommand)))
+}
+
+fn count_loop(lex: &mut Lexer<LbToken>) -> Option<(usize, Box<LbToken>)> {
+    let count = lex.slice()[1..].parse::<f64>().ok()?;
+    let subcommand = branch(lex, lex.extras)?;
+    // negative counts saturate to 0
+    Some((count as usize, Box::new(subcommand)))
+}
+
+fn base_if(lex: &mut Lexer<LbToken>) -> Option<(char, Box<LbToken>, Option<Box<LbToken>>)> {
+    let condition = lex.slice().chars().nth(1)?;
+    let subcommand = branch(lex, true)?;
+    let else_command = if lex.extras { None } else { else_branch(lex)? };
+    Some((condition, Box::new(subcommand), else_command))
+}
+
+/// Lexes the command that directly follows a condition, like the `X` in `LaX` or `La[ X ]`.
+/// Consumes exactly that command, so another command can directly follow it, as in `LaPbPc`.
+/// `in_conditional` is whether an else branch after the command belongs to an enclosing conditional.
+/// If there is no command there, or it can't be lexed, fails and consumes the part that couldn't be lexed.
+fn branch(lex: &mut Lexer<LbToken>, in_conditional: bool) -> Option<LbToken> {
+    let mut sub_lex = LbToken::lexer_with_extras(lex.remainder(), in_conditional);
+    let command = sub_lex.next()?;
+    let span = sub_lex.span();
+    if span.start != 0 {
+        return None;
+    }
+    lex.bump(span.end);
+    if command == LbToken::Error {
+        return None;
+    }
+    Some(command)
+}
+
+/// Lexes the `:Y` else branch that may directly follow a conditional.
+/// Returns `Some(None)` if there is no else branch,
+/// or `None` if the else branch is malformed.
+fn else_branch(lex: &mut Lexer<LbToken>) -> Option<Option<Box<LbToken>>> {
+    let Some(rest) = lex.remainder().strip_prefix(':') else {
+        return Some(None);
+    };
+    let mut sub_lex = LbToken::lexer(rest);
+    let command = sub_lex.next()?;
+    // the else command must directly follow the colon
+    if sub_lex.span().start != 0 || command == LbToken::Error {
+        return None;
+    }
+    lex.bump(1 + sub_lex.span().end);
+    Some(Some(Box::new(command)))
+}
+
+fn block(lex: &mut Lexer<LbToken>) -> Option<Vec<LbToken>> {
+    let remainder = lex.remainder();
+    let Some(end) = find_block_end(remainder) else {
+        // consume the rest of the program so the error covers the open block
+        lex.bump(remainder.len());
+        return None;
+    };
+    let body: Vec<LbToken> = LbToken::lexer(&remainder[..end]).collect();
+    lex.bump(end + 1);
+    Some(body)
+}
+
+fn block_comment(lex: &mut Lexer<LbToken>) -> Filter<()> {
+    let remainder = lex.remainder();
+    match find_comment_end(remainder) {
+        Some(end) => {
+            lex.bump(end);
+            Filter::Skip
+        },
+        None => {
+            // consume the rest of the program so the error covers the open comment
+            lex.bump(remainder.len());
+            Filter::Emit(())
+        },
+    }
+}
+
+fn execute_var(lex: &mut Lexer<LbToken>) -> Option<(char, String, Option<char>)> {
+    let token = lex.slice();
+    let fn_var = token.chars().nth(1)?;
+    let (args, dest) = match token[2..].split_once('>') {
+        Some((args, dest)) => (args, dest.chars().next()),
+        None => (&token[2..], None),
+    };
+    Some((fn_var, args.to_string(), dest))
+}
+
+fn execute_str(lex: &mut Lexer<LbToken>) -> Option<(String, String, Option<char>)> {
+    let token = lex.slice();
+    let end = token.rfind('\'')?;
+    let prog = unquote(&token[1..=end]);
+    let (args, dest) = match token[end + 1..].split_once('>') {
+        Some((args, dest)) => (args, dest.chars().next()),
+        None => (&token[end + 1..], None),
+    };
+    Some((prog, args.to_string(), dest))
+}
+
+fn get_input(lex: &mut Lexer<LbToken>) -> Option<(char, char, f64)> {
+    let token = lex.slice();
+    let valid_ops = "NS";
+    let op = token.chars().nth(1)?;
+    let var = token.chars().nth(2)?;
+    // op must be valid
+    if !valid_ops.contains(op) {
+        return None;
+    }
+    let num = token[3..].parse::<f64>().ok()?;
+    Some((op, var, num))
+}
+
+fn get_input_at(lex: &mut Lexer<LbToken>) -> Option<(char, char, char)> {
+    let token = lex.slice();
+    let valid_ops = "NS";
+    let op = token.chars().nth(1)?;
+    let var = token.chars().nth(2)?;
+    let index_var = token.chars().nth(3)?;
+    // op must be valid
+    if !valid_ops.contains(op) {
+        return None;
+    }
+    Some((op, var, index_var))
+}
+
+fn op_var_arg(lex: &mut Lexer<LbToken>) -> Option<char> {
+    lex.slice().chars().nth(2)
+}
+
+fn query_input(lex: &mut Lexer<LbToken>) -> Option<(char, char)> {
+    let token = lex.slice();
+    let var = token.chars().nth(2)?;
+    let op = token.chars().nth(3)?;
+    Some((var, op))
+}
+
+fn jump_if(lex: &mut Lexer<LbToken>) -> Option<(char, char)> {
+    let token = lex.slice();
+    let cond = token.chars().nth(2)?;
+    let label = token.chars().nth(3)?;
+    Some((cond, label))
+}
+
+impl LbToken {
+    /// Returns a copy of this command with every variable name passed through `rename`.
+    /// Nested commands are renamed too, but string literals and labels are left alone.
+    pub fn rename_vars(&self, rename: &impl Fn(char) -> char) -> LbToken {
+        use LbToken::*;
+        let r = rename;
+        let all = |vars: &String| vars.chars().map(r).collect::<String>();
+        // only the second variable of each argument pair belongs to this program
+        let argmap = |pairs: &String| pairs.chars()
+            .enumerate()
+            .map(|(i, c)| if i % 2 == 1 { r(c) } else { c })
+            .collect::<String>();
+        let sub = |command: &LbToken| Box::new(command.rename_vars(rename));
+        match self {
+            SaveNumber((a, num)) => SaveNumber((r(*a), *num)),
+            SaveStr((a, text)) => SaveStr((r(*a), text.clone())),
+            SaveList((a, items)) => SaveList((r(*a), items.clone())),
+            Copy((a, b)) => Copy((r(*a), r(*b))),
+            Append((a, b)) => Append((r(*a), r(*b))),
+            PrintVar(a) => PrintVar(r(*a)),
+            PrintLineVar(a) => PrintLineVar(r(*a)),
+            MathOp((op, a, b, c)) => MathOp((*op, r(*a), r(*b), r(*c))),
+            UnaryMathOp((op, a, b)) => UnaryMathOp((*op, r(*a), r(*b))),
+            BoolOp((op, a, b, c)) => BoolOp((*op, r(*a), r(*b), r(*c))),
+            UnaryBoolOp((op, a, b)) => UnaryBoolOp((*op, r(*a), r(*b))),
+            TextOp((op, vars)) => TextOp((*op, all(vars))),
+            ListOp((op, vars)) => ListOp((*op, all(vars))),
+            MapOp((op, vars)) => MapOp((*op, all(vars))),
+            Loop((a, x)) => Loop((r(*a), sub(x))),
+            Repeat((count, x)) => Repeat((*count, sub(x))),
+            IfStatement((a, x, y)) => IfStatement((r(*a), sub(x), y.as_deref().map(sub))),
+            Unless((a, x, y)) => Unless((r(*a), sub(x), y.as_deref().map(sub))),
+            WhileLoop((a, x)) => WhileLoop((r(*a), sub(x))),
+            ResetVar(a) => ResetVar(r(*a)),
+            GetInput((op, a, num)) => GetInput((*op, r(*a), *num)),
+            GetInputAt((op, a, b)) => GetInputAt((*op, r(*a), r(*b))),
+            InputCount(a) => InputCount(r(*a)),
+            QueryInput((a, op)) => QueryInput((r(*a), *op)),
+            Negate(a) => Negate(r(*a)),
+            Push(a) => Push(r(*a)),
+            Pop(a) => Pop(r(*a)),
+            StackDepth(a) => StackDepth(r(*a)),
+            Random(a) => Random(r(*a)),
+            Sleep(a) => Sleep(r(*a)),
+            Elapsed(a) => Elapsed(r(*a)),
+            Assert(a) => Assert(r(*a)),
+            AssertSame((a, b)) => AssertSame((r(*a), r(*b))),
+            FinishWithStatus(a) => FinishWithStatus(r(*a)),
+            JumpIf((a, label)) => JumpIf((r(*a), *label)),
+            Execute((z, pairs, dest)) => Execute((r(*z), argmap(pairs), dest.map(r))),
+            ExecuteStr((prog, pairs, dest)) => ExecuteStr((prog.clone(), argmap(pairs), dest.map(r))),
+            Return(a) => Return(r(*a)),
+            Block(commands) => Block(commands.iter().map(|command| command.rename_vars(rename)).collect()),
+            PrintStr(_) | PrintLineStr(_) | ResetAll | ResetAllBanks | SwitchBank(_) | Finish | Label(_) | Jump(_) | Error => {
+                self.clone()
+            },
+        }
+    }
+
+    /// Returns each variable this command names once, in order of appearance,
+    /// including the variables of nested commands.
+    pub fn vars(&self) -> Vec<char> {
+        let vars = std::cell::RefCell::new(Vec::new());
+        self.rename_vars(&|var| {
+            let mut vars = vars.borrow_mut();
+            if !vars.contains(&var) {
+                vars.push(var);
+            }
+            var
+        });
+        vars.into_inner()
+    }
+
+    /// Returns the commands nested directly inside this one,
+    /// such as the body of a loop or the contents of a block.
+    pub fn subcommands(&self) -> Vec<&LbToken> {
+        match self {
+            LbToken::Loop((_, sub)) | LbToken::Repeat((_, sub)) | LbToken::WhileLoop((_, sub)) => vec![sub.as_ref()],
+            LbToken::IfStatement((_, sub, else_command)) | LbToken::Unless((_, sub, else_command)) => {
+                let mut subs = vec![sub.as_ref()];
+                subs.extend(else_command.as_deref());
+                subs
+            },
+            LbToken::Block(commands) => commands.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns this command as source code that lexes back into the same command.
+    /// Strings are quoted, with quotes, backslashes, and control characters escaped.
+    /// The body of a loop or conditional follows its condition directly, like `LaPb` or `LaSb1`,
+    /// unless it ends with an else branch that would be taken by the conditional it is in.
+    /// Then it is put in a block, like `Ia[ IbPc:Pd ]`, so it lexes back as a block holding it.
+    /// Numbers must be finite, and are written in their shortest form that reads back
+    /// as the same number, so `4.0` is written as `4`, which lexes to the same value.
+    /// [LbToken::Error] is written as nothing. This is also how commands are displayed.
+    pub fn to_source(&self) -> String {
+        use LbToken::*;
+        let dest = |dest: &Option<char>| dest.map_or(String::new(), |dest| format!(">{dest}"));
+        match self {
+            SaveNumber((a, num)) => format!("S{a}{num}"),
+            SaveStr((a, text)) => format!("S{a}{}", quote(text)),
+            SaveList((a, items)) => format!("S{a}{}", list_source(items)),
+            Copy((a, b)) => format!("C{a}{b}"),
+            Append((a, b)) => format!("A{a}{b}"),
+            PrintVar(a) => format!("P{a}"),
+            PrintStr(text) => format!("P{}", quote(text)),
+            PrintLineVar(a) => format!("O{a}"),
+            PrintLineStr(text) => format!("O{}", quote(text)),
+            MathOp((op, a, b, c)) => format!("M{op}{a}{b}{c}"),
+            UnaryMathOp((op, a, b)) => format!("M{op}{a}{b}"),
+            BoolOp((op, a, b, c)) => format!("B{op}{a}{b}{c}"),
+            UnaryBoolOp((op, a, b)) => format!("B{op}{a}{b}"),
+            TextOp((op, vars)) => format!("T{op}{vars}"),
+            ListOp((op, vars)) => format!("V{op}{vars}"),
+            MapOp((op, vars)) => format!("H{op}{vars}"),
+            Loop((a, x)) => format!("L{a}{}", x.to_source()),
+            Repeat((count, x)) => format!("L{count}{}", x.to_source()),
+            WhileLoop((a, x)) => format!("W{a}{}", x.to_source()),
+            IfStatement((a, x, y)) => format!("I{a}{}{}", body_source(x), else_source(y)),
+            Unless((a, x, y)) => format!("U{a}{}{}", body_source(x), else_source(y)),
+            ResetVar(a) => format!("R{a}"),
+            ResetAll => String::from("R*"),
+            ResetAllBanks => String::from("R#"),
+            SwitchBank(bank) => format!("#{bank}"),
+            GetInput((op, a, num)) => format!("G{op}{a}{num}"),
+            GetInputAt((op, a, b)) => format!("G{op}{a}{b}"),
+            InputCount(a) => format!("G#{a}"),
+            QueryInput((a, op)) => format!("GQ{a}{op}"),
+            Negate(a) => format!("N{a}"),
+            Push(a) => format!("K+{a}"),
+            Pop(a) => format!("K-{a}"),
+            StackDepth(a) => format!("K#{a}"),
+            Random(a) => format!("D{a}"),
+            Sleep(a) => format!("Q{a}"),
+            Elapsed(a) => format!("E{a}"),
+            Assert(a) => format!("Z{a}"),
+            AssertSame((a, b)) => format!("Z{a}{b}"),
+            Finish => String::from("F"),
+            FinishWithStatus(a) => format!("F{a}"),
+            Label(a) => format!(":{a}"),
+            Jump(a) => format!("J{a}"),
+            JumpIf((a, label)) => format!("JI{a}{label}"),
+            Execute((z, pairs, d)) => format!("X{z}{pairs}{}", dest(d)),
+            ExecuteStr((prog, pairs, d)) => format!("X{}{pairs}{}", quote(prog), dest(d)),
+            Return(a) => format!("Y{a}"),
+            Block(commands) if commands.is_empty() => String::from("[ ]"),
+            Block(commands) => format!("[ {} ]", to_source(commands)),
+            Error => String::new(),
+        }
+    }
+}
+
+impl fmt::Display for LbToken {
+    /// Writes this command as source code that lexes back into the same command.
+    /// See [LbToken::to_source].
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_source())
+    }
+}
+
+/// Returns a list of commands as source code, separated by spaces. See [LbToken::to_source].
+pub fn to_source(commands: &[LbToken]) -> String {
+    commands.iter().map(LbToken::to_source).collect::<Vec<String>>().join(" ")
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for LbToken {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LbToken::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for LbToken {
+    /// Deserializes a command, failing if it couldn't have been lexed.
+    /// Nested commands are checked as they are deserialized.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let token = LbToken::deserialize(deserializer)?;
+        token.check_fields().map_err(serde::de::Error::custom)?;
+        Ok(token)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl LbToken {
+    /// Checks that this command's own fields are ones the lexer could give it:
+    /// variables are letters, labels are lowercase letters, ops belong to their command
+    /// and are given the right number of variables, and Execute parameters are paired.
+    /// Nested commands aren't checked.
+    fn check_fields(&self) -> Result<(), String> {
+        use LbToken::*;
+        let command = || self.to_source().chars().next().unwrap_or('?');
+        let vars = match self {
+            Loop((a, _)) | WhileLoop((a, _)) | IfStatement((a, _, _)) | Unless((a, _, _)) => vec![*a],
+            Repeat(_) | Block(_) => Vec::new(),
+            // the parameters of an executed program are its own variables
+            Execute((_, pairs, _)) | ExecuteStr((_, pairs, _)) => {
+                let mut vars = self.vars();
+                vars.extend(pairs.chars());
+                vars
+            },
+            _ => self.vars(),
+        };
+        if let Some(var) = vars.into_iter().find(|var| !var.is_ascii_alphabetic()) {
+            return Err(format!("{}: {var} is not a variable name", command()));
+        }
+
+        match self {
+            MathOp((op, ..)) | UnaryMathOp((op, ..)) | BoolOp((op, ..)) | UnaryBoolOp((op, ..)) => {
+                let ops = match self {
+                    MathOp(_) => MATH_OPS,
+                    UnaryMathOp(_) => UNARY_MATH_OPS,
+                    BoolOp(_) => BOOL_OPS,
+                    _ => UNARY_BOOL_OPS,
+                };
+                if !ops.contains(*op) {
+                    return Err(format!("{}: Invalid op {op}", command()));
+                }
+            },
+            TextOp((op, args)) | ListOp((op, args)) | MapOp((op, args)) => {
+                let found = args.chars().count();
+                match op_arity(command(), *op) {
+                    None => return Err(format!("{}: Invalid op {op}", command())),
+                    Some(expected) if expected != found => {
+                        return Err(format!("{}: Op {op} takes {expected} variables, but was given {found}", command()));
+                    },
+                    Some(_) => {},
+                }
+            },
+            GetInput((op, _, _)) | GetInputAt((op, _, _)) | QueryInput((_, op)) if !"NS".contains(*op) => {
+                return Err(format!("G: Invalid op {op}"));
+            },
+            GetInput((_, _, num)) if num.fract() != 0.0 || *num < 0.0 => {
+                return Err(format!("G: {num} is not an input index"));
+            },
+            Label(label) | Jump(label) | JumpIf((_, label)) if !label.is_ascii_lowercase() => {
+                return Err(format!("{}: {label} is not a label name", command()));
+            },
+            Execute((_, pairs, _)) | ExecuteStr((_, pairs, _)) if pairs.chars().count() % 2 == 1 => {
+                let last = pairs.chars().last().unwrap_or_default();
+                return Err(format!("X: Parameter {last} isn't given a variable"));
+            },
+            Error => return Err(String::from("Unrecognized instruction")),
+            _ => {},
+        }
+        Ok(())
+    }
+}
+
+/// Returns the source code of the body of a conditional, as it follows the condition.
+fn body_source(body: &LbToken) -> String {
+    let source = body.to_source();
+    if ends_with_else(body) {
+        format!("[ {source} ]")
+    }
+    else {
+        source
+    }
+}
+
+/// Returns whether a command's source code ends with an else branch, like `IaPb:Pc` or `LaIbPc:Pd`.
+pub fn ends_with_else(command: &LbToken) -> bool {
+    match command {
+        LbToken::IfStatement((_, _, Some(_))) | LbToken::Unless((_, _, Some(_))) => true,
+        LbToken::Loop((_, body)) | LbToken::Repeat((_, body)) | LbToken::WhileLoop((_, body)) => ends_with_else(body),
+        _ => false,
+    }
+}
+
+/// Returns the source code of a conditional's else command, with its colon, if it has one.
+fn else_source(else_command: &Option<Box<LbToken>>) -> String {
+    else_command.as_ref().map_or(String::new(), |command| format!(":{}", command.to_source()))
+}
+
+/// Returns the source code of a list literal, like `[1 'two' [3]]`.
+fn list_source(items: &[ListItem]) -> String {
+    let items: Vec<String> = items.iter().map(|item| match item {
+        ListItem::Number(num) => num.to_string(),
+        ListItem::Text(text) => quote(text),
+        ListItem::List(items) => list_source(items),
+    }).collect();
+    format!("[{}]", items.join(" "))
+}
+
+/// Quotes text as a string literal, escaping what [unescape] unescapes.
+pub fn quote(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('\'');
+    for c in text.chars() {
+        match c {
+            '\'' => literal.push_str("\\'"),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\t' => literal.push_str("\\t"),
+            '\r' => literal.push_str("\\r"),
+            other => literal.push(other),
+        }
+    }
+    literal.push('\'');
+    literal
+}
+
+/// Where a command and the commands nested in it are in the source code.
+/// The children are the bodies of loops and conditionals, in order,
+/// and the contents of blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanTree {
+    /// The byte range of the command.
+    pub span: Range<usize>,
+
+    /// Where each command nested directly inside this one is.
+    pub children: Vec<SpanTree>,
+}
+
+impl SpanTree {
+    /// Finds where the commands nested in a command are,
+    /// given the command and where it was lexed from in the source.
+    pub fn new(token: &LbToken, source: &str, span: Range<usize>) -> SpanTree {
+        use LbToken::*;
+        let slice = &source[span.clone()];
+        let mut child_spans: Vec<Range<usize>> = Vec::new();
+        match token {
+            // the command follows the condition, like the X in LaX
+            Loop(_) | WhileLoop(_) => child_spans.push(span.start + 2..span.end),
+            // the command follows the count, like the X in L12X
+            Repeat(_) => {
+                let count_len = slice[1..].find(|c: char| !c.is_ascii_digit() && c != '-' && c != '.').unwrap_or(0);
+                child_spans.push(span.start + 1 + count_len..span.end);
+            },
+            IfStatement(_) | Unless(_) => {
+                let body = branch_span(&slice[2..]);
+                child_spans.push(span.start + 2 + body.start..span.start + 2 + body.end);
+                // an else command follows a colon, like the Y in IaX:Y
+                let else_start = 2 + body.end + 1;
+                if let Some(rest) = slice[2 + body.end..].strip_prefix(':') {
+                    if let Some((_, sub)) = LbToken::lexer(rest).spanned().next() {
+                        child_spans.push(span.start + else_start + sub.start..span.start + else_start + sub.end);
+                    }
+                }
+            },
+            Block(_) => {
+                let inner = &slice[1..slice.len() - 1];
+                for (_, sub) in LbToken::lexer(inner).spanned() {
+                    child_spans.push(span.start + 1 + sub.start..span.start + 1 + sub.end);
+                }
+            },
+            _ => {},
+        }
+
+        let children = token.subcommands().into_iter()
+            .zip(child_spans)
+            .map(|(command, span)| SpanTree::new(command, source, span))
+            .collect();
+        SpanTree { span, children }
+    }
+
+    /// Returns the tree for the nested command at the given index,
+    /// or this tree if it couldn't be found.
+    pub fn child(&self, index: usize) -> &SpanTree {
+        self.children.get(index).unwrap_or(self)
+    }
+}
+
+// Utilities
+
+/// The ops of [LbToken::MathOp], [LbToken::UnaryMathOp], [LbToken::BoolOp], and [LbToken::UnaryBoolOp].
+pub const MATH_OPS: &str = "ASMDQEGLROPIXTNHW";
+pub const UNARY_MATH_OPS: &str = "FCRANSOTQLD";
+pub const BOOL_OPS: &str = "EAOXS&|^<>";
+pub const UNARY_BOOL_OPS: &str = "N";
+
+/// Returns how many variables an op of a text (`T`), list (`V`), or map (`H`) command takes,
+/// or `None` if the command has no such op.
+pub fn op_arity(command: char, op: char) -> Option<usize> {
+    match (command, op) {
+        ('T', 'S' | 'P' | 'R') => Some(4),
+        ('T', 'K' | 'N' | 'F' | 'C' | 'I') => Some(3),
+        ('T', 'U' | 'L' | 'T' | 'B' | 'E' | 'O' | 'H') => Some(2),
+        ('V', 'S' | 'G' | 'P') | ('H', 'S' | 'G' | 'C') => Some(3),
+        ('V', 'A' | 'L') | ('H', 'R' | 'L') => Some(2),
+        ('V' | 'H', 'N') => Some(1),
+        _ => None,
+    }
+}
+
+/// Returns whether a command that takes an op letter, like the `M` in `MAabc`, has the given op.
+pub fn is_valid_op(command: char, op: char) -> bool {
+    match command {
+        'M' => MATH_OPS.contains(op) || UNARY_MATH_OPS.contains(op),
+        'B' => BOOL_OPS.contains(op) || UNARY_BOOL_OPS.contains(op),
+        _ => op_arity(command, op).is_some(),
+    }
+}
+
+/// Finds the command that follows the condition of a conditional, like the `X` in `IaX:Y` or `Ia[ X ]`,
+/// given the source after the condition, as [branch] lexes it.
+fn branch_span(src: &str) -> Range<usize> {
+    LbToken::lexer_with_extras(src, true).spanned().next().map_or(0..src.len(), |(_, span)| span)
+}
+
+/// Lexes the variables that follow an op letter, like the `dsil` in `TSdsil`.
+/// Takes exactly `arity` letters, so another command can directly follow.
+/// If the op is invalid (`None`) or there aren't enough letters,
+/// consumes all of the following letters and fails.
+fn op_args(lex: &mut Lexer<LbToken>, arity: Option<usize>) -> Option<(char, String)> {
+    let op = lex.slice().chars().nth(1)?;
+    let letters = lex.remainder().chars().take_while(|c| c.is_ascii_alphabetic()).count();
+    match arity {
+        Some(arity) if letters >= arity => {
+            let args = lex.remainder()[..arity].to_string();
+            lex.bump(arity);
+            Some((op, args))
+        },
+        _ => {
+            lex.bump(letters);
+            None
+        },
+    }
+}
+
+/// Finds the bracket that closes a block whose opening bracket
+/// has already been consumed, skipping over nested blocks,
+/// string literals, and comments.
+/// Returns its byte offset in the given source, or `None` if the block never closes.
+fn find_block_end(src: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = src.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(i),
+            ']' => depth -= 1,
+            // skip string literals
+            '\'' => loop {
+                match chars.next()?.1 {
+                    '\\' => { chars.next(); },
+                    '\'' => break,
+                    _ => {},
+                }
+            },
+            // skip comments
+            '!' if src[i + 1..].starts_with('{') => {
+                let end = i + 2 + find_comment_end(&src[i + 2..])?;
+                // resume scanning after the comment
+                let skipped = src[i + 1..end].chars().count();
+                chars.nth(skipped - 1);
+            },
+            '!' => loop {
+                match chars.next()?.1 {
+                    '\n' | '\r' => break,
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Finds the end of a block comment whose opening `!{` has already been consumed,
+/// skipping over nested block comments.
+/// Returns the byte offset just past its closing `}!` in the given source,
+/// or `None` if the comment never closes.
+pub fn find_comment_end(src: &str) -> Option<usize> {
+    // the markers are ASCII, so scanning bytes never splits a character
+    let bytes = src.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"!{") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"}!") {
+            if depth == 0 {
+                return Some(i + 2);
+            }
+            depth -= 1;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Parses the items of a list literal, given the source between its brackets.
+/// Returns `None` if an item can't be parsed, or isn't separated from the next by whitespace.
+fn list_items(src: &str) -> Option<Vec<ListItem>> {
+    let mut items = Vec::new();
+    let mut rest = src.trim_start();
+    while !rest.is_empty() {
+        let len = if let Some(inner) = rest.strip_prefix('[') {
+            let end = find_block_end(inner)?;
+            items.push(ListItem::List(list_items(&inner[..end])?));
+            end + 2
+        } else if rest.starts_with('\'') {
+            let len = string_literal_len(rest)?;
+            items.push(ListItem::Text(unquote(&rest[..len])));
+            len
+        } else {
+            let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            items.push(ListItem::Number(parse_list_number(&rest[..len])?));
+            len
+        };
+        let after = &rest[len..];
+        rest = after.trim_start();
+        if !rest.is_empty() && rest.len() == after.len() {
+            return None;
+        }
+    }
+    Some(items)
+}
+
+/// Parses a number in a list literal, written as [LbToken::SaveNumber] would save it.
+fn parse_list_number(literal: &str) -> Option<f64> {
+    if let Some(num) = parse_radix_number(literal) {
+        return Some(num);
+    }
+    let digits = literal.strip_prefix('-').unwrap_or(literal);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) || digits.ends_with('.') {
+        return None;
+    }
+    literal.parse::<f64>().ok().filter(|num| num.is_finite())
+}
+
+/// Returns the length in bytes of the string literal at the start of the given source,
+/// including its quotes, or `None` if it never closes.
+fn string_literal_len(src: &str) -> Option<usize> {
+    let mut chars = src.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); },
+            '\'' => return Some(i + 1),
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Strips the surrounding quotes from a string literal
+/// and resolves its escape sequences.
+fn unquote(literal: &str) -> String {
+    unescape(&literal[1..literal.len() - 1])
+}
+
+/// Resolves the escape sequences in the body of a string literal.
+/// `\'` becomes `'`, `\\` becomes `\`, and `\n`, `\t`, and `\r`
+/// become a newline, tab, and carriage return.
+/// Any other backslash is kept as-is.
+fn unescape(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\'') => result.push('\''),
+            Some('\\') => result.push('\\'),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            },
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[test]
+fn tokens_parse_correctly() {
+    let mut lex = LbToken::lexer("Sa4.4 Cab P'hello world' Pa i ! This is a comment".trim());
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 4.4))));
+    assert_eq!(lex.slice(), "Sa4.4");
+    assert_eq!(lex.next(), Some(LbToken::Copy(('a', 'b'))));
+    assert_eq!(lex.slice(), "Cab");
+    assert_eq!(lex.next(), Some(LbToken::PrintStr(String::from("hello world"))));
+    assert_eq!(lex.slice(), "P'hello world'");
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.slice(), "Pa");
+    assert_eq!(lex.next(), Some(LbToken::Error)); 
+    assert_eq!(lex.slice(), "i");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn advanced_tokens() {
+    let mut lex = LbToken::lexer("MAbcd R* WaIcXzabcd !comment here".trim());
+    assert_eq!(lex.next(), Some(LbToken::MathOp(('A', 'b', 'c', 'd'))));
+    assert_eq!(lex.slice(), "MAbcd");
+    assert_eq!(lex.next(), Some(LbToken::ResetAll));
+    assert_eq!(lex.slice(), "R*");
+    assert_eq!(lex.next(), Some(
+        LbToken::WhileLoop(('a', Box::new(
+            LbToken::IfStatement(('c', Box::new(
+                LbToken::Execute(('z', String::from("abcd"), None))
+            ), None))
+        )))
+    ));
+    assert_eq!(lex.slice(), "WaIcXzabcd");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn branch_lengths() {
+    use LbToken::*;
+    // the body is exactly one command, and whatever follows it is lexed separately
+    let mut lex = LbToken::lexer("LaPb Pc LaPbPc WaIcXzab Pd");
+    assert_eq!(lex.next(), Some(Loop(('a', Box::new(PrintVar('b'))))));
+    assert_eq!(lex.next(), Some(PrintVar('c')));
+    assert_eq!(lex.next(), Some(Loop(('a', Box::new(PrintVar('b'))))));
+    assert_eq!(lex.slice(), "LaPb");
+    assert_eq!(lex.next(), Some(PrintVar('c')));
+    assert_eq!(lex.slice(), "Pc");
+    let execute = Box::new(Execute(('z', String::from("ab"), None)));
+    assert_eq!(lex.next(), Some(WhileLoop(('a', Box::new(IfStatement(('c', execute, None)))))));
+    assert_eq!(lex.slice(), "WaIcXzab");
+    assert_eq!(lex.next(), Some(PrintVar('d')));
+    assert_eq!(lex.next(), None);
+
+    // an else branch after a conditional body belongs to the outer conditional,
+    // even through a loop, but a conditional in a loop takes it
+    let mut lex = LbToken::lexer("IaLbIcPd:Pe LaIbPc:Pd");
+    let inner = Box::new(Loop(('b', Box::new(IfStatement(('c', Box::new(PrintVar('d')), None))))));
+    assert_eq!(lex.next(), Some(IfStatement(('a', inner, Some(Box::new(PrintVar('e')))))));
+    let inner = Box::new(IfStatement(('b', Box::new(PrintVar('c')), Some(Box::new(PrintVar('d'))))));
+    assert_eq!(lex.next(), Some(Loop(('a', inner))));
+    assert_eq!(lex.next(), None);
+
+    // a missing or broken body is an error that covers it
+    let mut lex = LbToken::lexer("La Pb Wa~ Ia");
+    assert_eq!(lex.next(), Some(Error));
+    assert_eq!(lex.slice(), "La");
+    assert_eq!(lex.next(), Some(PrintVar('b')));
+    assert_eq!(lex.next(), Some(Error));
+    assert_eq!(lex.slice(), "Wa~");
+    assert_eq!(lex.next(), Some(Error));
+    assert_eq!(lex.slice(), "Ia");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn branch_bodies() {
+    use LbToken::*;
+    let body = |command: LbToken| Box::new(command);
+    let text = |text: &str| String::from(text);
+    // any single command can be a body, including ones with numbers, strings, and spaces in strings
+    let mut lex = LbToken::lexer("LaSb5 IaP'hi there' UaGNb0 WaSb'x' LbX'Pa'ac>d IaO'[ ]'");
+    assert_eq!(lex.next(), Some(Loop(('a', body(SaveNumber(('b', 5.0)))))));
+    assert_eq!(lex.next(), Some(IfStatement(('a', body(PrintStr(text("hi there"))), None))));
+    assert_eq!(lex.next(), Some(Unless(('a', body(GetInput(('N', 'b', 0.0))), None))));
+    assert_eq!(lex.next(), Some(WhileLoop(('a', body(SaveStr(('b', text("x"))))))));
+    assert_eq!(lex.next(), Some(Loop(('b', body(ExecuteStr((text("Pa"), text("ac"), Some('d'))))))));
+    assert_eq!(lex.next(), Some(IfStatement(('a', body(PrintLineStr(text("[ ]"))), None))));
+    assert_eq!(lex.next(), None);
+
+    // nested two deep
+    let mut lex = LbToken::lexer("LaIbSc-1.5 IaLbP'x y':O'z' WaUbGSc1:Sd'e' LaWbLcO'!'");
+    assert_eq!(lex.next(), Some(Loop(('a', body(IfStatement(('b', body(SaveNumber(('c', -1.5))), None)))))));
+    let inner = body(Loop(('b', body(PrintStr(text("x y"))))));
+    assert_eq!(lex.next(), Some(IfStatement(('a', inner, Some(body(PrintLineStr(text("z"))))))));
+    let inner = body(Unless(('b', body(GetInput(('S', 'c', 1.0))), Some(body(SaveStr(('d', text("e"))))))));
+    assert_eq!(lex.next(), Some(WhileLoop(('a', inner))));
+    assert_eq!(lex.slice(), "WaUbGSc1:Sd'e'");
+    let inner = body(WhileLoop(('b', body(Loop(('c', body(PrintLineStr(text("!")))))))));
+    assert_eq!(lex.next(), Some(Loop(('a', inner))));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn counted_loops() {
+    use LbToken::*;
+    let mut lex = LbToken::lexer("L5Pa L12[ Pa ] L2.7Pa L-1Pa L0Sa5 LaL3Pb L3LaPb L Pa");
+    assert_eq!(lex.next(), Some(Repeat((5, Box::new(PrintVar('a'))))));
+    assert_eq!(lex.next(), Some(Repeat((12, Box::new(Block(vec![PrintVar('a')]))))));
+    assert_eq!(lex.next(), Some(Repeat((2, Box::new(PrintVar('a'))))));
+    assert_eq!(lex.next(), Some(Repeat((0, Box::new(PrintVar('a'))))));
+    assert_eq!(lex.next(), Some(Repeat((0, Box::new(SaveNumber(('a', 5.0)))))));
+    assert_eq!(lex.next(), Some(Loop(('a', Box::new(Repeat((3, Box::new(PrintVar('b')))))))));
+    assert_eq!(lex.next(), Some(Repeat((3, Box::new(Loop(('a', Box::new(PrintVar('b')))))))));
+    assert_eq!(lex.next(), Some(Error));
+    assert_eq!(lex.next(), Some(PrintVar('a')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn multi_line_comments() {
+    let mut lex = LbToken::lexer("! This program prints out n fibonacci numbers.
+! Works for any number n = 0 or greater.
+! Input
+Sn0 ! GNn0
+
+! variables
+Sa0 Sb1".trim());
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('n', 0.0))));
+    assert_eq!(lex.slice(), "Sn0");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 0.0))));
+    assert_eq!(lex.slice(), "Sa0");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('b', 1.0))));
+    assert_eq!(lex.slice(), "Sb1");
+    assert_eq!(lex.next(), None);
+}
+
+
+
+#[test]
+fn control_escapes() {
+    let mut lex = LbToken::lexer(r"P'a\nb\tc\rd' Sa'\\n' O'\q' P'back\\' P'open\'");
+    assert_eq!(lex.next(), Some(LbToken::PrintStr(String::from("a\nb\tc\rd"))));
+    // an escaped backslash followed by n is not a newline
+    assert_eq!(lex.next(), Some(LbToken::SaveStr(('a', String::from("\\n")))));
+    // unknown escapes are kept as-is
+    assert_eq!(lex.next(), Some(LbToken::PrintLineStr(String::from("\\q"))));
+    // a string can end with a backslash if it is escaped
+    assert_eq!(lex.next(), Some(LbToken::PrintStr(String::from("back\\"))));
+    // a lone backslash escapes the closing quote
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn block_comments() {
+    let mut lex = LbToken::lexer("Sa1 !{ Pa }! Pa !{ multi
+line !{ nested }! Pb }! Pc !{ []] }! [ Pd !{ ] }! ] !
+Pe !{ unclosed Pf");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 1.0))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('c')));
+    assert_eq!(lex.next(), Some(LbToken::Block(vec![LbToken::PrintVar('d')])));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('e')));
+    // an unclosed comment is an error starting where the comment opens
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "!{ unclosed Pf");
+    assert_eq!(lex.next(), None);
+
+    // comments can hold any text
+    let mut lex = LbToken::lexer("!{ é }! Pa !{ ü !{ 日本 }! ñ }! Pb [ !{ é }! Pc ]");
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('b')));
+    assert_eq!(lex.next(), Some(LbToken::Block(vec![LbToken::PrintVar('c')])));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn escaped_quotes() {
+    let mut lex = LbToken::lexer(r"P'don\'t panic' Sa'\'' P'back\\' Sb'a\\\'b'");
+    assert_eq!(lex.next(), Some(LbToken::PrintStr(String::from("don't panic"))));
+    assert_eq!(lex.slice(), r"P'don\'t panic'");
+    assert_eq!(lex.next(), Some(LbToken::SaveStr(('a', String::from("'")))));
+    assert_eq!(lex.slice(), r"Sa'\''");
+    assert_eq!(lex.next(), Some(LbToken::PrintStr(String::from("back\\"))));
+    assert_eq!(lex.slice(), r"P'back\\'");
+    assert_eq!(lex.next(), Some(LbToken::SaveStr(('b', String::from("a\\'b")))));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn trailing_backslash_leaves_string_open() {
+    let mut lex = LbToken::lexer(r"P'oops\'");
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn scientific_notation() {
+    let mut lex = LbToken::lexer("Sa1.5e-7 Sb2E10 Sc3e5 Sd-2.5e+3");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 1.5e-7))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('b', 2e10))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('c', 3e5))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('d', -2.5e3))));
+    assert_eq!(lex.next(), None);
+
+    let mut lex = LbToken::lexer("Sae5");
+    assert_eq!(lex.next(), Some(LbToken::Error));
+
+    // too big for an f64
+    let mut lex = LbToken::lexer("Sa1e308 Sa1e400 Sa-1e400");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 1e308))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn radix_numbers() {
+    let mut lex = LbToken::lexer("Sa0xFF Sb0b1010 Sc-0x10 Sd0x20000000000000 Se0x20000000000001");
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('a', 255.0))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('b', 10.0))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('c', -16.0))));
+    assert_eq!(lex.next(), Some(LbToken::SaveNumber(('d', 9007199254740992.0))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "Se0x20000000000001");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn blocks() {
+    let mut lex = LbToken::lexer("Wa[ Sb5 MAabc Pb ] [ Pa ]");
+    assert_eq!(lex.next(), Some(LbToken::WhileLoop(('a', Box::new(
+        LbToken::Block(vec![
+            LbToken::SaveNumber(('b', 5.0)),
+            LbToken::MathOp(('A', 'a', 'b', 'c')),
+            LbToken::PrintVar('b'),
+        ])
+    )))));
+    assert_eq!(lex.slice(), "Wa[ Sb5 MAabc Pb ]");
+    assert_eq!(lex.next(), Some(LbToken::Block(vec![LbToken::PrintVar('a')])));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn nested_blocks() {
+    let mut lex = LbToken::lexer("La[ Ib[ P']' ] ! ]
+    Pc ]");
+    assert_eq!(lex.next(), Some(LbToken::Loop(('a', Box::new(
+        LbToken::Block(vec![
+            LbToken::IfStatement(('b', Box::new(
+                LbToken::Block(vec![LbToken::PrintStr(String::from("]"))])
+            ), None)),
+            LbToken::PrintVar('c'),
+        ])
+    )))));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn unterminated_block() {
+    let mut lex = LbToken::lexer("Pa Wa[ Pb [ Pc ]");
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.span(), 3..16);
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn else_branches() {
+    let mut lex = LbToken::lexer("IaPb:Pc Ua[ Pb ]:[ Pc ] IaIbPc:Pd IaPb: Pc");
+    assert_eq!(lex.next(), Some(LbToken::IfStatement((
+        'a',
+        Box::new(LbToken::PrintVar('b')),
+        Some(Box::new(LbToken::PrintVar('c'))),
+    ))));
+    assert_eq!(lex.slice(), "IaPb:Pc");
+    assert_eq!(lex.next(), Some(LbToken::Unless((
+        'a',
+        Box::new(LbToken::Block(vec![LbToken::PrintVar('b')])),
+        Some(Box::new(LbToken::Block(vec![LbToken::PrintVar('c')]))),
+    ))));
+    assert_eq!(lex.next(), Some(LbToken::IfStatement((
+        'a',
+        Box::new(LbToken::IfStatement(('b', Box::new(LbToken::PrintVar('c')), None))),
+        Some(Box::new(LbToken::PrintVar('d'))),
+    ))));
+    // the else command must directly follow the colon
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "IaPb");
+}
+
+#[test]
+fn unary_math_ops() {
+    let mut lex = LbToken::lexer("MFab MCab MRab MZab MFabc");
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('F', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('C', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('R', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "MZab");
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "MFabc");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn bitwise_ops() {
+    let mut lex = LbToken::lexer("B&abc B|abc B^abc B<abc B>abc B~abc");
+    assert_eq!(lex.next(), Some(LbToken::BoolOp(('&', 'a', 'b', 'c'))));
+    assert_eq!(lex.next(), Some(LbToken::BoolOp(('|', 'a', 'b', 'c'))));
+    assert_eq!(lex.next(), Some(LbToken::BoolOp(('^', 'a', 'b', 'c'))));
+    assert_eq!(lex.next(), Some(LbToken::BoolOp(('<', 'a', 'b', 'c'))));
+    assert_eq!(lex.next(), Some(LbToken::BoolOp(('>', 'a', 'b', 'c'))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn unary_bool_ops() {
+    let mut lex = LbToken::lexer("BNab BAab BNabc IcBNab");
+    assert_eq!(lex.next(), Some(LbToken::UnaryBoolOp(('N', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "BAab");
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "BNabc");
+    assert_eq!(lex.next(), Some(LbToken::IfStatement(('c', Box::new(LbToken::UnaryBoolOp(('N', 'a', 'b'))), None))));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn random() {
+    let mut lex = LbToken::lexer("Da DA D1");
+    assert_eq!(lex.next(), Some(LbToken::Random('a')));
+    assert_eq!(lex.next(), Some(LbToken::Random('A')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn text_ops() {
+    let mut lex = LbToken::lexer("TSdsil TSdsi TSdsilPa TZab");
+    assert_eq!(lex.next(), Some(LbToken::TextOp(('S', String::from("dsil")))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "TSdsi");
+    assert_eq!(lex.next(), Some(LbToken::TextOp(('S', String::from("dsil")))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn list_literals() {
+    use ListItem::*;
+    let text = |text: &str| Text(String::from(text));
+    let mut lex = LbToken::lexer("Sa[1 -2.5 0xFF] Sb[ 'x y' '\\']' [3 []] ]Pa Sc[]");
+    assert_eq!(lex.next(), Some(LbToken::SaveList(('a', vec![Number(1.0), Number(-2.5), Number(255.0)]))));
+    assert_eq!(lex.next(), Some(LbToken::SaveList(('b', vec![text("x y"), text("']"), List(vec![Number(3.0), List(Vec::new())])]))));
+    assert_eq!(lex.slice(), "Sb[ 'x y' '\\']' [3 []] ]");
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::SaveList(('c', Vec::new()))));
+    assert_eq!(lex.next(), None);
+
+    // items must be numbers, strings, or lists, separated by whitespace
+    let mut lex = LbToken::lexer("Sa[1 a] Sb['x''y'] Sc[1.] Sd[[1][2]] Pa Se[1 2");
+    for slice in ["Sa[1 a]", "Sb['x''y']", "Sc[1.]", "Sd[[1][2]]"] {
+        assert_eq!(lex.next(), Some(LbToken::Error));
+        assert_eq!(lex.slice(), slice);
+    }
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    // an open list covers the rest of the program
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "Se[1 2");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn print_lines() {
+    let mut lex = LbToken::lexer("Oa O'it\\'s' Pa");
+    assert_eq!(lex.next(), Some(LbToken::PrintLineVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::PrintLineStr(String::from("it's"))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn get_input_at_tokens() {
+    let mut lex = LbToken::lexer("GNab GSab GQab GNa1 G#a");
+    assert_eq!(lex.next(), Some(LbToken::GetInputAt(('N', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::GetInputAt(('S', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::GetInput(('N', 'a', 1.0))));
+    assert_eq!(lex.next(), Some(LbToken::InputCount('a')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn query_input_tokens() {
+    let mut lex = LbToken::lexer("GQaN GQbS GNa0 GQaX");
+    assert_eq!(lex.next(), Some(LbToken::QueryInput(('a', 'N'))));
+    assert_eq!(lex.next(), Some(LbToken::QueryInput(('b', 'S'))));
+    assert_eq!(lex.next(), Some(LbToken::GetInput(('N', 'a', 0.0))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn execute_with_return() {
+    let mut lex = LbToken::lexer("Xz>r Xzab>r Xzab Ya");
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::new(), Some('r')))));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("ab"), Some('r')))));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("ab"), None))));
+    assert_eq!(lex.next(), Some(LbToken::Return('a')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn execute_inline_tokens() {
+    let mut lex = LbToken::lexer(r"X'Pa' X'P\'a\''ab>r X'' X'Pa");
+    assert_eq!(lex.next(), Some(LbToken::ExecuteStr((String::from("Pa"), String::new(), None))));
+    assert_eq!(lex.next(), Some(LbToken::ExecuteStr((String::from("P'a'"), String::from("ab"), Some('r')))));
+    assert_eq!(lex.next(), Some(LbToken::ExecuteStr((String::new(), String::new(), None))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn list_ops() {
+    let mut lex = LbToken::lexer("VNl VSliv VGdli VAlv VLdl VSli VZl");
+    assert_eq!(lex.next(), Some(LbToken::ListOp(('N', String::from("l")))));
+    assert_eq!(lex.next(), Some(LbToken::ListOp(('S', String::from("liv")))));
+    assert_eq!(lex.next(), Some(LbToken::ListOp(('G', String::from("dli")))));
+    assert_eq!(lex.next(), Some(LbToken::ListOp(('A', String::from("lv")))));
+    assert_eq!(lex.next(), Some(LbToken::ListOp(('L', String::from("dl")))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn map_ops() {
+    let mut lex = LbToken::lexer("HNm HSmkv HGdmk HCdmk HRmk HLdm HRm HZm");
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('N', String::from("m")))));
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('S', String::from("mkv")))));
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('G', String::from("dmk")))));
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('C', String::from("dmk")))));
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('R', String::from("mk")))));
+    assert_eq!(lex.next(), Some(LbToken::MapOp(('L', String::from("dm")))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn finish_tokens() {
+    let mut lex = LbToken::lexer("F Fa F a IaF:Fb");
+    assert_eq!(lex.next(), Some(LbToken::Finish));
+    assert_eq!(lex.next(), Some(LbToken::FinishWithStatus('a')));
+    assert_eq!(lex.next(), Some(LbToken::Finish));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::IfStatement((
+        'a',
+        Box::new(LbToken::Finish),
+        Some(Box::new(LbToken::FinishWithStatus('b'))),
+    ))));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn assert_tokens() {
+    let mut lex = LbToken::lexer("Za Zab Zabc");
+    assert_eq!(lex.next(), Some(LbToken::Assert('a')));
+    assert_eq!(lex.next(), Some(LbToken::AssertSame(('a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::AssertSame(('a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn sleep_tokens() {
+    let mut lex = LbToken::lexer("Qa QZ Q1");
+    assert_eq!(lex.next(), Some(LbToken::Sleep('a')));
+    assert_eq!(lex.next(), Some(LbToken::Sleep('Z')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn elapsed_tokens() {
+    let mut lex = LbToken::lexer("Ea EZ E1");
+    assert_eq!(lex.next(), Some(LbToken::Elapsed('a')));
+    assert_eq!(lex.next(), Some(LbToken::Elapsed('Z')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn stack_tokens() {
+    let mut lex = LbToken::lexer("K+a K-b K#c K*a");
+    assert_eq!(lex.next(), Some(LbToken::Push('a')));
+    assert_eq!(lex.next(), Some(LbToken::Pop('b')));
+    assert_eq!(lex.next(), Some(LbToken::StackDepth('c')));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+}
+
+#[test]
+fn uppercase_variables() {
+    let mut lex = LbToken::lexer("PA MAabC TSAbcdPa XzaBcD>E R* RA");
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('A')));
+    assert_eq!(lex.next(), Some(LbToken::MathOp(('A', 'a', 'b', 'C'))));
+    assert_eq!(lex.next(), Some(LbToken::TextOp(('S', String::from("Abcd")))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("aBcD"), Some('E')))));
+    assert_eq!(lex.next(), Some(LbToken::ResetAll));
+    assert_eq!(lex.next(), Some(LbToken::ResetVar('A')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn commands_after_uppercase_variables() {
+    // an uppercase letter after a unary op starts the next command
+    let mut lex = LbToken::lexer("MFabPc MCabMFcd BNabPc");
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('F', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('c')));
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('C', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::UnaryMathOp(('F', 'c', 'd'))));
+    assert_eq!(lex.next(), Some(LbToken::UnaryBoolOp(('N', 'a', 'b'))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('c')));
+    assert_eq!(lex.next(), None);
+
+    // Execute reads its parameters in pairs, so a command after them needs a space
+    let mut lex = LbToken::lexer("XzaB Xzab Pc XzabPc Xz>rPa X'Pa'Aq Pb XzabP");
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("aB"), None))));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("ab"), None))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('c')));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("abPc"), None))));
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::new(), Some('r')))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    assert_eq!(lex.next(), Some(LbToken::ExecuteStr((String::from("Pa"), String::from("Aq"), None))));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('b')));
+    // a parameter without a variable is left over
+    assert_eq!(lex.next(), Some(LbToken::Execute(('z', String::from("ab"), None))));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.slice(), "P");
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn banks() {
+    let mut lex = LbToken::lexer("#0 #9 R# #1Pa #10 #a R#a");
+    assert_eq!(lex.next(), Some(LbToken::SwitchBank(0)));
+    assert_eq!(lex.next(), Some(LbToken::SwitchBank(9)));
+    assert_eq!(lex.next(), Some(LbToken::ResetAllBanks));
+    assert_eq!(lex.next(), Some(LbToken::SwitchBank(1)));
+    assert_eq!(lex.next(), Some(LbToken::PrintVar('a')));
+    // there are only banks 0 to 9
+    assert_eq!(lex.next(), Some(LbToken::SwitchBank(1)));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), Some(LbToken::ResetAllBanks));
+    assert_eq!(lex.next(), Some(LbToken::Error));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn op_regexes_match_ops() {
+    // the op letters in the MathOp and BoolOp regexes are exactly the three-variable ops
+    for op in 'A'..='Z' {
+        let math = LbToken::lexer(&format!("M{op}abc")).next();
+        assert_eq!(math == Some(LbToken::MathOp((op, 'a', 'b', 'c'))), "ASMDQEGLROPIXTNHW".contains(op), "M{op}");
+        let bool_op = LbToken::lexer(&format!("B{op}abc")).next();
+        assert_eq!(bool_op == Some(LbToken::BoolOp((op, 'a', 'b', 'c'))), "EAOXS".contains(op), "B{op}");
+    }
+}
+
+#[test]
+fn labels_and_jumps() {
+    let mut lex = LbToken::lexer(":x Jx JIax IaJx :y IaPb :z");
+    assert_eq!(lex.next(), Some(LbToken::Label('x')));
+    assert_eq!(lex.next(), Some(LbToken::Jump('x')));
+    assert_eq!(lex.next(), Some(LbToken::JumpIf(('a', 'x'))));
+    assert_eq!(lex.next(), Some(LbToken::IfStatement(('a', Box::new(LbToken::Jump('x')), None))));
+    assert_eq!(lex.next(), Some(LbToken::Label('y')));
+    assert_eq!(lex.next(), Some(LbToken::IfStatement(('a', Box::new(LbToken::PrintVar('b')), None))));
+    assert_eq!(lex.next(), Some(LbToken::Label('z')));
+    assert_eq!(lex.next(), None);
+}
+
+#[test]
+fn tokens_to_source() {
+    let src = "Sa4 Sb-1.5e-7 Sc0xFF Sd'it\\'s a \\\\ \\n\\t\\r \\q' Cab Aab Pa P'hi' Oa O'' MAabc MFab BXabc B&abc BNab \
+        TSdsil VSliv HNm LaPb La[ Pb Pc ] Wa[ ] IaPb:Pc Ua[ Pb ]:[ Pc ] IaIbPc:Pd IaLbR* Ra R* GNa12 GSab G#a GQaN \
+        Na K+a K-a K#a Da Qa Ea Za Zab F Fa :a Ja JIab Xzacbd>r Xz X'P\\'y\\''ab>c Ya [ [ ] Pa ] R# #0 #9";
+    let tokens: Vec<LbToken> = LbToken::lexer(src).collect();
+    assert!(!tokens.contains(&LbToken::Error));
+    assert_eq!(to_source(&tokens[..4]), "Sa4 Sb-0.00000015 Sc255 Sd'it\\'s a \\\\ \\n\\t\\r \\\\q'");
+    assert_eq!(tokens[19].to_source(), "La[ Pb Pc ]");
+    assert_eq!(tokens[22].to_source(), "Ua[ Pb ]:[ Pc ]");
+    // printing and lexing again gives the same commands
+    let printed = to_source(&tokens);
+    assert_eq!(LbToken::lexer(&printed).collect::<Vec<LbToken>>(), tokens);
+    assert_eq!(to_source(&LbToken::lexer(&printed).collect::<Vec<LbToken>>()), printed);
+
+    // bodies follow the condition directly, unless their else branch would be taken
+    let save = Box::new(LbToken::SaveNumber(('b', 1.0)));
+    assert_eq!(LbToken::Loop(('a', save.clone())).to_source(), "LaSb1");
+    let inner = Box::new(LbToken::Loop(('b', Box::new(LbToken::IfStatement(('c', save, Some(Box::new(LbToken::Finish))))))));
+    assert_eq!(LbToken::Loop(('a', inner.clone())).to_source(), "LaLbIcSb1:F");
+    assert_eq!(LbToken::IfStatement(('a', inner, None)).to_source(), "Ia[ LbIcSb1:F ]");
+}
+
+#[test]
+fn display_round_trips() {
+    use LbToken::*;
+    let body = |command: LbToken| Box::new(command);
+    let block = |commands: Vec<LbToken>| Box::new(Block(commands));
+    let tokens = vec![
+        SaveNumber(('a', 4.4)),
+        SaveNumber(('a', 4.0)),
+        SaveNumber(('b', -0.5)),
+        SaveNumber(('c', 1e21)),
+        SaveNumber(('d', 1.5e-7)),
+        SaveNumber(('e', 9007199254740993.0)),
+        SaveNumber(('f', 0.1 + 0.2)),
+        SaveNumber(('g', f64::MAX)),
+        SaveStr(('a', String::from("it's a \\ \n\t\r 'test' \\q"))),
+        SaveStr(('Z', String::new())),
+        SaveList(('a', vec![ListItem::Number(-1.5), ListItem::Text(String::from("it's ]")), ListItem::List(Vec::new())])),
+        SaveList(('b', Vec::new())),
+        Copy(('a', 'B')),
+        Append(('a', 'b')),
+        PrintVar('a'),
+        PrintStr(String::from("'")),
+        PrintLineVar('a'),
+        PrintLineStr(String::from("line\n")),
+        MathOp(('A', 'a', 'b', 'c')),
+        UnaryMathOp(('A', 'a', 'b')),
+        BoolOp(('<', 'a', 'b', 'c')),
+        UnaryBoolOp(('N', 'a', 'b')),
+        TextOp(('R', String::from("dsfr"))),
+        ListOp(('N', String::from("l"))),
+        MapOp(('G', String::from("dmk"))),
+        Loop(('a', body(PrintVar('b')))),
+        Loop(('a', block(vec![SaveNumber(('b', 1.0)), PrintVar('b')]))),
+        Repeat((3, body(PrintVar('a')))),
+        Repeat((0, block(Vec::new()))),
+        Repeat((12, body(Loop(('a', body(SaveNumber(('b', 2.0)))))))),
+        WhileLoop(('a', body(IfStatement(('c', body(PrintVar('d')), None))))),
+        WhileLoop(('a', block(Vec::new()))),
+        IfStatement(('a', body(PrintVar('b')), Some(body(PrintStr(String::from("no")))))),
+        IfStatement(('a', body(IfStatement(('b', body(PrintVar('c')), None))), Some(body(PrintVar('d'))))),
+        Loop(('a', body(IfStatement(('b', body(SaveStr(('c', String::from("x y")))), Some(body(Finish))))))),
+        Loop(('a', body(WhileLoop(('b', body(GetInput(('S', 'c', 0.0)))))))),
+        Unless(('a', body(PrintLineStr(String::from("a ] b"))), Some(body(SaveNumber(('c', -2.5)))))),
+        Unless(('a', block(vec![PrintVar('b')]), Some(block(vec![PrintVar('c')])))),
+        Unless(('a', body(ResetAll), None)),
+        ResetVar('a'),
+        ResetAll,
+        ResetAllBanks,
+        SwitchBank(0),
+        SwitchBank(7),
+        GetInput(('N', 'a', 12.0)),
+        GetInputAt(('S', 'a', 'b')),
+        InputCount('a'),
+        QueryInput(('a', 'S')),
+        Negate('a'),
+        Push('a'),
+        Pop('a'),
+        StackDepth('a'),
+        Random('a'),
+        Sleep('a'),
+        Elapsed('a'),
+        Assert('a'),
+        AssertSame(('a', 'b')),
+        Finish,
+        FinishWithStatus('a'),
+        Label('a'),
+        Jump('a'),
+        JumpIf(('A', 'a')),
+        Execute(('z', String::from("acbd"), Some('r'))),
+        Execute(('z', String::new(), None)),
+        ExecuteStr((String::from("P'y' Pa"), String::from("ab"), None)),
+        Return('a'),
+        Block(vec![Block(Vec::new()), PrintVar('a')]),
+    ];
+    for token in tokens {
+        let source = token.to_string();
+        assert_eq!(LbToken::lexer(&source).collect::<Vec<LbToken>>(), vec![token], "{source}");
+    }
+
+    assert_eq!(SaveNumber(('a', 4.4)).to_string(), "Sa4.4");
+    assert_eq!(SaveNumber(('a', 4.0)).to_string(), "Sa4");
+    // every number that lexes can be written back, while one too big for an f64 doesn't lex
+    for source in ["Sa1e308", "Sa1e-400"] {
+        let token = LbToken::lexer(source).next().expect("No token");
+        assert_eq!(LbToken::lexer(&token.to_string()).collect::<Vec<LbToken>>(), vec![token], "{source}");
+    }
+    assert_eq!(LbToken::lexer("Sa1e400").collect::<Vec<LbToken>>(), vec![Error]);
+    assert_eq!(WhileLoop(('a', body(IfStatement(('c', body(PrintVar('d')), None))))).to_string(), "WaIcPd");
+    assert_eq!(Loop(('a', body(SaveNumber(('b', 5.0))))).to_string(), "LaSb5");
+}
@@ -0,0 +1,188 @@
+// letterbox-lang-macros
+// The lb! macro for letterbox-lang, re-exported from its prelude with the `macros` feature.
+
+use std::ops::Range;
+
+use logos::Logos;
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+// the macro lexes programs with the same lexer as the runtime
+use letterbox_lang_lexer::{self as lb_lexer, LbToken};
+
+/// Embeds a Letterbox program, given as a string literal, checking when compiling
+/// that every part of it can be parsed. Expands to an `LbAst`, the same type
+/// `parse` returns, ready to run with `LbAst::run_with` or `LbProgram::from_ast`.
+///
+/// ```
+/// use letterbox_lang::prelude::*;
+///
+/// let ast = lb!("Sa4 Pa");
+/// let mut data = LbStorage::new();
+/// let mut out = String::new();
+/// let inv = Vec::<String>::new();
+/// ast.run_with(&mut data, &inv, StringOutput::new(&mut out), 1000).unwrap();
+/// assert_eq!(out, "4");
+/// ```
+///
+/// A program that can't be parsed fails to compile, with an error at the literal
+/// saying which part couldn't be parsed and where it is in the program:
+///
+/// ```compile_fail
+/// use letterbox_lang::prelude::*;
+///
+/// // error: lb!: Unrecognized instruction `~` at line 1, column 5
+/// let ast = lb!("Sa4 ~ Pa");
+/// ```
+#[proc_macro]
+pub fn lb(input: TokenStream) -> TokenStream {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let literal = match tokens.as_slice() {
+        [TokenTree::Literal(literal)] => literal,
+        // literals passed through other macros arrive in an invisible group
+        [TokenTree::Group(group)] if group.delimiter() == Delimiter::None => return lb(group.stream()),
+        _ => return compile_error("lb!: Expected a string literal", Span::call_site()),
+    };
+
+    let result = unquote(&literal.to_string()).and_then(|source| check(&source).map(|_| source));
+    match result {
+        Ok(source) => expand(&source, literal.span()),
+        Err(message) => compile_error(&format!("lb!: {message}"), literal.span()),
+    }
+}
+
+/// Returns the code that lexes the program into an `LbAst` when it runs.
+fn expand(source: &str, span: Span) -> TokenStream {
+    let mut path = Vec::new();
+    for (i, segment) in ["letterbox_lang", "prelude", "LbAst", "lex"].iter().enumerate() {
+        // the path starts at the crate root, like ::letterbox_lang
+        if i == 0 {
+            path.extend(colons(span));
+        }
+        path.push(TokenTree::Ident(Ident::new(segment, span)));
+        if i < 3 {
+            path.extend(colons(span));
+        }
+    }
+    let mut literal = Literal::string(source);
+    literal.set_span(span);
+    let args = Group::new(Delimiter::Parenthesis, TokenTree::Literal(literal).into());
+    path.push(TokenTree::Group(args));
+    path.into_iter().collect()
+}
+
+/// Returns the `::` of a path.
+fn colons(span: Span) -> [TokenTree; 2] {
+    let mut first = Punct::new(':', Spacing::Joint);
+    let mut second = Punct::new(':', Spacing::Alone);
+    first.set_span(span);
+    second.set_span(span);
+    [TokenTree::Punct(first), TokenTree::Punct(second)]
+}
+
+/// Returns a `compile_error!` with the given message, reported at the given span.
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut literal = Literal::string(message);
+    literal.set_span(span);
+    let mut args = Group::new(Delimiter::Parenthesis, TokenTree::Literal(literal).into());
+    args.set_span(span);
+    [
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(bang),
+        TokenTree::Group(args),
+    ].into_iter().collect()
+}
+
+/// Checks that every part of a program can be parsed, at any depth,
+/// and describes the first part that can't.
+fn check(source: &str) -> Result<(), String> {
+    let lex = LbToken::lexer(source);
+    for (token, span) in lex.spanned() {
+        let tree = lb_lexer::SpanTree::new(&token, source, span);
+        if let Some(span) = find_error(&token, &tree) {
+            let before = &source[..span.start];
+            let line = before.matches('\n').count() + 1;
+            let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+            return Err(format!("Unrecognized instruction `{}` at line {line}, column {column}", &source[span]));
+        }
+    }
+    Ok(())
+}
+
+/// Returns where the first part of a command that couldn't be parsed is, at any depth.
+fn find_error(token: &LbToken, tree: &lb_lexer::SpanTree) -> Option<Range<usize>> {
+    if *token == LbToken::Error {
+        return Some(tree.span.clone());
+    }
+    token.subcommands().into_iter()
+        .zip(tree.children.iter())
+        .find_map(|(token, tree)| find_error(token, tree))
+}
+
+/// Returns the contents of a Rust string literal, written as in source code,
+/// like `"Sa'\n'"` or `r#"P'"'"#`.
+fn unquote(literal: &str) -> Result<String, String> {
+    if let Some(raw) = literal.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let contents = raw.get(hashes + 1..raw.len() - hashes - 1);
+        return contents.map(String::from).ok_or_else(|| String::from("Expected a string literal"));
+    }
+    let Some(contents) = literal.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return Err(String::from("Expected a string literal"));
+    };
+
+    let mut result = String::with_capacity(contents.len());
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some(c @ ('\\' | '\'' | '"')) => result.push(c),
+            Some('x') => {
+                let code: String = chars.by_ref().take(2).collect();
+                let c = u8::from_str_radix(&code, 16).map_err(|_| format!("Invalid escape \\x{code}"))?;
+                result.push(char::from(c));
+            },
+            Some('u') => {
+                let code: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                let c = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)
+                    .ok_or_else(|| format!("Invalid escape \\u{{{code}}}"))?;
+                result.push(c);
+            },
+            // a backslash at the end of a line skips the line break and the next line's indentation
+            Some('\n') => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            },
+            other => return Err(format!("Invalid escape \\{}", other.map(String::from).unwrap_or_default())),
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn checking() {
+    assert_eq!(check("Sa4 Pa"), Ok(()));
+    assert_eq!(check("Sa4\n  Ia[ Pa ]:[ Pb ] !comment ~"), Ok(()));
+    assert_eq!(check("Sa4 ~ Pa"), Err(String::from("Unrecognized instruction `~` at line 1, column 5")));
+    // errors nested in blocks are found too
+    assert_eq!(check("Sa4\nLa[ Pa\n  Pb ~ ]"), Err(String::from("Unrecognized instruction `~` at line 3, column 6")));
+    assert_eq!(check("La[ Pa"), Err(String::from("Unrecognized instruction `La[ Pa` at line 1, column 1")));
+}
+
+#[test]
+fn unquoting() {
+    assert_eq!(unquote(r#""Sa4 Pa""#), Ok(String::from("Sa4 Pa")));
+    assert_eq!(unquote(r#""P'\n' P\"\\\x41\u{e9}""#), Ok(String::from("P'\n' P\"\\Aé")));
+    assert_eq!(unquote("\"Sa4 \\\n    Pa\""), Ok(String::from("Sa4 Pa")));
+    assert_eq!(unquote(r###"r#"P'"' Pa"#"###), Ok(String::from(r#"P'"' Pa"#)));
+    assert_eq!(unquote("r\"Pa\\n\""), Ok(String::from("Pa\\n")));
+    assert_eq!(unquote("b\"Pa\""), Err(String::from("Expected a string literal")));
+    assert_eq!(unquote("4"), Err(String::from("Expected a string literal")));
+}
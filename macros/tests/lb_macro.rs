@@ -0,0 +1,20 @@
+use letterbox_lang::prelude::*;
+
+#[test]
+fn embedded_programs() {
+    let ast = lb!("Sa4 La[ Pa P' ' ] Ia[ O'yes' ]:[ O'no' ]");
+    // the macro gives the same program as parsing it at run time
+    assert_eq!(Some(ast.clone()), parse("Sa4 La[ Pa P' ' ] Ia[ O'yes' ]:[ O'no' ]").ok());
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    ast.run_with(&mut data, &inv, StringOutput::new(&mut out), 1000).expect("Program failed");
+    assert_eq!(out, "4 4 4 4 yes\n");
+
+    // escapes and raw strings are read as Rust reads them
+    let ast = lb!(r#"P'"quoted"'"#);
+    assert_eq!(ast.source, "P'\"quoted\"'");
+    let ast = lb!("O'one'\n\tO'two'");
+    assert_eq!(ast.instructions.len(), 2);
+}
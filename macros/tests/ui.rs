@@ -0,0 +1,53 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Builds each program in tests/ui as its own crate and checks that its errors
+/// match the ones in the `.stderr` file beside it.
+#[test]
+fn compile_errors() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let work_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("ui");
+    let mut programs: Vec<_> = fs::read_dir(manifest_dir.join("tests/ui")).expect("tests/ui should be readable")
+        .map(|entry| entry.expect("tests/ui entry should be readable").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty());
+
+    let mut failures = Vec::new();
+    for program in programs {
+        let name = program.file_stem().unwrap().to_string_lossy().into_owned();
+        let crate_dir = work_dir.join(&name);
+        fs::create_dir_all(crate_dir.join("src")).expect("Crate directory creation failed");
+        let manifest = format!(
+            "[package]\nname = \"ui-{}\"\nversion = \"0.0.0\"\nedition = \"2021\"\n\n\
+            [dependencies]\nletterbox-lang = {{ path = {:?}, features = [\"macros\"] }}\n\n[workspace]\n",
+            name.replace('_', "-"),
+            manifest_dir.parent().unwrap(),
+        );
+        fs::write(crate_dir.join("Cargo.toml"), manifest).expect("Manifest write failed");
+        fs::copy(&program, crate_dir.join("src/main.rs")).expect("Program copy failed");
+
+        let out = Command::new(env!("CARGO"))
+            .args(["build", "--offline", "--quiet", "--message-format=short"])
+            .current_dir(&crate_dir)
+            // every program shares one target directory, so the library is only built once
+            .env("CARGO_TARGET_DIR", work_dir.join("target"))
+            .output()
+            .expect("Cargo should start");
+        assert!(!out.status.success(), "{name}.rs should fail to compile");
+
+        // only the errors in the program are compared, not cargo's summary
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let errors: String = stderr.lines()
+            .filter(|line| line.starts_with("src/"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let expected = fs::read_to_string(program.with_extension("stderr")).unwrap_or_default();
+        if errors != expected {
+            failures.push(format!("{name}.rs\nexpected:\n{expected}actual:\n{errors}full stderr:\n{stderr}"));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
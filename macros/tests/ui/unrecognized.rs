@@ -0,0 +1,6 @@
+use letterbox_lang::prelude::*;
+
+fn main() {
+    let ast = lb!("Sa4 ~ Pa");
+    println!("{}", ast.source);
+}
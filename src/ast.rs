@@ -0,0 +1,85 @@
+use std::io::Write;
+use std::ops::Range;
+
+use logos::{Lexer, Logos};
+use crate::diagnostics::{check_ast, Diagnostic};
+use crate::error::LbError;
+use crate::input::LbInput;
+use crate::lb_lexer::{LbToken, SpanTree};
+use crate::program::LbProgram;
+use crate::storage::LbStorage;
+
+/// A value along with where it is in the source code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+
+    /// Where the value, and any commands nested in it, are.
+    pub spans: SpanTree,
+}
+
+impl<T> Spanned<T> {
+    /// Returns the byte range of the value.
+    pub fn span(&self) -> Range<usize> {
+        self.spans.span.clone()
+    }
+}
+
+/// A lexed Letterbox program, kept with its source code.
+/// It can be run any number of times without lexing it again,
+/// and inspected by tools like formatters and linters.
+/// See [parse] and [crate::program::LbProgram::from_ast].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LbAst {
+    /// The program's source code.
+    pub source: String,
+
+    /// Each top-level instruction, in order.
+    /// The bodies of loops, conditionals, and blocks are nested inside them.
+    pub instructions: Vec<Spanned<LbToken>>,
+}
+
+impl LbAst {
+    /// Lexes a program without checking it.
+    /// Parts that can't be parsed become [LbToken::Error] instructions,
+    /// which fail when the program reaches them.
+    pub fn lex(source: &str) -> LbAst {
+        LbAst::from_lexer(LbToken::lexer(source))
+    }
+
+    /// Lexes the rest of a program from the given lexer, like [LbAst::lex].
+    pub fn from_lexer(lex: Lexer<LbToken>) -> LbAst {
+        let source = lex.source();
+        let instructions = lex.spanned()
+            .map(|(token, span)| {
+                let spans = SpanTree::new(&token, source, span);
+                Spanned { node: token, spans }
+            })
+            .collect();
+        LbAst { source: source.to_string(), instructions }
+    }
+
+    /// Runs the program to the end against the given storage, input, and output,
+    /// like creating an [LbProgram] with [LbProgram::from_ast] and running it.
+    /// The program itself is left unchanged, so every run starts from the same instructions,
+    /// and nothing carries over from one run to the next except through the storage it is given.
+    pub fn run_with<W: Write, I: LbInput>(&self,
+        storage: &mut LbStorage,
+        input: I,
+        out: W,
+        loop_limit: usize,
+    ) -> Result<(), LbError> {
+        LbProgram::from_ast(self, storage, input, out, loop_limit)?.run()
+    }
+}
+
+/// Lexes a program and checks it for problems that can be found without running it.
+/// Returns every problem found by [crate::diagnostics::check] if there are any.
+pub fn parse(source: &str) -> Result<LbAst, Vec<Diagnostic>> {
+    let ast = LbAst::lex(source);
+    let diagnostics = check_ast(&ast);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(ast)
+}
@@ -0,0 +1,149 @@
+// letterbox
+// Runs a Letterbox program from a file or standard input.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use letterbox_lang::prelude::*;
+
+const USAGE: &str = "Usage: letterbox [--max-instructions N] [--trace] <file | -> [inputs...]";
+
+/// The options given on the command line.
+struct Options {
+    /// The program file, or `-` for standard input.
+    path: String,
+
+    /// The inputs for `G` commands.
+    inputs: Vec<String>,
+
+    /// The most instructions the program can run, or 0 for no limit.
+    max_instructions: u64,
+
+    /// Whether to print each instruction that runs to standard error.
+    trace: bool,
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("letterbox: {message}\n{USAGE}");
+            return ExitCode::from(2);
+        },
+    };
+
+    let source = if options.path == "-" {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).map(|_| source)
+    } else {
+        fs::read_to_string(&options.path)
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("letterbox: Could not read {}: {e}", options.path);
+            return ExitCode::from(2);
+        },
+    };
+
+    // included files are found relative to the program's file, or the current directory
+    let dir = Path::new(&options.path).parent().filter(|_| options.path != "-").unwrap_or(Path::new(""));
+    let included = match resolve_includes(&source, |file: &str| fs::read_to_string(dir.join(file))) {
+        Ok(included) => included,
+        Err(e) => {
+            eprintln!("letterbox: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+
+    let mut data = LbStorage::new();
+    let program = LbProgram::with_output(LbToken::lexer(&included.source), &mut data, &options.inputs, io::stdout(), usize::MAX);
+    let mut program = match program {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("letterbox: {e}");
+            return ExitCode::FAILURE;
+        },
+    };
+    // every part of the program is checked before it runs, so typos aren't missed
+    program.strict = true;
+    program.set_max_instructions(options.max_instructions);
+    // a program read from a file leaves standard input free for inputs that weren't given
+    if options.path != "-" {
+        program.set_stdin_fallback(io::stdin().lock());
+    }
+    if options.trace {
+        program.set_trace_callback(|entry| eprintln!("{}", trace_line(&entry)));
+    }
+
+    let result = program.run();
+    let frames = program.error_frames().to_vec();
+    let status = program.exit_status;
+    drop(program);
+    let _ = io::stdout().flush();
+
+    match result {
+        Ok(()) => ExitCode::from(u8::try_from(status as i64).unwrap_or(1)),
+        Err(LbError::UnparsedSource(errors)) => {
+            eprintln!("letterbox: Program can't be parsed:");
+            for error in errors {
+                eprintln!("  `{}` at {}", error.slice, position(&included.locate(error.span.start)));
+            }
+            ExitCode::FAILURE
+        },
+        Err(e) => {
+            eprintln!("letterbox: {e}");
+            for frame in frames {
+                match frame.program_var {
+                    Some(var) => eprintln!("  at line {}, column {} of the program in {var}", frame.line, frame.column),
+                    None => eprintln!("  at {}", position(&included.locate(frame.span.start))),
+                }
+            }
+            ExitCode::FAILURE
+        },
+    }
+}
+
+/// Reads the options from the command line arguments, after the name of the binary.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut max_instructions = 0;
+    let mut trace = false;
+    let path = loop {
+        let Some(arg) = args.next() else {
+            return Err(String::from("No program given"));
+        };
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--max-instructions" => {
+                let max = args.next().ok_or_else(|| String::from("--max-instructions needs a number"))?;
+                max_instructions = max.parse().map_err(|_| format!("{max} is not a number of instructions"))?;
+            },
+            "-" => break arg,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+            _ => break arg,
+        }
+    };
+    Ok(Options { path, inputs: args.collect(), max_instructions, trace })
+}
+
+/// Describes where part of the program is, like `line 2, column 7`,
+/// followed by the file it is in if it was included, like `of lib.lb`.
+fn position(location: &SourceLocation) -> String {
+    match &location.file {
+        Some(file) => format!("line {}, column {} of {file}", location.line, location.column),
+        None => format!("line {}, column {}", location.line, location.column),
+    }
+}
+
+/// Describes an instruction that ran, with the values of its variables before and after, like
+/// `1:5 MAcab c=0 a=1 b=2 -> c=3 a=1 b=2`.
+fn trace_line(entry: &TraceEntry) -> String {
+    let values = |vars: &[(char, Val)]| -> String {
+        vars.iter().map(|(var, val)| format!(" {var}={val}")).collect()
+    };
+    let (line, column) = entry.position;
+    format!("{line}:{column} {}{} ->{}", entry.token, values(&entry.before), values(&entry.after))
+}
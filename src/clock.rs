@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest [SystemClock] sleeps at once before checking whether the program was cancelled.
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+/// The source of timing for a [crate::program::LbProgram].
+/// Hosts can provide their own, for example to drive real hardware
+/// or to make tests run instantly and reproducibly.
+/// See [crate::program::LbProgram::set_clock].
+pub trait LbClock {
+    /// Pauses the program for the given duration. Used by `Qa`.
+    fn sleep(&mut self, duration: Duration);
+
+    /// Returns the time since the clock started. Used by `Ea`.
+    /// Must never decrease.
+    fn elapsed(&mut self) -> Duration;
+
+    /// Pauses the program like [LbClock::sleep], but stops early once `cancelled` is set,
+    /// and returns whether it was. Used by `Qa`, so a cancelled program doesn't wait out a long sleep.
+    /// By default, sleeps for the whole duration and then checks the flag,
+    /// so clocks that really wait should override it to check the flag while waiting.
+    fn sleep_until_cancelled(&mut self, duration: Duration, cancelled: &AtomicBool) -> bool {
+        self.sleep(duration);
+        cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// The default [LbClock], which uses the system's monotonic clock.
+/// It starts when it is created, so each program measures time from its own start.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    /// Returns a clock that starts now.
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LbClock for SystemClock {
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn elapsed(&mut self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Sleeps in short slices, checking the flag between them.
+    fn sleep_until_cancelled(&mut self, duration: Duration, cancelled: &AtomicBool) -> bool {
+        let start = Instant::now();
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                return true;
+            }
+            let remaining = duration.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return false;
+            }
+            thread::sleep(remaining.min(SLEEP_SLICE));
+        }
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use logos::Logos;
+use crate::ast::LbAst;
+use crate::lb_lexer::{is_valid_op, LbToken, SpanTree};
+use crate::lexing::line_columns;
+use crate::storage;
+
+/// How serious a [Diagnostic] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The program fails if it reaches this point.
+    Error,
+
+    /// This is likely a mistake, but the program can still run.
+    Warning,
+}
+
+/// A problem found in a program's source code without running it. See [check] and [validate].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// How serious the problem is.
+    pub severity: Severity,
+
+    /// The byte range of the problem in the source code.
+    pub span: Range<usize>,
+
+    /// The line and column where the problem starts, counting from 1.
+    pub line: usize,
+    pub column: usize,
+
+    /// The source code at the problem's span.
+    pub slice: String,
+
+    /// A short description of the problem.
+    pub message: String,
+}
+
+/// Finds every problem in a program that can be found without running it,
+/// in the order they appear in the source code.
+/// This includes parts of the source that can't be parsed, even inside blocks,
+/// Execute commands with an odd number of substitution characters,
+/// and jumps to labels that aren't defined.
+/// An empty list doesn't mean the program will run without errors.
+/// Every diagnostic is an [Severity::Error].
+pub fn check(source: &str) -> Vec<Diagnostic> {
+    check_ast(&LbAst::lex(source))
+}
+
+/// Lexes a program into its top-level commands, like [crate::lexing::lex] without the ranges,
+/// and describes every part of it that can't be parsed, even inside blocks, without stopping at the first.
+/// Characters that can't be parsed next to each other are one [LbToken::Error] and one diagnostic,
+/// and a diagnostic says which command the part looks like when it starts with a command's letter.
+/// Every diagnostic is an [Severity::Error].
+pub fn lex_with_recovery(source: &str) -> (Vec<LbToken>, Vec<Diagnostic>) {
+    let ast = LbAst::lex(source);
+    let mut tokens: Vec<LbToken> = Vec::with_capacity(ast.instructions.len());
+    let mut last_end = None;
+    for instruction in ast.instructions.iter() {
+        let span = instruction.span();
+        let joined = instruction.node == LbToken::Error && last_end == Some(span.start);
+        if !joined {
+            tokens.push(instruction.node.clone());
+        }
+        last_end = (instruction.node == LbToken::Error).then_some(span.end);
+    }
+
+    let instructions = ast.instructions.iter().map(|instruction| (&instruction.node, &instruction.spans));
+    (tokens, parse_errors(source, instructions))
+}
+
+/// Describes every part of a lexed program that can't be parsed, like [lex_with_recovery].
+pub(crate) fn parse_errors<'t>(source: &str, instructions: impl Iterator<Item = (&'t LbToken, &'t SpanTree)>) -> Vec<Diagnostic> {
+    let found = unparsed(source, instructions).into_iter()
+        .map(|(span, message)| (Severity::Error, span, message))
+        .collect();
+    diagnostics(source, found)
+}
+
+/// Finds the same problems as [check] in an already lexed program.
+pub(crate) fn check_ast(ast: &LbAst) -> Vec<Diagnostic> {
+    let found = check_errors(ast).into_iter()
+        .map(|(span, message)| (Severity::Error, span, message))
+        .collect();
+    diagnostics(&ast.source, found)
+}
+
+/// Finds the same problems as [check], along with likely mistakes:
+/// input indexes beyond the number of inputs, if it is given,
+/// variables that are read but never written,
+/// Execute commands that run a variable that is never given a string,
+/// and `RA` commands, which used to reset every variable but now only reset variable A.
+/// Which problems count as fatal is up to the host, but every [Severity::Error]
+/// fails the program if it is reached.
+/// 
+/// Variables can be written by storage left over from an earlier program, so a variable
+/// that is never written may be intended. Programs run with `Xzacbd` can write any variable,
+/// so variables aren't checked at all if a program runs one,
+/// while programs run with `X'Pa'` are checked as part of the program.
+pub fn validate(source: &str, input_count: Option<usize>) -> Vec<Diagnostic> {
+    let ast = LbAst::lex(source);
+    let mut found: Vec<(Severity, Range<usize>, String)> = check_errors(&ast).into_iter()
+        .map(|(span, message)| (Severity::Error, span, message))
+        .collect();
+
+    let mut usage = Usage::default();
+    for instruction in ast.instructions.iter() {
+        usage.add(&instruction.node, &instruction.spans);
+        if let Some(count) = input_count {
+            check_inputs(&instruction.node, &instruction.spans, count, &mut found);
+        }
+        check_resets(&instruction.node, &instruction.spans, &mut found);
+    }
+
+    for (var, span) in usage.executed.iter() {
+        if !usage.text_writes.contains(var) {
+            found.push((Severity::Warning, span.clone(), format!("X: Variable {var} is never given a string to run")));
+        }
+    }
+    if !usage.unknown_writes {
+        let mut reported = HashSet::new();
+        for (var, span) in usage.reads.iter() {
+            if !usage.writes.contains(var) && reported.insert(*var) {
+                found.push((Severity::Warning, span.clone(), format!("Variable {var} is read but never written")));
+            }
+        }
+    }
+
+    diagnostics(source, found)
+}
+
+/// Sorts problems by where they are and adds their positions.
+fn diagnostics(source: &str, mut found: Vec<(Severity, Range<usize>, String)>) -> Vec<Diagnostic> {
+    found.sort_by_key(|(_, span, _)| (span.start, span.end));
+    let offsets: Vec<usize> = found.iter().map(|(_, span, _)| span.start).collect();
+    let positions = line_columns(source, &offsets);
+    found.into_iter()
+        .zip(positions)
+        .map(|((severity, span, message), (line, column))| Diagnostic {
+            severity,
+            // programs built by hand may have spans outside their source
+            slice: source.get(span.clone()).unwrap_or_default().to_string(),
+            span,
+            line,
+            column,
+            message,
+        })
+        .collect()
+}
+
+/// Finds the problems reported by [check], without their positions.
+fn check_errors(ast: &LbAst) -> Vec<(Range<usize>, String)> {
+    let mut found = unparsed(&ast.source, ast.instructions.iter().map(|instruction| (&instruction.node, &instruction.spans)));
+    let mut labels: HashMap<char, Range<usize>> = HashMap::new();
+    let mut jumps: Vec<(char, Range<usize>)> = Vec::new();
+
+    for instruction in ast.instructions.iter() {
+        // only top-level labels can be jumped to
+        if let LbToken::Label(label) = instruction.node {
+            if labels.insert(label, instruction.span()).is_some() {
+                found.push((instruction.span(), format!("Label {label} is defined more than once")));
+            }
+        }
+        check_token(&instruction.node, &instruction.spans, &ast.source, &mut found, &mut jumps);
+    }
+
+    for (label, span) in jumps {
+        if !labels.contains_key(&label) {
+            found.push((span, format!("J: Label {label} is not defined")));
+        }
+    }
+
+    found
+}
+
+/// Checks a command and the commands nested in it, adding any problems to `found`
+/// and any jumps to `jumps` so they can be checked once every label is known.
+fn check_token(
+    token: &LbToken,
+    tree: &SpanTree,
+    source: &str,
+    found: &mut Vec<(Range<usize>, String)>,
+    jumps: &mut Vec<(char, Range<usize>)>,
+) {
+    let span = tree.span.clone();
+    match token {
+        LbToken::Jump(label) | LbToken::JumpIf((_, label)) => jumps.push((*label, span)),
+        LbToken::Execute((_, argmap, _)) | LbToken::ExecuteStr((_, argmap, _)) => {
+            // substitutions are pairs, so an odd letter is left over right after the command
+            if source[span.end..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                let end = span.end + 1;
+                found.push((span.start..end, String::from("X: Odd number of substitution characters")));
+            }
+            if let Some(c) = argmap.chars().find(|c| !storage::is_var(c)) {
+                found.push((span.clone(), format!("X: {c} is not a variable name")));
+            }
+            // a literal program can be checked too, though not at its exact position
+            if let LbToken::ExecuteStr((prog, _, _)) = token {
+                for diagnostic in check(prog) {
+                    let message = format!("X: {} in executed program: {}", diagnostic.message, diagnostic.slice);
+                    found.push((span.clone(), message));
+                }
+            }
+        },
+        _ => {},
+    }
+
+    for (i, subcommand) in token.subcommands().into_iter().enumerate() {
+        check_token(subcommand, tree.child(i), source, found, jumps);
+    }
+}
+
+/// Finds the parts of a program that can't be parsed, at any depth, with their descriptions.
+/// Parts next to each other are joined into one.
+fn unparsed<'t>(source: &str, instructions: impl Iterator<Item = (&'t LbToken, &'t SpanTree)>) -> Vec<(Range<usize>, String)> {
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    for (token, tree) in instructions {
+        error_spans(token, tree, &mut spans);
+    }
+
+    let mut joined: Vec<Range<usize>> = Vec::with_capacity(spans.len());
+    for span in spans {
+        match joined.last_mut() {
+            Some(last) if last.end == span.start => last.end = span.end,
+            _ => joined.push(span),
+        }
+    }
+    joined.into_iter()
+        .map(|span| {
+            let message = error_message(source.get(span.clone()).unwrap_or_default());
+            (span, message)
+        })
+        .collect()
+}
+
+/// Adds where a command and the commands nested in it couldn't be parsed to `spans`.
+fn error_spans(token: &LbToken, tree: &SpanTree, spans: &mut Vec<Range<usize>>) {
+    if *token == LbToken::Error {
+        spans.push(tree.span.clone());
+    }
+    for (i, subcommand) in token.subcommands().into_iter().enumerate() {
+        error_spans(subcommand, tree.child(i), spans);
+    }
+}
+
+/// Describes source code that couldn't be parsed.
+fn error_message(slice: &str) -> String {
+    let mut chars = slice.chars();
+    if let (Some(command @ ('M' | 'B' | 'T' | 'V' | 'H')), Some(op)) = (chars.next(), chars.next()) {
+        if !is_valid_op(command, op) {
+            return format!("{command}: Invalid op {op}");
+        }
+        if op.is_ascii_uppercase() || command == 'B' {
+            return format!("{command}: Wrong number of variables for op {op}");
+        }
+    }
+
+    // a list literal, like Sa[1 2]
+    let list = slice.starts_with('S') && slice.chars().nth(2) == Some('[');
+    let message = if slice.starts_with("!{") {
+        "Comment is never closed"
+    } else if slice.matches('[').count() > slice.matches(']').count() {
+        if list { "List is never closed" } else { "Block is never closed" }
+    } else if slice.starts_with('\'') || slice.matches('\'').count() % 2 == 1 {
+        "String is never closed"
+    } else if list {
+        "S: List items must be numbers, strings, or lists, separated by spaces"
+    } else if let Some(hint) = command_hint(slice) {
+        return hint;
+    } else {
+        "Unrecognized instruction"
+    };
+    message.to_string()
+}
+
+/// Guesses which command source code that couldn't be parsed was meant to be,
+/// from its first character, and says what it is missing.
+fn command_hint(slice: &str) -> Option<String> {
+    let mut chars = slice.chars();
+    let command = chars.next()?;
+    // what the command is missing, and what it is missing if a variable follows it
+    let (name, missing, missing_after_var) = match command {
+        'S' => ("SaveNumber", "its variable and value", Some("its value")),
+        'C' => ("Copy", "its variables", Some("its second variable")),
+        'A' => ("Append", "its variables", Some("its second variable")),
+        'P' => ("PrintVar", "a variable or string to print", None),
+        'O' => ("PrintLineVar", "a variable or string to print", None),
+        'L' => ("Loop", "its variable or count and a command to repeat", Some("a command to repeat right after its variable")),
+        'W' => ("WhileLoop", "its variable and a command to repeat", Some("a command to repeat right after its variable")),
+        'I' => ("IfStatement", "its variable and a command to run", Some("a command to run right after its variable")),
+        'U' => ("Unless", "its variable and a command to run", Some("a command to run right after its variable")),
+        'M' => ("MathOp", "its op and variables", None),
+        'B' => ("BoolOp", "its op and variables", None),
+        'T' => ("TextOp", "its op and variables", None),
+        'V' => ("ListOp", "its op and variables", None),
+        'H' => ("MapOp", "its op and variables", None),
+        'G' => ("GetInput", "its op, variable, and input", None),
+        'K' => ("Push", "+, -, or # and a variable", None),
+        'R' => ("ResetVar", "a variable, *, or #", None),
+        'N' => ("Negate", "its variable", None),
+        'D' => ("Random", "its variable", None),
+        'Q' => ("Sleep", "its variable", None),
+        'E' => ("Elapsed", "its variable", None),
+        'Z' => ("Assert", "its variables", None),
+        'Y' => ("Return", "its variable", None),
+        'X' => ("Execute", "a variable or string to run", None),
+        'J' => ("Jump", "a lowercase label", None),
+        ':' => ("Label", "a lowercase label", None),
+        '#' => ("SwitchBank", "a bank number", None),
+        _ => return None,
+    };
+    let missing = match missing_after_var {
+        Some(rest) if chars.next().is_some_and(|c| c.is_ascii_alphabetic()) => rest,
+        _ => missing,
+    };
+    Some(format!("{command}: Looks like {name}, but is missing {missing}"))
+}
+
+/// Reports `GNa1` and `GSa1` commands, at any depth, that read an input beyond the number of inputs.
+fn check_inputs(token: &LbToken, tree: &SpanTree, count: usize, found: &mut Vec<(Severity, Range<usize>, String)>) {
+    if let LbToken::GetInput((_, _, num)) = token {
+        if num.floor() as usize >= count {
+            found.push((Severity::Error, tree.span.clone(), format!("G: no input at index {num}")));
+        }
+    }
+    for (i, subcommand) in token.subcommands().into_iter().enumerate() {
+        check_inputs(subcommand, tree.child(i), count, found);
+    }
+}
+
+/// Reports `RA` commands, at any depth, since programs written before `R*`
+/// use them to reset every variable.
+fn check_resets(token: &LbToken, tree: &SpanTree, found: &mut Vec<(Severity, Range<usize>, String)>) {
+    if let LbToken::ResetVar('A') = token {
+        found.push((Severity::Warning, tree.span.clone(), String::from("R: RA only resets variable A, use R* to reset every variable")));
+    }
+    for (i, subcommand) in token.subcommands().into_iter().enumerate() {
+        check_resets(subcommand, tree.child(i), found);
+    }
+}
+
+/// Which variables a program reads and writes.
+#[derive(Default)]
+struct Usage {
+    /// Each variable read, with where it was read.
+    reads: Vec<(char, Range<usize>)>,
+
+    /// Each variable written.
+    writes: HashSet<char>,
+
+    /// Each variable that may be written with a string.
+    text_writes: HashSet<char>,
+
+    /// Each variable run as a program by `Xzacbd`, with where it was run.
+    executed: Vec<(char, Range<usize>)>,
+
+    /// Whether a program that can't be seen is run, so any variable may be written.
+    unknown_writes: bool,
+}
+
+impl Usage {
+    /// Adds the variables a command and the commands nested in it use.
+    fn add(&mut self, token: &LbToken, tree: &SpanTree) {
+        use LbToken::*;
+        let span = &tree.span;
+        let (reads, writes, text_writes): (Vec<char>, Vec<char>, Vec<char>) = match token {
+            SaveNumber((a, _)) | SaveList((a, _)) | ResetVar(a) | InputCount(a) | StackDepth(a) | Random(a) | Elapsed(a) => {
+                (vec![], vec![*a], vec![])
+            },
+            SaveStr((a, _)) | Pop(a) => (vec![], vec![*a], vec![*a]),
+            Copy((a, b)) => (vec![*a], vec![*b], vec![*b]),
+            Append((a, b)) => (vec![*a, *b], vec![*a], vec![*a]),
+            PrintVar(a) | PrintLineVar(a) | Push(a) | Sleep(a) | Assert(a) | FinishWithStatus(a) | Return(a)
+                | Loop((a, _)) | WhileLoop((a, _)) | IfStatement((a, _, _)) | Unless((a, _, _)) | JumpIf((a, _)) => {
+                (vec![*a], vec![], vec![])
+            },
+            AssertSame((a, b)) => (vec![*a, *b], vec![], vec![]),
+            Negate(a) => (vec![*a], vec![*a], vec![]),
+            MathOp((_, a, b, c)) | BoolOp((_, a, b, c)) => (vec![*b, *c], vec![*a], vec![]),
+            UnaryMathOp((_, a, b)) | UnaryBoolOp((_, a, b)) => (vec![*b], vec![*a], vec![]),
+            GetInput((op, a, _)) | QueryInput((a, op)) => (vec![], vec![*a], if *op == 'S' { vec![*a] } else { vec![] }),
+            GetInputAt((op, a, b)) => (vec![*b], vec![*a], if *op == 'S' { vec![*a] } else { vec![] }),
+            // the first variable is the result, except for the flag of TN
+            TextOp(('N', vars)) => {
+                let vars: Vec<char> = vars.chars().collect();
+                (vec![vars[1]], vec![vars[0], vars[2]], vec![])
+            },
+            TextOp((_, vars)) => {
+                let vars: Vec<char> = vars.chars().collect();
+                (vars[1..].to_vec(), vec![vars[0]], vec![vars[0]])
+            },
+            // N makes a new list or map, S, A, and R change one, and the rest store a result
+            ListOp((op, vars)) | MapOp((op, vars)) => {
+                let vars: Vec<char> = vars.chars().collect();
+                match op {
+                    'N' => (vec![], vars, vec![]),
+                    'S' | 'A' | 'R' => (vars.clone(), vec![vars[0]], vec![]),
+                    'G' => (vars[1..].to_vec(), vec![vars[0]], vec![vars[0]]),
+                    _ => (vars[1..].to_vec(), vec![vars[0]], vec![]),
+                }
+            },
+            Execute((z, pairs, dest)) => {
+                self.executed.push((*z, span.clone()));
+                self.unknown_writes = true;
+                let written = execute_writes(pairs, dest);
+                (vec![*z], written.clone(), written)
+            },
+            ExecuteStr((prog, pairs, dest)) => {
+                // the executed program uses this program's variables, with its parameters replaced
+                let params: Vec<char> = pairs.chars().collect();
+                let params: HashMap<char, char> = params.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+                let rename = |var: char| *params.get(&var).unwrap_or(&var);
+                // its commands are reported at the Execute command
+                let here = SpanTree { span: span.clone(), children: Vec::new() };
+                for command in LbToken::lexer(prog) {
+                    self.add(&command.rename_vars(&rename), &here);
+                }
+                let written = execute_writes(pairs, dest);
+                (vec![], written.clone(), written)
+            },
+            _ => (vec![], vec![], vec![]),
+        };
+
+        self.reads.extend(reads.into_iter().map(|var| (var, span.clone())));
+        self.writes.extend(writes);
+        self.text_writes.extend(text_writes);
+        for (i, subcommand) in token.subcommands().into_iter().enumerate() {
+            self.add(subcommand, tree.child(i));
+        }
+    }
+}
+
+/// Returns the variables of this program that an Execute command may write:
+/// the variables given for its parameters, and the destination.
+fn execute_writes(pairs: &str, dest: &Option<char>) -> Vec<char> {
+    let mut written: Vec<char> = pairs.chars().skip(1).step_by(2).collect();
+    written.extend(dest);
+    written
+}
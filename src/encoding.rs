@@ -0,0 +1,391 @@
+use crate::ast::{LbAst, Spanned};
+use crate::error::LbError;
+use crate::lb_lexer::{LbToken, ListItem, SpanTree};
+
+/// The version of the format written by [LbAst::to_bytes].
+/// Change it whenever the format changes, so old blobs fail cleanly instead of loading wrongly.
+const FORMAT_VERSION: u8 = 1;
+
+/// How deeply commands can be nested in a blob, so a corrupt one can't overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+impl LbAst {
+    /// Encodes this program in a compact binary form that [LbAst::from_bytes] can load
+    /// without lexing it again. The source code is kept so errors can still be located.
+    ///
+    /// The format starts with a version byte. Numbers are little-endian,
+    /// and lengths, characters, and spans are variable-length integers.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer(vec![FORMAT_VERSION]);
+        writer.text(&self.source);
+        writer.uint(self.instructions.len() as u64);
+        for instruction in self.instructions.iter() {
+            writer.token(&instruction.node);
+            writer.spans(&instruction.spans);
+        }
+        writer.0
+    }
+
+    /// Loads a program encoded by [LbAst::to_bytes].
+    /// Fails if the bytes are from an unknown version of the format,
+    /// if they end early, or if they aren't a valid program.
+    pub fn from_bytes(bytes: &[u8]) -> Result<LbAst, LbError> {
+        let mut reader = Reader { bytes, pos: 0, depth: 0 };
+        let version = reader.byte()?;
+        if version != FORMAT_VERSION {
+            return Err(LbError::UnknownFormatVersion(version));
+        }
+        let source = reader.text()?;
+        let count = reader.uint()?;
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let node = reader.token()?;
+            let spans = reader.spans(&source)?;
+            instructions.push(Spanned { node, spans });
+        }
+        if reader.pos != bytes.len() {
+            return Err(LbError::InvalidBytes(String::from("unexpected bytes after the program")));
+        }
+        Ok(LbAst { source, instructions })
+    }
+}
+
+/// Appends values to an encoded program.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    /// Writes an unsigned integer in as few bytes as it needs, 7 bits at a time.
+    fn uint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn char(&mut self, c: char) {
+        self.uint(c as u64);
+    }
+
+    fn chars(&mut self, chars: &[char]) {
+        for &c in chars {
+            self.char(c);
+        }
+    }
+
+    fn number(&mut self, num: f64) {
+        self.0.extend_from_slice(&num.to_le_bytes());
+    }
+
+    fn text(&mut self, text: &str) {
+        self.uint(text.len() as u64);
+        self.0.extend_from_slice(text.as_bytes());
+    }
+
+    fn opt_char(&mut self, c: Option<char>) {
+        match c {
+            Some(c) => {
+                self.0.push(1);
+                self.char(c);
+            },
+            None => self.0.push(0),
+        }
+    }
+
+    /// Writes a command as a tag byte followed by its arguments.
+    fn token(&mut self, token: &LbToken) {
+        use LbToken::*;
+        match token {
+            SaveNumber((a, num)) => { self.0.push(0); self.char(*a); self.number(*num); },
+            SaveStr((a, text)) => { self.0.push(1); self.char(*a); self.text(text); },
+            Copy((a, b)) => { self.0.push(2); self.chars(&[*a, *b]); },
+            Append((a, b)) => { self.0.push(3); self.chars(&[*a, *b]); },
+            PrintVar(a) => { self.0.push(4); self.char(*a); },
+            PrintStr(text) => { self.0.push(5); self.text(text); },
+            PrintLineVar(a) => { self.0.push(6); self.char(*a); },
+            PrintLineStr(text) => { self.0.push(7); self.text(text); },
+            MathOp((op, a, b, c)) => { self.0.push(8); self.chars(&[*op, *a, *b, *c]); },
+            UnaryMathOp((op, a, b)) => { self.0.push(9); self.chars(&[*op, *a, *b]); },
+            BoolOp((op, a, b, c)) => { self.0.push(10); self.chars(&[*op, *a, *b, *c]); },
+            UnaryBoolOp((op, a, b)) => { self.0.push(11); self.chars(&[*op, *a, *b]); },
+            TextOp((op, vars)) => { self.0.push(12); self.char(*op); self.text(vars); },
+            ListOp((op, vars)) => { self.0.push(13); self.char(*op); self.text(vars); },
+            MapOp((op, vars)) => { self.0.push(14); self.char(*op); self.text(vars); },
+            Loop((a, body)) => { self.0.push(15); self.char(*a); self.token(body); },
+            IfStatement((a, body, else_command)) => {
+                self.0.push(16);
+                self.char(*a);
+                self.token(body);
+                self.opt_token(else_command.as_deref());
+            },
+            Unless((a, body, else_command)) => {
+                self.0.push(17);
+                self.char(*a);
+                self.token(body);
+                self.opt_token(else_command.as_deref());
+            },
+            WhileLoop((a, body)) => { self.0.push(18); self.char(*a); self.token(body); },
+            ResetVar(a) => { self.0.push(19); self.char(*a); },
+            ResetAll => self.0.push(20),
+            GetInput((op, a, num)) => { self.0.push(21); self.chars(&[*op, *a]); self.number(*num); },
+            GetInputAt((op, a, b)) => { self.0.push(22); self.chars(&[*op, *a, *b]); },
+            InputCount(a) => { self.0.push(23); self.char(*a); },
+            QueryInput((a, op)) => { self.0.push(24); self.chars(&[*a, *op]); },
+            Negate(a) => { self.0.push(25); self.char(*a); },
+            Push(a) => { self.0.push(26); self.char(*a); },
+            Pop(a) => { self.0.push(27); self.char(*a); },
+            StackDepth(a) => { self.0.push(28); self.char(*a); },
+            Random(a) => { self.0.push(29); self.char(*a); },
+            Sleep(a) => { self.0.push(30); self.char(*a); },
+            Elapsed(a) => { self.0.push(31); self.char(*a); },
+            Assert(a) => { self.0.push(32); self.char(*a); },
+            AssertSame((a, b)) => { self.0.push(33); self.chars(&[*a, *b]); },
+            Finish => self.0.push(34),
+            FinishWithStatus(a) => { self.0.push(35); self.char(*a); },
+            Label(a) => { self.0.push(36); self.char(*a); },
+            Jump(a) => { self.0.push(37); self.char(*a); },
+            JumpIf((a, label)) => { self.0.push(38); self.chars(&[*a, *label]); },
+            Execute((z, pairs, dest)) => {
+                self.0.push(39);
+                self.char(*z);
+                self.text(pairs);
+                self.opt_char(*dest);
+            },
+            ExecuteStr((prog, pairs, dest)) => {
+                self.0.push(40);
+                self.text(prog);
+                self.text(pairs);
+                self.opt_char(*dest);
+            },
+            Return(a) => { self.0.push(41); self.char(*a); },
+            Block(commands) => {
+                self.0.push(42);
+                self.uint(commands.len() as u64);
+                for command in commands {
+                    self.token(command);
+                }
+            },
+            Error => self.0.push(43),
+            ResetAllBanks => self.0.push(44),
+            SwitchBank(bank) => { self.0.push(45); self.0.push(*bank); },
+            Repeat((count, body)) => { self.0.push(46); self.uint(*count as u64); self.token(body); },
+            SaveList((a, items)) => { self.0.push(47); self.char(*a); self.list_items(items); },
+        }
+    }
+
+    /// Writes the items of a list literal, each as a tag byte followed by its value.
+    fn list_items(&mut self, items: &[ListItem]) {
+        self.uint(items.len() as u64);
+        for item in items {
+            match item {
+                ListItem::Number(num) => { self.0.push(0); self.number(*num); },
+                ListItem::Text(text) => { self.0.push(1); self.text(text); },
+                ListItem::List(items) => { self.0.push(2); self.list_items(items); },
+            }
+        }
+    }
+
+    fn opt_token(&mut self, token: Option<&LbToken>) {
+        match token {
+            Some(token) => {
+                self.0.push(1);
+                self.token(token);
+            },
+            None => self.0.push(0),
+        }
+    }
+
+    fn spans(&mut self, tree: &SpanTree) {
+        self.uint(tree.span.start as u64);
+        self.uint(tree.span.end as u64);
+        self.uint(tree.children.len() as u64);
+        for child in tree.children.iter() {
+            self.spans(child);
+        }
+    }
+}
+
+/// Reads values from an encoded program, checking that each one is valid.
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+
+    /// How deeply nested the command being read is.
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, LbError> {
+        let byte = *self.bytes.get(self.pos).ok_or(LbError::TruncatedBytes)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn uint(&mut self) -> Result<u64, LbError> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(LbError::InvalidBytes(String::from("integer is too long")))
+    }
+
+    /// Reads a length, which can't be longer than the bytes that are left.
+    fn len(&mut self) -> Result<usize, LbError> {
+        let len = self.uint()?;
+        if len > (self.bytes.len() - self.pos) as u64 {
+            return Err(LbError::TruncatedBytes);
+        }
+        Ok(len as usize)
+    }
+
+    fn char(&mut self) -> Result<char, LbError> {
+        let code = self.uint()?;
+        u32::try_from(code).ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| LbError::InvalidBytes(format!("{code} is not a character")))
+    }
+
+    fn number(&mut self) -> Result<f64, LbError> {
+        let bytes = self.bytes.get(self.pos..self.pos + 8).ok_or(LbError::TruncatedBytes)?;
+        self.pos += 8;
+        Ok(f64::from_le_bytes(bytes.try_into().expect("slice is 8 bytes")))
+    }
+
+    fn text(&mut self) -> Result<String, LbError> {
+        let len = self.len()?;
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LbError::InvalidBytes(String::from("text is not UTF-8")))
+    }
+
+    fn opt_char(&mut self) -> Result<Option<char>, LbError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.char()?)),
+            flag => Err(LbError::InvalidBytes(format!("{flag} is not an option flag"))),
+        }
+    }
+
+    /// Reads a command written by [Writer::token].
+    fn token(&mut self) -> Result<LbToken, LbError> {
+        use LbToken::*;
+        if self.depth >= MAX_DEPTH {
+            return Err(LbError::InvalidBytes(String::from("commands are nested too deeply")));
+        }
+        self.depth += 1;
+        let tag = self.byte()?;
+        let token = match tag {
+            0 => SaveNumber((self.char()?, self.number()?)),
+            1 => SaveStr((self.char()?, self.text()?)),
+            2 => Copy((self.char()?, self.char()?)),
+            3 => Append((self.char()?, self.char()?)),
+            4 => PrintVar(self.char()?),
+            5 => PrintStr(self.text()?),
+            6 => PrintLineVar(self.char()?),
+            7 => PrintLineStr(self.text()?),
+            8 => MathOp((self.char()?, self.char()?, self.char()?, self.char()?)),
+            9 => UnaryMathOp((self.char()?, self.char()?, self.char()?)),
+            10 => BoolOp((self.char()?, self.char()?, self.char()?, self.char()?)),
+            11 => UnaryBoolOp((self.char()?, self.char()?, self.char()?)),
+            12 => TextOp((self.char()?, self.text()?)),
+            13 => ListOp((self.char()?, self.text()?)),
+            14 => MapOp((self.char()?, self.text()?)),
+            15 => Loop((self.char()?, Box::new(self.token()?))),
+            16 => IfStatement((self.char()?, Box::new(self.token()?), self.opt_token()?)),
+            17 => Unless((self.char()?, Box::new(self.token()?), self.opt_token()?)),
+            18 => WhileLoop((self.char()?, Box::new(self.token()?))),
+            19 => ResetVar(self.char()?),
+            20 => ResetAll,
+            21 => GetInput((self.char()?, self.char()?, self.number()?)),
+            22 => GetInputAt((self.char()?, self.char()?, self.char()?)),
+            23 => InputCount(self.char()?),
+            24 => QueryInput((self.char()?, self.char()?)),
+            25 => Negate(self.char()?),
+            26 => Push(self.char()?),
+            27 => Pop(self.char()?),
+            28 => StackDepth(self.char()?),
+            29 => Random(self.char()?),
+            30 => Sleep(self.char()?),
+            31 => Elapsed(self.char()?),
+            32 => Assert(self.char()?),
+            33 => AssertSame((self.char()?, self.char()?)),
+            34 => Finish,
+            35 => FinishWithStatus(self.char()?),
+            36 => Label(self.char()?),
+            37 => Jump(self.char()?),
+            38 => JumpIf((self.char()?, self.char()?)),
+            39 => Execute((self.char()?, self.text()?, self.opt_char()?)),
+            40 => ExecuteStr((self.text()?, self.text()?, self.opt_char()?)),
+            41 => Return(self.char()?),
+            42 => {
+                let count = self.len()?;
+                let mut commands = Vec::new();
+                for _ in 0..count {
+                    commands.push(self.token()?);
+                }
+                Block(commands)
+            },
+            43 => Error,
+            44 => ResetAllBanks,
+            45 => SwitchBank(self.byte()?),
+            46 => Repeat((usize::try_from(self.uint()?).unwrap_or(usize::MAX), Box::new(self.token()?))),
+            47 => SaveList((self.char()?, self.list_items()?)),
+            tag => return Err(LbError::InvalidBytes(format!("{tag} is not a command"))),
+        };
+        self.depth -= 1;
+        Ok(token)
+    }
+
+    /// Reads the items of a list literal written by [Writer::list_items].
+    fn list_items(&mut self) -> Result<Vec<ListItem>, LbError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(LbError::InvalidBytes(String::from("lists are nested too deeply")));
+        }
+        self.depth += 1;
+        let count = self.len()?;
+        let mut items = Vec::new();
+        for _ in 0..count {
+            let item = match self.byte()? {
+                0 => ListItem::Number(self.number()?),
+                1 => ListItem::Text(self.text()?),
+                2 => ListItem::List(self.list_items()?),
+                tag => return Err(LbError::InvalidBytes(format!("{tag} is not a list item"))),
+            };
+            items.push(item);
+        }
+        self.depth -= 1;
+        Ok(items)
+    }
+
+    fn opt_token(&mut self) -> Result<Option<Box<LbToken>>, LbError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => Ok(Some(Box::new(self.token()?))),
+            flag => Err(LbError::InvalidBytes(format!("{flag} is not an option flag"))),
+        }
+    }
+
+    /// Reads a span tree written by [Writer::spans], checking that its spans are in the source.
+    fn spans(&mut self, source: &str) -> Result<SpanTree, LbError> {
+        let start = self.uint()? as usize;
+        let end = self.uint()? as usize;
+        if start > end || source.get(start..end).is_none() {
+            return Err(LbError::InvalidBytes(format!("{start}..{end} is not in the source")));
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(LbError::InvalidBytes(String::from("commands are nested too deeply")));
+        }
+        self.depth += 1;
+        let count = self.len()?;
+        let mut children = Vec::new();
+        for _ in 0..count {
+            children.push(self.spans(source)?);
+        }
+        self.depth -= 1;
+        Ok(SpanTree { span: start..end, children })
+    }
+}
@@ -0,0 +1,267 @@
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use crate::diagnostics::Diagnostic;
+
+/// An error that stops a Letterbox program.
+/// Where a variant has a `command`, it is the letter of the command that failed,
+/// like the `M` in `MAcab`, and an `instruction` is the whole command as written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LbError {
+    /// A character was used as a variable name but isn't one.
+    InvalidVariable { command: char, var: char },
+
+    /// A variable that nothing has been stored in was read, while the storage's
+    /// [crate::storage::DefaultPolicy] is `Error`. `position` is the line and column of the instruction
+    /// that read it, or of the top-level instruction containing it, if known.
+    UnsetVariable { var: char, position: Option<(usize, usize)> },
+
+    /// A character was used as a variable name when reading or changing storage from Rust, but isn't one.
+    NotAVariable(char),
+
+    /// A variable was read from Rust as one kind of value but holds another.
+    /// `expected` and `found` are kinds of values, as in [LbError::TypeMismatch].
+    VarTypeMismatch { var: char, expected: &'static str, found: &'static str },
+
+    /// A variable holds the wrong kind of value, like a string where a number is needed.
+    /// `expected` is the kind needed: `"number"`, `"string"`, `"list"`, or `"map"`.
+    TypeMismatch { command: char, var: char, expected: &'static str },
+
+    /// A command was given an op it doesn't have, like the `Z` in `MZcab`.
+    InvalidOp { command: char, op: char },
+
+    /// An op was given the wrong number of variables, like the one variable in `TUd`.
+    WrongArgCount { command: char, op: char, expected: usize, found: usize },
+
+    /// A value couldn't be converted to a Rust type because it holds the wrong kind of value.
+    /// `expected` and `found` are kinds of values, as in [LbError::TypeMismatch].
+    ValueTypeMismatch { expected: &'static str, found: &'static str },
+
+    /// There is no bank of variables with the given number.
+    InvalidBank(usize),
+
+    /// A number can't be saved with `Sa` because it isn't finite.
+    InvalidNumber(f64),
+
+    /// `MD`, `MQ`, `MR`, or `MO` was asked to divide by zero.
+    DivisionByZero { instruction: String },
+
+    /// `MP` was asked for a power that isn't a real number.
+    NoRealResult { instruction: String },
+
+    /// `MQ` was asked for the square root of a negative number.
+    NegativeSquareRoot { var: char, instruction: String },
+
+    /// `ML` or `MD` was asked for the logarithm of a number that isn't positive.
+    NonPositiveLogarithm { var: char, instruction: String },
+
+    /// A bitwise op was given a number too large to use as a 64-bit integer.
+    BitwiseOutOfRange { var: char, value: f64 },
+
+    /// `B<` or `B>` was asked to shift by less than 0 or more than 63 bits.
+    ShiftOutOfRange { amount: i64, instruction: String },
+
+    /// A list was indexed outside of its bounds.
+    IndexOutOfBounds { list: char, index: f64, len: usize },
+
+    /// `HG` was asked for a key that isn't in the map.
+    MissingKey { map: char, key: String },
+
+    /// `TO` was asked for the character code of empty text.
+    EmptyText { var: char },
+
+    /// `TH` was given a number that isn't a character code.
+    InvalidCharCode(f64),
+
+    /// A delimiter variable didn't hold exactly one character.
+    InvalidDelimiter { command: char, var: char },
+
+    /// There is no input at the given index.
+    InputMissing(f64),
+
+    /// An input couldn't be read from the stdin fallback, with the reason it failed.
+    InputReadFailed(String),
+
+    /// `G#a` was used with an input source that can't count its inputs.
+    InputCountUnknown,
+
+    /// `GQaX` was used without an input callback.
+    NoInputCallback,
+
+    /// The input callback had no more input to give.
+    NoHostInput,
+
+    /// An input couldn't be read as a number.
+    InputNotNumber(String),
+
+    /// `Ya` was used outside of an executed program.
+    ReturnOutsideExecute,
+
+    /// `K-a` was used on an empty stack.
+    EmptyStack(char),
+
+    /// `Qa` was asked to sleep for longer than can be represented.
+    InvalidSleep(f64),
+
+    /// Printing failed because the output couldn't be written to.
+    OutputFailed { command: char, message: String },
+
+    /// `Za` or `Zab` failed, at the given line and column if known.
+    AssertionFailed { position: Option<(usize, usize)>, message: String },
+
+    /// A jump goes to a label that doesn't exist.
+    UndefinedLabel(char),
+
+    /// A label is defined more than once.
+    DuplicateLabel(char),
+
+    /// A jump was made from inside an executed program.
+    JumpFromExecute(char),
+
+    /// The loop with the given command letter ran more than the loop limit.
+    LoopLimit(char),
+
+    /// The program jumped back to the given label more than the loop limit.
+    JumpLimit(char),
+
+    /// The loop with the given command letter, at the given byte range, line, and column,
+    /// would run more times than [crate::program::LbProgram::set_max_loop_iterations] allows.
+    /// A loop in an executed program is located within that program.
+    LoopIterationLimit { command: char, max: u64, span: Range<usize>, line: usize, column: usize },
+
+    /// The program ran more instructions than its limit allowed.
+    InstructionLimit(u64),
+
+    /// The program was cancelled from outside.
+    Cancelled,
+
+    /// A breakpoint aborted the program.
+    Aborted,
+
+    /// Part of the program's source, at the given byte range, couldn't be parsed.
+    LexError { span: Range<usize> },
+
+    /// Parts of the program's source couldn't be parsed, found before it ran
+    /// because [crate::program::LbProgram::strict] is set. Lists each part, in order.
+    UnparsedSource(Vec<Diagnostic>),
+
+    /// Part of an executed program couldn't be parsed.
+    ExecuteLexError(String),
+
+    /// An Execute command's last parameter has no variable to replace it,
+    /// which only happens in commands built by hand rather than parsed.
+    UnpairedParameter(char),
+
+    /// The program counter doesn't point at an instruction.
+    NoCommand(usize),
+
+    /// A line starting with `!#include` doesn't name a file in double quotes, like `!#include "lib.lb"`.
+    /// Holds the line.
+    InvalidInclude(String),
+
+    /// A file couldn't be included because the loader failed, with the loader's message.
+    IncludeFailed { file: String, message: String },
+
+    /// A file includes itself, directly or through other files.
+    /// Holds the chain of includes, starting and ending with the same file.
+    IncludeCycle(Vec<String>),
+
+    /// An encoded program is from a version of the format that isn't known.
+    UnknownFormatVersion(u8),
+
+    /// An encoded program ends before it is complete.
+    TruncatedBytes,
+
+    /// An encoded program isn't valid, for the given reason.
+    InvalidBytes(String),
+}
+
+impl fmt::Display for LbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LbError::*;
+        match self {
+            InvalidVariable { command, var } => write!(f, "{command}: {var} is not a variable name"),
+            UnsetVariable { var, position: Some((line, column)) } => {
+                write!(f, "Variable {var} is read at line {line}, column {column} before it is set")
+            },
+            UnsetVariable { var, position: None } => write!(f, "Variable {var} is read before it is set"),
+            NotAVariable(var) => write!(f, "{var} is not a variable name"),
+            VarTypeMismatch { var, expected, found } => write!(f, "Variable {var} holds a {found}, not a {expected}"),
+            TypeMismatch { command, var, expected } => write!(f, "{command}: Variable {var} is not a {expected}"),
+            InvalidOp { command, op } => write!(f, "{command}: Invalid op {op}"),
+            WrongArgCount { command, op, expected, found } => {
+                write!(f, "{command}: Op {op} takes {expected} variables, but was given {found}")
+            },
+            ValueTypeMismatch { expected, found } => write!(f, "Value is a {found}, not a {expected}"),
+            InvalidBank(bank) => write!(f, "#: There is no bank {bank}"),
+            InvalidNumber(num) => write!(f, "S: {num} is not a finite number"),
+            DivisionByZero { instruction } => write!(f, "M: Division by zero in {instruction}"),
+            NoRealResult { instruction } => write!(f, "M: {instruction} has no real result"),
+            NegativeSquareRoot { var, instruction } => {
+                write!(f, "M: Square root of negative variable {var} in {instruction}")
+            },
+            NonPositiveLogarithm { var, instruction } => {
+                write!(f, "M: Logarithm of non-positive variable {var} in {instruction}")
+            },
+            BitwiseOutOfRange { var, value } => {
+                write!(f, "B: Variable {var} is out of range for bitwise operations: {value}")
+            },
+            ShiftOutOfRange { amount, instruction } => {
+                write!(f, "B: Shift amount {amount} is out of range in {instruction}")
+            },
+            IndexOutOfBounds { list, index, len } => {
+                write!(f, "V: Index {index} is out of bounds for list {list} of length {len}")
+            },
+            MissingKey { map, key } => write!(f, "H: Key {key} is not in map {map}"),
+            EmptyText { var } => write!(f, "T: Can't get the character code of empty text in variable {var}"),
+            InvalidCharCode(code) => write!(f, "T: {code} is not a valid character code"),
+            InvalidDelimiter { command, var } => write!(f, "{command}: Delimiter {var} must be a single character"),
+            InputMissing(index) => write!(f, "G: no input at index {index}"),
+            InputReadFailed(message) => write!(f, "G: Could not read input: {message}"),
+            InputCountUnknown => write!(f, "G: The number of inputs is not known"),
+            NoInputCallback => write!(f, "G: No input callback was provided"),
+            NoHostInput => write!(f, "G: The host provided no input"),
+            InputNotNumber(input) => write!(f, "G: Could not parse input into number: {input}"),
+            ReturnOutsideExecute => write!(f, "Y: Can't return outside of an executed program"),
+            EmptyStack(var) => write!(f, "K: Can't pop into {var} from an empty stack"),
+            InvalidSleep(millis) => write!(f, "Q: Can't sleep for {millis} milliseconds"),
+            OutputFailed { command, message } => write!(f, "{command}: Could not write output: {message}"),
+            AssertionFailed { position: Some((line, column)), message } => {
+                write!(f, "Z: Assertion failed at line {line}, column {column}: {message}")
+            },
+            AssertionFailed { position: None, message } => write!(f, "Z: Assertion failed: {message}"),
+            UndefinedLabel(label) => write!(f, "J: Label {label} is not defined"),
+            DuplicateLabel(label) => write!(f, "Label {label} is defined more than once"),
+            JumpFromExecute(label) => write!(f, "J: Can't jump to label {label} from an executed program"),
+            LoopLimit(command) => write!(f, "{command}: loop count exceeds loop limit"),
+            JumpLimit(label) => write!(f, "J: jump count to label {label} exceeds loop limit"),
+            LoopIterationLimit { command, max, line, column, .. } => {
+                write!(f, "{command}: Loop at line {line}, column {column} exceeded {max} iterations")
+            },
+            InstructionLimit(max) => write!(f, "Instruction limit of {max} exceeded"),
+            Cancelled => write!(f, "Program was cancelled"),
+            Aborted => write!(f, "Program was aborted at a breakpoint"),
+            LexError { span } => write!(f, "Unrecognized instruction at {}..{}", span.start, span.end),
+            UnparsedSource(errors) => {
+                write!(f, "Program can't be parsed:")?;
+                for (i, error) in errors.iter().enumerate() {
+                    let separator = if i == 0 { "" } else { ";" };
+                    write!(f, "{separator} `{}` at line {}, column {}", error.slice, error.line, error.column)?;
+                }
+                Ok(())
+            },
+            ExecuteLexError(text) => write!(f, "X: Could not parse {text} in executed program"),
+            UnpairedParameter(param) => write!(f, "X: Parameter {param} isn't given a variable"),
+            NoCommand(counter) => write!(f, "No command found at counter index {counter}"),
+            InvalidInclude(line) => write!(f, "Include directive should name a file in double quotes: {line}"),
+            IncludeFailed { file, message } => write!(f, "Could not include {file}: {message}"),
+            IncludeCycle(chain) => write!(f, "Include cycle: {}", chain.join(" -> ")),
+            UnknownFormatVersion(version) => write!(f, "Unknown program format version {version}"),
+            TruncatedBytes => write!(f, "Encoded program ends early"),
+            InvalidBytes(reason) => write!(f, "Invalid encoded program: {reason}"),
+        }
+    }
+}
+
+impl Error for LbError {}
@@ -0,0 +1,157 @@
+use std::ops::Range;
+
+use crate::ast::LbAst;
+use crate::lb_lexer::{LbToken, SpanTree};
+use crate::lexing::{layout, LayoutToken};
+
+/// The indentation of each level of nesting.
+const INDENT: &str = "    ";
+
+/// Formats a Letterbox program with one instruction per line.
+/// The contents of blocks, including the blocks of loops and conditionals,
+/// are indented on their own lines. Comments are kept, either after the instruction
+/// they follow on the same line or on their own line, and runs of blank lines become one.
+/// Each instruction is otherwise written exactly as it was, so literals keep their form.
+/// The formatted program lexes into the same instructions as the original.
+pub fn format(source: &str) -> String {
+    let mut printer = Printer { source, out: String::new(), depth: 0, line_open: false };
+    let ast = LbAst::lex(source);
+    let instructions = ast.instructions.iter().map(|instruction| (&instruction.node, &instruction.spans));
+    printer.sequence(instructions, 0..source.len());
+    if !printer.out.is_empty() {
+        printer.out.push('\n');
+    }
+    printer.out
+}
+
+/// Writes formatted source code.
+struct Printer<'s> {
+    source: &'s str,
+    out: String,
+
+    /// How many blocks the current line is in.
+    depth: usize,
+
+    /// Whether the current line has something on it that a comment could follow.
+    line_open: bool,
+}
+
+impl Printer<'_> {
+    /// Writes a list of instructions, one per line, with the comments between them.
+    /// `region` is the part of the source they are in.
+    /// Returns whether anything was written.
+    fn sequence<'t>(&mut self, instructions: impl Iterator<Item = (&'t LbToken, &'t SpanTree)>, region: Range<usize>) -> bool {
+        let mut pos = region.start;
+        let mut written = false;
+        for (token, tree) in instructions {
+            let before = self.out.len();
+            let blank = self.gap(pos..tree.span.start, written);
+            written |= self.out.len() > before;
+            if blank && written {
+                self.out.push('\n');
+            }
+            self.start_line();
+            self.instruction(token, tree);
+            pos = tree.span.end;
+            written = true;
+        }
+        // comments after the last instruction
+        let before = self.out.len();
+        self.gap(pos..region.end, written);
+        written || self.out.len() > before
+    }
+
+    /// Writes an instruction, starting on the current line.
+    fn instruction(&mut self, token: &LbToken, tree: &SpanTree) {
+        let source = self.source;
+        match token {
+            LbToken::Block(commands) => self.block(commands, tree),
+            LbToken::Loop((_, body)) | LbToken::Repeat((_, body)) | LbToken::WhileLoop((_, body))
+                | LbToken::IfStatement((_, body, _)) | LbToken::Unless((_, body, _)) => {
+                let else_command = match token {
+                    LbToken::IfStatement((_, _, else_command)) | LbToken::Unless((_, _, else_command)) => {
+                        else_command.as_deref().zip(tree.children.get(1))
+                    },
+                    _ => None,
+                };
+                match (body.as_ref(), tree.children.first()) {
+                    // the block starts on the condition's line, like La[
+                    (LbToken::Block(commands), Some(body_tree)) => {
+                        self.out.push_str(&source[tree.span.start..body_tree.span.start]);
+                        self.block(commands, body_tree);
+                    },
+                    // everything up to the else command is written as it was, like IaPb
+                    _ => {
+                        let end = else_command.map_or(tree.span.end, |(_, else_tree)| else_tree.span.start - 1);
+                        self.out.push_str(&source[tree.span.start..end]);
+                    },
+                }
+                if let Some((else_command, else_tree)) = else_command {
+                    self.out.push(':');
+                    self.instruction(else_command, else_tree);
+                }
+            },
+            _ => self.out.push_str(&source[tree.span.clone()]),
+        }
+        self.line_open = true;
+    }
+
+    /// Writes a block, with its contents indented on their own lines.
+    fn block(&mut self, commands: &[LbToken], tree: &SpanTree) {
+        self.out.push('[');
+        self.line_open = true;
+        self.depth += 1;
+        let inner = tree.span.start + 1..tree.span.end - 1;
+        let written = self.sequence(commands.iter().zip(tree.children.iter()), inner);
+        self.depth -= 1;
+        if written {
+            self.start_line();
+            self.out.push(']');
+        }
+        else {
+            self.out.push_str(" ]");
+        }
+    }
+
+    /// Writes the comments in a part of the source between instructions,
+    /// which holds nothing else but whitespace.
+    /// A comment on the same line as the instruction before it stays on that line.
+    /// `blank_ok` says whether a blank line may come before a comment.
+    /// Returns whether there is a blank line after the last comment.
+    fn gap(&mut self, range: Range<usize>, blank_ok: bool) -> bool {
+        let source = self.source;
+        let mut blank_ok = blank_ok;
+        let mut newlines = 0;
+        for (piece, span) in layout(source, range) {
+            if let LayoutToken::Whitespace(space) = piece {
+                newlines += space.matches('\n').count();
+                continue;
+            }
+
+            let comment = source[span].trim_end();
+            if newlines == 0 && self.line_open {
+                self.out.push(' ');
+            }
+            else {
+                if newlines >= 2 && blank_ok {
+                    self.out.push('\n');
+                }
+                self.start_line();
+            }
+            self.out.push_str(comment);
+            self.line_open = true;
+            blank_ok = true;
+            newlines = 0;
+        }
+        newlines >= 2
+    }
+
+    /// Starts a new line at the current indentation.
+    fn start_line(&mut self) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out.push_str(&INDENT.repeat(self.depth));
+        self.line_open = false;
+    }
+}
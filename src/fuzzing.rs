@@ -0,0 +1,289 @@
+//! Generating programs from arbitrary bytes, for fuzzing the lexer and interpreter.
+//!
+//! A fuzz target, such as one for `cargo fuzz`, only needs to pass its input on:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| letterbox_lang::fuzzing::run_fuzz_input(data));
+//! ```
+
+use std::time::Duration;
+
+use crate::ast::LbAst;
+use crate::clock::LbClock;
+use crate::lb_lexer::{self, ends_with_else, op_arity, LbToken, ListItem, BOOL_OPS, MATH_OPS, UNARY_BOOL_OPS, UNARY_MATH_OPS};
+use crate::program::{BreakAction, LbProgram, StringOutput, Val};
+use crate::storage::LbStorage;
+
+/// The most top-level commands in a generated program.
+const MAX_COMMANDS: usize = 64;
+
+/// How deeply the commands of a generated program can be nested in each other.
+const MAX_DEPTH: usize = 4;
+
+/// The most instructions a fuzzed program can run.
+const MAX_INSTRUCTIONS: u64 = 10_000;
+
+/// The loop limit of a fuzzed program.
+const LOOP_LIMIT: usize = 1000;
+
+/// The most bytes of text, and elements of lists and maps, the variables of a fuzzed program can hold.
+/// Commands like `Aaa` can double a value each time they run, which would otherwise run out of memory.
+const MAX_STORAGE: usize = 4096;
+
+/// Turns any bytes into a program of commands that the lexer could produce,
+/// with variables from a few lowercase letters, valid ops, and bodies nested at most a few deep.
+/// Every program it returns is written by [LbToken::to_source] as source code that lexes back into it.
+/// The same bytes always give the same program, and running out of bytes ends it.
+pub fn program_from_bytes(data: &[u8]) -> Vec<LbToken> {
+    let mut bytes = Bytes { data, pos: 0 };
+    let mut program = Vec::new();
+    while !bytes.is_empty() && program.len() < MAX_COMMANDS {
+        program.push(bytes.token(0));
+    }
+    program
+}
+
+/// Generates a program from the bytes with [program_from_bytes], writes it as source code,
+/// and lexes it again, panicking if the lexed program differs from the generated one.
+/// Then runs it, panicking only if running it panics.
+/// Runs are limited in the instructions they run and the storage they use,
+/// take no time to sleep, and print nothing.
+pub fn run_fuzz_input(data: &[u8]) {
+    let program = program_from_bytes(data);
+    let source = lb_lexer::to_source(&program);
+    let ast = LbAst::lex(&source);
+    let lexed: Vec<LbToken> = ast.instructions.iter().map(|instruction| instruction.node.clone()).collect();
+    assert_eq!(lexed, program, "Program doesn't lex back from its source: {source}");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inputs = vec![String::from("1"), String::from("two"), String::from("-3.5")];
+    let Ok(mut program) = LbProgram::from_ast(&ast, &mut data, &inputs, StringOutput::new(&mut out), LOOP_LIMIT) else {
+        return;
+    };
+    program.write_output = false;
+    program.set_seed(0);
+    program.set_max_instructions(MAX_INSTRUCTIONS);
+    program.set_clock(FuzzClock::default());
+    program.set_breakpoint_callback(|_, storage, _| {
+        let mut size = 0;
+        for (_, val) in storage.iter() {
+            size += val_size(val, MAX_STORAGE - size);
+            if size >= MAX_STORAGE {
+                return BreakAction::Abort;
+            }
+        }
+        BreakAction::Continue
+    });
+    let _ = program.run();
+}
+
+/// Returns the size of a value, in bytes of text and elements of lists and maps,
+/// counting no further than `limit`.
+fn val_size(val: &Val, limit: usize) -> usize {
+    match val {
+        Val::Text(text) => text.len(),
+        Val::Number(_) | Val::Bool(_) => 1,
+        Val::List(list) => {
+            let mut size = 1;
+            for item in list {
+                if size >= limit {
+                    break;
+                }
+                size += val_size(item, limit - size);
+            }
+            size
+        },
+        Val::Map(map) => {
+            let mut size = 1;
+            for (key, item) in map {
+                if size >= limit {
+                    break;
+                }
+                size += key.len() + val_size(item, limit.saturating_sub(size + key.len()));
+            }
+            size
+        },
+    }
+}
+
+/// A clock that only pretends to sleep, so fuzzed programs don't wait.
+#[derive(Default)]
+struct FuzzClock {
+    elapsed: Duration,
+}
+
+impl LbClock for FuzzClock {
+    fn sleep(&mut self, duration: Duration) {
+        self.elapsed = self.elapsed.saturating_add(duration);
+    }
+
+    fn elapsed(&mut self) -> Duration {
+        self.elapsed
+    }
+}
+
+/// Reads the choices that make up a program from bytes.
+/// Once the bytes run out, every choice is the first one.
+struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bytes<'_> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn byte(&mut self) -> u8 {
+        let byte = self.data.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+
+    /// Picks one of the characters of `options`.
+    fn pick(&mut self, options: &str) -> char {
+        let index = self.byte() as usize % options.chars().count();
+        options.chars().nth(index).unwrap_or_default()
+    }
+
+    /// Picks a variable from a few, so commands use each other's results.
+    fn var(&mut self) -> char {
+        self.pick("abcdlmz")
+    }
+
+    fn label(&mut self) -> char {
+        self.pick("abc")
+    }
+
+    /// Returns a small number, which may be negative or have a fraction.
+    fn number(&mut self) -> f64 {
+        (self.byte() as f64 - 64.0) / 4.0
+    }
+
+    /// Returns a short string, often with commands in it to run with `Xz`.
+    fn text(&mut self) -> String {
+        let len = self.byte() % 8;
+        (0..len).map(|_| self.pick("abPSLI1 '\\\n:[]")).collect()
+    }
+
+    /// Returns the items of a short list literal, with lists nested in it at most [MAX_DEPTH] deep.
+    fn list_items(&mut self, depth: usize) -> Vec<ListItem> {
+        let len = self.byte() % 4;
+        (0..len).map(|_| match self.byte() % 3 {
+            0 => ListItem::Number(self.number()),
+            1 => ListItem::Text(self.text()),
+            _ if depth < MAX_DEPTH => ListItem::List(self.list_items(depth + 1)),
+            _ => ListItem::List(Vec::new()),
+        }).collect()
+    }
+
+    fn vars(&mut self, count: usize) -> String {
+        (0..count).map(|_| self.var()).collect()
+    }
+
+    /// Returns the parameters and destination of an Execute command.
+    fn execute_args(&mut self) -> (String, Option<char>) {
+        let pairs = self.byte() as usize % 3;
+        let args = self.vars(pairs * 2);
+        let dest = (self.byte() % 2 == 1).then(|| self.var());
+        (args, dest)
+    }
+
+    /// Returns a command, with any commands nested in it nested at most [MAX_DEPTH] deep.
+    fn token(&mut self, depth: usize) -> LbToken {
+        use LbToken::*;
+        let nested = depth < MAX_DEPTH;
+        match self.byte() % 47 {
+            0 => SaveNumber((self.var(), self.number())),
+            1 => SaveStr((self.var(), self.text())),
+            2 => Copy((self.var(), self.var())),
+            3 => Append((self.var(), self.var())),
+            4 => PrintVar(self.var()),
+            5 => PrintStr(self.text()),
+            6 => PrintLineVar(self.var()),
+            7 => PrintLineStr(self.text()),
+            8 => MathOp((self.pick(MATH_OPS), self.var(), self.var(), self.var())),
+            9 => UnaryMathOp((self.pick(UNARY_MATH_OPS), self.var(), self.var())),
+            10 => BoolOp((self.pick(BOOL_OPS), self.var(), self.var(), self.var())),
+            11 => UnaryBoolOp((self.pick(UNARY_BOOL_OPS), self.var(), self.var())),
+            12 => {
+                let op = self.pick("SPKNFUCIRTBEOHL");
+                TextOp((op, self.vars(op_arity('T', op).unwrap_or(0))))
+            },
+            13 => {
+                let op = self.pick("NSGALP");
+                ListOp((op, self.vars(op_arity('V', op).unwrap_or(0))))
+            },
+            14 => {
+                let op = self.pick("NSGCRL");
+                MapOp((op, self.vars(op_arity('H', op).unwrap_or(0))))
+            },
+            15 if nested => Loop((self.var(), Box::new(self.token(depth + 1)))),
+            16 if nested => Repeat((self.byte() as usize % 5, Box::new(self.token(depth + 1)))),
+            17 if nested => WhileLoop((self.var(), Box::new(self.token(depth + 1)))),
+            18 | 19 if nested => {
+                let condition = self.var();
+                let body = self.body(depth + 1);
+                let else_command = (self.byte() % 2 == 1).then(|| Box::new(self.token(depth + 1)));
+                if self.byte() % 2 == 1 {
+                    Unless((condition, body, else_command))
+                }
+                else {
+                    IfStatement((condition, body, else_command))
+                }
+            },
+            20 if nested => {
+                let len = self.byte() % 4;
+                Block((0..len).map(|_| self.token(depth + 1)).collect())
+            },
+            21 => ResetVar(self.var()),
+            22 => ResetAll,
+            23 => ResetAllBanks,
+            24 => SwitchBank(self.byte() % 10),
+            25 => GetInput((self.pick("NS"), self.var(), (self.byte() % 4) as f64)),
+            26 => GetInputAt((self.pick("NS"), self.var(), self.var())),
+            27 => InputCount(self.var()),
+            28 => QueryInput((self.var(), self.pick("NS"))),
+            29 => Negate(self.var()),
+            30 => Push(self.var()),
+            31 => Pop(self.var()),
+            32 => StackDepth(self.var()),
+            33 => Random(self.var()),
+            34 => Sleep(self.var()),
+            35 => Elapsed(self.var()),
+            36 => Assert(self.var()),
+            37 => AssertSame((self.var(), self.var())),
+            38 => Finish,
+            39 => FinishWithStatus(self.var()),
+            40 => Label(self.label()),
+            41 => Jump(self.label()),
+            42 => JumpIf((self.var(), self.label())),
+            43 => {
+                let (args, dest) = self.execute_args();
+                Execute((self.var(), args, dest))
+            },
+            44 if nested => {
+                let len = self.byte() % 4;
+                let program: Vec<LbToken> = (0..len).map(|_| self.token(depth + 1)).collect();
+                let (args, dest) = self.execute_args();
+                ExecuteStr((lb_lexer::to_source(&program), args, dest))
+            },
+            45 => Return(self.var()),
+            46 => SaveList((self.var(), self.list_items(depth))),
+            _ => PrintVar(self.var()),
+        }
+    }
+
+    /// Returns the body of a conditional. A body ending with an else branch of its own
+    /// is put in a block, which is how it is written as source code.
+    fn body(&mut self, depth: usize) -> Box<LbToken> {
+        let body = self.token(depth);
+        if ends_with_else(&body) {
+            Box::new(LbToken::Block(vec![body]))
+        }
+        else {
+            Box::new(body)
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt::Display;
+
+use crate::error::LbError;
+use crate::lexing::line_column;
+
+/// A program's source code with its includes spliced in, as returned by [resolve_includes],
+/// along with where each part of it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncludedSource {
+    /// The source code, ready to lex.
+    pub source: String,
+
+    /// Where each stretch of [IncludedSource::source] starts, in order: its byte offset there,
+    /// the index in `files` of the file it came from, and its byte offset in that file.
+    pieces: Vec<(usize, usize, usize)>,
+
+    /// The name and original source code of each file, in the order they were included.
+    /// The program itself comes first, with no name.
+    files: Vec<(Option<String>, String)>,
+}
+
+/// Where a part of a program is in its original files. See [IncludedSource::locate].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The name of the included file, as written in its directive,
+    /// or `None` for the program itself.
+    pub file: Option<String>,
+
+    /// The line and column in that file, counting from 1.
+    pub line: usize,
+    pub column: usize,
+}
+
+impl IncludedSource {
+    /// Finds where a byte offset into [IncludedSource::source] came from,
+    /// such as the start of an [crate::prelude::ErrorFrame]'s or [crate::prelude::Diagnostic]'s span.
+    pub fn locate(&self, offset: usize) -> SourceLocation {
+        let index = self.pieces.partition_point(|(start, _, _)| *start <= offset).saturating_sub(1);
+        let (start, file, file_start) = self.pieces.get(index).copied().unwrap_or((0, 0, 0));
+        let (name, source) = &self.files[file];
+        let (line, column) = line_column(source, file_start + offset - start);
+        SourceLocation { file: name.clone(), line, column }
+    }
+}
+
+/// Splices included files into a program's source code, before it is lexed.
+/// A line holding only a directive like `!#include "lib.lb"` is replaced with the source of that file,
+/// which is itself resolved first, so included files can include others.
+/// Since directives are comments, a program that hasn't been resolved still runs without its includes.
+///
+/// The loader is given each file's name as written in its directive, and returns its source code,
+/// so files can come from the file system, memory, or anywhere else.
+/// A file included more than once is spliced in each time.
+/// Fails if the loader fails, or if a file includes itself, directly or through others.
+///
+/// ```
+/// use letterbox_lang::prelude::*;
+///
+/// let included = resolve_includes("Sa2\n!#include \"double.lb\"\nPa", |file: &str| match file {
+///     "double.lb" => Ok(String::from("MAaaa")),
+///     _ => Err("No such file"),
+/// }).unwrap();
+/// assert_eq!(included.source, "Sa2\nMAaaa\nPa");
+/// assert_eq!(included.locate(4).file.as_deref(), Some("double.lb"));
+/// ```
+pub fn resolve_includes<F, E>(source: &str, mut loader: F) -> Result<IncludedSource, LbError>
+where F: FnMut(&str) -> Result<String, E>, E: Display {
+    let mut included = IncludedSource { source: String::new(), pieces: Vec::new(), files: Vec::new() };
+    splice(&mut included, None, source.to_string(), &mut Vec::new(), &mut loader)?;
+    Ok(included)
+}
+
+/// Appends a file's source code to the included source, splicing in the files it includes.
+/// `chain` holds the names of the files being included, outermost first.
+fn splice<F, E>(included: &mut IncludedSource,
+    name: Option<String>,
+    source: String,
+    chain: &mut Vec<String>,
+    loader: &mut F,
+) -> Result<(), LbError>
+where F: FnMut(&str) -> Result<String, E>, E: Display {
+    let file = included.files.len();
+    included.files.push((name, String::new()));
+    included.pieces.push((included.source.len(), file, 0));
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\n', '\r']);
+        match include_name(text)? {
+            Some(include) => {
+                if chain.iter().any(|outer| outer == include) {
+                    let start = chain.iter().position(|outer| outer == include).unwrap_or(0);
+                    let mut cycle = chain[start..].to_vec();
+                    cycle.push(include.to_string());
+                    return Err(LbError::IncludeCycle(cycle));
+                }
+                let include_source = loader(include).map_err(|e| LbError::IncludeFailed {
+                    file: include.to_string(),
+                    message: e.to_string(),
+                })?;
+                chain.push(include.to_string());
+                splice(included, Some(include.to_string()), include_source, chain, loader)?;
+                chain.pop();
+                // the rest of this file carries on from the line break after the directive
+                included.pieces.push((included.source.len(), file, offset + text.len()));
+                included.source.push_str(&line[text.len()..]);
+            },
+            None => included.source.push_str(line),
+        }
+        offset += line.len();
+    }
+    included.files[file].1 = source;
+    Ok(())
+}
+
+/// Returns the name of the file a line includes, if the line is an include directive.
+fn include_name(line: &str) -> Result<Option<&str>, LbError> {
+    let Some(rest) = line.trim().strip_prefix("!#include") else {
+        return Ok(None);
+    };
+    let name = rest.trim()
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|name| !name.is_empty() && !name.contains('"'));
+    match name {
+        Some(name) => Ok(Some(name)),
+        None => Err(LbError::InvalidInclude(line.trim().to_string())),
+    }
+}
@@ -0,0 +1,75 @@
+use crate::program::Val;
+
+/// The source of the inputs that `GNa1`, `GSab`, and `G#a` commands read.
+/// Hosts can provide their own, for example to read inputs lazily
+/// from stdin or the network. See [crate::program::LbProgram::with_io].
+pub trait LbInput {
+    /// Returns the input at the given index, or `None` if there isn't one.
+    fn get(&mut self, index: usize) -> Option<Val>;
+
+    /// Returns how many inputs there are, or `None` if that isn't known.
+    /// Used by `G#a`.
+    fn count(&mut self) -> Option<usize>;
+}
+
+impl LbInput for Vec<String> {
+    fn get(&mut self, index: usize) -> Option<Val> {
+        self.as_slice().get(index).map(|input| Val::Text(input.clone()))
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl LbInput for &Vec<String> {
+    fn get(&mut self, index: usize) -> Option<Val> {
+        self.as_slice().get(index).map(|input| Val::Text(input.clone()))
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl LbInput for Vec<Val> {
+    fn get(&mut self, index: usize) -> Option<Val> {
+        self.as_slice().get(index).cloned()
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+/// An [LbInput] that takes its inputs from an iterator as they are needed,
+/// such as the lines of stdin.
+/// Inputs are kept once taken, so any index can be read again.
+/// The number of inputs isn't known, so `G#a` fails.
+#[derive(Debug, Clone)]
+pub struct IterInput<It: Iterator> {
+    iter: It,
+    taken: Vec<Val>,
+}
+
+impl<It: Iterator> IterInput<It> {
+    /// Returns an input source that reads from the given iterator.
+    pub fn new(iter: It) -> IterInput<It> {
+        IterInput { iter, taken: Vec::new() }
+    }
+}
+
+impl<It> LbInput for IterInput<It>
+where It: Iterator, It::Item: Into<Val> {
+    fn get(&mut self, index: usize) -> Option<Val> {
+        while self.taken.len() <= index {
+            let input = self.iter.next()?;
+            self.taken.push(input.into());
+        }
+        self.taken.as_slice().get(index).cloned()
+    }
+
+    fn count(&mut self) -> Option<usize> {
+        None
+    }
+}
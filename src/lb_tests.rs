@@ -1,6 +1,9 @@
 use crate::storage::*;
 use crate::program::*;
-use crate::lb_lexer::LbToken;
+use crate::error::LbError;
+use crate::diagnostics::*;
+use crate::ast::*;
+use crate::lb_lexer::{LbToken, SpanTree};
 use logos::Logos;
 
 /// Made for testing Letterbox programs.
@@ -42,6 +45,21 @@ macro_rules! assert_lb_from_input {
     };
 }
 
+/// Made for testing Letterbox programs.
+/// Asserts that string A, when run as a Letterbox program,
+/// fails with error message B.
+#[macro_export]
+macro_rules! assert_lb_err {
+    ( $x:expr, $y:expr ) => {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let lex = LbToken::lexer($x);
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from($y)));
+    };
+}
+
 #[test]
 fn print_store_copy() {
     assert_lb_out!("Sb3", "");
@@ -49,10 +67,341 @@ fn print_store_copy() {
     assert_lb_out!("Sa5.5 Pa", "5.5");
     assert_lb_out!("Sa-6 Pa", "-6");
     assert_lb_out!("Sa-6.5 Pa", "-6.5");
+    assert_lb_out!("Sa1.5e3 Pa", "1500");
+    assert_lb_out!("Sa-2E-2 Pa", "-0.02");
+    assert_lb_out!("Sa0xff Sb0b11 MAcab Pc", "258");
     assert_lb_out!("P'Hello world'", "Hello world");
     assert_lb_out!("Sz'This is a test' Pz", "This is a test");
 }
 
+#[test]
+fn print_lines() {
+    assert_lb_out!("Sa4 Oa O'hello' Pa P'hello'", "4\nhello\n4hello");
+    assert_lb_out!("P'Loading' Sa3 La[ P'.' ] O'' Sb'done' Ob", "Loading...\ndone\n");
+    assert_lb_out!("Sz'Oa' Sa1 Xz Pa", "1\n1");
+}
+
+#[test]
+fn integer_printing() {
+    assert_lb_out!("Sa0.1 Sb0.2 MAcab Sd10 MMecd Pe", "3");
+    assert_lb_out!("Sa0.1 Sb0.2 MAcab Sd10 MMecd Sf'x' Afe Pf", "x3");
+    assert_lb_out!("Sa0.1 Sb0.2 MAcab Pc", "0.30000000000000004");
+    assert_lb_out!("Sa-0.7 Sb10 MMcab Pc", "-7");
+    assert_lb_out!("Sa9007199254740992 Pa", "9007199254740992");
+    assert_lb_out!("Sa1e20 Sb3 MDcab Pc", "33333333333333330000");
+    assert_lb_out!("Sa1.5 Pa", "1.5");
+}
+
+#[test]
+fn exact_number_printing() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa0.1 Sb0.2 MAcab Sd10 MMccd Pc P' ' Sz'Pc' Xz");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.exact_numbers = true;
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(out, "3.0000000000000004 3.0000000000000004");
+}
+
+#[test]
+fn uppercase_variables() {
+    assert_lb_out!("SA4 Sa1 PA Pa", "41");
+    assert_lb_out!("SZ'hi' CZB PB", "hi");
+    assert_lb_out!("SA1 SB2 MACAB PC", "3");
+    assert_lb_out!("SN3 SQ1 WN[ PN MSNNQ ]", "321");
+    assert_lb_out!("SB5 RB PB", "0");
+    assert_lb_out!("SA1 Sa2 RA PA Pa", "02");
+    // R* resets everything
+    assert_lb_out!("SA1 Sa2 R* PA Pa", "00");
+}
+
+#[test]
+fn uppercase_storage() {
+    let mut data = LbStorage::new();
+    assert!(is_var(&'A') && is_var(&'z'));
+    assert!(!is_var(&'1') && !is_var(&'é'));
+    data.set_var('A', &Val::Number(1.0)).unwrap();
+    data.set_var('a', &Val::Number(2.0)).unwrap();
+    assert_eq!(data.get_var('A').map(|val| val.to_string()), Some(String::from("1")));
+    assert_eq!(data.get_var('a').map(|val| val.to_string()), Some(String::from("2")));
+    data.reset_all().unwrap();
+    assert_eq!(data.get_var('A').map(|val| val.to_string()), Some(String::from("0")));
+    assert_eq!(data.get_var('a').map(|val| val.to_string()), Some(String::from("0")));
+}
+
+#[test]
+fn invalid_storage_names() {
+    let mut data = LbStorage::new();
+    let changes = std::sync::Arc::new(std::sync::Mutex::new(0));
+    let counter = std::sync::Arc::clone(&changes);
+    data.set_observer(move |_, _, _| *counter.lock().unwrap() += 1);
+    for var in ['1', '\n', 'é', ' ', '#'] {
+        assert_eq!(data.set_var(var, &Val::Number(1.0)), Err(LbError::NotAVariable(var)));
+        assert_eq!(data.set(var, "x"), Err(LbError::NotAVariable(var)));
+        assert_eq!(data.reset_var(var), Err(LbError::NotAVariable(var)));
+        assert_eq!(data.copy('a', var), Err(LbError::NotAVariable(var)));
+        assert!(data.get_var_mut(var).is_none());
+    }
+    // nothing was stored, and the observer wasn't told about anything
+    assert!(data.is_empty());
+    assert_eq!(*changes.lock().unwrap(), 0);
+    assert_eq!(data.set_var('Z', &Val::Number(1.0)), Ok(()));
+    assert_eq!(data.reset_var('Z'), Ok(()));
+}
+
+#[test]
+fn storage_iteration() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    // b, c, and Z are only read, and d is reset
+    let lex = LbToken::lexer("Sx2 MAaxb Pc SZ'' Sd1 Rd Ib[ Pa ] CZy");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    assert_eq!(program.run(), Ok(()));
+
+    let vars: Vec<String> = data.iter().map(|(var, val)| format!("{var}={}", val.format(false))).collect();
+    assert_eq!(vars, vec!["Z=", "a=2", "x=2", "y="]);
+    assert_eq!(data.len(), 4);
+    assert!(data.contains_var('a') && data.contains_var('Z'));
+    assert!(!data.contains_var('b') && !data.contains_var('c') && !data.contains_var('d'));
+
+    // reading unset variables doesn't store them
+    assert_eq!(data.get_var('q').map(|val| val.to_string()), Some(String::from("0")));
+    assert_eq!(data.var_as_bool('q'), Some(false));
+    assert!(!data.contains_var('q'));
+    assert_eq!((&data).into_iter().count(), 4);
+
+    data.reset_all().unwrap();
+    assert!(data.is_empty());
+    assert_eq!(data.iter().next(), None);
+}
+
+#[test]
+fn default_policies() {
+    // g is misspelled as q in the loop
+    let buggy = "Sg5 Ss0\nLg[ MAssq ] Ps";
+    let run = |policy: DefaultPolicy| {
+        let mut data = LbStorage::new();
+        data.set_default_policy(policy);
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(buggy), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        let result = program.run();
+        let frame = program.error_frames().first().map(|frame| (frame.line, frame.column));
+        (result, out, frame)
+    };
+
+    // by default, q quietly holds 0
+    assert_eq!(run(DefaultPolicy::Zero), (Ok(()), String::from("0"), None));
+    assert_eq!(run(DefaultPolicy::Value(Val::Number(1.0))), (Ok(()), String::from("5"), None));
+
+    let (result, out, frame) = run(DefaultPolicy::Error);
+    let error = LbError::UnsetVariable { var: 'q', position: Some((2, 1)) };
+    assert_eq!(result, Err(error.clone()));
+    assert_eq!(error.to_string(), "Variable q is read at line 2, column 1 before it is set");
+    assert_eq!(out, "");
+    // the error frame has the position of the instruction inside the loop
+    assert_eq!(frame, Some((2, 5)));
+
+    // variables that are set or reset follow the policy too
+    let mut data = LbStorage::new();
+    data.set_default_policy(DefaultPolicy::Error);
+    data.set('a', 1).unwrap();
+    assert_eq!(data.get_number('a'), Ok(1.0));
+    data.reset_var('a').unwrap();
+    assert!(data.get_var('a').is_none() && data.peek_var('a').is_none() && data.var_as_bool('a').is_none());
+    assert_eq!(data.copy('a', 'b'), Err(LbError::UnsetVariable { var: 'a', position: None }));
+    assert_eq!(data.get_number('a'), Err(LbError::UnsetVariable { var: 'a', position: None }));
+    assert_eq!(data.copy('!', 'b'), Err(LbError::InvalidVariable { command: 'C', var: '!' }));
+
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut program = LbProgram::new(LbToken::lexer("Sa'x' Ia[ Pa ] Ib[ Pa ]"), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    assert_eq!(program.run(), Err(LbError::UnsetVariable { var: 'b', position: Some((1, 16)) }));
+    assert_eq!(out, "x");
+
+    data.set_default_policy(DefaultPolicy::Value(Val::from("none")));
+    assert_eq!(data.get_text('z'), Ok("none"));
+    // changing an unset variable in place starts from the default
+    if let Some(Val::Text(text)) = data.get_var_mut('z') {
+        text.push('!');
+    }
+    assert_eq!(data.get_text('z'), Ok("none!"));
+}
+
+#[test]
+fn storage_banks() {
+    // bank 0 is active to begin with, and each bank has its own variables
+    assert_lb_out!("Sa1 #1 Pa Sa2 Pa #0 Pa #1 Pa", "0212");
+    // the stack is shared, so it can carry values between banks
+    assert_lb_out!("Sa5 K+a #3 K-b Pb Pa", "50");
+    // R* only resets the active bank, while R# resets all of them
+    assert_lb_out!("Sa1 #1 Sa2 R* Pa #0 Pa", "01");
+    assert_lb_out!("Sa1 #1 Sa2 R# Pa #0 Pa", "00");
+    // executed programs switch banks for the program that ran them too
+    assert_lb_out!("Sz'#2 Sa3' Xz Pa #0 Pa", "30");
+
+    let mut data = LbStorage::new();
+    data.set('a', 1).unwrap();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("#4 Sb'four' #9 Sc9");
+    LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed").run().unwrap();
+    // the bank stays switched after the program ends
+    assert_eq!(data.bank(), 9);
+    assert_eq!(data.dump(), "c: 9");
+    let bank = |data: &LbStorage, bank: usize| {
+        data.bank_iter(bank).map(|(var, val)| format!("{var}={val}")).collect::<Vec<String>>()
+    };
+    assert_eq!(bank(&data, 0), vec!["a=1"]);
+    assert_eq!(bank(&data, 4), vec!["b=four"]);
+    assert_eq!(bank(&data, 9), vec!["c=9"]);
+    assert!(bank(&data, 5).is_empty() && bank(&data, 10).is_empty());
+
+    // snapshots include every bank
+    let snapshot = data.snapshot();
+    data.set_bank(0).unwrap();
+    data.reset_all_banks().unwrap();
+    assert!(bank(&data, 4).is_empty());
+    data.restore(&snapshot);
+    assert_eq!(data.bank(), 9);
+    assert_eq!(bank(&data, 4), vec!["b=four"]);
+
+    assert_eq!(data.set_bank(BANK_COUNT), Err(LbError::InvalidBank(10)));
+    assert_eq!(LbError::InvalidBank(10).to_string(), "#: There is no bank 10");
+    assert_eq!(LbProgramBuilder::new().switch_bank(10).build(), Err(LbError::InvalidBank(10)));
+    let ast = LbProgramBuilder::new().switch_bank(3).save('a', 1.0).reset_all_banks().build().unwrap();
+    assert_eq!(ast.source, "#3 Sa1 R#");
+}
+
+#[test]
+fn storage_snapshots() {
+    let dump = |data: &mut LbStorage| {
+        let vars: Vec<(char, Val)> = data.iter().map(|(var, val)| (var, val.clone())).collect();
+        let mut stack = Vec::new();
+        while let Some(val) = data.pop() {
+            stack.push(val);
+        }
+        for val in stack.iter().rev() {
+            data.push(val);
+        }
+        (vars, stack)
+    };
+    let run = |data: &mut LbStorage, src: &str| {
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), data, &inv, &mut out, 1000).expect("Program init failed");
+        assert_eq!(program.run(), Ok(()));
+    };
+
+    let mut data = LbStorage::new();
+    run(&mut data, "Sa1 Sb'two' VNl VAla K+a K+b");
+    let before = dump(&mut data);
+    let snapshot = data.snapshot();
+    let copy = snapshot.clone();
+
+    run(&mut data, "Sa5 Rb VAla Sc3 K-x K+c K+c");
+    assert_ne!(dump(&mut data), before);
+    data.restore(&snapshot);
+    assert_eq!(dump(&mut data), before);
+    assert!(!data.contains_var('c') && !data.contains_var('x'));
+
+    // a snapshot can be restored more than once
+    data.reset_all().unwrap();
+    data.restore(&copy);
+    assert_eq!(dump(&mut data), before);
+}
+
+#[test]
+fn storage_diffs() {
+    let mut left = LbStorage::new();
+    let mut right = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa0 Sb7 Sc'7' SZ1 K+b");
+    LbProgram::new(lex, &mut left, &inv, &mut out, 1000).expect("Program init failed").run().unwrap();
+    right.set('b', 5).unwrap();
+    right.set('c', 7).unwrap();
+    right.set('d', 1).unwrap();
+
+    let diffs = left.diff(&right);
+    let lines: Vec<String> = diffs.iter().map(|diff| diff.to_string()).collect();
+    // a was set to 0, which is the same as unset, and the stack isn't compared
+    assert_eq!(lines, vec!["Z: 1 != 0", "b: 7 != 5", "c: '7' != 7", "d: 0 != 1"]);
+    assert_eq!(diffs[1], VarDiff { var: 'b', left: Val::Number(7.0), right: Val::Number(5.0) });
+    assert_eq!(right.diff(&left)[1], VarDiff { var: 'b', left: Val::Number(5.0), right: Val::Number(7.0) });
+
+    right.restore(&left.snapshot());
+    assert!(left.diff(&right).is_empty());
+    crate::assert_storage_eq!(left, right);
+    right.reset_var('a').unwrap();
+    crate::assert_storage_eq!(left, right);
+}
+
+#[test]
+fn storage_equality() {
+    let run = |source: &str| {
+        let mut data = LbStorage::default();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        LbProgram::new(LbToken::lexer(source), &mut data, &inv, &mut out, 1000)
+            .expect("Program init failed")
+            .run()
+            .expect("Program failed");
+        data
+    };
+    // the same variables and stack, reached in different ways
+    let left = run("Sa3 Sb4 MAcab #1 SZ'x' #0 K+a");
+    let right = run("#1 SZ'x' #0 Sc7 Sb4 Sa3 Sd0 Re K+a");
+    assert_eq!(left, right);
+    assert_eq!(left, left.clone());
+    assert_eq!(LbStorage::new(), run("Sa0 Rb"));
+
+    // a variable in another bank, the active bank, or the stack makes them differ
+    assert_ne!(left, run("Sa3 Sb4 MAcab #1 SZ'y' #0 K+a"));
+    assert_ne!(left, run("Sa3 Sb4 MAcab #1 SZ'x' K+a"));
+    assert_ne!(left, run("Sa3 Sb4 MAcab #1 SZ'x' #0"));
+
+    // clones keep everything but the observer
+    let mut watched = left.clone();
+    watched.set_default_policy(DefaultPolicy::Error);
+    watched.set_observer(|_, _, _| {});
+    let copy = watched.clone();
+    assert_eq!(copy, watched);
+    assert!(!format!("{copy:?}").contains("observer: true"));
+    assert_eq!(copy.peek_var('q'), None);
+    assert_eq!(Val::default(), Val::Number(0.0));
+}
+
+#[test]
+fn storage_dumps() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sc-2.5 Sa0.1 Sb0.2 MAaab Sb'it\\'s' VNl VAla VAlb Sd0 Rd");
+    LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed").run().unwrap();
+    assert_eq!(data.dump(), "a: 0.30000000000000004\nb: 'it\\'s'\nc: -2.5\nl: [0.30000000000000004, it's]");
+    assert_eq!(data.to_string(), data.dump());
+    assert_eq!(LbStorage::new().dump(), "");
+
+    data.reset_all().unwrap();
+    data.set('A', 3).unwrap();
+    data.push(&Val::from("x"));
+    assert_eq!(format!("{data:?}"), "LbStorage { vars: {'A': Number(3.0)}, bank: 0, other_banks: {}, stack: [Text(\"x\")], observer: false }");
+}
+
+#[test]
+#[should_panic(expected = "storages differ:\n  b: 7 != 5\n  c: 0 != 'x'")]
+fn storage_diff_assertion() {
+    let mut left = LbStorage::new();
+    let mut right = LbStorage::new();
+    left.set('b', 7).unwrap();
+    right.set('b', 5).unwrap();
+    right.set('c', "x").unwrap();
+    crate::assert_storage_eq!(left, right);
+}
+
 #[test]
 fn append() {
     assert_lb_out!("Arc Pr", "00");
@@ -64,6 +413,31 @@ fn append() {
     assert_lb_out!("Sr'fizz' Sc'buzz' Arr Arr Pr", "fizzfizzfizzfizz");
 }
 
+#[test]
+fn escaped_strings() {
+    assert_lb_out!(r"P'don\'t panic'", "don't panic");
+    assert_lb_out!(r"Sa'\'' Pa", "'");
+    assert_lb_out!(r"Sa'it\'s ' Sb'a \\ b' Aab Pa", "it's a \\ b");
+    assert_lb_out!(r"Sz'P\'it\\\'s\'' Xz", "it's");
+    assert_lb_out!(r"Sz'Pa P\'a\\\'s\'' Sb3 Xzab", "3a's");
+}
+
+#[test]
+fn control_escapes() {
+    assert_lb_out!(r"P'one\ntwo\tthree\r'", "one\ntwo\tthree\r");
+    assert_lb_out!(r"Sa'a\nb' Oa", "a\nb\n");
+    assert_lb_out!(r"P'C:\\new'", "C:\\new");
+    assert_lb_out!(r"Sz'P\'x\\ny\'' Xz", "x\ny");
+}
+
+#[test]
+fn block_comments() {
+    assert_lb_out!("Sa1 !{ Pa }! Pa", "1");
+    assert_lb_out!("Sa2 La[ P'x' !{ P'y' }! ]", "xx");
+    assert_lb_out!("Sa2 !{ La[ P'x' !{ P'y' }! ] }! P'z'", "z");
+    assert_lb_out!("!{ café !{ naïve }! 日本 }! P'ok'", "ok");
+}
+
 #[test]
 fn reset_var() {
     assert_lb_out!("Ra", "");
@@ -73,19 +447,45 @@ fn reset_var() {
 
 #[test]
 fn reset_all() {
-    assert_lb_out!("RA", "");
-    assert_lb_out!("Sa1 Sb2 Sc'3' RA Pa Pb Pc", "000");
+    assert_lb_out!("R*", "");
+    assert_lb_out!("Sa1 Sb2 Sc'3' R* Pa Pb Pc", "000");
 }
 
 #[test]
 fn discrete_loop() {
     assert_lb_out!("Sa3 Sb4 LaPb", "444");
     assert_lb_out!("Sa2 Sd11 LdMAbab Pb", "22");
+    // only the first command after the condition is repeated
+    assert_lb_out!("Sa3 Sb1 LaPbPa", "1113");
+    assert_lb_out!("Sa3 Sb1 LaPb Pa", "1113");
+}
+
+#[test]
+fn counted_loop() {
+    assert_lb_out!("L3P'x'", "xxx");
+    assert_lb_out!("L2[ P'a' P'b' ]", "abab");
+    // fractions are truncated, and counts of zero or less don't run the body
+    assert_lb_out!("L2.9P'x' L0P'y' L-3P'z'", "xx");
+    // counted loops nest inside loops on variables, and the other way around
+    assert_lb_out!("Sa2 LaL3P'x' P'.'", "xxxxxx.");
+    assert_lb_out!("Sa2 L3LaP'x' P'.'", "xxxxxx.");
+    assert_lb_out!("Sa1 L2[ Pa MAaaa ]", "12");
 }
 
 #[test]
 fn while_loop() {
     assert_lb_out!("Sa10 Sb1 WaMSaab Pa", "0");
+    assert_lb_out!("Sa2 Sb1 Sc1 WaIcMSaab Pa", "0");
+}
+
+#[test]
+fn blocks() {
+    assert_lb_out!("[ Pa Pb ]", "00");
+    assert_lb_out!("Sa3 La[ Pa P' ' ]", "3 3 3 ");
+    assert_lb_out!("Sa3 Sb1 Wa[ Pa MSaab ] Pa", "3210");
+    assert_lb_out!("Sa1 Ia[ P'yes' Pa ] Ub[ P'no' ]", "yes1no");
+    assert_lb_out!("Sa2 Sb2 Sc1 La[ Pa Cbd Wd[ P'-' MSddc ] ]", "2--2--");
+    assert_lb_out!("Sa5 Sb1 Wa[ Pa MSaab Ia[ F ] ] Pa", "5");
 }
 
 #[test]
@@ -97,6 +497,16 @@ fn if_statement() {
     assert_lb_out!("Sa10 Sb2 MLcab IcPb", "");
 }
 
+#[test]
+fn bodies_with_literals() {
+    assert_lb_out!("Sa2 LaP'hi '", "hi hi ");
+    assert_lb_out!("Sa1 IaSb5 UaSb6 Pb", "5");
+    assert_lb_out!("Sa2 Sb2 LaLbO'x y'", "x y\nx y\nx y\nx y\n");
+    assert_lb_out!("Sa3 Sb1 LaIbSc-1.5:Sc2 Pc", "-1.5");
+    assert_lb_out!("Sa1 Ia[ IbP'b':P'not b' ]:P'not a'", "not b");
+    assert_lb_from_input!("Sa1 IaGNb0 UaGNb1 Pb", vec![String::from("4"), String::from("5")], "4");
+}
+
 #[test]
 fn unless() {
     assert_lb_out!("Sb5 UaPb", "5");
@@ -106,6 +516,18 @@ fn unless() {
     assert_lb_out!("Sa10 Sb2 MLcab UcPb", "2");
 }
 
+#[test]
+fn else_branches() {
+    assert_lb_out!("St'then' Se'else' Sa1 IaPt:Pe", "then");
+    assert_lb_out!("St'then' Se'else' IaPt:Pe", "else");
+    assert_lb_out!("St'then' Se'else' Sa1 UaPt:Pe", "else");
+    assert_lb_out!("St'then' Se'else' UaPt:Pe", "then");
+    // the condition is only read once, even if the branch changes it
+    assert_lb_out!("Sa1 Ia[ Ra P'then' ]:[ P'else' ]", "then");
+    assert_lb_out!("Sb1 Ia[ P'a' ]:Ib[ P'b' ]:[ P'neither' ]", "b");
+    assert_lb_out!("Sa3 Sb1 Sd2 Wa[ MRcad Ic[ Pa ]:[ P'-' ] MSaab ]", "3-1");
+}
+
 #[test]
 fn negate() {
     assert_lb_out!("Na", "");
@@ -129,6 +551,47 @@ fn execute_with_params() {
     assert_lb_out!("Sf'MAcab' Se2 Sg4 Xfaebgcz Pz", "6");
 }
 
+#[test]
+fn execute_with_uppercase_params() {
+    assert_lb_out!("Sz'PA' Sq9 XzAq", "9");
+    assert_lb_out!("Sz'Pa' SQ9 XzaQ", "9");
+    // only variables are replaced, not commands with the same letter
+    assert_lb_out!("Sz'SA1 AAB PA' SB'!' XzAc Pc", "1!1!");
+    // parameters are replaced all at once, so they can be swapped
+    assert_lb_out!("Sz'Pa Pb' Sa1 Sb2 Xzabba", "21");
+}
+
+#[test]
+fn execute_with_return() {
+    assert_lb_out!("Sa3 Sz'MAbaa Yb' Xz>r Pr", "6");
+    assert_lb_out!("Sf'MMcaa Yc' Sx5 Xfax>r Pr", "25");
+    assert_lb_out!(r"Sz'Sa\'hi\' Ya' Xz>r Pr", "hi");
+    // returning stops the executed program, but not this one
+    assert_lb_out!(r"Sz'Sa1 Ya P\'no\'' Xz>r Pr P'!'", "1!");
+    assert_lb_out!("Sz'Sa1 Sb2 La[ Ya ]' Xz>r Pr", "1");
+    // without a return, the destination is untouched
+    assert_lb_out!("Sr'old' Sz'Sa1' Xz>r Pr", "old");
+    assert_lb_out!("Sr'old' Sz'Sa1 F Ya' Xz>r Pr", "old");
+    // a returned value can be ignored
+    assert_lb_out!("Sz'Sa1 Ya' Xz P'ok'", "ok");
+    // nested programs return to their own caller
+    assert_lb_out!("Sy'Sa2 Ya' Sz'Xy>b Sa1 Ya' Xz>r Pr Pb", "12");
+
+    assert_lb_err!("Sa1 Ya", "Y: Can't return outside of an executed program");
+}
+
+#[test]
+fn execute_inline() {
+    assert_lb_out!("X'Sa1 Pa'", "1");
+    assert_lb_out!("Sb2 X'Pa'ab", "2");
+    assert_lb_out!("Sx3 X'MMbaa Yb'ax>r Pr", "9");
+    assert_lb_out!(r"X'P\'hi\''", "hi");
+    assert_lb_out!(r"Sa2 La[ X'P\'a\'' ]", "aa");
+
+    // unescaped quotes end the program early
+    assert_lb_err!("X'P'hi''", "X: Could not parse P in executed program");
+}
+
 #[test]
 fn input() {
     assert_lb_from_input!("Sa4 Pa", vec!["1".to_string(), "2".to_string()], "4");
@@ -136,6 +599,135 @@ fn input() {
     assert_lb_from_input!("GSa0 Pa", vec!["Pizza".to_string()], "Pizza");
 }
 
+#[test]
+fn input_from_variable_index() {
+    let inputs = || vec!["4", "8", "15", "16", "23"].into_iter().map(String::from).collect::<Vec<String>>();
+    assert_lb_from_input!("Sn5 Sb1 Ln[ GNxi MAssx MAiib ] Ps", inputs(), "66");
+    assert_lb_from_input!("Si1.8 GSai Pa", inputs(), "8");
+    assert_lb_from_input!("Si4 Sb1 Wb[ GSai Pa MSiib MHbiz ]", inputs(), "23161584");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = inputs();
+    let mut program = LbProgram::new(LbToken::lexer("Si5 GNai"), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from("G: no input at index 5")));
+    let mut program = LbProgram::new(LbToken::lexer("Si-1 GNai"), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from("G: no input at index -1")));
+}
+
+#[test]
+fn input_count() {
+    assert_lb_out!("G#n Pn", "0");
+    assert_lb_from_input!("G#n Pn", vec!["1".to_string(), "2".to_string()], "2");
+    // sum all inputs
+    assert_lb_from_input!(
+        "G#n Sb1 Ln[ GNxi MAssx MAiib ] Ps",
+        vec!["4", "8", "15", "16", "23", "42"].into_iter().map(String::from).collect::<Vec<String>>(),
+        "108");
+}
+
+#[test]
+fn input_sources() {
+    use crate::input::IterInput;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // inputs can be values other than text
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inputs = vec![Val::Number(2.5), Val::Text(String::from("4")), Val::List(vec![Val::zero()])];
+    let lex = LbToken::lexer("GNa0 GNb1 MAcab Pc GSd0 Pd GSe2 Pe G#n Pn");
+    let mut program = LbProgram::with_io(lex, &mut data, inputs, StringOutput::new(&mut out), 1000).expect("Program init failed");
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(out, "6.52.5[0]3");
+
+    // inputs from an iterator are only taken once they're needed
+    let taken = Rc::new(Cell::new(0));
+    let counter = Rc::clone(&taken);
+    let lines = (1..).map(move |n| {
+        counter.set(counter.get() + 1);
+        format!("{}", n * 10)
+    });
+    let mut out = String::new();
+    let lex = LbToken::lexer("GNa2 Pa GSb0 Pb");
+    let mut program = LbProgram::with_io(lex, &mut data, IterInput::new(lines), StringOutput::new(&mut out), 1000).expect("Program init failed");
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(taken.get(), 3);
+    assert_eq!(out, "3010");
+
+    let mut out = String::new();
+    let lines = vec!["a", "b"].into_iter().map(String::from);
+    let lex = LbToken::lexer("GSa2");
+    let mut program = LbProgram::with_io(lex, &mut data, IterInput::new(lines.clone()), StringOutput::new(&mut out), 1000).expect("Program init failed");
+    assert_eq!(program.run(), Err(LbError::InputMissing(2.0)));
+    let lex = LbToken::lexer("G#n");
+    let mut program = LbProgram::with_io(lex, &mut data, IterInput::new(lines), StringOutput::new(&mut out), 1000).expect("Program init failed");
+    assert_eq!(program.run(), Err(LbError::InputCountUnknown));
+}
+
+#[test]
+fn query_input() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut answers = vec!["3", "Ada"].into_iter();
+    let lex = LbToken::lexer("O'How many?' GQnN O'Name?' GQsS Sz'GQtS' Ln[ Ps ] Xz");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_input_callback(move || answers.next().map(String::from));
+    assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from("G: The host provided no input")));
+    assert_eq!(out, "How many?\nName?\nAdaAdaAda");
+}
+
+#[test]
+fn stdin_fallback() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = vec![String::from("5")];
+    // given inputs come first, and lines are appended after them as they are needed
+    let lex = LbToken::lexer("GNa0 GNb1 GSc3 GNd1 MAeab Pe O'' Pc Pd O'' G#f Pf GSg2 Pg GNh4");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_stdin_fallback(std::io::Cursor::new("2.5\r\nsome text\nlast\n"));
+    assert_eq!(program.run(), Err(LbError::InputMissing(4.0)));
+    assert_eq!(out, "7.5\nlast2.5\n4some text");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let lex = LbToken::lexer("GNa0");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_stdin_fallback(std::io::Cursor::new("ten\n"));
+    assert_eq!(program.run(), Ok(()));
+    let lex = LbToken::lexer("GNa1");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_stdin_fallback(std::io::Cursor::new("ten\n"));
+    assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from("G: Could not parse input into number: ten")));
+
+    // a reader that fails is an error, rather than a missing input
+    struct FailingReader;
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("device unplugged"))
+        }
+    }
+    let lex = LbToken::lexer("GNa1");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_stdin_fallback(std::io::BufReader::new(FailingReader));
+    assert_eq!(program.run(), Err(LbError::InputReadFailed(String::from("device unplugged"))));
+    assert_eq!(LbError::InputReadFailed(String::from("device unplugged")).to_string(), "G: Could not read input: device unplugged");
+}
+
+#[test]
+fn query_input_errors() {
+    assert_lb_err!("GQaN", "G: No input callback was provided");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("GQaN");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_input_callback(|| Some(String::from("three")));
+    assert_eq!(program.run().map_err(|e| e.to_string()), Err(String::from("G: Could not parse input into number: three")));
+}
+
 #[test]
 fn finish() {
     assert_lb_out!("F", "");
@@ -143,99 +735,2190 @@ fn finish() {
     assert_lb_out!("Sa4 Pa IbF Sa3 Pa", "43");
 }
 
-#[cfg(test)]
-mod math_ops {
-    use crate::storage::*;
-    use crate::program::*;
-    use crate::lb_lexer::LbToken;
-    use logos::Logos;
+#[test]
+fn finish_with_status() {
+    let run_status = |src: &str| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        let result = program.run();
+        (result, program.exit_status, out)
+    };
 
-    #[test]
-    fn add() {
-        assert_lb_out!("Sa3 Sb2 MAcab Pc", "5");
-        assert_lb_out!("Sa3.5 Sb1 MAcab Pc", "4.5");
-    }
+    assert_eq!(run_status("Sa3 P'a' Fa P'b'"), (Ok(()), 3.0, String::from("a")));
+    assert_eq!(run_status("Sa-1 Sb1 Ib[ Fa ] P'b'"), (Ok(()), -1.0, String::new()));
+    // other ways of finishing report 0
+    assert_eq!(run_status("Sa3 F Fa"), (Ok(()), 0.0, String::new()));
+    assert_eq!(run_status("Sa3 Pa"), (Ok(()), 0.0, String::from("3")));
+    // finishing an executed program doesn't report a status
+    assert_eq!(run_status("Sa3 Sz'Fa' Xz P'b'"), (Ok(()), 0.0, String::from("b")));
 
-    #[test]
-    fn subtract() {
-        assert_lb_out!("Sa3 Sb2 MScab Pc", "1");
-        assert_lb_out!("Sa3.5 Sb5 MScab Pc", "-1.5");
-    }
+    assert_lb_err!("Sa'bad' Fa", "F: Variable a is not a number");
+}
 
-    #[test]
-    fn multiply() {
-        assert_lb_out!("Sa3 Sb2 MMcab Pc", "6");
-        assert_lb_out!("Sa0.5 Sb5 MMcab Pc", "2.5");
-    }
+#[test]
+fn assertions() {
+    assert_lb_out!("Sa1 Za Sb'x' Zb VNl VAla Zl P'ok'", "ok");
+    assert_lb_out!("Sa1 Sb1.0 Zab Sc'x' Sd'x' Zcd P'ok'", "ok");
+    assert_lb_err!("Sa1 Za Zb", "Z: Assertion failed at line 1, column 8: b is 0");
+    assert_lb_err!("Sa1\n  VNl Zl", "Z: Assertion failed at line 2, column 7: l is []");
+    assert_lb_err!("Sa3 Sb4 Zab", "Z: Assertion failed at line 1, column 9: a is 3 but b is 4");
+    assert_lb_err!("Sa3 Sb'3' Zab", "Z: Assertion failed at line 1, column 11: a is 3 but b is 3");
+    // nested assertions report the position of the command containing them
+    assert_lb_err!("Sa1 Ia[ Zb ]", "Z: Assertion failed at line 1, column 5: b is 0");
+    assert_lb_err!("Sz'Zb' P'' Xz", "Z: Assertion failed at line 1, column 12: b is 0");
+}
 
-    #[test]
-    fn divide() {
-        assert_lb_out!("Sa3 Sb2 MDcab Pc", "1.5");
-        assert_lb_out!("Sa10 Sb5 MDcab Pc", "2");
-    }
+#[test]
+fn jumps() {
+    assert_lb_out!("P'a' Jx P'b' :x P'c'", "ac");
+    assert_lb_out!("Sa1 JIax P'b' :x Ra JIax P'c'", "c");
+    // count down with a backwards jump
+    assert_lb_out!("Sa3 Sb1 :x Pa MSaab JIax", "321");
+    // jumping out of loops and blocks stops them
+    assert_lb_out!("Sa5 La[ P'a' Jx ] :x P'b'", "ab");
+    assert_lb_out!("Sa1 WaJx :x P'b'", "b");
+    assert_lb_out!("Sa1 Ia[ Jx P'a' ] :x P'b'", "b");
+    // labels can be jumped to from the end of the program
+    assert_lb_out!("Sa0 :x IaF Sa1 Jx", "");
+}
 
-    #[test]
-    fn equal_to() {
-        assert_lb_out!("Sa3 Sb2 MEcab Pc", "0");
-        assert_lb_out!("Sa10 Sb10 MEcab Pc", "1");
-    }
+#[test]
+fn jump_errors() {
+    let init_error = |src: &str| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).err()
+    };
 
-    #[test]
-    fn greater_than() {
-        assert_lb_out!("Sa3 Sb2 MGcab Pc", "1");
-        assert_lb_out!("Sa3 Sb2 MGcba Pc", "0");
-    }
+    // jumps to undefined labels are caught before running
+    assert_eq!(init_error("P'a' Jx"), Some(LbError::UndefinedLabel('x')));
+    assert_eq!(init_error("Sa1 La[ Ia[ JIay ] ] :x"), Some(LbError::UndefinedLabel('y')));
+    assert_eq!(init_error(":x :x"), Some(LbError::DuplicateLabel('x')));
+    // labels inside blocks don't count
+    assert_eq!(init_error("[ :x ] Jx"), Some(LbError::UndefinedLabel('x')));
+    assert_eq!(init_error("Jx :x"), None);
 
-    #[test]
-    fn less_than() {
-        assert_lb_out!("Sa3 Sb2 MLcba Pc", "1");
-        assert_lb_out!("Sa3 Sb2 MLcab Pc", "0");
-    }
+    // infinite jump loops are stopped by the loop limit
+    assert_lb_err!(":x Jx", "J: jump count to label x exceeds loop limit");
+    assert_lb_err!("Sa'Jx' :x Xa", "J: Can't jump to label x from an executed program");
+}
 
-    #[test]
-    fn remainder() {
-        assert_lb_out!("Sa3 Sb2 MRcab Pc", "1");
-        assert_lb_out!("Sa10 Sb10 MRcab Pc", "0");
-        assert_lb_out!("Sa5 Sb10 MRcab Pc", "5");
-    }
+#[test]
+fn error_kinds() {
+    let run_error = |src: &str| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.run().expect_err("Program should fail")
+    };
+
+    assert_eq!(run_error("Sa'x' MAcab"), LbError::TypeMismatch { command: 'M', var: 'a', expected: "number" });
+    assert_eq!(run_error("Sb0 MQcab"), LbError::DivisionByZero { instruction: String::from("MQcab") });
+    // every kind of division fails on zero, even 0 / 0
+    assert_eq!(run_error("Sa1 Sb0 MDcab"), LbError::DivisionByZero { instruction: String::from("MDcab") });
+    assert_eq!(run_error("Sb0 MDcab"), LbError::DivisionByZero { instruction: String::from("MDcab") });
+    assert_eq!(run_error("Sa1 Sb-0 MRcab"), LbError::DivisionByZero { instruction: String::from("MRcab") });
+    assert_eq!(run_error("Sa1 Sb0 MOcab"), LbError::DivisionByZero { instruction: String::from("MOcab") });
+    assert_eq!(run_error("VNl Si3 VGxli"), LbError::IndexOutOfBounds { list: 'l', index: 3.0, len: 0 });
+    assert_eq!(run_error("GNa0"), LbError::InputMissing(0.0));
+    assert_eq!(run_error("K-a"), LbError::EmptyStack('a'));
+    assert_eq!(run_error("Sa2000 LaPa"), LbError::LoopLimit('L'));
+    assert_eq!(run_error("Sa1 ~~"), LbError::LexError { span: 4..5 });
+    assert_eq!(run_error("Sz'Pa ~' Xz"), LbError::ExecuteLexError(String::from("~")));
+
+    // errors display as messages naming the failing command
+    assert_eq!(run_error("Sb0 MQcab").to_string(), "M: Division by zero in MQcab");
+    assert_eq!(run_error("Sa1 ~~").to_string(), "Unrecognized instruction at 4..5");
 }
 
-#[cfg(test)]
-mod bool_ops {
-    use crate::storage::*;
-    use crate::program::*;
-    use crate::lb_lexer::LbToken;
-    use logos::Logos;
+#[test]
+fn error_positions() {
+    let run_frames = |src: &str| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.run().expect_err("Program should fail");
+        program.error_frames().to_vec()
+    };
+    let frame = |span: std::ops::Range<usize>, line, column, program_var, slice: &str| {
+        ErrorFrame { span, line, column, program_var, slice: String::from(slice) }
+    };
 
-    #[test]
-    fn equal() {
-        assert_lb_out!("Sa1 Sb'x' BEcab Pc",  "1"); // t t
-        assert_lb_out!("Sa0 Sb'' BEcab Pc",   "0"); // f t
-        assert_lb_out!("Sa'cz' Sb0 BEcab Pc", "0"); // t f
-        assert_lb_out!("Sa0 Sb0.0 BEcab Pc",  "1"); // f f
-    }
+    // an error on line 3
+    assert_eq!(run_frames("Sa1\nSb0\nPa MQcab Pa"), vec![frame(11..16, 3, 4, None, "MQcab")]);
+    // inside a loop body and a block
+    assert_eq!(run_frames("Sa2 Sb0\nLa[ Pa\n  MQcab ]"), vec![frame(17..22, 3, 3, None, "MQcab")]);
+    assert_eq!(run_frames("Sa1 Sb0\nIbPa:MQcab"), vec![frame(13..18, 2, 6, None, "MQcab")]);
+    // inside an executed program, then at the Execute command
+    assert_eq!(run_frames("Sb0\nSz'Pb\nMQcab'\n Xz"), vec![
+        frame(3..8, 2, 1, Some('z'), "MQcab"),
+        frame(18..20, 4, 2, None, "Xz"),
+    ]);
+    assert_eq!(run_frames("Sz'Pa ~' Xz"), vec![frame(3..4, 1, 4, Some('z'), "~"), frame(9..11, 1, 10, None, "Xz")]);
+    assert_eq!(run_frames("X'K-a'"), vec![frame(0..3, 1, 1, None, "K-a"), frame(0..6, 1, 1, None, "X'K-a'")]);
 
-    #[test]
-    fn and() {
-        assert_lb_out!("Sa1 Sb'x' BAcab Pc",  "1"); // t t
-        assert_lb_out!("Sa0 Sb'' BAcab Pc",   "0"); // f t
-        assert_lb_out!("Sa'cz' Sb0 BAcab Pc", "0"); // t f
-        assert_lb_out!("Sa0 Sb0.0 BAcab Pc",  "0"); // f f
-    }
+    // the position is also set when stepping into a loop
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa2 Sb0\nLa[ Pa MQcab ]");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.step_into = true;
+    program.run().expect_err("Program should fail");
+    assert_eq!(program.error_frames(), &[frame(15..20, 2, 8, None, "MQcab")]);
+}
 
-    #[test]
-    fn or() {
-        assert_lb_out!("Sa1 Sb'x' BOcab Pc",  "1"); // t t
-        assert_lb_out!("Sa0 Sb'' BOcab Pc",   "1"); // f t
-        assert_lb_out!("Sa'cz' Sb0 BOcab Pc", "1"); // t f
-        assert_lb_out!("Sa0 Sb0.0 BOcab Pc",  "0"); // f f
-    }
+#[test]
+fn describe_errors() {
+    let describe = |src: &str| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        let error = program.run().expect_err("Program should fail");
+        program.describe_error(&error)
+    };
 
-    #[test]
-    fn xor() {
-        assert_lb_out!("Sa1 Sb'x' BXcab Pc",  "0"); // t t
-        assert_lb_out!("Sa0 Sb'' BXcab Pc",   "1"); // f t
-        assert_lb_out!("Sa'cz' Sb0 BXcab Pc", "1"); // t f
-        assert_lb_out!("Sa0 Sb0.0 BXcab Pc",  "0"); // f f
+    assert_eq!(describe("Sb0\n  MQcab"), "error at line 2, column 3 (`MQcab`): M: Division by zero in MQcab");
+    // a broken program two Executes deep names each program it was run from
+    assert_eq!(
+        describe("Sa1 Sb0 Sy'Pa MQcab' Sz'Pa\nXy Pb'\nPa Xz"),
+        "error in program executed from variable y, offset 3 (`MQcab`), \
+        run from program executed from variable z, offset 3 (`Xy`), \
+        run from line 3, column 4 (`Xz`): M: Division by zero in MQcab");
+    assert_eq!(
+        describe("Sy'Pa ~' X'Xy'"),
+        "error in program executed from variable y, offset 3 (`~`), \
+        run from program executed from a string, offset 0 (`Xy`), \
+        run from line 1, column 10 (`X'Xy'`): X: Could not parse ~ in executed program");
+    // long instructions are cut short
+    assert_eq!(
+        describe("Sz'Pa L5000[ Pa Pa Pa Pa\n]' Xz"),
+        "error in program executed from variable z, offset 3 (`L5000[ Pa Pa Pa…`), \
+        run from line 2, column 4 (`Xz`): L: loop count exceeds loop limit");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let program = LbProgram::new(LbToken::lexer("Pa"), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    assert_eq!(program.describe_error(&LbError::Cancelled), "Program was cancelled");
+}
+
+#[test]
+fn stack() {
+    assert_lb_out!("Sa1 Sb'two' K+a K+b K#d Pd K-c Pc K-c Pc K#d Pd", "2two10");
+    // reverse a string
+    assert_lb_out!(
+        "Ss'hello' Se'' Si0 So1 TCcsi BSdce Nd Wd[ K+c MAiio TCcsi BSdce Nd ] Sr'' K#n Wn[ K-c Arc K#n ] Pr",
+        "olleh");
+    assert_lb_out!("Sa1 K+a R* K#d Pd", "0");
+    assert_lb_err!("Sa1 K+a K-b K-b", "K: Can't pop into b from an empty stack");
+
+    // the stack persists between programs sharing storage
+    let mut data = LbStorage::new();
+    let inv = Vec::<String>::new();
+    let mut out = String::new();
+    let lex = LbToken::lexer("Sa'saved' K+a");
+    LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed").run().expect("Program failed");
+    let lex = LbToken::lexer("K-b Pb");
+    LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed").run().expect("Program failed");
+    assert_eq!(out, "saved");
+}
+
+#[test]
+fn sleep() {
+    use crate::clock::LbClock;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Records how long the program asked to sleep, without sleeping.
+    struct FakeClock(Rc<RefCell<Vec<Duration>>>);
+    impl LbClock for FakeClock {
+        fn sleep(&mut self, duration: Duration) {
+            self.0.borrow_mut().push(duration);
+        }
+
+        fn elapsed(&mut self) -> Duration {
+            Duration::ZERO
+        }
     }
-}
\ No newline at end of file
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa1500 Qa Sa-5 Qa Ss'NaN' TNasf Qa Sa0.5 Qa P'done'");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    let sleeps = Rc::new(RefCell::new(Vec::new()));
+    program.set_clock(FakeClock(sleeps.clone()));
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(out, "done");
+    assert_eq!(*sleeps.borrow(), vec![
+        Duration::from_millis(1500),
+        Duration::ZERO,
+        Duration::ZERO,
+        Duration::from_micros(500),
+    ]);
+
+    // the default clock really sleeps
+    assert_lb_out!("Sa1 Qa P'done'", "done");
+    assert_lb_err!("Sa'x' Qa", "Q: Variable a is not a number");
+}
+
+#[test]
+fn elapsed() {
+    use crate::clock::LbClock;
+    use std::time::Duration;
+
+    /// A clock where time only passes while sleeping.
+    struct FakeClock(Duration);
+    impl LbClock for FakeClock {
+        fn sleep(&mut self, duration: Duration) {
+            self.0 += duration;
+        }
+
+        fn elapsed(&mut self) -> Duration {
+            self.0
+        }
+    }
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Ea Pa P' ' Sd250 Qd Qd Eb Pb P' ' Sd0.5 Qd Ec Pc");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.set_clock(FakeClock(Duration::ZERO));
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(out, "0 500 500.5");
+
+    // reading the real clock twice never goes backwards
+    assert_lb_out!("Ea Eb MWcab Pc", "true");
+    // timing a loop gives a positive duration
+    assert_lb_out!("Sn1 Sm3 Ea Lm[ Qn ] Eb MSdba MGpdz Pp", "true");
+}
+
+#[test]
+fn random() {
+    // random numbers are in [0, 1)
+    assert_lb_out!("Sa100 So0 Sl1 La[ Dr MLcro MLdrl Nd BOecd Ie[ P'bad' ] ]", "");
+
+    let run_seeded = |seed: u64, data: &mut LbStorage| {
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer("Da Db Sx'Dc' Xx Pa P' ' Pb P' ' Pc");
+        let mut program = LbProgram::new(lex, data, &inv, &mut out, 1000).expect("Program init failed");
+        program.set_seed(seed);
+        program.run().expect("Program failed");
+        out
+    };
+
+    // the same seed gives the same numbers, even for programs sharing storage
+    let mut data = LbStorage::new();
+    let first = run_seeded(42, &mut data);
+    let second = run_seeded(42, &mut data);
+    assert_eq!(first, second);
+    assert_ne!(first, run_seeded(43, &mut data));
+}
+
+#[test]
+fn output_to_writer() {
+    use std::io;
+
+    let mut data = LbStorage::new();
+    let mut bytes: Vec<u8> = Vec::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa4 Pa O'!' P'ü'");
+    let mut program = LbProgram::with_output(lex, &mut data, &inv, &mut bytes, 1000).expect("Program init failed");
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(bytes, "4!\nü".as_bytes());
+
+    /// A writer that is always out of space.
+    struct FullWriter;
+
+    impl io::Write for FullWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let lex = LbToken::lexer("Sa4 Oa Sa5");
+    let mut program = LbProgram::with_output(lex, &mut data, &inv, FullWriter, 1000).expect("Program init failed");
+    let error = LbError::OutputFailed { command: 'O', message: String::from("disk full") };
+    assert_eq!(program.run(), Err(error.clone()));
+    assert_eq!(error.to_string(), "O: Could not write output: disk full");
+    // the program stopped at the failed write
+    assert_eq!(data.get_var('a').map(|a| a.format(false)), Some(String::from("4")));
+}
+
+#[test]
+fn instruction_limit() {
+    let run_limited = |src: &str, max: u64| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer(src);
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, usize::MAX).expect("Program init failed");
+        program.set_max_instructions(max);
+        let result = program.run();
+        (result, out)
+    };
+
+    // Sa1 and Wa, then exactly 8 runs of the body
+    let error = Err(LbError::InstructionLimit(10));
+    assert_eq!(run_limited("Sa1 WaPa", 10), (error, String::from("11111111")));
+    // each run of a counted loop's body counts too
+    let error = Err(LbError::InstructionLimit(4));
+    assert_eq!(run_limited("L9Pa", 4), (error, String::from("000")));
+    // a block and each instruction inside it count
+    let error = Err(LbError::InstructionLimit(7));
+    assert_eq!(run_limited("Sa1 Wa[ Pa P'.' ]", 7), (error, String::from("1.1")));
+    // so do instructions in executed programs
+    let error = Err(LbError::InstructionLimit(3));
+    assert_eq!(run_limited("Sz'Pa Pa' Xz", 3), (error, String::from("0")));
+    assert_eq!(run_limited("Sz'Pa Pa' Xz", 4), (Ok(()), String::from("00")));
+    // 0 means no limit
+    assert_eq!(run_limited("Sa3 LaPa", 0), (Ok(()), String::from("333")));
+}
+
+#[test]
+fn loop_iteration_limit() {
+    let run_limited = |src: &str, max: u64| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer(src);
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, usize::MAX).expect("Program init failed");
+        program.set_max_loop_iterations(max);
+        let result = program.run();
+        (result, out)
+    };
+
+    // a runaway loop stops after its body runs the most times allowed
+    let (result, out) = run_limited("Sa1\nSb0\n\n  Wa[ MAbbaPb ]", 5);
+    let error = LbError::LoopIterationLimit { command: 'W', max: 5, span: 11..24, line: 4, column: 3 };
+    assert_eq!(result, Err(error.clone()));
+    assert_eq!(out, "12345");
+    assert_eq!(error.to_string(), "W: Loop at line 4, column 3 exceeded 5 iterations");
+    // the limit is per loop, so loops that each stay under it are fine
+    assert_eq!(run_limited("Sa5 LaPa LaPa Sb5 Wb[ Nb ]", 5), (Ok(()), String::from("5555555555")));
+    // a counted loop that would run too many times doesn't start
+    let error = Err(LbError::LoopIterationLimit { command: 'L', max: 5, span: 15..19, line: 1, column: 16 });
+    assert_eq!(run_limited("Sa3 LaPa Sa1e9 LaPa", 5), (error, String::from("333")));
+    // loops in executed programs are located within them
+    let (result, _) = run_limited("Sa1 Sz'\nWaPa' Xz", 5);
+    assert!(matches!(result, Err(LbError::LoopIterationLimit { line: 2, column: 1, .. })));
+    // so does one with a literal count
+    let error = Err(LbError::LoopIterationLimit { command: 'L', max: 5, span: 0..4, line: 1, column: 1 });
+    assert_eq!(run_limited("L6Pa", 5), (error, String::new()));
+    // 0 means no limit
+    assert_eq!(run_limited("Sa2000 LaRb", 0), (Ok(()), String::new()));
+}
+
+#[test]
+fn cancellation() {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        let handle = scope.spawn(move || {
+            let mut data = LbStorage::new();
+            let mut out = String::new();
+            let inv = Vec::<String>::new();
+            // loops forever, with the cancellation checked inside an executed program
+            let lex = LbToken::lexer("Sa1 Sz'Sb1' Wa[ Xz ]");
+            let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, usize::MAX).expect("Program init failed");
+            sender.send(program.cancel_token()).expect("Could not send token");
+            program.run()
+        });
+
+        let token = receiver.recv().expect("Could not receive token");
+        thread::sleep(Duration::from_millis(50));
+        let cancelled_at = Instant::now();
+        token.store(true, Ordering::Relaxed);
+        assert_eq!(handle.join().expect("Program thread panicked"), Err(LbError::Cancelled));
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn cancelling_sleep() {
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        let handle = scope.spawn(move || {
+            let mut data = LbStorage::new();
+            let mut out = String::new();
+            let inv = Vec::<String>::new();
+            // sleeps for about 11 days
+            let lex = LbToken::lexer("SA1000000000 QA P'woke'");
+            let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, usize::MAX).expect("Program init failed");
+            sender.send(program.cancel_token()).expect("Could not send token");
+            let result = program.run();
+            drop(program);
+            (result, out)
+        });
+
+        let token = receiver.recv().expect("Could not receive token");
+        thread::sleep(Duration::from_millis(50));
+        let cancelled_at = Instant::now();
+        token.store(true, Ordering::Relaxed);
+        assert_eq!(handle.join().expect("Program thread panicked"), (Err(LbError::Cancelled), String::new()));
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+    });
+}
+
+#[test]
+fn storage_observer() {
+    use std::sync::{Arc, Mutex};
+
+    let mut data = LbStorage::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Arc::clone(&log);
+    data.set_observer(move |var, new, previous| {
+        let previous = previous.map_or(String::from("none"), |val| val.format(false));
+        recorder.lock().unwrap().push(format!("{var}={} was {previous}", new.format(false)));
+    });
+
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa1 Sb'x' Cab MAaab Ra Sz'SA1' Xz R* Sc5");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.run().expect("Program failed");
+    assert_eq!(*log.lock().unwrap(), vec![
+        "a=1 was none",
+        "b=x was none",
+        "b=1 was x",
+        "a=2 was 1",
+        "a=0 was 2",
+        "z=SA1 was none",
+        "A=1 was none",
+        // R* reports each variable that held a value
+        "A=0 was 1",
+        "b=0 was 1",
+        "z=0 was SA1",
+        "c=5 was none",
+    ].into_iter().map(String::from).collect::<Vec<String>>());
+}
+
+#[test]
+fn shared_storage() {
+    use std::thread;
+
+    let shared = SharedStorage::new(LbStorage::new());
+    let run_on_thread = |src: &'static str| {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let mut out = String::new();
+            let inv = Vec::<String>::new();
+            let mut program = LbProgram::new(LbToken::lexer(src), shared, &inv, &mut out, 1_000_000).expect("Program init failed");
+            program.run().expect("Program failed");
+            out
+        })
+    };
+
+    // one program waits for another to count past a threshold
+    let waiter = run_on_thread("St100 MLdnt Wd[ MLdnt ] MGdnt Pd");
+    let counter = run_on_thread("Sb1 Sc1000 Lc[ MAnnb ]");
+    assert_eq!(counter.join().expect("Counter panicked"), "");
+    assert_eq!(waiter.join().expect("Waiter panicked"), "true");
+    // each increment is a whole instruction, so none are lost
+    assert_eq!(shared.with(|data| data.get_var('n').map(|val| val.format(false))), Some(String::from("1000")));
+
+    // a program on this thread sees what the others left
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut program = LbProgram::new(LbToken::lexer("Pn Sn0"), shared.clone(), &inv, &mut out, 1000).expect("Program init failed");
+    program.run().expect("Program failed");
+    drop(program);
+    assert_eq!(out, "1000");
+    assert_eq!(shared.with(|data| data.get_var('n').map(|val| val.format(false))), Some(String::from("0")));
+}
+
+#[test]
+fn print_callback() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let src = "Sa1 Pa P'-' Oa O'two' Sz'Pb Ob' Sb'x' Xz La[ X'P\\'y\\'' ]";
+    let run_printing = |write_output: bool| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        let prints = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&prints);
+        program.set_print_callback(move |text| recorder.borrow_mut().push(text.to_string()));
+        program.write_output = write_output;
+        program.run().expect("Program failed");
+        (out, prints.take())
+    };
+
+    // each print is passed on in order, including those in executed programs
+    let (out, prints) = run_printing(true);
+    assert_eq!(prints, vec!["1", "-", "1\n", "two\n", "x", "x\n", "y"]);
+    assert_eq!(prints.concat(), out);
+    // the output can be left out
+    let (out, prints) = run_printing(false);
+    assert_eq!(out, "");
+    assert_eq!(prints.concat(), "1-1\ntwo\nxx\ny");
+}
+
+#[test]
+fn run_stats() {
+    let src = "Sa2 La[ Pa ] Sy'Ob' Sz'Pa Xy' Xz Wb[ ]";
+    let run_measuring = |measure_time: bool| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.measure_time = measure_time;
+        program.run().expect("Program failed");
+        program.stats().clone()
+    };
+
+    // the loop runs its block and Pa twice, and the executed programs run Pa, Xy, and Ob
+    let stats = run_measuring(false);
+    assert_eq!(stats.instructions, 13);
+    assert_eq!(stats.loop_iterations, 2);
+    assert_eq!(stats.max_execute_depth, 2);
+    assert_eq!(stats.prints, 4);
+    assert_eq!(stats.output_bytes, 5);
+    assert_eq!(stats.wall_time, None);
+    // timing is opt-in, and doesn't change the counts
+    let timed = run_measuring(true);
+    assert!(timed.wall_time.is_some());
+    assert_eq!(RunStats { wall_time: None, ..timed }, stats);
+
+    // counting stops where the program fails
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut program = LbProgram::new(LbToken::lexer("Pa Sa3 Wa[ Pa ]"), &mut data, &inv, &mut out, 2).expect("Program init failed");
+    assert!(program.run().is_err());
+    assert_eq!(program.stats().instructions, 7);
+    assert_eq!(program.stats().loop_iterations, 2);
+    assert_eq!(program.stats().prints, 3);
+}
+
+#[test]
+fn yielding() {
+    let run_with_yields = |src: &str, interval: u64, step_into: bool| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = vec![String::from("4")];
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.set_yield_interval(interval);
+        program.step_into = step_into;
+        let mut yields = 0;
+        loop {
+            match program.run_until_yield() {
+                Ok(YieldResult::Yielded) => yields += 1,
+                Ok(YieldResult::Finished) => break,
+                other => panic!("Unexpected result {other:?}"),
+            }
+        }
+        let instructions = program.stats().instructions;
+        drop(program);
+        (out, yields, instructions)
+    };
+
+    let programs = [
+        "GNa0 Sb1 La[ Pa MSaab Ia[ P',' ]:[ P'!' ] ]",
+        "Sz'Sn3 Ln[ On MSnnm ] Ym' Sm1 Xz>r Pr Sc0 Wc[ P'x' Uc[ Sc1 ] ]",
+        "Sy'Pa Sa0' Sz'Sa7 Ln[ Xy ]' Sn2 Xz P'.' Ja P'skipped' :a P'end'",
+        "Sa1 [ [ Pa Pa ] X'Pa' ] Sb3 Lb[ Ib[ [ Pb ] ] ] Ls[ Ps ]",
+    ];
+    for src in programs {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = vec![String::from("4")];
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.run().expect("Program failed");
+        let instructions = program.stats().instructions;
+        drop(program);
+
+        // any interval gives the same output, and every instruction runs exactly once
+        for interval in [1, 2, 3, 5, 0] {
+            let (yielded_out, yields, yielded_instructions) = run_with_yields(src, interval, false);
+            assert_eq!(yielded_out, out, "{src} with interval {interval}");
+            assert_eq!(yielded_instructions, instructions, "{src} with interval {interval}");
+            if let Some(expected) = (instructions - 1).checked_div(interval) {
+                assert_eq!(yields, expected, "{src} with interval {interval}");
+            }
+        }
+        // including when stepping into loops
+        assert_eq!(run_with_yields(src, 2, true).0, out, "{src} stepping into loops");
+    }
+
+    // a single instruction yields partway through its loop and executed program
+    let (out, yields, _) = run_with_yields("X'Sn5 Ln[ Pn ]'", 2, false);
+    assert_eq!(out, "55555");
+    assert_eq!(yields, 6);
+}
+
+#[test]
+fn program_builder() {
+    let builder = LbProgramBuilder::new()
+        .save('a', 4.0)
+        .save('o', 1.0)
+        .save_str('q', "it's")
+        .print_var('q')
+        .while_loop('a', |b| b
+            .print_var('a')
+            .math('S', 'a', 'a', 'o')
+            .if_else('a', |b| b.print_str(","), |b| b.print_line_str("!")))
+        .execute_str("Pa Yb", &[('a', 'q')], Some('r'))
+        .text('U', "rr")
+        .print_var('r')
+        .block(|b| b.jump('x').print_str("skipped"))
+        .label('x')
+        .repeat_times(2, |b| b.print_str("."));
+    let source = builder.to_source().expect("Program should build");
+    assert_eq!(source, "Sa4 So1 Sq'it\\'s' Pq Wa[ Pa MSaao Ia[ P',' ]:[ O'!' ] ] X'Pa Yb'aq>r TUrr Pr [ Jx P'skipped' ] :x L2[ P'.' ]");
+
+    // the built program lexes from its source into the same commands
+    let ast = builder.build().expect("Program should build");
+    assert_eq!(ast.source, source);
+    let lexed = LbAst::lex(&source);
+    assert_eq!(ast, lexed);
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    ast.run_with(&mut data, &inv, StringOutput::new(&mut out), 1000).expect("Program failed");
+    assert_eq!(out, "it's4,3,2,1!\nit's0..");
+
+    // the first invalid argument is reported when building
+    let invalid = |builder: LbProgramBuilder| builder.build().map(|_| ()).map_err(|e| e.to_string());
+    assert_eq!(invalid(LbProgramBuilder::new().print_var('1').print_var('!')), Err(String::from("P: 1 is not a variable name")));
+    assert_eq!(invalid(LbProgramBuilder::new().math('F', 'a', 'b', 'c')), Err(String::from("M: Invalid op F")));
+    assert_eq!(invalid(LbProgramBuilder::new().unary_math('F', 'a', 'b')), Ok(()));
+    assert_eq!(invalid(LbProgramBuilder::new().repeat('a', |b| b.bool_op('Z', 'a', 'b', 'c'))), Err(String::from("B: Invalid op Z")));
+    assert_eq!(invalid(LbProgramBuilder::new().text('S', "dsi")), Err(String::from("T: Op S takes 4 variables, but was given 3")));
+    assert_eq!(invalid(LbProgramBuilder::new().list('Q', "l")), Err(String::from("V: Invalid op Q")));
+    assert_eq!(invalid(LbProgramBuilder::new().save('a', f64::NAN)), Err(String::from("S: NaN is not a finite number")));
+    assert_eq!(invalid(LbProgramBuilder::new().get_input('X', 'a', 0)), Err(String::from("G: Invalid op X")));
+    assert_eq!(invalid(LbProgramBuilder::new().label('A')), Err(String::from(":: A is not a variable name")));
+    assert_eq!(invalid(LbProgramBuilder::new().execute('z', &[('a', ' ')], None)), Err(String::from("X:   is not a variable name")));
+    assert_eq!(invalid(LbProgramBuilder::new().reset('A')), Ok(()));
+}
+
+#[test]
+fn tracing() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let fibonacci = "Sn5 Sa0 Sb1\nLn[ Pa P' ' MAcab Cba Ccb ]";
+    let run_traced = |max: u64| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer(fibonacci);
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&trace);
+        program.set_trace_callback(move |entry| recorder.borrow_mut().push(entry));
+        program.set_max_instructions(max);
+        let result = program.run();
+        (result, out, trace.take())
+    };
+    let show = |vals: &Vec<(char, Val)>| vals.iter()
+        .map(|(var, val)| format!("{var}={}", val.format(false)))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    // 3 stores, the loop, and 5 runs of a block holding 5 instructions
+    let (result, out, trace) = run_traced(0);
+    assert_eq!(result, Ok(()));
+    assert_eq!(out, "0 1 1 2 3 ");
+    assert_eq!(trace.len(), 34);
+    // the trace has an entry for each instruction the limit counts
+    assert!(run_traced(33).0.is_err());
+    assert_eq!(run_traced(34).0, Ok(()));
+
+    assert_eq!(trace[0].token, LbToken::SaveNumber(('n', 5.0)));
+    assert_eq!(trace[0].position, (1, 1));
+    assert_eq!(show(&trace[0].before), "n=0");
+    assert_eq!(show(&trace[0].after), "n=5");
+
+    // the first addition, inside the first run of the block
+    assert_eq!(trace[5].token, LbToken::MathOp(('A', 'c', 'a', 'b')));
+    assert_eq!(trace[5].position, (2, 1));
+    assert_eq!(show(&trace[5].before), "c=0 a=0 b=1");
+    assert_eq!(show(&trace[5].after), "c=1 a=0 b=1");
+
+    // the loop itself finishes last
+    assert!(matches!(trace[33].token, LbToken::Loop(('n', _))));
+    assert_eq!(show(&trace[33].after), "n=5 a=5 c=8 b=8");
+}
+
+#[test]
+fn breakpoints() {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// Collects output that can be read while the program is still running.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // pause before the third instruction and look at a variable
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa1 Sb2\n MAcab Pc");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let recorder = Rc::clone(&seen);
+    program.set_breakpoint_callback(move |token, data, position| {
+        let mut seen = recorder.borrow_mut();
+        seen.push(token.clone());
+        if seen.len() == 3 {
+            assert_eq!(position, (2, 2));
+            assert_eq!(data.peek_var('b').map(|b| b.format(false)), Some(String::from("2")));
+            return BreakAction::Pause;
+        }
+        BreakAction::Continue
+    });
+    assert_eq!(program.run(), Ok(()));
+    assert!(!program.finished);
+    // resuming doesn't stop at the same breakpoint again
+    assert_eq!(program.step(), StepResult::Ran(LbToken::MathOp(('A', 'c', 'a', 'b'))));
+    assert_eq!(program.run(), Ok(()));
+    assert!(program.finished);
+    assert_eq!(seen.borrow().len(), 4);
+    assert_eq!(out, "3");
+
+    let run_with = |src: &str, step_into: bool, on_token: fn(&LbToken) -> BreakAction| {
+        let mut data = LbStorage::new();
+        let output = SharedOutput::default();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer(src);
+        let mut program = LbProgram::with_output(lex, &mut data, &inv, output.clone(), 1000).expect("Program init failed");
+        program.step_into = step_into;
+        program.set_breakpoint_callback(move |token, _, _| on_token(token));
+        // the output so far each time the program stops
+        let mut stops = Vec::new();
+        loop {
+            let result = program.run();
+            stops.push(String::from_utf8(output.0.borrow().clone()).expect("Output is not UTF-8"));
+            if result.is_err() || program.finished {
+                return (result, stops);
+            }
+        }
+    };
+    let pause_at_print = |token: &LbToken| match token {
+        LbToken::PrintVar(_) => BreakAction::Pause,
+        _ => BreakAction::Continue,
+    };
+    let abort_at_print = |token: &LbToken| match token {
+        LbToken::PrintVar(_) => BreakAction::Abort,
+        _ => BreakAction::Continue,
+    };
+
+    // a pause inside a loop waits for the loop to finish
+    let stops = vec![String::from("333"), String::from("333x"), String::from("333x3")];
+    assert_eq!(run_with("Sa3 La[ Pa ] P'x' Pa", false, pause_at_print), (Ok(()), stops));
+    // unless the loop is being stepped into
+    let stops = vec![String::from(""), String::from("3"), String::from("33"), String::from("333x"), String::from("333x3")];
+    assert_eq!(run_with("Sa3 LaPa P'x' Pa", true, pause_at_print), (Ok(()), stops));
+    // breakpoints work in executed programs
+    let stops = vec![String::from("a0"), String::from("a0b")];
+    assert_eq!(run_with("Sz'Pa' P'a' Xz P'b'", false, pause_at_print), (Ok(()), stops));
+    let error = Err(LbError::Aborted);
+    assert_eq!(run_with("Sz'Pa' P'a' Xz P'b'", false, abort_at_print), (error, vec![String::from("a")]));
+}
+
+#[test]
+fn stepping() {
+    // returns the output and the number of steps taken
+    let step_through = |src: &str, step_into: bool| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer(src);
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.step_into = step_into;
+        let mut steps = 0;
+        loop {
+            match program.step() {
+                StepResult::Ran(_) => steps += 1,
+                StepResult::Finished => break,
+                StepResult::Paused => panic!("Program paused without a breakpoint"),
+                StepResult::Error(msg) => panic!("Program failed: {}", msg),
+            }
+        }
+        (out, steps)
+    };
+
+    let src = "Sa3 Sb1 La[ P'x' ] Wa[ Pa MSaab ] Sc0 LcPc Jy :x P'!' :y Pa";
+    assert_lb_out!(src, "xxx3210");
+    assert_eq!(step_through(src, false), (String::from("xxx3210"), 9));
+    // each execution of a loop body is a step, and a loop that doesn't run is one step
+    assert_eq!(step_through(src, true), (String::from("xxx3210"), 13));
+}
+
+#[test]
+fn step_results() {
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa2 LaPa Za");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.step_into = true;
+    assert_eq!(program.step(), StepResult::Ran(LbToken::SaveNumber(('a', 2.0))));
+    assert_eq!(program.step(), StepResult::Ran(LbToken::PrintVar('a')));
+    assert_eq!(program.step(), StepResult::Ran(LbToken::PrintVar('a')));
+    assert_eq!(program.step(), StepResult::Ran(LbToken::Assert('a')));
+    assert_eq!(program.step(), StepResult::Finished);
+    assert_eq!(program.result, Ok(()));
+
+    // an error finishes the program
+    let lex = LbToken::lexer("Sa0 Za P'x'");
+    let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.step();
+    let error = LbError::AssertionFailed { position: Some((1, 5)), message: String::from("a is 0") };
+    assert_eq!(program.step(), StepResult::Error(error.clone()));
+    assert_eq!(program.step(), StepResult::Finished);
+    assert_eq!(program.result, Err(error));
+    assert_eq!(out, "22");
+}
+
+#[test]
+fn bool_compatibility() {
+    // fibonacci, looping while a comparison holds
+    assert_lb_out!("Sa0 Sb1 Sm50 MLdam Wd[ Pa P' ' MAcab Cba Ccb MLdam ]", "0 1 1 2 3 5 8 13 21 34 ");
+    // counting the even fibonacci numbers by adding up comparisons
+    assert_lb_out!("Sn10 Sa0 Sb1 Se0 St2 Sz0 Ln[ MOrat MErrz MAeer MAcab Cba Ccb ] Pe", "4");
+    // the first fibonacci number over 100, with a flag that stops the loop
+    assert_lb_out!("Sa0 Sb1 Sm100 Sd1 Wd[ MAcab Cba Ccb MWdam ] Pa P' ' Pd", "144 false");
+    // scaling, shifting, and branching on comparisons
+    assert_lb_out!("Sa8 Sb1 MGcab MMdca Pd", "8");
+    assert_lb_out!("Sa1 Sb1 BEcab Sd4 B<ecd Pe", "16");
+    assert_lb_out!("Sa1 Sb2 MLcab IcP'less' UcP'more' BNdc IdP'more'", "less");
+    // a bool equals its number in M, but isn't the same value
+    assert_lb_out!("Sa1 Sb1 MEcab MEdca Pd BSdca Pd", "truefalse");
+    assert_lb_out!("Sa1 Sb2 MGcab Pc P' ' MAcca Pc", "false 1");
+
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let lex = LbToken::lexer("Sa3 Sb2 MGcab BNdc");
+    LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed").run().expect("Program failed");
+    assert_eq!(data.get_var('c'), Some(&Val::Bool(true)));
+    assert_eq!(data.get_number('d'), Ok(0.0));
+    assert_eq!(data.var_as_bool('d'), Some(false));
+    assert_eq!(data.get_var('c').and_then(Val::as_bool), Some(true));
+    assert_eq!(data.get_text('c'), Err(LbError::VarTypeMismatch { var: 'c', expected: "string", found: "bool" }));
+    assert_eq!(Val::Bool(true) + Val::Number(2.0), Some(Val::Number(3.0)));
+    // comparing values counts bools as 1 and 0, as M does
+    assert_lb_out!("Sa1 Sb0 BOcab Sd0 MGecd Pe", "true");
+    assert!(Val::Bool(true) > Val::Number(0.0));
+    assert!(Val::Bool(false) < Val::Bool(true));
+    assert_eq!(Val::Bool(true).partial_cmp(&Val::Number(1.0)), Some(std::cmp::Ordering::Equal));
+    assert!(Val::Bool(true) != Val::Number(1.0));
+}
+
+#[test]
+fn val_conversions() {
+    assert_eq!(Val::from(2.5), Val::Number(2.5));
+    assert_eq!(Val::from(-3i64), Val::Number(-3.0));
+    assert_eq!(Val::from("hi"), Val::Text(String::from("hi")));
+    assert_eq!(Val::from(String::from("hi")), Val::Text(String::from("hi")));
+    assert_eq!(Val::from(true), Val::Bool(true));
+    assert_eq!(Val::from(false), Val::Bool(false));
+
+    assert_eq!(f64::try_from(Val::Number(2.5)), Ok(2.5));
+    assert_eq!(String::try_from(Val::from("hi")), Ok(String::from("hi")));
+    let not_number = f64::try_from(Val::from("2.5"));
+    assert_eq!(not_number, Err(LbError::ValueTypeMismatch { expected: "number", found: "string" }));
+    assert_eq!(not_number.unwrap_err().to_string(), "Value is a string, not a number");
+    let not_text = String::try_from(Val::List(Vec::new()));
+    assert_eq!(not_text, Err(LbError::ValueTypeMismatch { expected: "string", found: "list" }));
+
+    let mut data = LbStorage::new();
+    data.set('a', 4).unwrap();
+    data.set('b', "four").unwrap();
+    assert_eq!(data.get_number('a'), Ok(4.0));
+    assert_eq!(data.get_text('b'), Ok("four"));
+}
+
+#[test]
+fn typed_storage_getters() {
+    let mut data = LbStorage::new();
+    data.set('a', 4).unwrap();
+    data.set('b', " 2.5 ").unwrap();
+    data.set('c', "four").unwrap();
+    data.set('l', Val::List(Vec::new())).unwrap();
+
+    assert_eq!(data.get_number('a'), Ok(4.0));
+    assert_eq!(data.get_text('c'), Ok("four"));
+    // text isn't read as a number unless asked for
+    let error = LbError::VarTypeMismatch { var: 'b', expected: "number", found: "string" };
+    assert_eq!(data.get_number('b'), Err(error.clone()));
+    assert_eq!(error.to_string(), "Variable b holds a string, not a number");
+    assert_eq!(data.get_number_lossy('b'), Ok(2.5));
+    assert_eq!(data.get_number_lossy('a'), Ok(4.0));
+    assert_eq!(data.get_number_lossy('c'), Err(LbError::VarTypeMismatch { var: 'c', expected: "number", found: "string" }));
+    assert_eq!(data.get_number_lossy('l'), Err(LbError::VarTypeMismatch { var: 'l', expected: "number", found: "list" }));
+    assert_eq!(data.get_text('a'), Err(LbError::VarTypeMismatch { var: 'a', expected: "string", found: "number" }));
+
+    // unset variables hold 0, and reading them doesn't store it
+    assert_eq!(data.get_number('z'), Ok(0.0));
+    assert_eq!(data.get_number_lossy('z'), Ok(0.0));
+    assert_eq!(data.get_text('z'), Err(LbError::VarTypeMismatch { var: 'z', expected: "string", found: "number" }));
+    assert!(!data.contains_var('z'));
+    assert_eq!(data.len(), 4);
+    data.set_default_policy(DefaultPolicy::Error);
+    assert_eq!(data.get_number('z'), Err(LbError::UnsetVariable { var: 'z', position: None }));
+    assert_eq!(data.get_text('z'), Err(LbError::UnsetVariable { var: 'z', position: None }));
+
+    assert_eq!(data.get_number('!'), Err(LbError::NotAVariable('!')));
+    assert_eq!(LbError::NotAVariable('!').to_string(), "! is not a variable name");
+}
+
+#[cfg(test)]
+mod math_ops {
+    use crate::storage::*;
+    use crate::program::*;
+    use crate::lb_lexer::LbToken;
+    use logos::Logos;
+
+    #[test]
+    fn add() {
+        assert_lb_out!("Sa3 Sb2 MAcab Pc", "5");
+        assert_lb_out!("Sa3.5 Sb1 MAcab Pc", "4.5");
+    }
+
+    #[test]
+    fn subtract() {
+        assert_lb_out!("Sa3 Sb2 MScab Pc", "1");
+        assert_lb_out!("Sa3.5 Sb5 MScab Pc", "-1.5");
+    }
+
+    #[test]
+    fn multiply() {
+        assert_lb_out!("Sa3 Sb2 MMcab Pc", "6");
+        assert_lb_out!("Sa0.5 Sb5 MMcab Pc", "2.5");
+    }
+
+    #[test]
+    fn divide() {
+        assert_lb_out!("Sa3 Sb2 MDcab Pc", "1.5");
+        assert_lb_out!("Sa10 Sb5 MDcab Pc", "2");
+    }
+
+    #[test]
+    fn equal_to() {
+        assert_lb_out!("Sa3 Sb2 MEcab Pc", "false");
+        assert_lb_out!("Sa10 Sb10 MEcab Pc", "true");
+    }
+
+    #[test]
+    fn greater_than() {
+        assert_lb_out!("Sa3 Sb2 MGcab Pc", "true");
+        assert_lb_out!("Sa3 Sb2 MGcba Pc", "false");
+    }
+
+    #[test]
+    fn less_than() {
+        assert_lb_out!("Sa3 Sb2 MLcba Pc", "true");
+        assert_lb_out!("Sa3 Sb2 MLcab Pc", "false");
+    }
+
+    #[test]
+    fn not_equal_to() {
+        assert_lb_out!("Sa3 Sb2 MNcab Pc", "true");
+        assert_lb_out!("Sa10 Sb10 MNcab Pc", "false");
+    }
+
+    #[test]
+    fn greater_or_equal() {
+        assert_lb_out!("Sa3 Sb2 MHcab Pc", "true");
+        assert_lb_out!("Sa3 Sb3 MHcab Pc", "true");
+        assert_lb_out!("Sa3 Sb2 MHcba Pc", "false");
+    }
+
+    #[test]
+    fn less_or_equal() {
+        assert_lb_out!("Sa3 Sb2 MWcba Pc", "true");
+        assert_lb_out!("Sa3 Sb3 MWcba Pc", "true");
+        assert_lb_out!("Sa3 Sb2 MWcab Pc", "false");
+    }
+
+    #[test]
+    fn comparisons_with_nan() {
+        let mut data = LbStorage::new();
+        data.set_var('n', &Val::Number(f64::NAN)).unwrap();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer("Sa1 MEcan Pc MNcan Pc MGcan Pc MLcan Pc MHcan Pc MWcan Pc");
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        assert_eq!(program.run(), Ok(()));
+        assert_eq!(out, "falsetruefalsefalsefalsefalse");
+    }
+
+    #[test]
+    fn val_operators() {
+        let num = |num: f64| Val::Number(num);
+        let text = |text: &str| Val::Text(String::from(text));
+        let format = |val: Option<Val>| val.map(|val| val.format(false));
+
+        // numbers work as they do in M
+        assert_eq!(format(num(3.0) + num(2.0)), Some(String::from("5")));
+        assert_eq!(format(num(3.0) - num(2.0)), Some(String::from("1")));
+        assert_eq!(format(num(3.0) * num(2.0)), Some(String::from("6")));
+        assert_eq!(format(num(3.0) / num(2.0)), Some(String::from("1.5")));
+        // though dividing by zero gives nothing, where M fails
+        assert_eq!(format(num(1.0) / num(0.0)), None);
+        assert_eq!(format(num(0.0) / num(-0.0)), None);
+        assert_eq!(format(-num(3.0)), Some(String::from("-3")));
+        assert!(num(3.0) > num(2.0));
+        assert!(num(2.0) <= num(2.0));
+        assert!(num(2.0) == num(2.0));
+        assert!(num(f64::NAN) != num(f64::NAN));
+        assert_eq!(num(f64::NAN).partial_cmp(&num(1.0)), None);
+
+        // text and numbers can't be mixed, where M fails with a type mismatch
+        assert!((text("3") + num(2.0)).is_none());
+        assert!((num(3.0) - text("2")).is_none());
+        assert!((text("3") * num(2.0)).is_none());
+        assert!((num(3.0) / text("2")).is_none());
+        assert_eq!(text("3").partial_cmp(&num(2.0)), None);
+        assert!(text("3") != num(3.0));
+
+        // nor can text be used on its own
+        assert!((text("ab") + text("cd")).is_none());
+        assert!((text("ab") - text("cd")).is_none());
+        assert!((text("ab") * text("cd")).is_none());
+        assert!((text("ab") / text("cd")).is_none());
+        assert!((-text("ab")).is_none());
+        assert_eq!(text("ab").partial_cmp(&text("cd")), None);
+        // though equal text is equal, as in BS
+        assert!(text("ab") == text("ab"));
+        assert!(text("ab") <= text("ab"));
+
+        assert_eq!(num(4.0).as_number(), Some(4.0));
+        assert_eq!(text("4").as_number(), None);
+        assert_eq!(text("4").as_text(), Some("4"));
+        assert_eq!(num(4.0).as_text(), None);
+    }
+
+    #[test]
+    fn remainder() {
+        assert_lb_out!("Sa3 Sb2 MRcab Pc", "1");
+        assert_lb_out!("Sa10 Sb10 MRcab Pc", "0");
+        assert_lb_out!("Sa5 Sb10 MRcab Pc", "5");
+    }
+
+    #[test]
+    fn integer_division() {
+        assert_lb_out!("Sa7 Sb2 MQcab Pc", "3");
+        assert_lb_out!("Sa-7 Sb2 MQcab Pc", "-3");
+        assert_lb_out!("Sa7 Sb-2 MQcab Pc", "-3");
+        assert_lb_out!("Sa-7.5 Sb-2 MQcab Pc", "3");
+        assert_lb_out!("Sa6 Sb3 MQcab Pc", "2");
+        // divmod: the quotient and remainder rebuild the dividend
+        assert_lb_out!("Sa-7 Sb2 MQqab MRrab Pq P' ' Pr MMxqb MAxxr P' ' Px", "-3 -1 -7");
+    }
+
+    #[test]
+    fn modulo() {
+        assert_lb_out!("Sa7 Sb3 MOcab Pc", "1");
+        assert_lb_out!("Sa-7 Sb3 MOcab Pc", "2");
+        assert_lb_out!("Sa7 Sb-3 MOcab Pc", "-2");
+        assert_lb_out!("Sa-7 Sb-3 MOcab Pc", "-1");
+        assert_lb_out!("Sa5.5 Sb2 MOcab Pc", "1.5");
+        assert_lb_out!("Sa6 Sb3 MOcab Pc", "0");
+    }
+
+    #[test]
+    fn power() {
+        assert_lb_out!("Sa2 Sb10 MPcab Pc", "1024");
+        assert_lb_out!("Sa9 Sb0.5 MPcab Pc", "3");
+        assert_lb_out!("Sa2 Sb-1 MPcab Pc", "0.5");
+        assert_lb_out!("Sa-2 Sb3 MPcab Pc", "-8");
+        assert_lb_out!("MPcab Pc", "1");
+        assert_lb_err!("Sa-8 Sb0.5 MPcab", "M: MPcab has no real result");
+    }
+
+    #[test]
+    fn power_in_while_loop() {
+        assert_lb_out!("Sa3 Sb1 Sc2 Wa[ MPdca Pd P' ' MSaab ]", "8 4 2 ");
+        assert_lb_out!("Sa4 Sb1 Sd2 Wa[ MSaab MPeda MAcce ] Pc", "15");
+    }
+
+    #[test]
+    fn rounding() {
+        assert_lb_out!("Sb2.5 MFab Pa P' ' MCab Pa P' ' MRab Pa", "2 3 3");
+        assert_lb_out!("Sb-2.5 MFab Pa P' ' MCab Pa P' ' MRab Pa", "-3 -2 -3");
+        assert_lb_out!("Sb7 Sc2 MDbbc MFab Pa", "3");
+        assert_lb_err!("Sb'2.5' MFab", "M: Variable b is not a number");
+    }
+
+    #[test]
+    fn abs_and_sign() {
+        assert_lb_out!("Sb-2.5 MAab Pa P' ' MNab Pa", "2.5 -1");
+        assert_lb_out!("Sb4 MAab Pa P' ' MNab Pa", "4 1");
+        assert_lb_out!("Sb-0 MAab Pa P' ' MNab Pa", "0 0");
+        assert_lb_err!("Sb'x' MAab", "M: Variable b is not a number");
+        assert_lb_err!("Sb'x' MNab", "M: Variable b is not a number");
+    }
+
+    #[test]
+    fn abs_and_sign_in_loops() {
+        assert_lb_out!("Sa-3 Sb1 Sc3 Lc[ MAda Pd MNda Pd MAaab ]", "3-12-11-1");
+        assert_lb_out!("Sa-2 Sb1 MNca Wc[ Pa MAaab MNca ]", "-2-1");
+    }
+
+    #[test]
+    fn min_and_max() {
+        assert_lb_out!("Sa3 Sb-2 MIcab Pc MXcab Pc", "-23");
+        assert_lb_out!("Sa3 Sb3 MIcab Pc MXcab Pc", "33");
+        // clamp x into [l, h]
+        assert_lb_out!("Sx15 Sl0 Sh10 MXxxl MIxxh Px", "10");
+        assert_lb_err!("Sa3 Sb'2' MIcab", "M: Variable b is not a number");
+        assert_lb_err!("Sa'3' Sb2 MXcab", "M: Variable a is not a number");
+    }
+
+    #[test]
+    fn min_and_max_with_nan() {
+        let mut data = LbStorage::new();
+        data.set_var('n', &Val::Number(f64::NAN)).unwrap();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let lex = LbToken::lexer("Sa3 MIcan Pc MXcna Pc");
+        let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        assert_eq!(program.run(), Ok(()));
+        assert_eq!(out, "33");
+    }
+
+    #[test]
+    fn trigonometry() {
+        assert_lb_out!("MSab Pa P' ' MOab Pa P' ' MTab Pa", "0 1 0");
+        assert_lb_out!("Sy1 Sx0 MTpyx MSap Pa", "1");
+        assert_lb_err!("Sb'x' MSab", "M: Variable b is not a number");
+    }
+
+    #[test]
+    fn atan2_quadrants() {
+        assert_lb_out!("Sy1 Sx1 MTayx Pa", "0.7853981633974483");
+        assert_lb_out!("Sy1 Sx-1 MTayx Pa", "2.356194490192345");
+        assert_lb_out!("Sy-1 Sx-1 MTayx Pa", "-2.356194490192345");
+        assert_lb_out!("Sy-1 Sx1 MTayx Pa", "-0.7853981633974483");
+        assert_lb_out!("Sy0 Sx-1 MTayx Pa", "3.141592653589793");
+        assert_lb_out!("Sy1 Sx0 MTayx Pa", "1.5707963267948966");
+    }
+
+    #[test]
+    fn roots_and_logarithms() {
+        assert_lb_out!("Sb16 MQab Pa P' ' Sb0 MQab Pa", "4 0");
+        assert_lb_out!("Sb1000 MDab Pa P' ' Sb1 MLab Pa", "3 0");
+        assert_lb_out!("Sb2 MLab Pa", "0.6931471805599453");
+        assert_lb_err!("Sb-4 MQab", "M: Square root of negative variable b in MQab");
+        assert_lb_err!("MLab", "M: Logarithm of non-positive variable b in MLab");
+        assert_lb_err!("Sb-1 MDab", "M: Logarithm of non-positive variable b in MDab");
+    }
+
+    #[test]
+    fn roots_and_logarithms_output() {
+        assert_lb_out!("Sb81 MQab Cac Sd'root: ' Adc Pd", "root: 9");
+        assert_lb_out!("Sb100 MDab Sd'log ' Ada Ada Pd", "log 22");
+    }
+
+    #[test]
+    fn modulo_by_zero() {
+        assert_lb_err!("Sa7 MOcab Pc", "M: Division by zero in MOcab");
+        assert_lb_err!("Sa-7 Sb0 MObab", "M: Division by zero in MObab");
+        assert_lb_err!("Sa7 MQcab Pc", "M: Division by zero in MQcab");
+    }
+}
+
+#[cfg(test)]
+mod text_ops {
+    use crate::storage::*;
+    use crate::program::*;
+    use crate::lb_lexer::LbToken;
+    use logos::Logos;
+
+    #[test]
+    fn substring() {
+        assert_lb_out!("Ss'letterbox' Si3 Sl4 TSdsil Pd", "terb");
+        assert_lb_out!("Ss'letterbox' Si0 Sl6 TSdsil Pd", "letter");
+        assert_lb_out!("Ss12345 Si1.9 Sl2.9 TSdsil Pd", "23");
+    }
+
+    #[test]
+    fn substring_clamps() {
+        assert_lb_out!("Ss'box' Si1 Sl10 TSdsil Pd", "ox");
+        assert_lb_out!("Ss'box' Si10 Sl2 TSdsil Pd", "");
+        assert_lb_out!("Ss'box' Si-2 Sl2 TSdsil Pd", "bo");
+        assert_lb_out!("Ss'box' Si1 Sl-2 TSdsil Pd", "");
+        assert_lb_err!("Ss'box' Si'1' Sl2 TSdsil", "T: Variable i is not a number");
+    }
+
+    #[test]
+    fn split() {
+        assert_lb_out!("Ss'name,age,,city' Sc',' Sn1 TPdscn Pd", "age");
+        assert_lb_out!("Ss'name,age,,city' Sc',' Sn2 TPdscn Pd", "");
+        assert_lb_out!("Ss'name,age,,city' Sc',' Sn3 TPdscn Pd", "city");
+        assert_lb_out!("Ss'name,age,,city' Sc',' Sn4 TPdscn Pd", "");
+        assert_lb_out!("Ss'name,age,,city' Sc',' TKdsc Pd", "4");
+        assert_lb_out!("Ss3.25 Sc'.' Sn1 TPdscn Pd", "25");
+    }
+
+    #[test]
+    fn split_without_delimiter() {
+        assert_lb_out!("Ss'letterbox' Sc',' TPdscn Pd P' ' TKdsc Pd", "letterbox 1");
+        assert_lb_out!("Ss'' Sc',' TKdsc Pd", "1");
+        assert_lb_err!("Ss'a,b' Sc',,' TKdsc", "T: Delimiter c must be a single character");
+        assert_lb_err!("Ss'a,b' Sc'' TKdsc", "T: Delimiter c must be a single character");
+    }
+
+    #[test]
+    fn split_loop() {
+        assert_lb_out!(
+            "Ss'1 2 3 4' Sc' ' Sb1 TKksc Wk[ MSkkb TPdsck Pd ]",
+            "4321");
+    }
+
+    #[test]
+    fn parse_number() {
+        assert_lb_out!("Ss'42' TNdsf Pd P' ' Pf", "42 true");
+        assert_lb_out!("Ss' -1.5e2\n' TNdsf Pd P' ' Pf", "-150 true");
+        assert_lb_out!("Ss'forty two' Sd5 TNdsf Pd P' ' Pf", "0 false");
+        assert_lb_out!("Ss'' TNdsf Pd P' ' Pf", "0 false");
+        assert_lb_out!("Ss7 TNdsf Sa1 MAdda Pd", "8");
+    }
+
+    #[test]
+    fn parse_number_from_split() {
+        assert_lb_out!(
+            "Ss'10,x,5' Sc',' Sn0 Sb1 TKksc Lk[ TPpscn TNqpf If[ MAttq ]:[ P'bad ' ] MAnnb ] Pt",
+            "bad 15");
+    }
+
+    #[test]
+    fn format_number() {
+        assert_lb_out!("Sa0.1 Sb0.2 MAnab Sp2 TFdnp Pd", "0.30");
+        assert_lb_out!("Sn3 Sp3 TFdnp Pd", "3.000");
+        assert_lb_out!("Sn2.5 Sp0 TFdnp Pd", "2");
+        assert_lb_out!("Sn-1.25 Sp1.9 TFdnp Pd", "-1.2");
+        assert_lb_out!("Sn1.75 Sp-3 TFdnp Pd", "2");
+        assert_lb_err!("Sn'1.5' Sp2 TFdnp", "T: Variable n is not a number");
+    }
+
+    #[test]
+    fn change_case() {
+        assert_lb_out!("Ss'Letter Box' TUds Pd P' ' TLds Pd", "LETTER BOX letter box");
+        assert_lb_out!("Ss'straße' TUds Pd P' ' TLdd Pd", "STRASSE strasse");
+        assert_lb_out!("Ss'ÀÉÎ' TLds Pd", "àéî");
+        assert_lb_out!("Ss1e21 TUds Pd", "1000000000000000000000");
+    }
+
+    #[test]
+    fn char_at() {
+        assert_lb_out!("Ss'box' Si0 TCdsi Pd Si2.7 TCdsi Pd", "bx");
+        assert_lb_out!("Ss'box' Si3 TCdsi Pd Si-1 TCdsi Pd P'.'", ".");
+        assert_lb_out!("Ss'naïve' Si2 TCdsi Pd", "ï");
+        assert_lb_out!("Ss1234 Si1 TCdsi Pd", "2");
+    }
+
+    #[test]
+    fn char_at_loop() {
+        // print each character until the string runs out
+        assert_lb_out!(
+            "Ss'añb' Sb1 Si0 TCdsi Se'' BSnde Nn Wn[ Pd P'.' MAiib TCdsi BSnde Nn ]",
+            "a.ñ.b.");
+    }
+
+    #[test]
+    fn find() {
+        assert_lb_out!("Sh'letterbox' Sn'box' TIdhn Pd", "6");
+        assert_lb_out!("Sh'letterbox' Sn't' TIdhn Pd", "2");
+        assert_lb_out!("Sh'letterbox' Sn'crate' TIdhn Pd", "-1");
+        assert_lb_out!("Sh'letterbox' Sn'' TIdhn Pd", "0");
+        assert_lb_out!("Sh'box' Sn'boxes' TIdhn Pd", "-1");
+        assert_lb_out!("Sh'día de fiesta' Sn'de' TIdhn Pd", "4");
+        assert_lb_out!("Sh31415 Sn4 TIdhn Pd", "2");
+    }
+
+    #[test]
+    fn replace() {
+        assert_lb_out!("Ss'fizz buzz' Sf'zz' Sr'z' TRdsfr Pd", "fiz buz");
+        assert_lb_out!("Ss'fizz buzz' Sf'x' Sr'z' TRdsfr Pd", "fizz buzz");
+        assert_lb_out!("Ss'fizz' Sf'' Sr'-' TRdsfr Pd", "fizz");
+        assert_lb_out!("Ss'fizz' Sf'z' Sr'' TRdsfr Pd", "fi");
+        assert_lb_out!("Ss10.5 Sf'.' Sr0 TRdsfr Pd", "1005");
+    }
+
+    #[test]
+    fn replace_overlapping() {
+        assert_lb_out!("Ss'aaaa' Sf'aa' Sr'b' TRdsfr Pd", "bb");
+        assert_lb_out!("Ss'aaa' Sf'aa' Sr'b' TRdsfr Pd", "ba");
+        assert_lb_out!("Ss'abab' Sf'ab' Sr'abab' TRdsfr Pd", "abababab");
+    }
+
+    #[test]
+    fn trim() {
+        assert_lb_out!("Ss' \tbox \n' TTds P'[' Pd P']'", "[box]");
+        assert_lb_out!("Ss' \tbox \n' TBds P'[' Pd P']'", "[box \n]");
+        assert_lb_out!("Ss' \tbox \n' TEds P'[' Pd P']'", "[ \tbox]");
+        assert_lb_out!("Ss'\u{3000}box\u{a0}' TTds P'[' Pd P']'", "[box]");
+        assert_lb_out!("Ss-12 TTds Pd", "-12");
+    }
+
+    #[test]
+    fn trim_input() {
+        assert_lb_from_input!(
+            "GSa0 TTaa Sb'yes' BScab Pc",
+            vec!["yes\r\n".to_string()],
+            "true");
+    }
+
+    #[test]
+    fn substring_unicode() {
+        assert_lb_out!("Ss'héllo wörld' Si1 Sl4 TSdsil Pd", "éllo");
+        assert_lb_out!("Ss'日本語テキスト' Si2 Sl3 TSdsil Pd", "語テキ");
+    }
+
+    #[test]
+    fn character_codes() {
+        assert_lb_out!("Ss'A' TOds Pd", "65");
+        assert_lb_out!("Ss'abc' TOds Pd", "97");
+        assert_lb_out!("Ss'é' TOds Pd", "233");
+        assert_lb_out!("Sn97 THdn Pd", "a");
+        assert_lb_out!("Sn0x1F600 THdn TOmd Pm", "128512");
+        // build a string character by character
+        assert_lb_out!("Sn65 So1 Sr'' Sl5 Ll[ THcn Arc MAnno ] Pr", "ABCDE");
+    }
+
+    #[test]
+    fn character_code_errors() {
+        assert_lb_err!("Ss'' TOds", "T: Can't get the character code of empty text in variable s");
+        assert_lb_err!("Sn0xD800 THdn", "T: 55296 is not a valid character code");
+        assert_lb_err!("Sn0x110000 THdn", "T: 1114112 is not a valid character code");
+        assert_lb_err!("Sn-1 THdn", "T: -1 is not a valid character code");
+        assert_lb_err!("Sn65.5 THdn", "T: 65.5 is not a valid character code");
+        assert_lb_err!("Ss'NaN' TNnsf THdn", "T: NaN is not a valid character code");
+    }
+}
+
+#[cfg(test)]
+mod bool_ops {
+    use crate::storage::*;
+    use crate::program::*;
+    use crate::lb_lexer::LbToken;
+    use logos::Logos;
+
+    #[test]
+    fn equal() {
+        assert_lb_out!("Sa1 Sb'x' BEcab Pc",  "true"); // t t
+        assert_lb_out!("Sa0 Sb'' BEcab Pc",   "false"); // f t
+        assert_lb_out!("Sa'cz' Sb0 BEcab Pc", "false"); // t f
+        assert_lb_out!("Sa0 Sb0.0 BEcab Pc",  "true"); // f f
+    }
+
+    #[test]
+    fn and() {
+        assert_lb_out!("Sa1 Sb'x' BAcab Pc",  "true"); // t t
+        assert_lb_out!("Sa0 Sb'' BAcab Pc",   "false"); // f t
+        assert_lb_out!("Sa'cz' Sb0 BAcab Pc", "false"); // t f
+        assert_lb_out!("Sa0 Sb0.0 BAcab Pc",  "false"); // f f
+    }
+
+    #[test]
+    fn or() {
+        assert_lb_out!("Sa1 Sb'x' BOcab Pc",  "true"); // t t
+        assert_lb_out!("Sa0 Sb'' BOcab Pc",   "true"); // f t
+        assert_lb_out!("Sa'cz' Sb0 BOcab Pc", "true"); // t f
+        assert_lb_out!("Sa0 Sb0.0 BOcab Pc",  "false"); // f f
+    }
+
+    #[test]
+    fn not() {
+        assert_lb_out!("Sb1 BNab Pa Pb",     "false1");
+        assert_lb_out!("Sb0 BNab Pa Pb",     "true0");
+        assert_lb_out!("Sb'x' BNab Pa",      "false");
+        assert_lb_out!("VNb BNab Pa",        "true");
+        assert_lb_out!("Sa1 BNaa Pa",        "false");
+        // nested under conditionals and loops
+        assert_lb_out!("Sb1 Sc1 IcBNab Pa",  "false");
+        assert_lb_out!("Sa1 Sb0 Sc3 Sd1 Wa[ Pc MSccd BNbc BNab ]", "321");
+        assert_lb_out!("Sa1 WaBNaa Pa",      "false");
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_lb_out!("Sa12 Sb10 B&cab Pc", "8");
+        assert_lb_out!("Sa12 Sb10 B|cab Pc", "14");
+        assert_lb_out!("Sa12 Sb10 B^cab Pc", "6");
+        assert_lb_out!("Sa3 Sb4 B<cab Pc",   "48");
+        assert_lb_out!("Sa48 Sb4 B>cab Pc",  "3");
+        // fractions are truncated, and negative numbers use two's complement
+        assert_lb_out!("Sa12.9 Sb-1 B&cab Pc", "12");
+        assert_lb_out!("Sa-8 Sb1 B>cab Pc",  "-4");
+        // a simple checksum
+        assert_lb_out!(
+            "VNl Sx3 VAlx Sx5 VAlx Sx200 VAlx VLnl Si0 So1 Sm255 Sc0 Ln[ VGxli B<cco B^ccx B&ccm MAiio ] Pc",
+            "206");
+    }
+
+    #[test]
+    fn bitwise_errors() {
+        assert_lb_err!("Sa1 Sb64 B<cab", "B: Shift amount 64 is out of range in B<cab");
+        assert_lb_err!("Sa1 Sb-1 B>cab", "B: Shift amount -1 is out of range in B>cab");
+        assert_lb_err!("Sa1e19 Sb1 B&cab", "B: Variable a is out of range for bitwise operations: 10000000000000000000");
+        assert_lb_err!("Ss'NaN' TNasf Sb1 B|cab", "B: Variable a is out of range for bitwise operations: NaN");
+        assert_lb_err!("Sa'x' Sb1 B^cab", "B: Variable a is not a number");
+    }
+
+    #[test]
+    fn same() {
+        assert_lb_out!("Sa'guess' Sb'guess' BScab Pc", "true"); // text == text
+        assert_lb_out!("Sa'guess' Sb'Guess' BScab Pc", "false"); // text != text
+        assert_lb_out!("Sa'' Sb'' BScab Pc",           "true"); // empty text
+        assert_lb_out!("Sa4 Sb4.0 BScab Pc",           "true"); // number == number
+        assert_lb_out!("Sa4 Sb5 BScab Pc",             "false"); // number != number
+        assert_lb_out!("Sa'4' Sb4 BScab Pc",           "false"); // mixed types
+        assert_lb_out!("Sa4 Sb'4' BScab Pc",           "false"); // mixed types
+    }
+
+    #[test]
+    fn xor() {
+        assert_lb_out!("Sa1 Sb'x' BXcab Pc",  "false"); // t t
+        assert_lb_out!("Sa0 Sb'' BXcab Pc",   "true"); // f t
+        assert_lb_out!("Sa'cz' Sb0 BXcab Pc", "true"); // t f
+        assert_lb_out!("Sa0 Sb0.0 BXcab Pc",  "false"); // f f
+    }
+}
+#[cfg(test)]
+mod list_ops {
+    use crate::storage::*;
+    use crate::program::*;
+    use crate::lb_lexer::LbToken;
+    use logos::Logos;
+
+    #[test]
+    fn new_and_append() {
+        assert_lb_out!("VNl Pl", "[]");
+        assert_lb_out!("VNl Sa1 VAla Sb'two' VAlb Pl VLnl Pn", "[1, two]2");
+        assert_lb_out!("VNl VNm Sa1 VAma VAlm VAlm Pl", "[[1], [1]]");
+    }
+
+    #[test]
+    fn set_and_get() {
+        assert_lb_out!("VNl Sa1 VAla VAla Si1 Sv5 VSliv VGdli Pd Pl", "5[1, 5]");
+        assert_lb_out!("VNl Sa'x' VAla Si0.9 VGdli Pd", "x");
+        // copies of a list don't share elements
+        assert_lb_out!("VNl Clm Sa1 VAma Pl Pm", "[][1]");
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        assert_lb_err!("VNl Si0 VGdli", "V: Index 0 is out of bounds for list l of length 0");
+        assert_lb_err!("VNl VAla Si-1 VSlia", "V: Index -1 is out of bounds for list l of length 1");
+        assert_lb_err!("VNl VAla Si1 VSlia", "V: Index 1 is out of bounds for list l of length 1");
+    }
+
+    #[test]
+    fn not_a_list() {
+        assert_lb_err!("Sl1 VAla", "V: Variable l is not a list");
+        assert_lb_err!("Sl'abc' VLnl", "V: Variable l is not a list");
+    }
+
+    #[test]
+    fn other_commands() {
+        // an empty list is false
+        assert_lb_out!("VNl Il[ P'full' ]:[ P'empty' ] VAla Il[ P'full' ]:[ P'empty' ]", "emptyfull");
+        // appending to a list adds an element, while appending a list to text formats it
+        assert_lb_out!("VNl Sa3 Ala Pl", "[3]");
+        assert_lb_out!("Sa'x' VNl Sb1 VAlb Aal Pa", "x[1]");
+        // lists are the same if their elements are
+        assert_lb_out!("VNl VNm BSslm Ps Sa1 VAla BSslm Ps VAma BSslm Ps", "truefalsetrue");
+    }
+
+    #[test]
+    fn literals() {
+        assert_lb_out!("Sa[1 2 3] Pa", "[1, 2, 3]");
+        assert_lb_out!("Sa[-1.5 'two' [3 []]] Pa VLna Pn", "[-1.5, two, [3, []]]3");
+        assert_lb_out!("Sa[] Ia[ P'full' ]:[ P'empty' ] Sa[0] Ia[ P'full' ]:[ P'empty' ]", "emptyfull");
+        // a literal is a new list each time it runs
+        assert_lb_out!("Sx1 L2[ Sa[] VAax Pa ]", "[1][1]");
+        // copies are deep, so changing a nested list doesn't change the copy
+        assert_lb_out!("Sa[[1] 2] Cab Si0 VGlai Sx3 VAlx VSail Pa Pb", "[[1, 3], 2][[1], 2]");
+    }
+
+    #[test]
+    fn built_in_a_loop() {
+        assert_lb_out!("VNl Sn5 Si0 So1 Ln[ MMsii VAls MAiio ] Pl VLcl Pc", "[0, 1, 4, 9, 16]5");
+        // appending to a list pushes onto it
+        assert_lb_out!("Sl[] Sn3 Ln[ Sx'ab' Alx ] Pl", "[ab, ab, ab]");
+    }
+
+    #[test]
+    fn split() {
+        assert_lb_out!("Ss'a,b,,c' Sc',' VPlsc Pl VLnl Pn", "[a, b, , c]4");
+        // empty text is one empty piece
+        assert_lb_out!("Ss'' Sc',' VPlsc VLnl Pn", "1");
+        assert_lb_err!("Ss'a b' Sc'' VPlsc", "V: Delimiter c must be a single character");
+        assert_lb_err!("Ss'a' Sc',,' TPdsci", "T: Delimiter c must be a single character");
+    }
+}
+
+#[cfg(test)]
+mod map_ops {
+    use crate::storage::*;
+    use crate::program::*;
+    use crate::lb_lexer::LbToken;
+    use logos::Logos;
+
+    #[test]
+    fn set_and_get() {
+        assert_lb_out!("HNm Pm", "{}");
+        assert_lb_out!("HNm Sk'one' Sv1 HSmkv HGdmk Pd", "1");
+        // keys are printed in sorted order
+        assert_lb_out!("HNm Sk'pear' Sv1 HSmkv Sk'apple' Sv'two' HSmkv Pm", "{apple: two, pear: 1}");
+        // numbers used as keys become text
+        assert_lb_out!("HNm Sk4 Sv'four' HSmkv Sk'4' HGdmk Pd", "four");
+    }
+
+    #[test]
+    fn overwrite() {
+        assert_lb_out!("HNm Sk'a' Sv1 HSmkv Sv2 HSmkv HGdmk HLnm Pd Pn", "21");
+    }
+
+    #[test]
+    fn missing_key() {
+        assert_lb_err!("HNm Sk'nope' HGdmk", "H: Key nope is not in map m");
+        assert_lb_out!("HNm Sk'nope' HCdmk Pd Sv1 HSmkv HCdmk Pd", "falsetrue");
+    }
+
+    #[test]
+    fn remove() {
+        assert_lb_out!("HNm Sk'a' Sv1 HSmkv HRmk HCdmk Pd Pm", "false{}");
+        // removing a missing key does nothing
+        assert_lb_out!("HNm Sk'a' HRmk Pm", "{}");
+    }
+
+    #[test]
+    fn not_a_map() {
+        assert_lb_err!("Sm1 Sk'a' HCdmk", "H: Variable m is not a map");
+        assert_lb_err!("VNm HLdm", "H: Variable m is not a map");
+    }
+
+    #[test]
+    fn other_commands() {
+        // an empty map is false
+        assert_lb_out!("HNm Im[ P'full' ]:[ P'empty' ] HSmkv Im[ P'full' ]:[ P'empty' ]", "emptyfull");
+        // copies of a map don't share entries
+        assert_lb_out!("HNm Cmn Sk'a' HSnkv Pm Pn", "{}{a: 0}");
+        assert_lb_out!("HNm HNn BSsmn Ps Sk'a' HSnkk BSsmn Ps HSmkk BSsmn Ps", "truefalsetrue");
+    }
+
+    #[test]
+    fn word_count() {
+        assert_lb_out!(
+            "Ss'the cat and the hat' Sc' ' TKnsc Si0 So1 HNm Ln[ TPwsci HCfmw Sv0 If[ HGvmw ] MAvvo HSmwv MAiio ] Pm",
+            "{and: 1, cat: 1, hat: 1, the: 2}");
+    }
+}
+
+#[test]
+fn diagnostics() {
+    let found = |src: &str| -> Vec<(usize, usize, String, String)> {
+        check(src).into_iter().map(|d| (d.line, d.column, d.slice, d.message)).collect()
+    };
+    let diagnostic = |line, column, slice: &str, message: &str| (line, column, slice.to_string(), message.to_string());
+
+    assert_eq!(found("Sa1 Pa\nLa[ Pa ]"), vec![]);
+    // every error is reported, even inside blocks, and nothing runs
+    assert_eq!(found("Sa1 ~ Pa\nLa[ Pa ~ ]\nSb'open"), vec![
+        diagnostic(1, 5, "~", "Unrecognized instruction"),
+        diagnostic(2, 8, "~", "Unrecognized instruction"),
+        diagnostic(3, 1, "Sb'open", "String is never closed"),
+    ]);
+    assert_eq!(check("Pa\n  !{ open")[0].span, 5..12);
+    assert_eq!(found("La[ Pa"), vec![diagnostic(1, 1, "La[ Pa", "Block is never closed")]);
+    assert_eq!(found("!{ open"), vec![diagnostic(1, 1, "!{ open", "Comment is never closed")]);
+    assert_eq!(found("Sa[1 [2]"), vec![diagnostic(1, 1, "Sa[1 [2]", "List is never closed")]);
+    assert_eq!(found("Sa[1 b]"), vec![diagnostic(1, 1, "Sa[1 b]", "S: List items must be numbers, strings, or lists, separated by spaces")]);
+
+    // structural problems
+    assert_eq!(found("Sz'Pa' Xzabc"), vec![
+        diagnostic(1, 8, "Xzabc", "X: Odd number of substitution characters"),
+        diagnostic(1, 12, "c", "Unrecognized instruction"),
+    ]);
+    assert_eq!(found("X'Pa ~'"), vec![diagnostic(1, 1, "X'Pa ~'", "X: Unrecognized instruction in executed program: ~")]);
+    assert_eq!(found(":a Ja La[ Jb ] :a"), vec![
+        diagnostic(1, 11, "Jb", "J: Label b is not defined"),
+        diagnostic(1, 16, ":a", "Label a is defined more than once"),
+    ]);
+}
+
+#[test]
+fn recovering_lexer() {
+    let found = |src: &str| -> Vec<(usize, usize, String, String)> {
+        lex_with_recovery(src).1.into_iter().map(|d| (d.line, d.column, d.slice, d.message)).collect()
+    };
+    let diagnostic = |line, column, slice: &str, message: &str| (line, column, slice.to_string(), message.to_string());
+
+    assert_eq!(lex_with_recovery("Sa1 Pa"), (vec![LbToken::SaveNumber(('a', 1.0)), LbToken::PrintVar('a')], vec![]));
+    // characters that can't be parsed next to each other are one error
+    let (tokens, diagnostics) = lex_with_recovery("Sa1 ~~~ Pa ~ ~");
+    assert_eq!(tokens, vec![
+        LbToken::SaveNumber(('a', 1.0)),
+        LbToken::Error,
+        LbToken::PrintVar('a'),
+        LbToken::Error,
+        LbToken::Error,
+    ]);
+    assert_eq!(diagnostics.iter().map(|d| d.span.clone()).collect::<Vec<_>>(), vec![4..7, 11..12, 13..14]);
+
+    // every typo is found at once, with a hint when it looks like a command
+    assert_eq!(found("Sz Pa\nCa Pb\nLa[ Pa P ]"), vec![
+        diagnostic(1, 1, "Sz", "S: Looks like SaveNumber, but is missing its value"),
+        diagnostic(2, 1, "Ca", "C: Looks like Copy, but is missing its second variable"),
+        diagnostic(3, 8, "P", "P: Looks like PrintVar, but is missing a variable or string to print"),
+    ]);
+    assert_eq!(found("S Ia"), vec![
+        diagnostic(1, 1, "S", "S: Looks like SaveNumber, but is missing its variable and value"),
+        diagnostic(1, 3, "Ia", "I: Looks like IfStatement, but is missing a command to run right after its variable"),
+    ]);
+    assert_eq!(found("abc La[ Pa"), vec![
+        diagnostic(1, 1, "abc", "Unrecognized instruction"),
+        diagnostic(1, 5, "La[ Pa", "Block is never closed"),
+    ]);
+    // check reports the same errors along with the rest
+    assert_eq!(check("Sz Pa ~~ Jb").len(), 3);
+}
+
+#[test]
+fn strict_mode() {
+    let src = "Sa0 Pa Ia[ i ]\nF ~~ Pa";
+    let run = |strict: bool| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = Vec::<String>::new();
+        let mut program = LbProgram::new(LbToken::lexer(src), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+        program.strict = strict;
+        let result = program.run();
+        (result, out)
+    };
+
+    // the errors are never reached, so the program runs
+    assert_eq!(run(false), (Ok(()), String::from("0")));
+    // in strict mode nothing runs, and every error is listed
+    let (result, out) = run(true);
+    assert_eq!(out, "");
+    let Err(LbError::UnparsedSource(errors)) = result else {
+        panic!("Program should fail before running: {result:?}");
+    };
+    let listed: Vec<(&str, usize, usize)> = errors.iter().map(|e| (e.slice.as_str(), e.line, e.column)).collect();
+    assert_eq!(listed, vec![("i", 1, 12), ("~~", 2, 3)]);
+    assert_eq!(
+        LbError::UnparsedSource(errors).to_string(),
+        "Program can't be parsed: `i` at line 1, column 12; `~~` at line 2, column 3",
+    );
+
+    // programs that parse run as usual
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut program = LbProgram::new(LbToken::lexer("Sa1 Pa"), &mut data, &inv, &mut out, 1000).expect("Program init failed");
+    program.strict = true;
+    assert_eq!(program.run(), Ok(()));
+    assert_eq!(out, "1");
+}
+
+#[test]
+fn validate_ops() {
+    let errors = |src: &str| -> Vec<(String, String)> {
+        validate(src, None).into_iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| (d.slice, d.message))
+            .collect()
+    };
+    let error = |slice: &str, message: &str| (slice.to_string(), message.to_string());
+
+    assert_eq!(errors("MAabc MFab BXabc BNab TUds VNl HNm"), vec![]);
+    assert_eq!(errors("MZabc MZab BZabc"), vec![
+        error("MZabc", "M: Invalid op Z"),
+        error("MZab", "M: Invalid op Z"),
+        error("BZabc", "B: Invalid op Z"),
+    ]);
+    assert_eq!(errors("TZds VZl HZm"), vec![
+        error("TZds", "T: Invalid op Z"),
+        error("VZl", "V: Invalid op Z"),
+        error("HZm", "H: Invalid op Z"),
+    ]);
+    assert_eq!(errors("TSds VSli"), vec![
+        error("TSds", "T: Wrong number of variables for op S"),
+        error("VSli", "V: Wrong number of variables for op S"),
+    ]);
+}
+
+#[test]
+fn validate_inputs() {
+    let inputs = |src: &str, count| -> Vec<(usize, usize, String)> {
+        validate(src, count).into_iter()
+            .filter(|d| d.message.starts_with("G:"))
+            .map(|d| (d.line, d.column, d.message))
+            .collect()
+    };
+
+    assert_eq!(inputs("GNa0 GSb1 Oa Ob", Some(2)), vec![]);
+    assert_eq!(inputs("GNa0 GSb1 Oa Ob", Some(1)), vec![(1, 6, String::from("G: no input at index 1"))]);
+    assert_eq!(inputs("GNa0\nLa[ GNb2 Ob ]", Some(2)), vec![(2, 5, String::from("G: no input at index 2"))]);
+    // without a count, any index might exist
+    assert_eq!(inputs("GNa9 Oa", None), vec![]);
+    // and the result is the same severity as a lex error
+    assert_eq!(validate("GNa1 Oa", Some(0))[0].severity, Severity::Error);
+}
+
+#[test]
+fn validate_variables() {
+    let warnings = |src: &str| -> Vec<(String, String)> {
+        validate(src, None).into_iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .map(|d| (d.slice, d.message))
+            .collect()
+    };
+    let warning = |slice: &str, message: &str| (slice.to_string(), message.to_string());
+
+    assert_eq!(warnings("Sa1 Sb2 MAcab Oc"), vec![]);
+    // each unwritten variable is reported where it is first read
+    assert_eq!(warnings("Sa1 MAcab Oc Ob"), vec![warning("MAcab", "Variable b is read but never written")]);
+    assert_eq!(warnings("Sa1\nLa[ Pa Px ]"), vec![warning("Px", "Variable x is read but never written")]);
+    assert_eq!(warnings("Ia[ Sb1 ]:Pb"), vec![warning("Ia[ Sb1 ]:Pb", "Variable a is read but never written")]);
+    // a variable written anywhere counts, even after it is read
+    assert_eq!(warnings("Ia[ Pb ] Sa0 Sb1"), vec![]);
+    assert_eq!(warnings("Cab Sa1 Ob"), vec![]);
+    assert_eq!(warnings("GNa0 TNdaf Od Of"), vec![]);
+    // executed string literals read and write this program's variables
+    assert_eq!(warnings("X'Sa1' Pa"), vec![]);
+    assert_eq!(warnings("Sb1 X'Ob Pc'"), vec![warning("X'Ob Pc'", "Variable c is read but never written")]);
+    assert_eq!(warnings("X'Sa1'ab Pb"), vec![]);
+    assert_eq!(warnings("X'Ya'>r Or"), vec![warning("X'Ya'>r", "Variable a is read but never written")]);
+}
+
+#[test]
+fn validate_execute() {
+    let warnings = |src: &str| -> Vec<(String, String)> {
+        validate(src, None).into_iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .map(|d| (d.slice, d.message))
+            .collect()
+    };
+    let warning = |slice: &str, message: &str| (slice.to_string(), message.to_string());
+
+    assert_eq!(warnings("Sz'Pa' Xz"), vec![]);
+    assert_eq!(warnings("GSz0 Xz"), vec![]);
+    assert_eq!(warnings("Xz"), vec![warning("Xz", "X: Variable z is never given a string to run")]);
+    assert_eq!(warnings("Sz1 Xz Xz"), vec![
+        warning("Xz", "X: Variable z is never given a string to run"),
+        warning("Xz", "X: Variable z is never given a string to run"),
+    ]);
+    // the executed program could write anything, so reads aren't checked
+    assert_eq!(warnings("Sz'Sa1' Xz Pa"), vec![]);
+}
+
+#[test]
+fn validate_resets() {
+    let warnings = |src: &str| -> Vec<(String, String)> {
+        validate(src, None).into_iter()
+            .filter(|d| d.severity == Severity::Warning)
+            .map(|d| (d.slice, d.message))
+            .collect()
+    };
+    let warning = |slice: &str, message: &str| (slice.to_string(), message.to_string());
+
+    assert_eq!(warnings("Ra RB R* R#"), vec![]);
+    // RA used to reset every variable
+    assert_eq!(warnings("RA"), vec![warning("RA", "R: RA only resets variable A, use R* to reset every variable")]);
+    assert_eq!(warnings("Sa1 La[ RA ]"), vec![warning("RA", "R: RA only resets variable A, use R* to reset every variable")]);
+}
+
+#[test]
+fn formatting() {
+    assert_eq!(crate::format("Sa1 Sb2   MAcab\tPc"), "Sa1\nSb2\nMAcab\nPc\n");
+    assert_eq!(crate::format(""), "");
+    // blocks are indented, and stay on the line of their loop or condition
+    assert_eq!(
+        crate::format("Sa3 La[ Pa Ib[ Pb ]:[ Pc Wd[ Pd ] ] ] [ Pe ] [ ]"),
+        "Sa3\nLa[\n    Pa\n    Ib[\n        Pb\n    ]:[\n        Pc\n        Wd[\n            Pd\n        ]\n    ]\n]\n[\n    Pe\n]\n[ ]\n",
+    );
+    // instructions without blocks are written as they were
+    assert_eq!(crate::format("LaPb IaPb:Pc Ua[ Pb ]:Pc IaPb:[ Pc ]"), "LaPb\nIaPb:Pc\nUa[\n    Pb\n]:Pc\nIaPb:[\n    Pc\n]\n");
+    assert_eq!(crate::format("Sa0xFF  P'a  \\'b\\''"), "Sa0xFF\nP'a  \\'b\\''\n");
+    // comments stay on the line they were on, and blank lines are collapsed
+    assert_eq!(
+        crate::format("! header\n\n\nSa1 ! set a\nLa[ ! body\n  Pa !{ print\n  it }!\n\n  ! last\n] !{ done }! !{ twice }!\nPa"),
+        "! header\n\nSa1 ! set a\nLa[ ! body\n    Pa !{ print\n  it }!\n\n    ! last\n] !{ done }! !{ twice }!\nPa\n",
+    );
+    assert_eq!(crate::format("[ ! only a comment\n]"), "[ ! only a comment\n]\n");
+}
+
+#[test]
+fn formatting_round_trip() {
+    let programs = [
+        "Sa1 Sb2   MAcab\tPc",
+        "Sa3 La[ Pa Ib[ Pb ]:[ Pc Wd[ Pd ] ] ] [ Pe ] [ ]",
+        "LaPb IaPb:Pc Ua[ Pb ]:Pc IaPb:[ Pc ] IaIbPc:Pd",
+        "! header\n\nSa1 ! set a\nLa[ ! body\n  Pa !{ print\n  it }!\n\n  ! last\n] !{ done }!\nPa",
+        "Sz'Pa [ Pb ]' Xzab>r :x Jx JIax X'Pa'ab TSdsil VNl HSmkv GNa0 Sa-1.5e-7",
+        "Sa1Pa[Pb]LaPbLb[Pc]",
+        "L12[ Pa L-2.5[ Pb ] ] L3Pc",
+        "Sa1 ~ La[ Pa ~ ]",
+    ];
+    for program in programs {
+        let formatted = crate::format(program);
+        let original: Vec<LbToken> = LbToken::lexer(program).collect();
+        let reformatted: Vec<LbToken> = LbToken::lexer(&formatted).collect();
+        assert_eq!(original, reformatted, "{formatted}");
+        // formatting is idempotent
+        assert_eq!(crate::format(&formatted), formatted);
+    }
+}
+
+#[test]
+fn spanned_lexing() {
+    use crate::lexing::{lex, line_column, line_columns};
+    let source = "! adds up\nSa1 Sb2 ! two numbers\nMAcab !{ and\n  prints }! Pc\n~ Lb[ Pé\n] Oé";
+    let tokens = lex(source);
+    let slices: Vec<&str> = tokens.iter().map(|(_, span)| &source[span.clone()]).collect();
+    assert_eq!(slices, vec!["Sa1", "Sb2", "MAcab", "Pc", "~", "Lb[ Pé\n]", "O", "é"]);
+    assert_eq!(tokens[2], (LbToken::MathOp(('A', 'c', 'a', 'b')), 32..37));
+    // errors are kept, with the text that couldn't be lexed
+    assert_eq!(tokens[4], (LbToken::Error, 60..61));
+    assert_eq!(tokens.iter().filter(|(token, _)| *token == LbToken::Error).count(), 3);
+
+    // positions count lines and characters from 1
+    assert_eq!(line_column(source, 0), (1, 1));
+    assert_eq!(line_column(source, 10), (2, 1));
+    assert_eq!(line_column(source, 32), (3, 1));
+    assert_eq!(line_column(source, 57), (4, 13));
+    // é is two bytes, but one column
+    assert_eq!(line_column(source, tokens[7].1.start), (6, 4));
+    assert_eq!(line_column(source, tokens[7].1.start + 1), (6, 4));
+    assert_eq!(line_column(source, source.len() + 10), (6, 5));
+    let starts: Vec<usize> = tokens.iter().map(|(_, span)| span.start).collect();
+    assert_eq!(line_columns(source, &starts), vec![(2, 1), (2, 5), (3, 1), (4, 13), (5, 1), (5, 3), (6, 3), (6, 4)]);
+    assert_eq!(line_columns(source, &[57, 10]), vec![(4, 13), (2, 1)]);
+}
+
+#[test]
+fn layout_lexing() {
+    use crate::lexing::{lex_with_layout, LayoutToken};
+    let source = "! adds up\r\nSa1 Sb2 ! two\n\n\tMAcab !{ and !{ nested }! }!Pc\n~ Lb[ Pa ! inside\n] !";
+    let tokens = lex_with_layout(source);
+    let pieces: Vec<LayoutToken> = tokens.iter().map(|(token, _)| token.clone()).collect();
+    let comment = |text: &str| LayoutToken::Comment(text.to_string());
+    let space = |text: &str| LayoutToken::Whitespace(text.to_string());
+    assert_eq!(pieces[..8], [
+        comment(" adds up"),
+        space("\r\n"),
+        LayoutToken::Command(LbToken::SaveNumber(('a', 1.0))),
+        space(" "),
+        LayoutToken::Command(LbToken::SaveNumber(('b', 2.0))),
+        space(" "),
+        comment(" two"),
+        space("\n\n\t"),
+    ]);
+    assert_eq!(pieces[10], comment(" and !{ nested }! "));
+    // comments in blocks stay in their command
+    assert_eq!(pieces.iter().filter(|token| matches!(token, LayoutToken::Comment(_))).count(), 4);
+    assert_eq!(pieces.last(), Some(&comment("")));
+
+    // the ranges cover the whole source in order
+    for source in [source, "", "  ", "Pa", "!{ open", "Sa1 !{ open", "Pa!x\n!{}!Pb  \n", "~ é ~~ !", "!{ é !{ ü }! }!Pa"] {
+        let tokens = lex_with_layout(source);
+        let rebuilt: String = tokens.iter().map(|(_, span)| &source[span.clone()]).collect();
+        assert_eq!(rebuilt, source);
+        assert!(tokens.windows(2).all(|pair| pair[0].1.end == pair[1].1.start));
+    }
+}
+
+#[test]
+fn parsed_programs() {
+    let ast = parse("Sa2\nLa[ Pa Ib[ Pb ]:Pc ]").expect("Program should parse");
+    assert_eq!(ast.instructions.len(), 2);
+    assert_eq!(ast.instructions[1].span(), 4..24);
+    // the bodies of loops, conditionals, and blocks have their own spans
+    let body = ast.instructions[1].spans.child(0);
+    assert_eq!(body.span, 6..24);
+    assert_eq!(body.children.iter().map(|tree| tree.span.clone()).collect::<Vec<_>>(), vec![8..10, 11..22]);
+    assert_eq!(body.child(1).children.iter().map(|tree| tree.span.clone()).collect::<Vec<_>>(), vec![13..19, 20..22]);
+    // an else branch after a nested body belongs to the outer conditional
+    let nested = parse("IaLbIcPd:Pe Pf").expect("Program should parse");
+    let spans = &nested.instructions[0].spans;
+    assert_eq!(spans.children.iter().map(|tree| tree.span.clone()).collect::<Vec<_>>(), vec![2..8, 9..11]);
+    assert_eq!(spans.child(0).child(0).span, 4..8);
+    assert_eq!(spans.child(0).child(0).child(0).span, 6..8);
+    let LbToken::Loop((_, body_token)) = &ast.instructions[1].node else {
+        panic!("Expected a loop");
+    };
+    assert_eq!(body_token.subcommands().len(), 2);
+
+    // the same parsed program runs any number of times
+    let inv = Vec::<String>::new();
+    for _ in 0..2 {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let mut program = LbProgram::from_ast(&ast, &mut data, &inv, StringOutput::new(&mut out), 1000)
+            .expect("Program init failed");
+        program.run().expect("Program failed");
+        assert_eq!(out, "2020");
+    }
+
+    // each run is bound to its own storage, input, and output
+    let ast = parse("GNa0 Sb1 MAbba Pb Ja P'?' :a P'!' Pa").expect("Program should parse");
+    let copy = ast.clone();
+    let run_fresh = |inputs: &Vec<String>| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        ast.run_with(&mut data, inputs, StringOutput::new(&mut out), 1000).expect("Program failed");
+        (out, data.get_var('b').map(|val| val.format(false)))
+    };
+    let five = vec![String::from("5")];
+    let first = run_fresh(&five);
+    assert_eq!(first, (String::from("6!5"), Some(String::from("6"))));
+    assert_eq!(run_fresh(&vec![String::from("2")]).0, "3!2");
+    assert_eq!(run_fresh(&five), first);
+    // storage that is reused keeps what earlier runs left in it
+    let mut data = LbStorage::new();
+    data.set_var('z', &Val::Number(7.0)).expect("Failed to set z");
+    let mut out = String::new();
+    ast.run_with(&mut data, &five, StringOutput::new(&mut out), 1000).expect("Program failed");
+    assert_eq!(out, "6!5");
+    assert_eq!(data.get_var('z').map(|val| val.format(false)), Some(String::from("7")));
+    assert_eq!(ast, copy);
+
+    // errors at the same position as a lexed program
+    let ast = parse("Sa1 Sb0\n MQcab").expect("Program should parse");
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let mut program = LbProgram::from_ast(&ast, &mut data, &inv, StringOutput::new(&mut out), 1000)
+        .expect("Program init failed");
+    program.run().expect_err("Program should fail");
+    assert_eq!(program.error_frames()[0].line, 2);
+
+    // problems found without running are returned instead
+    let errors = parse("Sa1 ~ Jx").expect_err("Program should not parse");
+    assert_eq!(errors.iter().map(|d| d.slice.as_str()).collect::<Vec<_>>(), vec!["~", "Jx"]);
+    // lexing keeps them, to fail when reached
+    assert_eq!(LbAst::lex("Sa1 ~").instructions[1].node, LbToken::Error);
+}
+
+#[test]
+fn encoded_programs() {
+    let corpus = [
+        "Sa4 Sb-1.5e-7 Sc0xFF Sd'it\\'s\\n' Cab Aab Pa P'hi' Oa O'hi'",
+        "MAabc MFab BXabc B&abc BNab TSdsil TUds VNl VSliv VPlsc HSmkv HGdmk",
+        "Sa[1 -2.5 'x' [3 [] 'y']] Sb[]",
+        "Sa3 LaPb La[ Pa Ib[ Pb ]:Pc ] IaPb:[ Pc ] UaPb Ua[ Pb ] WaNa Wb[ Nb ]",
+        "L5Pa L0[ Pa ] LaL2Pb Ra R* R# #0 #9 GNa0 GSab G#a GQaN Na K+a K-a K#a Da Qa Ea Za Zab F Fa",
+        ":x Jx JIax Xzacbd>r Xz X'Pa [ Pb ]'ab>r X'' Ya [ Pa [ Pb ] ] [ ]",
+        "Sa1 ~ La[ Pa ~ ] !{ comment }! ! another\nPa",
+        "",
+    ];
+    for program in corpus {
+        let ast = LbAst::lex(program);
+        let bytes = ast.to_bytes();
+        assert_eq!(LbAst::from_bytes(&bytes), Ok(ast.clone()), "{program}");
+
+        // every shorter prefix is cleanly rejected
+        for len in 0..bytes.len() {
+            assert_eq!(LbAst::from_bytes(&bytes[..len]), Err(LbError::TruncatedBytes), "{program} at {len}");
+        }
+    }
+
+    // a loaded program runs like the original
+    let ast = LbAst::from_bytes(&LbAst::lex("Sa3 Sz'Oa' La[ Xz Na ]").to_bytes()).expect("Program should load");
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    LbProgram::from_ast(&ast, &mut data, &inv, StringOutput::new(&mut out), 1000)
+        .expect("Program init failed")
+        .run()
+        .expect("Program failed");
+    assert_eq!(out, "3\n0\n1\n");
+
+    // unknown versions and corrupt data are errors
+    let mut bytes = LbAst::lex("Pa").to_bytes();
+    assert_eq!(bytes.len(), 10);
+    bytes[0] = 99;
+    assert_eq!(LbAst::from_bytes(&bytes), Err(LbError::UnknownFormatVersion(99)));
+    let bytes = LbAst::lex("Pa").to_bytes();
+    let with_extra = [bytes.as_slice(), &[0]].concat();
+    assert!(matches!(LbAst::from_bytes(&with_extra), Err(LbError::InvalidBytes(_))));
+    let mut bad_tag = bytes.clone();
+    bad_tag[5] = 200;
+    assert_eq!(LbAst::from_bytes(&bad_tag), Err(LbError::InvalidBytes(String::from("200 is not a command"))));
+    let mut bad_span = bytes.clone();
+    bad_span[8] = 9;
+    assert!(matches!(LbAst::from_bytes(&bad_span), Err(LbError::InvalidBytes(_))));
+}
+
+/// Runs a program, failing the test if it panics rather than returning.
+fn run_without_panic(ast: &LbAst) -> Result<(), LbError> {
+    let run = std::panic::catch_unwind(|| {
+        let mut data = LbStorage::new();
+        let mut out = String::new();
+        let inv = vec![String::from("1")];
+        LbProgram::from_ast(ast, &mut data, &inv, StringOutput::new(&mut out), 1000)?.run()
+    });
+    run.unwrap_or_else(|_| panic!("Program panicked: {:?}", ast.instructions))
+}
+
+#[test]
+fn hostile_programs() {
+    use LbToken::*;
+    // commands built by hand can break rules the lexer keeps
+    let spans = SpanTree { span: 0..0, children: Vec::new() };
+    let hand_built = [
+        (vec![ListOp(('S', String::from("l")))], LbError::WrongArgCount { command: 'V', op: 'S', expected: 3, found: 1 }),
+        (vec![MapOp(('G', String::new()))], LbError::WrongArgCount { command: 'H', op: 'G', expected: 3, found: 0 }),
+        (vec![TextOp(('S', String::from("ab")))], LbError::WrongArgCount { command: 'T', op: 'S', expected: 4, found: 2 }),
+        (vec![TextOp(('Z', String::from("abcd")))], LbError::InvalidOp { command: 'T', op: 'Z' }),
+        (vec![ListOp(('N', String::from("1")))], LbError::InvalidVariable { command: 'V', var: '1' }),
+        (vec![SaveStr(('z', String::from("Pa"))), Execute(('z', String::from("abc"), None))], LbError::UnpairedParameter('c')),
+        (vec![ExecuteStr((String::from("Pa"), String::from("a"), None))], LbError::UnpairedParameter('a')),
+        (vec![SaveNumber(('1', 4.0))], LbError::NotAVariable('1')),
+        (vec![Copy(('\n', 'a'))], LbError::InvalidVariable { command: 'C', var: '\n' }),
+        (vec![ResetVar('!')], LbError::NotAVariable('!')),
+        (vec![SwitchBank(200)], LbError::InvalidBank(200)),
+    ];
+    for (tokens, error) in hand_built {
+        let instructions = tokens.into_iter().map(|node| Spanned { node, spans: spans.clone() }).collect();
+        let ast = LbAst { source: String::new(), instructions };
+        assert_eq!(run_without_panic(&ast), Err(error));
+    }
+    // errors are still reported when the spans are outside the source
+    let spans = SpanTree { span: 5..9, children: Vec::new() };
+    let ast = LbAst { source: String::from("Pa"), instructions: vec![Spanned { node: ResetVar('!'), spans }] };
+    assert_eq!(run_without_panic(&ast), Err(LbError::NotAVariable('!')));
+
+    // truncated and garbled source fails to lex or fails when run
+    let sources = [
+        "Sa", "GN", "GNa", "X'", "X'Pa", "La[", "Ia[ Pa ]:", "T", "TSa", "V", "H",
+        "\0", "Lé", "Sé5", "'", "]", "[ ]]", "Xz", "X'X'", "Sa1e999", "GNa-1", "GNa1e30",
+    ];
+    for source in sources {
+        let _ = run_without_panic(&LbAst::lex(source));
+    }
+
+    // so does any corrupted byte in an encoded program
+    let bytes = LbAst::lex("Sa3 Sz'Oa' La[ Xzab>c Na ] TSdsil VSliv HGdmk GNa0 #1").to_bytes();
+    for i in 1..bytes.len() {
+        for value in [0, 1, 0x7f, 0x80, 0xff] {
+            let mut corrupt = bytes.clone();
+            corrupt[i] = value;
+            if let Ok(ast) = LbAst::from_bytes(&corrupt) {
+                let _ = run_without_panic(&ast);
+            }
+        }
+    }
+}
+
+#[test]
+fn sessions() {
+    let mut session = crate::session::LbSession::new(1000);
+    assert_eq!(session.eval("Sa1"), Ok(String::new()));
+    // storage carries over, and F only finishes its own snippet
+    assert_eq!(session.eval("MAaaa Pa F Pa"), Ok(String::from("2")));
+    assert_eq!(session.eval("Pa"), Ok(String::from("2")));
+    // a snippet that can't be parsed doesn't run at all
+    assert_eq!(session.eval("Sa9 La[ ~ ]"), Err(LbError::LexError { span: 8..9 }));
+    assert_eq!(session.eval("Pa"), Ok(String::from("2")));
+    // a snippet that fails keeps what it did before failing
+    assert_eq!(session.eval("Sa3 K-b"), Err(LbError::EmptyStack('b')));
+    assert_eq!(session.storage().peek_var('a').map(|val| val.format(false)), Some(String::from("3")));
+    // labels belong to one snippet
+    assert_eq!(session.eval(":x Pa"), Ok(String::from("3")));
+    assert_eq!(session.eval("Jx"), Err(LbError::UndefinedLabel('x')));
+
+    session.inputs = vec![String::from("7")];
+    session.storage_mut().reset_all().expect("Reset failed");
+    assert_eq!(session.eval("GNa0 Pa Pb"), Ok(String::from("70")));
+}
+
+#[test]
+fn fuzz_inputs() {
+    let mut rng = crate::rng::LbRng::new(7);
+    let mut commands = 0;
+    for len in 0..400 {
+        let data: Vec<u8> = (0..len % 120).map(|_| rng.next_u64() as u8).collect();
+        commands += crate::fuzzing::program_from_bytes(&data).len();
+        crate::fuzzing::run_fuzz_input(&data);
+    }
+    assert!(commands > 1000);
+    // the same bytes always give the same program
+    assert_eq!(crate::fuzzing::program_from_bytes(b"\x04\x01"), crate::fuzzing::program_from_bytes(b"\x04\x01"));
+    assert_eq!(crate::fuzzing::program_from_bytes(b""), vec![]);
+}
+
+#[test]
+fn includes() {
+    use crate::include::{resolve_includes, SourceLocation};
+    let files = |file: &str| match file {
+        "lib.lb" => Ok(String::from("!#include \"zero.lb\"\nSz'X' !{ helpers }!\r\n")),
+        "zero.lb" => Ok(String::from("Sb0")),
+        "loop.lb" => Ok(String::from("  !#include \"loop2.lb\"  ")),
+        "loop2.lb" => Ok(String::from("!#include \"loop.lb\"")),
+        _ => Err(format!("No file named {file}")),
+    };
+    let included = resolve_includes("Sa1\n  !#include \"lib.lb\"\nPa MQcab", files).expect("Include failed");
+    assert_eq!(included.source, "Sa1\nSb0\nSz'X' !{ helpers }!\r\n\nPa MQcab");
+    let at = |file: Option<&str>, line, column| SourceLocation { file: file.map(String::from), line, column };
+    assert_eq!(included.locate(0), at(None, 1, 1));
+    assert_eq!(included.locate(4), at(Some("zero.lb"), 1, 1));
+    assert_eq!(included.locate(8), at(Some("lib.lb"), 2, 1));
+    assert_eq!(included.locate(30), at(None, 3, 1));
+
+    // errors are located in the file they came from
+    let ast = LbAst::lex(&included.source);
+    let mut data = LbStorage::new();
+    let mut out = String::new();
+    let inv = Vec::<String>::new();
+    let mut program = LbProgram::from_ast(&ast, &mut data, &inv, StringOutput::new(&mut out), 1000).expect("Program init failed");
+    assert!(program.run().is_err());
+    let frame = &program.error_frames()[0];
+    assert_eq!(included.locate(frame.span.start), at(None, 3, 4));
+    assert_eq!(out, "1");
+
+    assert_eq!(resolve_includes("Pa\n!#include \"loop.lb\"", files),
+        Err(LbError::IncludeCycle(vec![String::from("loop.lb"), String::from("loop2.lb"), String::from("loop.lb")])));
+    assert_eq!(resolve_includes("!#include \"gone.lb\"", files).map_err(|e| e.to_string()),
+        Err(String::from("Could not include gone.lb: No file named gone.lb")));
+    assert_eq!(resolve_includes("!#include lib.lb", files), Err(LbError::InvalidInclude(String::from("!#include lib.lb"))));
+    // directives are only read on lines of their own
+    assert_eq!(resolve_includes("Pa !#include \"gone.lb\"", files).map(|included| included.source), Ok(String::from("Pa !#include \"gone.lb\"")));
+}
@@ -0,0 +1,103 @@
+//! Lexing with the positions of commands, for tools like editors and linters.
+
+use std::ops::Range;
+
+use logos::Logos;
+use crate::lb_lexer::{find_comment_end, LbToken};
+
+/// A part of a program's source code, as lexed by [lex_with_layout].
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutToken {
+    /// A command, as [lex] lexes it, including any comments and whitespace in its body.
+    Command(LbToken),
+
+    /// A comment, with the text between its `!` or `!{ }!` markers.
+    Comment(String),
+
+    /// Spaces, tabs, and line breaks between commands and comments.
+    Whitespace(String),
+}
+
+/// Lexes a program into its top-level commands, each with the byte range of the source it was lexed from.
+/// Parts that can't be parsed are kept as [LbToken::Error] with the range of the unparsed text,
+/// and comments and whitespace between commands are skipped.
+/// The bodies of loops, conditionals, and blocks are nested in their commands;
+/// [crate::prelude::LbAst::lex] also finds where each of them is.
+pub fn lex(source: &str) -> Vec<(LbToken, Range<usize>)> {
+    LbToken::lexer(source).spanned().collect()
+}
+
+/// Lexes a program like [lex], but keeps the comments and whitespace between top-level commands
+/// as tokens of their own, so the ranges of the tokens cover the whole source in order.
+/// The source can be rebuilt exactly by joining the text at each range.
+pub fn lex_with_layout(source: &str) -> Vec<(LayoutToken, Range<usize>)> {
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    for (token, span) in lex(source) {
+        tokens.extend(layout(source, pos..span.start));
+        pos = span.end;
+        tokens.push((LayoutToken::Command(token), span));
+    }
+    tokens.extend(layout(source, pos..source.len()));
+    tokens
+}
+
+/// Splits a part of the source between commands, which holds nothing but comments and whitespace,
+/// into comments and runs of whitespace.
+pub(crate) fn layout(source: &str, range: Range<usize>) -> Vec<(LayoutToken, Range<usize>)> {
+    let mut pieces = Vec::new();
+    let mut pos = range.start;
+    while pos < range.end {
+        let rest = &source[pos..range.end];
+        let (token, len) = if let Some(comment) = rest.strip_prefix("!{") {
+            let end = find_comment_end(comment).unwrap_or(comment.len());
+            let text = comment[..end].strip_suffix("}!").unwrap_or(&comment[..end]);
+            (LayoutToken::Comment(text.to_string()), end + 2)
+        } else if let Some(comment) = rest.strip_prefix('!') {
+            // line comments run to the end of the line
+            let end = comment.find(['\n', '\r']).unwrap_or(comment.len());
+            (LayoutToken::Comment(comment[..end].to_string()), end + 1)
+        } else {
+            let end = rest.find('!').unwrap_or(rest.len());
+            (LayoutToken::Whitespace(rest[..end].to_string()), end)
+        };
+        pieces.push((token, pos..pos + len));
+        pos += len;
+    }
+    pieces
+}
+
+/// Converts a byte offset into the source code into a line and column, counting from 1.
+/// Columns count characters rather than bytes. An offset inside a character is counted
+/// as the start of that character, and one past the end of the source as the end.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    line_columns(source, &[offset])[0]
+}
+
+/// Converts byte offsets into the source code into lines and columns, like [line_column],
+/// reading the source once if the offsets are in increasing order.
+/// Offsets out of order, as in programs built by hand, are counted from the start again.
+pub fn line_columns(source: &str, offsets: &[usize]) -> Vec<(usize, usize)> {
+    let mut positions = Vec::with_capacity(offsets.len());
+    let (mut line, mut column, mut pos) = (1, 1, 0);
+    for &offset in offsets {
+        if offset < pos {
+            (line, column, pos) = (1, 1, 0);
+        }
+        let mut end = offset.min(source.len());
+        while !source.is_char_boundary(end) {
+            end -= 1;
+        }
+        for c in source[pos..end].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        pos = end;
+        positions.push((line, column));
+    }
+    positions
+}
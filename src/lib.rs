@@ -2,16 +2,44 @@
 // An experimental esolang by Chris Natcharian
 
 mod storage;
-mod lb_lexer;
+use letterbox_lang_lexer as lb_lexer;
 mod program;
+mod rng;
+mod clock;
+mod input;
+mod error;
+mod ast;
+mod encoding;
+mod session;
+mod diagnostics;
+mod formatter;
+mod include;
+pub mod lexing;
+pub mod fuzzing;
+
+pub use formatter::format;
+pub use lexing::{lex, lex_with_layout, line_column, LayoutToken};
 
 pub mod prelude {
     pub use logos::{Logos, Lexer};
-    pub use crate::program::LbProgram;
-    pub use crate::storage::LbStorage;
-    pub use crate::lb_lexer::LbToken;
+    pub use crate::program::{BreakAction, ErrorFrame, LbProgram, LbProgramBuilder, RunStats, StepResult, StringOutput, TraceEntry, YieldResult};
+    pub use crate::storage::{BANK_COUNT, DefaultPolicy, LbSnapshot, LbStorage, SharedStorage, StorageIter, VarDiff};
+    pub use crate::lb_lexer::{LbToken, ListItem, SpanTree};
+    pub use crate::ast::{parse, LbAst, Spanned};
+    pub use crate::session::LbSession;
     pub use crate::program::Val;
+    pub use crate::clock::{LbClock, SystemClock};
+    pub use crate::input::{IterInput, LbInput};
+    pub use crate::error::LbError;
+    pub use crate::diagnostics::{check, lex_with_recovery, validate, Diagnostic, Severity};
+    pub use crate::formatter::format;
+    pub use crate::include::{resolve_includes, IncludedSource, SourceLocation};
+    pub use crate::lexing::{lex, lex_with_layout, line_column, LayoutToken};
+    #[cfg(feature = "macros")]
+    pub use letterbox_lang_macros::lb;
 }
 
 #[cfg(test)]
 mod lb_tests;
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests;
@@ -1,17 +1,49 @@
 use core::fmt;
-use regex::Regex;
-
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{BufRead, Write};
+use std::ops::{self, Range};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::rng::LbRng;
+use crate::clock::{LbClock, SystemClock};
+use crate::input::LbInput;
+use crate::error::LbError;
+use crate::ast::{LbAst, Spanned};
+use crate::diagnostics::parse_errors;
+use crate::lexing::line_columns;
 use crate::storage;
-use crate::storage::LbStorage;
+use crate::storage::{LbStorage, ProgramStorage};
 use logos::{Lexer, Logos};
-use crate::lb_lexer::LbToken;
+use crate::lb_lexer::{self, LbToken, ListItem, SpanTree};
 use crate::lb_lexer::LbToken::*;
 
+/// How many instructions a program runs in each call to [LbProgram::run_until_yield],
+/// unless [LbProgram::set_yield_interval] is called.
+const DEFAULT_YIELD_INTERVAL: u64 = 1000;
+
+/// The largest number of decimal places that `TF` can format a number with.
+const MAX_PRECISION: f64 = 100.0;
+
+/// The largest integer that an f64 can represent exactly (2^53).
+const MAX_EXACT_INT: f64 = 9007199254740992.0;
+
+/// How many units of rounding error a number can be from an integer
+/// and still be printed as that integer.
+const INTEGER_TOLERANCE: f64 = 4.0;
+
 /// A value that can be stored in a Letterbox variable.
 #[derive(Debug, Clone)]
 pub enum Val {
     Text(String),
     Number(f64),
+    Bool(bool),
+    List(Vec<Val>),
+    Map(BTreeMap<String, Val>),
 }
 
 impl Val {
@@ -22,430 +54,2284 @@ impl Val {
     }
 }
 
-impl fmt::Display for Val {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let x = match self {
-            Val::Text(text) => text.to_owned(),
-            Val::Number(num) => format!("{}", num),
-        };
-        write!(f, "{}", x)
+impl From<String> for Val {
+    fn from(text: String) -> Val {
+        Val::Text(text)
     }
 }
 
-/// A struct that represents a Letterbox program.
-/// It combines a list of parsed instructions and a [LbStorage] struct,
-/// executing each instruction in order.
-pub struct LbProgram<'a> {
-    /// An ordered list of parsed instructions. See [LbToken] for details.
-    pub program_list: Vec<LbToken>,
+impl From<&str> for Val {
+    fn from(text: &str) -> Val {
+        Val::Text(String::from(text))
+    }
+}
 
-    /// An integer that indicates the number of the next 
-    /// instruction to execute from the program list.
-    program_counter: usize,
+impl From<f64> for Val {
+    fn from(num: f64) -> Val {
+        Val::Number(num)
+    }
+}
 
-    /// A reference to a [LbStorage] struct which will be modified 
-    /// by the execution of this program.
-    data: &'a mut LbStorage,
+impl From<i64> for Val {
+    /// Integers beyond 2^53 are rounded to the nearest number a Letterbox variable can hold.
+    fn from(num: i64) -> Val {
+        Val::Number(num as f64)
+    }
+}
 
-    /// If true, this program has completed execution and can
-    /// no longer be run or stepped through.
-    pub finished: bool,
+impl From<&ListItem> for Val {
+    /// The value of an item of a list literal, like the `[3]` in `Sa[1 'two' [3]]`.
+    fn from(item: &ListItem) -> Val {
+        match item {
+            ListItem::Number(num) => Val::Number(*num),
+            ListItem::Text(text) => Val::Text(text.clone()),
+            ListItem::List(items) => Val::List(items.iter().map(Val::from).collect()),
+        }
+    }
+}
 
-    /// The result of the last executed instruction.
-    /// If this program is finished, this will be considered the
-    /// result of the whole program.
-    pub result: Result<(), String>,
+impl Default for Val {
+    /// The float 0.0, which every variable holds before it is set.
+    fn default() -> Val {
+        Val::zero()
+    }
+}
 
-    /// Contains all input passed into this program from the environment
-    /// i.e. the command line.
-    pub input_vec: &'a Vec<String>,
+impl From<bool> for Val {
+    /// A bool, like the results of `B` and comparisons in `M`.
+    fn from(b: bool) -> Val {
+        Val::Bool(b)
+    }
+}
 
-    /// A reference to a buffer to which output will be printed.
-    pub output_buffer: &'a mut String,
+impl TryFrom<Val> for f64 {
+    type Error = LbError;
 
-    /// The maximum number of times a loop can run in this program.
-    /// If a single loop exceeds this number, the program will crash.
-    pub loop_limit: usize,
+    /// Gets the number in a value, or fails with [LbError::ValueTypeMismatch] if it isn't one.
+    fn try_from(val: Val) -> Result<f64, LbError> {
+        val.as_number().ok_or(LbError::ValueTypeMismatch { expected: "number", found: val.type_name() })
+    }
 }
 
-impl<'a> LbProgram<'a> {
-    /// Create a new unexecuted [LbProgram] from the contents of
-    /// the given lexer. Requires a reference to a [LbStorage] struct.
-    pub fn new(lex: Lexer<LbToken>,
-        starting_data: &'a mut LbStorage,
-        inv: &'a Vec<String>,
-        out: &'a mut String,
-        loop_limit: usize,
-    ) -> Result<LbProgram<'a>, String> {
-        let plist: Vec<LbToken> = lex.collect();
-        let prog = LbProgram {
-            program_list: plist,
-            program_counter: 0,
-            data: starting_data,
-            finished: false,
-            result: Ok(()),
-            input_vec: inv,
-            output_buffer: out,
-            loop_limit
-        };
+impl TryFrom<Val> for String {
+    type Error = LbError;
 
-        Ok(prog)
+    /// Gets the text in a value, or fails with [LbError::ValueTypeMismatch] if it isn't text.
+    /// Numbers aren't formatted as text; use [Val::format] for that.
+    fn try_from(val: Val) -> Result<String, LbError> {
+        match val {
+            Val::Text(text) => Ok(text),
+            other => Err(LbError::ValueTypeMismatch { expected: "string", found: other.type_name() }),
+        }
     }
+}
 
-    /// Run the program until it finishes.
-    pub fn run(&mut self) -> Result<(), String> {
-        while !self.finished {
-            let step_result = self.step();
-            if let Err(_) = step_result {
-                self.finished = true;
-                return self.result.clone();
-            }
+impl Val {
+    /// Returns the number in this value, or None if it isn't a number.
+    /// A bool is read as 1 if true and 0 if false, so arithmetic on comparisons works,
+    /// but text is not read as a number, just as `M` doesn't.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Val::Number(num) => Some(*num),
+            Val::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+
+    /// Returns the bool in this value, or None if it isn't a bool.
+    /// Other values aren't read as bools; see [crate::storage::LbStorage::var_as_bool] for their truthiness.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Val::Bool(b) => Some(*b),
+            _ => None,
         }
+    }
 
-        return self.result.clone();
+    /// Returns the text in this value, or None if it isn't text.
+    /// Numbers are not formatted as text; use [Val::format] for that.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Val::Text(text) => Some(text),
+            _ => None,
+        }
     }
 
-    /// Run the next instruction as indicated by the program counter.
-    pub fn step(&mut self) -> Result<(), String> {
-        if self.finished {
-            return Err(String::from("Program is already finished."));
+    /// Returns the kind of value this is, as named in errors:
+    /// `"number"`, `"string"`, `"bool"`, `"list"`, or `"map"`.
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Val::Text(_) => "string",
+            Val::Number(_) => "number",
+            Val::Bool(_) => "bool",
+            Val::List(_) => "list",
+            Val::Map(_) => "map",
         }
+    }
 
-        // Get the instruction at the next position in the program.
-        if let Some(token) = self.program_list.get(self.program_counter) {
+    /// Applies an arithmetic op to two values, as `M` does.
+    /// Both values must be numbers or bools.
+    fn arithmetic(self, other: Val, op: fn(f64, f64) -> f64) -> Option<Val> {
+        Some(Val::Number(op(self.as_number()?, other.as_number()?)))
+    }
 
-            // Clone the token to prevent an immutable borrow
-            let command = &token.clone();
+    /// Formats this value as text.
+    /// Unless `exact` is true, a number within rounding error of an integer
+    /// is formatted as that integer, so `0.1 * 30` becomes `3` rather than `3.0000000000000004`.
+    /// Other numbers use the shortest representation that reads back as the same number.
+    /// Bools are formatted as `true` or `false`.
+    /// Lists are formatted as their elements in brackets, like `[1, 2, three]`,
+    /// and maps as their entries in braces sorted by key, like `{a: 1, b: two}`.
+    pub fn format(&self, exact: bool) -> String {
+        match self {
+            Val::Text(text) => text.to_owned(),
+            Val::Number(num) => format_number(*num, exact),
+            Val::Bool(b) => b.to_string(),
+            Val::List(list) => {
+                let items: Vec<String> = list.iter().map(|item| item.format(exact)).collect();
+                format!("[{}]", items.join(", "))
+            },
+            Val::Map(map) => {
+                let entries: Vec<String> = map.iter()
+                    .map(|(key, val)| format!("{key}: {}", val.format(exact)))
+                    .collect();
+                format!("{{{}}}", entries.join(", "))
+            },
+        }
+    }
 
-            // Evaluate the instruction
-            let step_result: Result<(), String> = self.evaluate(command);
+    /// Returns true if both values have the same type and contents.
+    /// Text is compared by content, numbers and bools by value, and lists and maps element by element.
+    /// A bool is never the same as a number.
+    fn same_as(&self, other: &Val) -> bool {
+        match (self, other) {
+            (Val::Text(t_a), Val::Text(t_b)) => t_a == t_b,
+            (Val::Number(n_a), Val::Number(n_b)) => n_a == n_b,
+            (Val::Bool(b_a), Val::Bool(b_b)) => b_a == b_b,
+            (Val::List(l_a), Val::List(l_b)) => {
+                l_a.len() == l_b.len() && l_a.iter().zip(l_b).all(|(a, b)| a.same_as(b))
+            },
+            (Val::Map(m_a), Val::Map(m_b)) => {
+                m_a.len() == m_b.len() && m_a.iter().zip(m_b).all(|((k_a, a), (k_b, b))| k_a == k_b && a.same_as(b))
+            },
+            _ => false,
+        }
+    }
+}
 
-            // Set the current result to the most recent instruction's result
-            self.result = step_result;
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(false))
+    }
+}
 
-            // If there is an error, don't execute any further.
-            if let Err(msg) = &self.result {
-                return Err(msg.to_string());
-            }
+// Arithmetic on values works as it does in `M`. Only numbers and bools can be used in it,
+// so an op with text, a list, or a map gives None, where `M` fails with a type mismatch.
 
-            // Increment the program counter
-            self.increment_counter();
+impl ops::Add for Val {
+    type Output = Option<Val>;
 
-            Ok(())
-        }
-        else {
-            return Err(format!("No command found at counter index {}", self.program_counter));
-        }
+    /// Adds two numbers, like `MAcab`.
+    fn add(self, other: Val) -> Option<Val> {
+        self.arithmetic(other, |n_a, n_b| n_a + n_b)
     }
+}
 
-    /// Runs an instruction and returns a result.
-    /// This is the main location where parser tokens are mapped to
-    /// execution implementations. Side effects abound as these implementations 
-    /// can and will manipulate this program's data storage.
-    fn evaluate(&mut self, command: &LbToken) -> Result<(), String> {
-        match command {
+impl ops::Sub for Val {
+    type Output = Option<Val>;
 
-            // Sa4
-            SaveNumber((var_name, float_val)) => {
-                self.data.set_var(*var_name, &Val::Number(*float_val))
-            },
+    /// Subtracts one number from another, like `MScab`.
+    fn sub(self, other: Val) -> Option<Val> {
+        self.arithmetic(other, |n_a, n_b| n_a - n_b)
+    }
+}
 
-            // Sa'Hello'
-            SaveStr((var_name, string_val)) => {
-                self.data.set_var(*var_name, &Val::Text(string_val.clone()))
-            },
+impl ops::Mul for Val {
+    type Output = Option<Val>;
 
-            // Cab
-            Copy((from_var, to_var)) => {
-                self.data.copy(*from_var, *to_var)
-            },
+    /// Multiplies two numbers, like `MMcab`.
+    fn mul(self, other: Val) -> Option<Val> {
+        self.arithmetic(other, |n_a, n_b| n_a * n_b)
+    }
+}
 
-            // Aab
-            Append((base_var, add_var)) => {
-                // get base string
-                let Some(base_val) = self.data.get_var(*base_var).to_owned() else {
-                    return Err(format!("A: {base_var} is not a variable"));
-                };
-                let mut base_string = match base_val {
-                    Val::Text(text) => text.to_string(),
-                    Val::Number(num) => num.to_string(),
-                };
-                
-                // get string to append
-                let Some(add_val) = self.data.get_var(*add_var).to_owned() else {
-                    return Err(format!("A: {add_var} is not a variable"));
-                };
-                let add_string = match add_val {
-                    Val::Text(text) => text.to_string(),
-                    Val::Number(num) => num.to_string(),
-                };
+impl ops::Div for Val {
+    type Output = Option<Val>;
 
-                // append the strings and save them to the base variable
-                base_string.push_str(&add_string);
-                self.data.set_var(*base_var, &Val::Text(base_string))
-            },
+    /// Divides one number by another, like `MDcab`.
+    /// Dividing by zero gives None, where `M` fails with a division by zero error.
+    fn div(self, other: Val) -> Option<Val> {
+        if other.as_number() == Some(0.0) {
+            return None;
+        }
+        self.arithmetic(other, |n_a, n_b| n_a / n_b)
+    }
+}
 
-            // Pa
-            PrintVar(var_name) => {
-                let print_str = self.data.get_var(*var_name).expect("Could not get variable.");
-                self.output_buffer.push_str(format!("{}", print_str).as_str());
-                Ok(())
-            },
+impl ops::Neg for Val {
+    type Output = Option<Val>;
 
-            // P'Hello'
-            PrintStr(string_val) => {
-                self.output_buffer.push_str(format!("{}", string_val).as_str());
-                Ok(())
-            },
+    /// Negates a number.
+    fn neg(self) -> Option<Val> {
+        self.as_number().map(|num| Val::Number(-num))
+    }
+}
 
-            // MAcab
-            MathOp((op, target, a, b)) => {
-                let Val::Number(n_a) = self.data
-                    .get_var(*a)
-                    .expect(&format!("M: Could not get variable {a}"))
-                    .to_owned() 
-                else {
-                    return Err(format!("M: Variable {a} is not a number"));
-                };
-                let Val::Number(n_b) = self.data
-                    .get_var(*b)
-                    .expect(&format!("M: Could not get variable {b}"))
-                    .to_owned() 
-                else {
-                    return Err(format!("M: Variable {b} is not a number"));
-                };
+impl PartialEq for Val {
+    /// Values are equal if they have the same type and contents, like `BSab`.
+    /// Numbers are compared by value, as `MEcab` does, so NaN is not equal to itself.
+    fn eq(&self, other: &Val) -> bool {
+        self.same_as(other)
+    }
+}
 
-                // compute result
-                let result = match op {
-                    'A' => n_a + n_b,                               // add
-                    'S' => n_a - n_b,                               // subtract
-                    'M' => n_a * n_b,                               // multiply
-                    'D' => n_a / n_b,                               // divide
-                    'R' => n_a % n_b,                               // remainder
-                    'E' => if n_a == n_b { 1.0 } else { 0.0 },      // equal to
-                    'G' => if n_a > n_b { 1.0 } else { 0.0 },       // greater than
-                    'L' => if n_a < n_b { 1.0 } else { 0.0 },       // less than
-                    _ => {
-                        return Err(format!("M: Invalid op {}", op));
-                    },
-                };
-                // save result to storage
-                self.data.set_var(*target, &Val::Number(result))
-            },
+impl PartialOrd for Val {
+    /// Numbers are ordered by value, like `MGcab` and `MLcab`, and bools count as 1 and 0.
+    /// So `true` and `1` compare as equal, as in `MEcab`, even though `==` says they differ.
+    /// Other values can't be ordered, though equal values compare as equal.
+    fn partial_cmp(&self, other: &Val) -> Option<cmp::Ordering> {
+        match (self.as_number(), other.as_number()) {
+            (Some(n_a), Some(n_b)) => n_a.partial_cmp(&n_b),
+            _ if self.same_as(other) => Some(cmp::Ordering::Equal),
+            _ => None,
+        }
+    }
+}
 
-            // BAcab
-            BoolOp((op, target, a, b)) => {
-                let b_a = self.data
-                    .var_as_bool(*a)
-                    .expect(&format!("B: Could not get variable {a}"))
-                    .to_owned();
-                let b_b = self.data
-                    .var_as_bool(*b)
-                    .expect(&format!("B: Could not get variable {b}"))
-                    .to_owned();
+/// Formats a number as text. See [Val::format].
+fn format_number(num: f64, exact: bool) -> String {
+    // only integers up to 2^53 can be represented exactly
+    if !exact && num.is_finite() && num.abs() <= MAX_EXACT_INT {
+        let rounded = num.round();
+        let tolerance = INTEGER_TOLERANCE * f64::EPSILON * num.abs().max(1.0);
+        if (num - rounded).abs() <= tolerance {
+            return format!("{}", rounded);
+        }
+    }
+    format!("{}", num)
+}
 
-                // compute result
-                let result = match op {
-                    'E' => if b_a == b_b { 1.0 } else { 0.0 },                       // equal to
-                    'A' => if b_a && b_b { 1.0 } else { 0.0 },                       // and
-                    'O' => if b_a || b_b { 1.0 } else { 0.0 },                       // or
-                    'X' => if (b_a && !b_b) || (!b_a && b_b) { 1.0 } else { 0.0 }, // xor
-                    _ => {
-                        return Err(format!("B: Invalid op {}", op));
-                    },
-                };
-                // save result to storage
-                self.data.set_var(*target, &Val::Number(result))
-            },
+/// Reads text as a number, ignoring surrounding whitespace, as `TN` does.
+pub(crate) fn parse_number(text: &str) -> Option<f64> {
+    text.trim().parse::<f64>().ok()
+}
 
-            // Ra
-            ResetVar(var_name) => {
-                self.data.reset_var(*var_name)
-            },
+/// Checks that an index is inside a list of the given length,
+/// and converts it to a usize. Fractional indexes are truncated.
+fn list_index(list_var: char, index: f64, len: usize) -> Result<usize, LbError> {
+    let index = index.trunc();
+    if index < 0.0 || index >= len as f64 {
+        return Err(LbError::IndexOutOfBounds { list: list_var, index, len });
+    }
+    Ok(index as usize)
+}
 
-            // Na
-            Negate(var_name) => {
-                let current = self.data
-                    .var_as_bool(*var_name)
-                    .expect(&format!("Could not get variable {var_name}"))
-                    .to_owned();
-                if current {
-                    return self.data.reset_var(*var_name);
-                }
-                else {
-                    return self.data.set_var(*var_name, &Val::Number(1.0));
-                }
-            },
+/// The outcome of a call to [LbProgram::step].
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    /// An instruction ran successfully.
+    /// When stepping into a loop, this is the loop's body.
+    Ran(LbToken),
 
-            // RA
-            ResetAll => {
-                self.data.reset_all()
-            },
+    /// The program paused at a breakpoint, so nothing ran.
+    /// The next step resumes it.
+    Paused,
 
-            // LaX
-            Loop((times, subcommand)) => {
-                // get number of loops
-                let Val::Number(t) = self.data
-                    .get_var(*times)
-                    .expect(&format!("L: Could not get variable {times}"))
-                    .to_owned() 
-                else {
-                    return Err(format!("L: Variable {times} is not a number"));
-                };
+    /// The program had already finished, so nothing ran.
+    Finished,
+
+    /// An instruction failed, which finishes the program.
+    Error(LbError),
+}
 
-                let mut loops = t.floor() as usize;
+/// Where a program stopped when run with [LbProgram::run_until_yield].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum YieldResult {
+    /// The program ran for as many instructions as [LbProgram::set_yield_interval] allows,
+    /// possibly partway through a loop, block, or executed program.
+    /// Calling [LbProgram::run_until_yield] again carries on from there.
+    Yielded,
 
-                if loops > self.loop_limit {
-                    return Err(format!("L: loop count exceeds loop limit"));
-                }
-                
-                // execute subcommand that many times
-                while loops > 0 {
-                    if let Err(msg) = self.evaluate(subcommand) {
-                        return Err(msg);
-                    }
-                    loops -= 1;
-                }
+    /// The program paused at a breakpoint. See [LbProgram::set_breakpoint_callback].
+    Paused,
 
-                Ok(())
-            },
+    /// The program has finished.
+    Finished,
+}
 
-            // IaX
-            IfStatement((cond, subcommand)) => {
-                // get condition as bool
-                let c = self.data
-                    .var_as_bool(*cond)
-                    .expect(&format!("I: Could not get variable {cond}"))
-                    .to_owned();
-                
-                // execute subcommand if condition is true
-                if c {
-                    return self.evaluate(subcommand);
-                }
+/// Lets a program print into a [String]. Used by [LbProgram::new].
+pub struct StringOutput<'a>(&'a mut String);
 
-                Ok(())
-            },
+impl<'a> StringOutput<'a> {
+    /// Returns a writer that appends to the given string.
+    pub fn new(out: &'a mut String) -> StringOutput<'a> {
+        StringOutput(out)
+    }
+}
 
-            // UaX
-            Unless((cond, subcommand)) => {
-                // get condition as bool
-                let c = self.data
-                    .var_as_bool(*cond)
-                    .expect(&format!("I: Could not get variable {cond}"))
-                    .to_owned();
-                
-                // execute subcommand if condition is false
-                if !c {
-                    return self.evaluate(subcommand);
-                }
+impl Write for StringOutput<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // programs only ever write whole strings, so this is always valid UTF-8
+        self.0.push_str(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
 
-                Ok(())
-            },
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-            // WaX
-            WhileLoop((cond, subcommand)) => {
-                // get condition as bool
-                let mut c = self.data
-                    .var_as_bool(*cond)
-                    .expect(&format!("W: Could not get variable {cond}"))
-                    .to_owned();
-                
-                // be sure to count loops
-                let mut loops: usize = 0;
+/// What a program should do when it reaches an instruction.
+/// See [LbProgram::set_breakpoint_callback].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakAction {
+    /// Run the instruction.
+    Continue,
 
-                // execute subcommand until condition evaluates false or loop count reached
-                while c {
-                    loops += 1;
-                    if loops > self.loop_limit {
-                        return Err(format!("W: loop count exceeds loop limit"));
-                    }
+    /// Stop before the instruction, so the program can be resumed later.
+    Pause,
 
-                    if let Err(msg) = self.evaluate(subcommand) {
-                        return Err(msg);
-                    }
+    /// Stop the program with an error.
+    Abort,
+}
 
-                    c = self.data
-                    .var_as_bool(*cond)
-                    .expect(&format!("W: Could not get variable {cond}"))
-                    .to_owned();
-                }
+/// A function that decides what to do before each instruction.
+/// See [LbProgram::set_breakpoint_callback].
+type BreakpointCallback = dyn FnMut(&LbToken, &LbStorage, (usize, usize)) -> BreakAction;
 
-                Ok(())
-            },
+/// A function that receives printed text. See [LbProgram::set_print_callback].
+type PrintCallback = dyn FnMut(&str);
 
-            // GXa1
-            GetInput((op, var, num)) => {
-                let index = num.floor() as usize;
-                let Some(input) = self.input_vec.get(index) else {
-                    return Err(format!("G: no input at index {num}"))
-                };
-                let input_item = input.to_string();
+/// A record of one instruction that a program ran. See [LbProgram::set_trace_callback].
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    /// The instruction that ran.
+    pub token: LbToken,
 
-                if !storage::is_var(var) {
-                    return Err(format!("G: character {var} is not a variable name"));
-                }
-                match *op {
-                    'N' => {
-                        if let Some(val) = input_item.parse::<f64>().ok() {
-                            self.data.set_var(*var, &Val::Number(val))
-                        }
-                        else {
-                            Err(format!("G: Could not parse input into number: {input_item}"))
-                        }
-                    },
-                    'S' => {
-                        self.data.set_var(*var, &Val::Text(String::from(input_item)))
-                    },
-                    _ => Err(format!("G: invalid operation {op}")),
-                }
-            },
+    /// The line and column of the top-level instruction this one ran as part of.
+    /// Instructions inside an executed program have the position of the `Xzacbd` command.
+    pub position: (usize, usize),
 
-            // Xzacbd
-            Execute((fn_var, argmap)) => {
-                // validate argmap
-                for c in argmap.chars() {
-                    if !storage::is_var(&c) {
-                        return Err(format!("X: Character {c} is not a variable name"));
-                    }
-                }
+    /// The value of each variable the instruction names, including in nested instructions,
+    /// from before it ran.
+    pub before: Vec<(char, Val)>,
 
-                // get string to execute
-                let Val::Text(prog) = self.data
-                    .get_var(*fn_var)
-                    .expect(&format!("X: Could not get variable {fn_var}"))
-                    .to_owned() 
-                else {
-                    return Err(format!("X: Variable {fn_var} is not a string"));
-                };
+    /// The value of the same variables once it stopped.
+    pub after: Vec<(char, Val)>,
+}
 
-                // substitute provided arguments
-                let prog_with_params = Self::apply_argmap(prog, argmap.to_string());
+/// Where an error happened in a program's source code. See [LbProgram::error_frames].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorFrame {
+    /// The byte range of the instruction that failed.
+    pub span: Range<usize>,
 
-                // create lexer to parse the string
-                let sub_lex = LbToken::lexer(&prog_with_params);
-                // create new program using this program's params
-                let sub_program = LbProgram::new(
-                    sub_lex,
-                    self.data, 
-                    self.input_vec, 
-                    self.output_buffer, 
-                    self.loop_limit.clone());
+    /// The line and column where the instruction starts, counting from 1.
+    pub line: usize,
+    pub column: usize,
 
-                match sub_program {
-                    Ok(mut program) => program.run(),
-                    Err(msg) => Err(msg),
-                }
-            },
+    /// If the instruction is in an executed program, the variable the program came from,
+    /// like the `z` in `Xzacbd`. Positions are then within that variable's text.
+    /// `None` for the main program and for string literals run with `X'Pa'`.
+    pub program_var: Option<char>,
 
-            // F
-            Finish => {
-                self.finished = true;
-                Ok(())
-            },
+    /// The source code of the instruction that failed.
+    pub slice: String,
+}
 
-            _ => Err(format!("Unrecognized instruction at counter index {}", self.program_counter)),
+impl ErrorFrame {
+    /// Describes where this frame is, like ``line 2, column 5 (`MQcab`)``, or if `executed` is true,
+    /// like ``program executed from variable z, offset 14 (`MQcab`)``.
+    /// Long instructions are cut short after their first line or 16 characters.
+    fn describe(&self, executed: bool) -> String {
+        let first_line = self.slice.lines().next().unwrap_or("");
+        let mut slice: String = first_line.chars().take(16).collect();
+        if slice.len() < self.slice.len() {
+            slice = format!("{}…", slice.trim_end());
+        }
+        match (executed, self.program_var) {
+            (false, _) => format!("line {}, column {} (`{slice}`)", self.line, self.column),
+            (true, Some(var)) => format!("program executed from variable {var}, offset {} (`{slice}`)", self.span.start),
+            (true, None) => format!("program executed from a string, offset {} (`{slice}`)", self.span.start),
         }
     }
+}
+
+/// Counts of what a program has done so far. See [LbProgram::stats].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RunStats {
+    /// How many instructions have run, counting every instruction
+    /// inside a loop, block, or executed program.
+    pub instructions: u64,
+
+    /// How many times the body of an `La` or `Wa` loop has run, across all loops.
+    pub loop_iterations: u64,
+
+    /// The most Execute (`Xzacbd`) commands that were running inside each other at once.
+    pub max_execute_depth: usize,
+
+    /// How many times something was printed.
+    pub prints: u64,
+
+    /// How many bytes of text were printed.
+    pub output_bytes: u64,
+
+    /// How long the program has spent running, if [LbProgram::measure_time] was set.
+    /// Time spent paused between steps isn't counted.
+    pub wall_time: Option<Duration>,
+}
+
+/// Where a loop, conditional, block, or executed program stopped when the program yielded,
+/// so it can carry on from there. See [LbProgram::run_until_yield].
+#[derive(Debug, Clone)]
+enum ResumeFrame {
+    /// A loop, with its progress, stopped in its body.
+    Loop(LoopState),
+
+    /// A conditional stopped in its body (0) or its else command (1).
+    Branch(usize),
+
+    /// A block stopped at the instruction with the given index.
+    Block(usize),
+
+    /// An executed program stopped partway through.
+    Execute(ExecuteFrame),
+}
+
+/// A program being run by an Execute command (`Xzacbd>r`).
+#[derive(Debug, Clone)]
+struct ExecuteFrame {
+    /// The program's source code, and the variable it came from, if any.
+    source: (String, Option<char>),
+
+    /// The program's instructions, with their variables renamed by the Execute command.
+    commands: Vec<(LbToken, SpanTree)>,
+
+    /// The index of the next instruction to run.
+    next: usize,
+
+    /// Where the value returned with `Ya` is stored, if anywhere.
+    dest: Option<char>,
+}
+
+/// The progress of an `La` or `Wa` loop that is running.
+#[derive(Debug, Clone)]
+enum LoopState {
+    /// An `La` loop, with the number of times its body has left to run.
+    Times(usize),
+
+    /// A `Wa` loop, with its condition variable
+    /// and the number of times its body has run.
+    While(char, usize),
+}
+
+/// A struct that represents a Letterbox program.
+/// It combines a list of parsed instructions and a [LbStorage] struct,
+/// executing each instruction in order.
+/// Output is written to `W`, which collects it into a [String] by default,
+/// and input is read from `I`, which is a list of strings by default.
+pub struct LbProgram<'a, W = StringOutput<'a>, I = &'a Vec<String>> {
+    /// An ordered list of parsed instructions. See [LbToken] for details.
+    pub program_list: Vec<LbToken>,
+
+    /// The line and column in the source code where each instruction starts,
+    /// counting from 1.
+    positions: Vec<(usize, usize)>,
+
+    /// Where each instruction, and the instructions nested in it, are in the source code.
+    span_trees: Rc<Vec<SpanTree>>,
+
+    /// The source code of the program and of each Execute command that is running,
+    /// innermost last, with the variable each executed program came from.
+    sources: Vec<(String, Option<char>)>,
+
+    /// Where the last error happened, innermost first. See [LbProgram::error_frames].
+    error_frames: Vec<ErrorFrame>,
+
+    /// Whether the innermost frame of the current error has been recorded.
+    error_located: bool,
+
+    /// An integer that indicates the number of the next 
+    /// instruction to execute from the program list.
+    program_counter: usize,
+
+    /// A reference to a [LbStorage] struct which will be modified 
+    /// by the execution of this program.
+    data: ProgramStorage<'a>,
+
+    /// If true, this program has completed execution and can
+    /// no longer be run or stepped through.
+    pub finished: bool,
+
+    /// The result of the last executed instruction.
+    /// If this program is finished, this will be considered the
+    /// result of the whole program.
+    pub result: Result<(), LbError>,
+
+    /// The exit status reported by `Fa`.
+    /// It is 0 if the program finishes any other way, including with `F`.
+    pub exit_status: f64,
+
+    /// Provides all input passed into this program from the environment
+    /// i.e. the command line.
+    input: I,
+
+    /// Where output will be printed.
+    output: W,
+
+    /// The maximum number of times a loop can run in this program.
+    /// If a single loop exceeds this number, the program will crash.
+    pub loop_limit: usize,
+
+    /// The most instructions this program can run, or 0 for no limit.
+    /// See [LbProgram::set_max_instructions].
+    max_instructions: u64,
+
+    /// The most times a single loop's body can run, or 0 for no limit.
+    /// See [LbProgram::set_max_loop_iterations].
+    max_loop_iterations: u64,
+
+    /// What this program has done so far. See [LbProgram::stats].
+    stats: RunStats,
+
+    /// How many instructions run between yields, or 0 to never yield.
+    /// See [LbProgram::set_yield_interval].
+    yield_interval: u64,
+
+    /// How many instructions have run since [LbProgram::run_until_yield] was called.
+    since_yield: u64,
+
+    /// True while [LbProgram::run_until_yield] is running, so the program can yield.
+    can_yield: bool,
+
+    /// True if the program is yielding, so every loop, conditional, block,
+    /// and executed program that is running should record where it is and stop.
+    yielding: bool,
+
+    /// Where each of the instructions that were running when the program yielded stopped,
+    /// innermost first. Each one takes its own frame as it carries on.
+    resume: Vec<ResumeFrame>,
+
+    /// Set from outside the program to stop it. See [LbProgram::cancel_token].
+    cancelled: Arc<AtomicBool>,
+
+    /// If true, [LbProgram::step] steps into loops at the top level of the program,
+    /// running one execution of the loop's body at a time.
+    /// Otherwise (the default), it runs each loop all at once.
+    pub step_into: bool,
+
+    /// If true, numbers are printed exactly as stored.
+    /// Otherwise (the default), numbers within rounding error of an integer
+    /// are printed as that integer. See [Val::format].
+    pub exact_numbers: bool,
+
+    /// If true (the default), printed text is written to the output.
+    /// Otherwise, it only goes to the print callback. See [LbProgram::set_print_callback].
+    pub write_output: bool,
+
+    /// If true, the program fails before running anything if any part of it,
+    /// at any depth, couldn't be parsed, with an [LbError::UnparsedSource] listing each part.
+    /// Otherwise (the default), the program only fails if it reaches a part that couldn't be parsed.
+    pub strict: bool,
+
+    /// If true, [LbProgram::stats] includes how long the program has spent running.
+    /// Otherwise (the default), the time isn't measured.
+    pub measure_time: bool,
+
+    /// Called by `GQ` commands to ask the host for input while running.
+    /// See [LbProgram::set_input_callback].
+    input_callback: Option<Box<dyn FnMut() -> Option<String>>>,
+
+    /// Read from by `GNa1` and `GSab` commands for inputs that weren't given, if set.
+    /// See [LbProgram::set_stdin_fallback].
+    stdin_fallback: Option<Box<dyn BufRead>>,
+
+    /// The lines read from [LbProgram::stdin_fallback], which come after the given inputs.
+    fallback_inputs: Vec<Val>,
+
+    /// Called with a [TraceEntry] for each instruction that runs, if set.
+    /// See [LbProgram::set_trace_callback].
+    trace_callback: Option<Box<dyn FnMut(TraceEntry)>>,
+
+    /// Called with the text of each print, if set.
+    /// See [LbProgram::set_print_callback].
+    print_callback: Option<Box<PrintCallback>>,
+
+    /// Called before each instruction runs, if set.
+    /// See [LbProgram::set_breakpoint_callback].
+    breakpoint_callback: Option<Box<BreakpointCallback>>,
+
+    /// True if a breakpoint paused the program partway through an instruction,
+    /// so it should pause before the next one.
+    pause_pending: bool,
+
+    /// True if the program just paused, so the breakpoint it paused at should be skipped.
+    resuming: bool,
+
+    /// How many instructions are running inside the current top-level instruction.
+    nesting: usize,
+
+    /// The source of random numbers for `Da`.
+    /// Seeded from the system clock unless [LbProgram::set_seed] is called.
+    rng: LbRng,
+
+    /// The source of timing for `Qa` and `Ea`.
+    /// Uses the system clock unless [LbProgram::set_clock] is called.
+    clock: Box<dyn LbClock>,
+
+    /// The index in the program list of each label (`:a`).
+    labels: HashMap<char, usize>,
+
+    /// The label a jump has been made to, if any.
+    /// Set by `Ja` and `JIab`, and followed once the current instruction stops.
+    pending_jump: Option<char>,
+
+    /// How many times the program has jumped backwards to each label.
+    /// Like a loop, a label can't be jumped back to more than [LbProgram::loop_limit] times.
+    back_jumps: HashMap<char, usize>,
+
+    /// How many Execute (`Xzacbd`) commands are currently running.
+    execute_depth: usize,
+
+    /// The value returned by `Ya` from the innermost executed program, if any.
+    return_value: Option<Val>,
+
+    /// The loop at the program counter, if it is being stepped into.
+    active_loop: Option<LoopState>,
+}
+
+impl<'a> LbProgram<'a> {
+    /// Create a new unexecuted [LbProgram] from the contents of
+    /// the given lexer. Requires a reference to a [LbStorage] struct.
+    /// Output is collected into the given string.
+    pub fn new(lex: Lexer<LbToken>,
+        starting_data: impl Into<ProgramStorage<'a>>,
+        inv: &'a Vec<String>,
+        out: &'a mut String,
+        loop_limit: usize,
+    ) -> Result<LbProgram<'a>, LbError> {
+        Self::with_output(lex, starting_data, inv, StringOutput::new(out), loop_limit)
+    }
+}
+
+impl<'a, W: Write> LbProgram<'a, W> {
+    /// Create a new unexecuted [LbProgram] like [LbProgram::new],
+    /// but with output written to any [Write] sink, such as a file or socket.
+    /// Output is written as it is printed and the sink is never flushed,
+    /// so wrap it in a [std::io::BufWriter] if it is slow to write to.
+    /// If writing fails, the program stops with an error.
+    pub fn with_output(lex: Lexer<LbToken>,
+        starting_data: impl Into<ProgramStorage<'a>>,
+        inv: &'a Vec<String>,
+        out: W,
+        loop_limit: usize,
+    ) -> Result<LbProgram<'a, W>, LbError> {
+        LbProgram::with_io(lex, starting_data, inv, out, loop_limit)
+    }
+}
+
+impl<'a, W: Write, I: LbInput> LbProgram<'a, W, I> {
+    /// Create a new unexecuted [LbProgram] like [LbProgram::with_output],
+    /// but with input read from any [LbInput], such as an [crate::input::IterInput]
+    /// that produces inputs on demand.
+    pub fn with_io(lex: Lexer<LbToken>,
+        starting_data: impl Into<ProgramStorage<'a>>,
+        input: I,
+        out: W,
+        loop_limit: usize,
+    ) -> Result<LbProgram<'a, W, I>, LbError> {
+        Self::from_ast(&LbAst::from_lexer(lex), starting_data, input, out, loop_limit)
+    }
+
+    /// Create a new unexecuted [LbProgram] like [LbProgram::with_io],
+    /// from a program that has already been lexed, such as by [crate::ast::parse].
+    /// The same [LbAst] can be run any number of times without lexing it again.
+    pub fn from_ast(ast: &LbAst,
+        starting_data: impl Into<ProgramStorage<'a>>,
+        input: I,
+        out: W,
+        loop_limit: usize,
+    ) -> Result<LbProgram<'a, W, I>, LbError> {
+        let plist: Vec<LbToken> = ast.instructions.iter().map(|instruction| instruction.node.clone()).collect();
+        let span_trees: Vec<SpanTree> = ast.instructions.iter().map(|instruction| instruction.spans.clone()).collect();
+        let offsets: Vec<usize> = span_trees.iter().map(|tree| tree.span.start).collect();
+        let positions = line_columns(&ast.source, &offsets);
+        let labels = Self::find_labels(&plist)?;
+        let prog = LbProgram {
+            program_list: plist,
+            positions,
+            span_trees: Rc::new(span_trees),
+            sources: vec![(ast.source.clone(), None)],
+            error_frames: Vec::new(),
+            error_located: false,
+            program_counter: 0,
+            data: starting_data.into(),
+            finished: false,
+            result: Ok(()),
+            exit_status: 0.0,
+            input,
+            output: out,
+            loop_limit,
+            max_instructions: 0,
+            max_loop_iterations: 0,
+            stats: RunStats::default(),
+            yield_interval: DEFAULT_YIELD_INTERVAL,
+            since_yield: 0,
+            can_yield: false,
+            yielding: false,
+            resume: Vec::new(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            step_into: false,
+            exact_numbers: false,
+            input_callback: None,
+            stdin_fallback: None,
+            fallback_inputs: Vec::new(),
+            write_output: true,
+            measure_time: false,
+            strict: false,
+            trace_callback: None,
+            print_callback: None,
+            breakpoint_callback: None,
+            pause_pending: false,
+            resuming: false,
+            nesting: 0,
+            rng: LbRng::from_time(),
+            clock: Box::new(SystemClock::new()),
+            labels,
+            pending_jump: None,
+            back_jumps: HashMap::new(),
+            execute_depth: 0,
+            return_value: None,
+            active_loop: None,
+        };
+
+        Ok(prog)
+    }
+
+    /// Seeds this program's random number generator,
+    /// so that every run with the same seed produces the same random numbers.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = LbRng::new(seed);
+    }
+
+    /// Sets the function that `GQ` commands call to ask the host for input while running.
+    /// It should return `None` if no more input is available.
+    pub fn set_input_callback<F: FnMut() -> Option<String> + 'static>(&mut self, callback: F) {
+        self.input_callback = Some(Box::new(callback));
+    }
+
+    /// Sets a reader, such as stdin, for `GNa1` and `GSab` commands to fall back on
+    /// when there is no input at their index. Its lines, without their line breaks,
+    /// are appended to the given inputs as they are needed, so reading an index past the end
+    /// also reads the lines before it, and `G#a` counts the lines read so far.
+    /// Once the reader reaches its end, missing inputs are errors as usual,
+    /// and if it fails, the program fails with [LbError::InputReadFailed].
+    pub fn set_stdin_fallback<R: BufRead + 'static>(&mut self, reader: R) {
+        self.stdin_fallback = Some(Box::new(reader));
+    }
+
+    /// Sets a function to call with a [TraceEntry] for every instruction that runs,
+    /// including each one inside a loop, block, or executed program.
+    /// Each entry is passed on as its instruction stops, even if it fails,
+    /// so a loop's entry comes after the entries for its body.
+    /// No trace is recorded unless this is set.
+    pub fn set_trace_callback<F: FnMut(TraceEntry) + 'static>(&mut self, callback: F) {
+        self.trace_callback = Some(Box::new(callback));
+    }
+
+    /// Sets a function to call with the exact text of every print, as it is printed,
+    /// including prints inside executed programs. `Oa` and `O'text'` include their newline.
+    /// It is called before the text is written to the output,
+    /// or instead of that if [LbProgram::write_output] is false.
+    pub fn set_print_callback<F: FnMut(&str) + 'static>(&mut self, callback: F) {
+        self.print_callback = Some(Box::new(callback));
+    }
+
+    /// Sets a function to call before every instruction runs,
+    /// including each one inside a loop, block, or executed program.
+    /// It is given the instruction, the program's storage, and the line and column
+    /// of the top-level instruction, and returns whether to continue, pause, or abort.
+    /// 
+    /// Pausing stops [LbProgram::run] or [LbProgram::step] before the instruction,
+    /// and calling either again resumes from there.
+    /// A pause inside a running instruction, like a loop, can't stop it partway through,
+    /// so the program pauses once it finishes instead.
+    /// Set [LbProgram::step_into] to pause between the executions of a loop's body.
+    pub fn set_breakpoint_callback<F>(&mut self, callback: F)
+    where F: FnMut(&LbToken, &LbStorage, (usize, usize)) -> BreakAction + 'static {
+        self.breakpoint_callback = Some(Box::new(callback));
+    }
+
+    /// Sets the clock that `Qa` commands use to pause the program,
+    /// and `Ea` commands use to tell the time.
+    pub fn set_clock<C: LbClock + 'static>(&mut self, clock: C) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Sets the most instructions this program can run before it stops with an error,
+    /// or 0 (the default) for no limit.
+    /// Every instruction counts, including each one inside a loop, block, or executed program.
+    /// Any output printed before the limit was reached stays in the output buffer.
+    pub fn set_max_instructions(&mut self, max: u64) {
+        self.max_instructions = max;
+    }
+
+    /// Sets the most times the body of any single `La` or `Wa` loop can run,
+    /// or 0 (the default) for no limit. A `Wa` loop stops with an error, naming
+    /// where the loop is, when its body is about to run one time too many.
+    /// An `La` loop asked to run too many times stops with the same error before it starts.
+    /// This applies along with [LbProgram::loop_limit], so whichever is lower is reached first.
+    pub fn set_max_loop_iterations(&mut self, max: u64) {
+        self.max_loop_iterations = max;
+    }
+
+    /// Sets how many instructions [LbProgram::run_until_yield] runs before it returns,
+    /// or 0 to run until the program finishes. The default is 1000.
+    /// Every instruction counts, including each one inside a loop, block, or executed program.
+    pub fn set_yield_interval(&mut self, interval: u64) {
+        self.yield_interval = interval;
+    }
+
+    /// Returns a handle that can stop this program from another thread.
+    /// Once it is set to true, the program stops with an error before its next instruction,
+    /// including instructions inside loops and executed programs.
+    /// A `Qa` sleep that is already running stops early, as long as the program's clock
+    /// checks for cancellation while it waits, as the default [SystemClock] does.
+    /// See [LbClock::sleep_until_cancelled].
+    pub fn cancel_token(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    /// Returns where the error that stopped this program happened, innermost first.
+    /// The first frame is the instruction that failed, which may be nested in a loop or block.
+    /// If it is in an executed program, the next frame is the Execute command that ran it, and so on.
+    /// Empty if the program hasn't failed.
+    pub fn error_frames(&self) -> &[ErrorFrame] {
+        &self.error_frames
+    }
+
+    /// Describes an error that stopped this program along with where it happened, from [LbProgram::error_frames].
+    /// An error in an executed program names the variable the program came from,
+    /// the byte offset of the failing instruction in it, and the instruction,
+    /// followed by each Execute command it was run from, out to the main program.
+    /// If no frames were recorded, only describes the error.
+    /// 
+    /// ```
+    /// use letterbox_lang::prelude::*;
+    /// 
+    /// let mut data = LbStorage::new();
+    /// let mut out = String::new();
+    /// let inv = Vec::<String>::new();
+    /// let lex = LbToken::lexer("Sb0 Sz'Pa MQcab' Xz");
+    /// let mut program = LbProgram::new(lex, &mut data, &inv, &mut out, 1000).unwrap();
+    /// let error = program.run().unwrap_err();
+    /// assert_eq!(program.describe_error(&error), "error in program executed from variable z, offset 3 (`MQcab`), \
+    ///     run from line 1, column 18 (`Xz`): M: Division by zero in MQcab");
+    /// ```
+    pub fn describe_error(&self, error: &LbError) -> String {
+        let Some((main, executed)) = self.error_frames.split_last() else {
+            return error.to_string();
+        };
+        let mut chain: Vec<String> = executed.iter().map(|frame| frame.describe(true)).collect();
+        chain.push(main.describe(false));
+        let place = if executed.is_empty() { "at" } else { "in" };
+        format!("error {place} {}: {error}", chain.join(", run from "))
+    }
+
+    /// Returns counts of what this program has done so far,
+    /// such as how many instructions it has run and how much it has printed.
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    /// Run the program until it finishes or pauses at a breakpoint.
+    /// See [LbProgram::set_breakpoint_callback].
+    pub fn run(&mut self) -> Result<(), LbError> {
+        while let StepResult::Ran(_) = self.step() {}
+
+        self.result.clone()
+    }
+
+    /// Run the program for about as many instructions as [LbProgram::set_yield_interval] allows,
+    /// then return so the host can do other work, such as in an async task.
+    /// The program can stop anywhere, even inside a loop or executed program,
+    /// and carries on from there the next time this is called.
+    /// Running a program in any number of calls gives the same result as [LbProgram::run].
+    pub fn run_until_yield(&mut self) -> Result<YieldResult, LbError> {
+        self.since_yield = 0;
+        self.can_yield = true;
+        let result = loop {
+            if self.yield_due() && self.resume.is_empty() && !self.finished {
+                break Ok(YieldResult::Yielded);
+            }
+            match self.step() {
+                StepResult::Ran(_) => {},
+                StepResult::Paused if self.yielding => {
+                    self.yielding = false;
+                    break Ok(YieldResult::Yielded);
+                },
+                StepResult::Paused => break Ok(YieldResult::Paused),
+                StepResult::Finished => break self.result.clone().map(|_| YieldResult::Finished),
+                StepResult::Error(e) => break Err(e),
+            }
+        };
+        self.can_yield = false;
+        result
+    }
+
+    /// Returns true if the program has run enough instructions that it should yield.
+    fn yield_due(&self) -> bool {
+        self.can_yield && self.yield_interval > 0 && self.since_yield >= self.yield_interval
+    }
+
+    /// Run the next instruction as indicated by the program counter,
+    /// and return the instruction that ran.
+    /// If [LbProgram::step_into] is set, a loop runs one execution of its body per step.
+    /// An error finishes the program.
+    pub fn step(&mut self) -> StepResult {
+        if self.finished {
+            return StepResult::Finished;
+        }
+        if self.strict && self.program_counter == 0 && self.stats.instructions == 0 {
+            let instructions = self.program_list.iter().zip(self.span_trees.iter());
+            let errors = parse_errors(&self.sources[0].0, instructions);
+            if !errors.is_empty() {
+                let error = LbError::UnparsedSource(errors);
+                self.result = Err(error.clone());
+                self.finished = true;
+                return StepResult::Error(error);
+            }
+        }
+
+        let start = self.measure_time.then(Instant::now);
+
+        // Get the instruction at the next position in the program.
+        self.data.acquire();
+        let step_result = match self.program_list.get(self.program_counter) {
+            // Clone the token to prevent an immutable borrow
+            Some(token) => self.step_command(&token.clone()),
+            None => Err(LbError::NoCommand(self.program_counter)),
+        };
+        self.data.release();
+
+        if let Some(start) = start {
+            self.stats.wall_time = Some(self.stats.wall_time.unwrap_or_default() + start.elapsed());
+        }
+
+        // Set the current result to the most recent instruction's result
+        match step_result {
+            Ok(Some(ran)) => {
+                self.result = Ok(());
+                StepResult::Ran(ran)
+            },
+            Ok(None) => StepResult::Paused,
+            Err(msg) => {
+                // errors outside of any instruction, like a breakpoint abort, are at the program counter
+                if let Some(tree) = self.span_trees.clone().get(self.program_counter) {
+                    self.locate_error(&tree.span);
+                }
+                // If there is an error, don't execute any further.
+                self.result = Err(msg.clone());
+                self.finished = true;
+                StepResult::Error(msg)
+            },
+        }
+    }
+
+    /// Runs the instruction at the program counter, or the next execution of its body
+    /// if it is a loop being stepped into.
+    /// Returns the token that ran, or `None` if the program paused before it.
+    fn step_command(&mut self, command: &LbToken) -> Result<Option<LbToken>, LbError> {
+        let span_trees = Rc::clone(&self.span_trees);
+        let spans = &span_trees[self.program_counter];
+        let mut state = self.active_loop.take();
+        // an instruction that yielded partway through has already started
+        if state.is_none() && self.resume.is_empty() {
+            if self.should_pause(command, true)? {
+                return Ok(None);
+            }
+            if self.step_into {
+                if let Some(mut new_state) = self.start_loop(command, &spans.span)? {
+                    if !self.loop_continues(&mut new_state, &spans.span)? {
+                        // the body doesn't run at all, so the loop is one step
+                        self.advance();
+                        return Ok(Some(command.clone()));
+                    }
+                    state = Some(new_state);
+                }
+            }
+        }
+
+        let Some(mut state) = state else {
+            self.evaluate(command, spans)?;
+            if self.yielding {
+                return Ok(None);
+            }
+            self.advance();
+            return Ok(Some(command.clone()));
+        };
+
+        // loops have exactly one subcommand, their body
+        let body = command.subcommands()[0].clone();
+        if self.resume.is_empty() && self.should_pause(&body, true)? {
+            self.active_loop = Some(state);
+            return Ok(None);
+        }
+        self.evaluate(&body, spans.child(0))?;
+        if self.yielding {
+            self.active_loop = Some(state);
+            return Ok(None);
+        }
+        if self.loop_continues(&mut state, &spans.span)? {
+            self.active_loop = Some(state);
+        }
+        else {
+            self.advance();
+        }
+        Ok(Some(body))
+    }
+
+    /// Asks the breakpoint callback, if there is one, what to do before running the given command.
+    /// Returns true if the program should pause before it,
+    /// which only happens at the top level (`resumable`); otherwise the pause waits for the next one.
+    fn should_pause(&mut self, command: &LbToken, resumable: bool) -> Result<bool, LbError> {
+        if resumable && self.resuming {
+            self.resuming = false;
+            return Ok(false);
+        }
+
+        let mut pause = resumable && self.pause_pending;
+        if let Some(callback) = self.breakpoint_callback.as_mut() {
+            let position = self.positions.get(self.program_counter).copied().unwrap_or((0, 0));
+            match callback(command, &self.data, position) {
+                BreakAction::Continue => {},
+                BreakAction::Pause => pause = true,
+                BreakAction::Abort => return Err(LbError::Aborted),
+            }
+        }
+
+        if !pause {
+            return Ok(false);
+        }
+        if resumable {
+            self.pause_pending = false;
+            self.resuming = true;
+            return Ok(true);
+        }
+        self.pause_pending = true;
+        Ok(false)
+    }
+
+    /// Runs an instruction and returns a result.
+    /// This is the main location where parser tokens are mapped to
+    /// execution implementations. Side effects abound as these implementations 
+    /// can and will manipulate this program's data storage.
+    /// `spans` says where the instruction is in the source code, for locating errors.
+    /// With [crate::storage::SharedStorage], the instruction takes the storage for itself,
+    /// so other programs can use it between the instructions in a loop or block.
+    fn evaluate(&mut self, command: &LbToken, spans: &SpanTree) -> Result<(), LbError> {
+        let inside = self.data.release();
+        self.data.acquire();
+        let result = self.evaluate_checked(command, spans);
+        self.data.release();
+        if inside {
+            self.data.acquire();
+        }
+        if result.is_err() {
+            self.locate_error(&spans.span);
+        }
+        result
+    }
+
+    /// Runs an instruction after checking that it is allowed to run. See [LbProgram::evaluate].
+    fn evaluate_checked(&mut self, command: &LbToken, spans: &SpanTree) -> Result<(), LbError> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(LbError::Cancelled);
+        }
+        // an instruction that yielded partway through was already counted and checked
+        if self.resume.is_empty() {
+            // top-level instructions yield before they start, in LbProgram::run_until_yield
+            if self.nesting > 0 && self.yield_due() {
+                self.yielding = true;
+                return Ok(());
+            }
+            if self.max_instructions > 0 && self.stats.instructions >= self.max_instructions {
+                return Err(LbError::InstructionLimit(self.max_instructions));
+            }
+            self.stats.instructions += 1;
+            self.since_yield += 1;
+
+            // top-level instructions were already checked before they started
+            if self.nesting > 0 {
+                self.should_pause(command, false)?;
+            }
+        }
+
+        self.nesting += 1;
+        let result = self.evaluate_traced(command, spans);
+        self.nesting -= 1;
+        result
+    }
+
+    /// Runs an instruction, passing a [TraceEntry] to the trace callback if there is one.
+    fn evaluate_traced(&mut self, command: &LbToken, spans: &SpanTree) -> Result<(), LbError> {
+        if self.trace_callback.is_none() {
+            return self.evaluate_command(command, spans);
+        }
+        let vars = command.vars();
+        let before = self.var_values(&vars);
+        let result = self.evaluate_command(command, spans);
+        // an instruction that yields is traced once it finishes
+        if self.yielding {
+            return result;
+        }
+        let entry = TraceEntry {
+            token: command.clone(),
+            position: self.positions.get(self.program_counter).copied().unwrap_or((0, 0)),
+            before,
+            after: self.var_values(&vars),
+        };
+        if let Some(callback) = self.trace_callback.as_mut() {
+            callback(entry);
+        }
+        result
+    }
+
+    /// Runs an instruction without counting, checking, or tracing it. See [LbProgram::evaluate].
+    fn evaluate_command(&mut self, command: &LbToken, spans: &SpanTree) -> Result<(), LbError> {
+        match command {
+
+            // Sa4
+            SaveNumber((var_name, float_val)) => {
+                self.data.set_var(*var_name, &Val::Number(*float_val))
+            },
+
+            // Sa'Hello'
+            SaveStr((var_name, string_val)) => {
+                self.data.set_var(*var_name, &Val::Text(string_val.clone()))
+            },
+
+            // Sa[1 2 3]
+            SaveList((var_name, items)) => {
+                self.data.set_var(*var_name, &Val::List(items.iter().map(Val::from).collect()))
+            },
+
+            // Cab
+            Copy((from_var, to_var)) => {
+                let val = self.value_var('C', *from_var)?;
+                self.data.set_var(*to_var, &val)
+            },
+
+            // Aab
+            Append((base_var, add_var)) => {
+                let exact = self.exact_numbers;
+
+                // appending to a list adds the value as a new element
+                if let Some(Val::List(_)) = self.data.get_var(*base_var) {
+                    let add_val = self.value_var('A', *add_var)?;
+                    self.list_var_mut('A', *base_var)?.push(add_val);
+                    return Ok(());
+                }
+
+                // get base string
+                let mut base_string = self.value_var('A', *base_var)?.format(exact);
+                
+                // get string to append
+                let add_string = self.value_var('A', *add_var)?.format(exact);
+
+                // append the strings and save them to the base variable
+                base_string.push_str(&add_string);
+                self.data.set_var(*base_var, &Val::Text(base_string))
+            },
+
+            // Pa
+            PrintVar(var_name) => {
+                let print_str = self.text_var('P', *var_name)?;
+                self.print('P', &print_str)
+            },
+
+            // P'Hello'
+            PrintStr(string_val) => {
+                self.print('P', string_val)
+            },
+
+            // Oa
+            PrintLineVar(var_name) => {
+                let print_str = self.text_var('O', *var_name)?;
+                self.print('O', &format!("{print_str}\n"))
+            },
+
+            // O'Hello'
+            PrintLineStr(string_val) => {
+                self.print('O', &format!("{string_val}\n"))
+            },
+
+            // MAcab
+            MathOp((op, target, a, b)) => {
+                let val_a = self.value_var('M', *a)?;
+                let val_b = self.value_var('M', *b)?;
+                // bools are counted as 1 and 0
+                let Some(n_a) = val_a.as_number() else {
+                    return Err(LbError::TypeMismatch { command: 'M', var: *a, expected: "number" });
+                };
+                let Some(n_b) = val_b.as_number() else {
+                    return Err(LbError::TypeMismatch { command: 'M', var: *b, expected: "number" });
+                };
+                // both values are numbers or bools, so arithmetic on them gives a number
+                let number = |result: Option<Val>| result.and_then(|val| val.as_number()).unwrap_or(f64::NAN);
+                let equal = val_a.partial_cmp(&val_b) == Some(cmp::Ordering::Equal);
+
+                // comparisons give bools
+                let comparison = match op {
+                    'E' => Some(equal),                             // equal to
+                    'G' => Some(val_a > val_b),                     // greater than
+                    'L' => Some(val_a < val_b),                     // less than
+                    'N' => Some(!equal),                            // not equal to
+                    'H' => Some(val_a >= val_b),                    // greater than or equal to
+                    'W' => Some(val_a <= val_b),                    // less than or equal to
+                    _ => None,
+                };
+                if let Some(result) = comparison {
+                    return self.data.set_var(*target, &Val::Bool(result));
+                }
+
+                // every kind of division fails the same way
+                if matches!(op, 'D' | 'Q' | 'R' | 'O') && n_b == 0.0 {
+                    return Err(LbError::DivisionByZero { instruction: format!("M{op}{target}{a}{b}") });
+                }
+
+                // compute result
+                let result = match op {
+                    'A' => number(val_a + val_b),                   // add
+                    'S' => number(val_a - val_b),                   // subtract
+                    'M' => number(val_a * val_b),                   // multiply
+                    'D' => number(val_a / val_b),                   // divide
+                    'Q' => (n_a / n_b).trunc(),                     // integer division
+                    'R' => n_a % n_b,                               // remainder
+                    'O' => {                                        // modulo
+                        let rem = n_a % n_b;
+                        if rem != 0.0 && (rem < 0.0) != (n_b < 0.0) { rem + n_b } else { rem }
+                    },
+                    'P' => {                                        // power
+                        // 0^0 is 1, but a negative base can't have a fractional exponent
+                        let pow = n_a.powf(n_b);
+                        if pow.is_nan() && !n_a.is_nan() && !n_b.is_nan() {
+                            return Err(LbError::NoRealResult { instruction: format!("M{op}{target}{a}{b}") });
+                        }
+                        pow
+                    },
+                    'I' => n_a.min(n_b),                            // minimum
+                    'X' => n_a.max(n_b),                            // maximum
+                    'T' => n_a.atan2(n_b),                          // arctangent of a/b
+                    _ => {
+                        return Err(LbError::InvalidOp { command: 'M', op: *op });
+                    },
+                };
+                // save result to storage
+                self.data.set_var(*target, &Val::Number(result))
+            },
+
+            // MFab
+            UnaryMathOp((op, target, a)) => {
+                let n_a = self.number_var('M', *a)?;
+
+                // compute result
+                let result = match op {
+                    'F' => n_a.floor(),                             // floor
+                    'C' => n_a.ceil(),                              // ceiling
+                    'R' => n_a.round(),                             // round
+                    'A' => n_a.abs(),                               // absolute value
+                    'N' => if n_a == 0.0 { 0.0 } else { n_a.signum() }, // sign, treating -0 as 0
+                    'S' => n_a.sin(),                               // sine
+                    'O' => n_a.cos(),                               // cosine
+                    'T' => n_a.tan(),                               // tangent
+                    'Q' => {                                        // square root
+                        if n_a < 0.0 {
+                            return Err(LbError::NegativeSquareRoot { var: *a, instruction: format!("M{op}{target}{a}") });
+                        }
+                        n_a.sqrt()
+                    },
+                    'L' | 'D' => {                                  // natural and base 10 logarithms
+                        if n_a <= 0.0 {
+                            return Err(LbError::NonPositiveLogarithm { var: *a, instruction: format!("M{op}{target}{a}") });
+                        }
+                        if *op == 'L' { n_a.ln() } else { n_a.log10() }
+                    },
+                    _ => {
+                        return Err(LbError::InvalidOp { command: 'M', op: *op });
+                    },
+                };
+                // save result to storage
+                self.data.set_var(*target, &Val::Number(result))
+            },
+
+            // BAcab
+            BoolOp((op, target, a, b)) => {
+                // compare values rather than truthiness
+                if *op == 'S' {
+                    let val_a = self.value_var('B', *a)?;
+                    let val_b = self.value_var('B', *b)?;
+                    let same = val_a.same_as(&val_b);
+                    return self.data.set_var(*target, &Val::Bool(same));
+                }
+
+                // bitwise ops work on the numbers as integers
+                if "&|^<>".contains(*op) {
+                    let i_a = self.integer_var(*a)?;
+                    let i_b = self.integer_var(*b)?;
+                    let result = match op {
+                        '&' => i_a & i_b,                           // and
+                        '|' => i_a | i_b,                           // or
+                        '^' => i_a ^ i_b,                           // xor
+                        _ => {                                      // shifts
+                            if !(0..64).contains(&i_b) {
+                                return Err(LbError::ShiftOutOfRange { amount: i_b, instruction: format!("B{op}{target}{a}{b}") });
+                            }
+                            if *op == '<' { i_a << i_b } else { i_a >> i_b }
+                        },
+                    };
+                    return self.data.set_var(*target, &Val::Number(result as f64));
+                }
+
+                let b_a = self.bool_var('B', *a)?;
+                let b_b = self.bool_var('B', *b)?;
+
+                // compute result
+                let result = match op {
+                    'E' => b_a == b_b,                              // equal to
+                    'A' => b_a && b_b,                              // and
+                    'O' => b_a || b_b,                              // or
+                    'X' => b_a != b_b,                              // xor
+                    _ => {
+                        return Err(LbError::InvalidOp { command: 'B', op: *op });
+                    },
+                };
+                // save result to storage
+                self.data.set_var(*target, &Val::Bool(result))
+            },
+
+            // VSliv
+            ListOp((op, args)) => {
+                // tokens built by hand may have too few variables
+                let vars: Vec<char> = op_args('V', *op, args)?.chars().collect();
+                match op {
+                    'N' => self.data.set_var(vars[0], &Val::List(Vec::new())),
+                    'S' => {
+                        let index = self.number_var('V', vars[1])?;
+                        let val = self.value_var('V', vars[2])?;
+                        let list = self.list_var_mut('V', vars[0])?;
+                        let i = list_index(vars[0], index, list.len())?;
+                        list[i] = val;
+                        Ok(())
+                    },
+                    'G' => {
+                        let index = self.number_var('V', vars[2])?;
+                        let list = self.list_var_mut('V', vars[1])?;
+                        let i = list_index(vars[1], index, list.len())?;
+                        let val = list[i].clone();
+                        self.data.set_var(vars[0], &val)
+                    },
+                    'A' => {
+                        let val = self.value_var('V', vars[1])?;
+                        self.list_var_mut('V', vars[0])?.push(val);
+                        Ok(())
+                    },
+                    'L' => {
+                        let len = self.list_var_mut('V', vars[1])?.len() as f64;
+                        self.data.set_var(vars[0], &Val::Number(len))
+                    },
+                    'P' => {
+                        let text = self.text_var('V', vars[1])?;
+                        let delimiter = self.delimiter_var('V', vars[2])?;
+                        let pieces = text.split(delimiter).map(|piece| Val::Text(piece.to_string())).collect();
+                        self.data.set_var(vars[0], &Val::List(pieces))
+                    },
+                    _ => Err(LbError::InvalidOp { command: 'V', op: *op }),
+                }
+            },
+
+            // HSmkv
+            MapOp((op, args)) => {
+                let vars: Vec<char> = op_args('H', *op, args)?.chars().collect();
+                match op {
+                    'N' => self.data.set_var(vars[0], &Val::Map(BTreeMap::new())),
+                    'S' => {
+                        let key = self.text_var('H', vars[1])?;
+                        let val = self.value_var('H', vars[2])?;
+                        self.map_var_mut(vars[0])?.insert(key, val);
+                        Ok(())
+                    },
+                    'G' => {
+                        let key = self.text_var('H', vars[2])?;
+                        let Some(val) = self.map_var_mut(vars[1])?.get(&key).cloned() else {
+                            return Err(LbError::MissingKey { map: vars[1], key });
+                        };
+                        self.data.set_var(vars[0], &val)
+                    },
+                    'C' => {
+                        let key = self.text_var('H', vars[2])?;
+                        let found = self.map_var_mut(vars[1])?.contains_key(&key);
+                        self.data.set_var(vars[0], &Val::Bool(found))
+                    },
+                    'R' => {
+                        let key = self.text_var('H', vars[1])?;
+                        self.map_var_mut(vars[0])?.remove(&key);
+                        Ok(())
+                    },
+                    'L' => {
+                        let len = self.map_var_mut(vars[1])?.len() as f64;
+                        self.data.set_var(vars[0], &Val::Number(len))
+                    },
+                    _ => Err(LbError::InvalidOp { command: 'H', op: *op }),
+                }
+            },
+
+            // BNab
+            UnaryBoolOp((op, target, a)) => {
+                let b_a = self.bool_var('B', *a)?;
+
+                // compute result
+                let result = match op {
+                    'N' => !b_a,                            // not
+                    _ => {
+                        return Err(LbError::InvalidOp { command: 'B', op: *op });
+                    },
+                };
+                // save result to storage
+                self.data.set_var(*target, &Val::Bool(result))
+            },
+
+            // Ra
+            ResetVar(var_name) => {
+                self.data.reset_var(*var_name)
+            },
+
+            // Na
+            Negate(var_name) => {
+                let current = self.bool_var('N', *var_name)?;
+                if current {
+                    self.data.reset_var(*var_name)
+                }
+                else {
+                    self.data.set_var(*var_name, &Val::Number(1.0))
+                }
+            },
+
+            // RA
+            ResetAll => {
+                self.data.reset_all()
+            },
+
+            // R#
+            ResetAllBanks => {
+                self.data.reset_all_banks()
+            },
+
+            // #1
+            SwitchBank(bank) => {
+                self.data.set_bank(usize::from(*bank))
+            },
+
+            // TSdsil
+            TextOp((op, args)) => {
+                let vars: Vec<char> = op_args('T', *op, args)?.chars().collect();
+                let result = match op {
+                    'S' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let start = self.number_var('T', vars[2])?.trunc() as usize;
+                        let len = self.number_var('T', vars[3])?.trunc() as usize;
+                        Val::Text(text.chars().skip(start).take(len).collect())
+                    },
+                    'P' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let delimiter = self.delimiter_var('T', vars[2])?;
+                        let index = self.number_var('T', vars[3])?.trunc();
+                        let piece = if index < 0.0 {
+                            None
+                        } else {
+                            text.split(delimiter).nth(index as usize)
+                        };
+                        Val::Text(piece.unwrap_or("").to_string())
+                    },
+                    'K' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let delimiter = self.delimiter_var('T', vars[2])?;
+                        Val::Number(text.split(delimiter).count() as f64)
+                    },
+                    'N' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let parsed = parse_number(&text);
+                        self.data.set_var(vars[2], &Val::Bool(parsed.is_some()))?;
+                        Val::Number(parsed.unwrap_or(0.0))
+                    },
+                    'F' => {
+                        let num = self.number_var('T', vars[1])?;
+                        let precision = self.number_var('T', vars[2])?.trunc().clamp(0.0, MAX_PRECISION) as usize;
+                        Val::Text(format!("{:.*}", precision, num))
+                    },
+                    'U' => Val::Text(self.text_var('T', vars[1])?.to_uppercase()),
+                    'L' => Val::Text(self.text_var('T', vars[1])?.to_lowercase()),
+                    'T' => Val::Text(self.text_var('T', vars[1])?.trim().to_string()),
+                    'B' => Val::Text(self.text_var('T', vars[1])?.trim_start().to_string()),
+                    'E' => Val::Text(self.text_var('T', vars[1])?.trim_end().to_string()),
+                    'C' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let index = self.number_var('T', vars[2])?.trunc();
+                        let character = if index < 0.0 {
+                            None
+                        } else {
+                            text.chars().nth(index as usize)
+                        };
+                        Val::Text(character.map(String::from).unwrap_or_default())
+                    },
+                    'I' => {
+                        let haystack = self.text_var('T', vars[1])?;
+                        let needle = self.text_var('T', vars[2])?;
+                        // convert the byte offset into a character index
+                        let index = match haystack.find(&needle) {
+                            Some(byte_index) => haystack[..byte_index].chars().count() as f64,
+                            None => -1.0,
+                        };
+                        Val::Number(index)
+                    },
+                    'R' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let from = self.text_var('T', vars[2])?;
+                        let to = self.text_var('T', vars[3])?;
+                        // replacing an empty string would insert between every character
+                        if from.is_empty() {
+                            Val::Text(text)
+                        } else {
+                            Val::Text(text.replace(&from, &to))
+                        }
+                    },
+                    'O' => {
+                        let text = self.text_var('T', vars[1])?;
+                        let Some(first) = text.chars().next() else {
+                            return Err(LbError::EmptyText { var: vars[1] });
+                        };
+                        Val::Number(first as u32 as f64)
+                    },
+                    'H' => {
+                        let code = self.number_var('T', vars[1])?;
+                        // rule out fractions and NaN before converting
+                        let character = if code.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&code) {
+                            char::from_u32(code as u32)
+                        } else {
+                            None
+                        };
+                        let Some(character) = character else {
+                            return Err(LbError::InvalidCharCode(code));
+                        };
+                        Val::Text(character.to_string())
+                    },
+                    _ => {
+                        return Err(LbError::InvalidOp { command: 'T', op: *op });
+                    },
+                };
+                // save result to storage
+                self.data.set_var(vars[0], &result)
+            },
+
+            // LaX, L5X, WaX
+            Loop((_, subcommand)) | Repeat((_, subcommand)) | WhileLoop((_, subcommand)) => {
+                // carry on from where the body yielded, or start the loop
+                let (state, mut resumed) = match self.resume.pop() {
+                    Some(ResumeFrame::Loop(state)) => (Some(state), true),
+                    _ => (self.start_loop(command, &spans.span)?, false),
+                };
+
+                // execute subcommand until the loop is done
+                if let Some(mut state) = state {
+                    while resumed || self.loop_continues(&mut state, &spans.span)? {
+                        resumed = false;
+                        self.evaluate(subcommand, spans.child(0))?;
+                        if self.yielding {
+                            self.resume.push(ResumeFrame::Loop(state));
+                            break;
+                        }
+                    }
+                }
+
+                Ok(())
+            },
+
+            // IaX:Y
+            IfStatement((cond, subcommand, else_command)) => {
+                if let Some(ResumeFrame::Branch(branch)) = self.resume.pop() {
+                    return self.evaluate_branch(branch, subcommand, else_command.as_deref(), spans);
+                }
+
+                // get condition as bool
+                let c = self.bool_var('I', *cond)?;
+                
+                // execute subcommand if condition is true, else command otherwise
+                let branch = if c { 0 } else { 1 };
+                self.evaluate_branch(branch, subcommand, else_command.as_deref(), spans)
+            },
+
+            // UaX:Y
+            Unless((cond, subcommand, else_command)) => {
+                if let Some(ResumeFrame::Branch(branch)) = self.resume.pop() {
+                    return self.evaluate_branch(branch, subcommand, else_command.as_deref(), spans);
+                }
+
+                // get condition as bool
+                let c = self.bool_var('U', *cond)?;
+                
+                // execute subcommand if condition is false, else command otherwise
+                let branch = if !c { 0 } else { 1 };
+                self.evaluate_branch(branch, subcommand, else_command.as_deref(), spans)
+            },
+
+            // [ X Y ]
+            Block(commands) => {
+                // carry on from where the block yielded, or start from the beginning
+                let start = match self.resume.pop() {
+                    Some(ResumeFrame::Block(index)) => index,
+                    _ => 0,
+                };
+                for (i, command) in commands.iter().enumerate().skip(start) {
+                    self.evaluate(command, spans.child(i))?;
+                    if self.yielding {
+                        self.resume.push(ResumeFrame::Block(i));
+                        break;
+                    }
+                    // stop early if the block finished the program or jumped
+                    if self.interrupted() {
+                        break;
+                    }
+                }
+                Ok(())
+            },
+
+            // GXa1
+            GetInput((op, var, num)) => {
+                let index = num.floor() as usize;
+                let Some(input_item) = self.input_at(index)? else {
+                    return Err(LbError::InputMissing(*num))
+                };
+
+                self.store_input(*op, *var, input_item)
+            },
+
+            // GXab
+            GetInputAt((op, var, index_var)) => {
+                let num = self.number_var('G', *index_var)?.trunc();
+                let input = if num < 0.0 { None } else { self.input_at(num as usize)? };
+                let Some(input_item) = input else {
+                    return Err(LbError::InputMissing(num))
+                };
+
+                self.store_input(*op, *var, input_item)
+            },
+
+            // G#a
+            InputCount(var_name) => {
+                let Some(count) = self.input.count() else {
+                    return Err(LbError::InputCountUnknown);
+                };
+                let count = count + self.fallback_inputs.len();
+                self.data.set_var(*var_name, &Val::Number(count as f64))
+            },
+
+            // GQaX
+            QueryInput((var, op)) => {
+                let Some(callback) = self.input_callback.as_mut() else {
+                    return Err(LbError::NoInputCallback);
+                };
+                let Some(input_item) = callback() else {
+                    return Err(LbError::NoHostInput);
+                };
+                self.store_input(*op, *var, Val::Text(input_item))
+            },
+
+            // Xzacbd>r
+            Execute((fn_var, argmap, dest)) => {
+                if let Some(ResumeFrame::Execute(frame)) = self.resume.pop() {
+                    return self.run_executed(frame);
+                }
+
+                // get string to execute
+                let Val::Text(prog) = self.value_var('X', *fn_var)? else {
+                    return Err(LbError::TypeMismatch { command: 'X', var: *fn_var, expected: "string" });
+                };
+
+                self.execute(prog, Some(*fn_var), argmap, *dest)
+            },
+
+            // X'Pa'acbd>r
+            ExecuteStr((prog, argmap, dest)) => {
+                if let Some(ResumeFrame::Execute(frame)) = self.resume.pop() {
+                    return self.run_executed(frame);
+                }
+                self.execute(prog.clone(), None, argmap, *dest)
+            },
+
+            // Ya
+            Return(var_name) => {
+                if self.execute_depth == 0 {
+                    return Err(LbError::ReturnOutsideExecute);
+                }
+                self.return_value = Some(self.value_var('Y', *var_name)?);
+                self.finished = true;
+                Ok(())
+            },
+
+            // K+a
+            Push(var_name) => {
+                let val = self.value_var('K', *var_name)?;
+                self.data.push(&val);
+                Ok(())
+            },
+
+            // K-a
+            Pop(var_name) => {
+                let Some(val) = self.data.pop() else {
+                    return Err(LbError::EmptyStack(*var_name));
+                };
+                self.data.set_var(*var_name, &val)
+            },
+
+            // K#a
+            StackDepth(var_name) => {
+                let depth = self.data.stack_depth() as f64;
+                self.data.set_var(*var_name, &Val::Number(depth))
+            },
+
+            // Da
+            Random(var_name) => {
+                let num = self.rng.next_f64();
+                self.data.set_var(*var_name, &Val::Number(num))
+            },
+
+            // Qa
+            Sleep(var_name) => {
+                let millis = self.number_var('Q', *var_name)?;
+                // negative and NaN durations don't pause
+                let duration = if millis > 0.0 {
+                    Duration::try_from_secs_f64(millis / 1000.0)
+                        .map_err(|_| LbError::InvalidSleep(millis))?
+                } else {
+                    Duration::ZERO
+                };
+                // other programs can use shared storage while this one sleeps
+                let held = self.data.release();
+                // a long sleep is cut short if the program is cancelled
+                let cancelled = self.clock.sleep_until_cancelled(duration, &self.cancelled);
+                if held {
+                    self.data.acquire();
+                }
+                if cancelled {
+                    return Err(LbError::Cancelled);
+                }
+                Ok(())
+            },
+
+            // Ea
+            Elapsed(var_name) => {
+                let millis = self.clock.elapsed().as_nanos() as f64 / 1_000_000.0;
+                self.data.set_var(*var_name, &Val::Number(millis))
+            },
+
+            // Za
+            Assert(var_name) => {
+                let val = self.value_var('Z', *var_name)?;
+                let truthy = self.bool_var('Z', *var_name)?;
+                if !truthy {
+                    let val = val.format(self.exact_numbers);
+                    return Err(self.assertion_error(format!("{var_name} is {val}")));
+                }
+                Ok(())
+            },
+
+            // Zab
+            AssertSame((a, b)) => {
+                let val_a = self.value_var('Z', *a)?;
+                let val_b = self.value_var('Z', *b)?;
+                if !val_a.same_as(&val_b) {
+                    let exact = self.exact_numbers;
+                    let message = format!("{a} is {} but {b} is {}", val_a.format(exact), val_b.format(exact));
+                    return Err(self.assertion_error(message));
+                }
+                Ok(())
+            },
+
+            // F
+            Finish => {
+                self.finished = true;
+                Ok(())
+            },
+
+            // Fa
+            FinishWithStatus(var_name) => {
+                let status = self.number_var('F', *var_name)?;
+                // finishing an executed program doesn't end this one, so it has no status to report
+                if self.execute_depth == 0 {
+                    self.exit_status = status;
+                }
+                self.finished = true;
+                Ok(())
+            },
+
+            // :a
+            Label(_) => Ok(()),
+
+            // Ja
+            Jump(label) => self.jump(*label),
+
+            // JIab
+            JumpIf((cond, label)) => {
+                let c = self.bool_var('J', *cond)?;
+                if c {
+                    return self.jump(*label);
+                }
+                Ok(())
+            },
+
+            _ => Err(LbError::LexError { span: spans.span.clone() }),
+        }
+    }
+
+    /// Used by Execute (`Xzacbd>r`) and ExecuteStr (`X'Pa'acbd>r`).
+    /// 
+    /// Runs a string as part of this program, so it shares this program's storage,
+    /// input, output, and settings. Applies the given argument mappings first,
+    /// and stores any value returned with `Ya` in the destination variable.
+    /// `program_var` is the variable the string came from, if any, for locating errors.
+    fn execute(&mut self, prog: String, program_var: Option<char>, argmap: &str, dest: Option<char>) -> Result<(), LbError> {
+        // validate argmap
+        for c in argmap.chars() {
+            if !storage::is_var(&c) {
+                return Err(LbError::InvalidVariable { command: 'X', var: c });
+            }
+        }
+
+        // each parameter is replaced with its given variable
+        let params: Vec<char> = argmap.chars().collect();
+        if let [.., last] = params.as_slice() {
+            if params.len() % 2 == 1 {
+                return Err(LbError::UnpairedParameter(*last));
+            }
+        }
+        let params: HashMap<char, char> = params.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+        let rename = |var: char| *params.get(&var).unwrap_or(&var);
+
+        // parse the whole string before running any of it
+        self.sources.push((prog.clone(), program_var));
+        let mut commands: Vec<(LbToken, SpanTree)> = Vec::new();
+        for (command, span) in LbToken::lexer(&prog).spanned() {
+            if command == Error {
+                self.locate_error(&span);
+                self.sources.pop();
+                self.error_located = false;
+                return Err(LbError::ExecuteLexError(prog[span].to_string()));
+            }
+            let spans = SpanTree::new(&command, &prog, span);
+            commands.push((command.rename_vars(&rename), spans));
+        }
+
+        self.sources.pop();
+
+        self.run_executed(ExecuteFrame { source: (prog, program_var), commands, next: 0, dest })
+    }
+
+    /// Runs a program parsed by [LbProgram::execute], from its next instruction.
+    /// If it yields, records where it stopped so it can carry on from there.
+    fn run_executed(&mut self, mut frame: ExecuteFrame) -> Result<(), LbError> {
+        self.sources.push(frame.source.clone());
+        self.execute_depth += 1;
+        self.stats.max_execute_depth = self.stats.max_execute_depth.max(self.execute_depth);
+        let mut result = Ok(());
+        while let Some((command, spans)) = frame.commands.get(frame.next) {
+            result = self.evaluate(command, spans);
+            if result.is_err() || self.finished || self.yielding {
+                break;
+            }
+            frame.next += 1;
+        }
+        self.execute_depth -= 1;
+        self.sources.pop();
+        // the Execute command itself is the next frame out
+        self.error_located = false;
+        if self.yielding && result.is_ok() {
+            self.resume.push(ResumeFrame::Execute(frame));
+            return Ok(());
+        }
+        let dest = frame.dest;
+        let return_value = self.return_value.take();
+        result?;
+
+        // finishing the executed program doesn't finish this one
+        self.finished = false;
+
+        // store the returned value, if there is one and somewhere to put it
+        match (dest, return_value) {
+            (Some(dest), Some(val)) => self.data.set_var(dest, &val),
+            _ => Ok(()),
+        }
+    }
+
+    /// Used by IfStatement (`IaX:Y`) and Unless (`UaX:Y`).
+    ///
+    /// Runs a conditional's body (branch 0) or its else command (branch 1), if it has one.
+    /// If it yields, records which one was running so it can carry on from there.
+    fn evaluate_branch(&mut self, branch: usize, subcommand: &LbToken, else_command: Option<&LbToken>, spans: &SpanTree) -> Result<(), LbError> {
+        let command = match (branch, else_command) {
+            (0, _) => subcommand,
+            (_, Some(else_command)) => else_command,
+            (_, None) => return Ok(()),
+        };
+        self.evaluate(command, spans.child(branch))?;
+        if self.yielding {
+            self.resume.push(ResumeFrame::Branch(branch));
+        }
+        Ok(())
+    }
+
+    /// Records where an error happened, at the given byte range of the innermost running source,
+    /// unless the innermost frame of this error has already been recorded.
+    fn locate_error(&mut self, span: &Range<usize>) {
+        if self.error_located {
+            return;
+        }
+        self.error_located = true;
+        let Some((source, program_var)) = self.sources.last() else {
+            return;
+        };
+        let (line, column) = line_columns(source, &[span.start])[0];
+        let slice = source.get(span.clone()).unwrap_or("").to_string();
+        self.error_frames.push(ErrorFrame { span: span.clone(), line, column, program_var: *program_var, slice });
+    }
+
+    /// Used by GetInput (`GNa1`) and GetInputAt (`GSab`).
+    ///
+    /// Returns the input at the given index, reading it from the stdin fallback
+    /// if it wasn't given and there is one.
+    fn input_at(&mut self, index: usize) -> Result<Option<Val>, LbError> {
+        if let Some(input) = self.input.get(index) {
+            return Ok(Some(input));
+        }
+        let Some(reader) = self.stdin_fallback.as_mut() else {
+            return Ok(None);
+        };
+        // an input source that can't count its inputs ends at its first missing one
+        let given = match self.input.count() {
+            Some(count) => count,
+            None => (0..index).find(|i| self.input.get(*i).is_none()).unwrap_or(index),
+        };
+        // lines are appended in order, so every line before the index is read first
+        while given + self.fallback_inputs.len() <= index {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {},
+                Err(e) => return Err(LbError::InputReadFailed(e.to_string())),
+            }
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            self.fallback_inputs.push(Val::Text(String::from(line.strip_suffix('\r').unwrap_or(line))));
+        }
+        Ok(Some(self.fallback_inputs[index - given].clone()))
+    }
+
+    /// Used by GetInput (`GNa1`), GetInputAt (`GSab`), and QueryInput (`GQaN`).
+    /// 
+    /// Stores an input item in the given variable, as a number if the op is `N`
+    /// or a string if the op is `S`.
+    fn store_input(&mut self, op: char, var: char, input_item: Val) -> Result<(), LbError> {
+        if !storage::is_var(&var) {
+            return Err(LbError::InvalidVariable { command: 'G', var });
+        }
+        match (op, input_item) {
+            ('N', Val::Number(num)) => {
+                self.data.set_var(var, &Val::Number(num))
+            },
+            ('N', Val::Bool(b)) => {
+                self.data.set_var(var, &Val::Number(if b { 1.0 } else { 0.0 }))
+            },
+            ('N', Val::Text(text)) => {
+                if let Ok(val) = text.parse::<f64>() {
+                    self.data.set_var(var, &Val::Number(val))
+                }
+                else {
+                    Err(LbError::InputNotNumber(text))
+                }
+            },
+            ('N', other) => {
+                Err(LbError::InputNotNumber(other.format(self.exact_numbers)))
+            },
+            ('S', Val::Text(text)) => {
+                self.data.set_var(var, &Val::Text(text))
+            },
+            ('S', other) => {
+                self.data.set_var(var, &Val::Text(other.format(self.exact_numbers)))
+            },
+            _ => Err(LbError::InvalidOp { command: 'G', op }),
+        }
+    }
+
+    /// Passes text to the print callback, if there is one, and writes it to this program's output.
+    /// The command letter is used in error messages.
+    fn print(&mut self, command: char, text: &str) -> Result<(), LbError> {
+        self.stats.prints += 1;
+        self.stats.output_bytes += text.len() as u64;
+        if let Some(callback) = self.print_callback.as_mut() {
+            callback(text);
+        }
+        if !self.write_output {
+            return Ok(());
+        }
+        self.output.write_all(text.as_bytes())
+            .map_err(|e| LbError::OutputFailed { command, message: e.to_string() })
+    }
+
+    /// Gets the number stored in the given variable, counting a bool as 1 or 0.
+    /// `command` is the letter of the calling command, which error messages name.
+    fn number_var(&mut self, command: char, var_name: char) -> Result<f64, LbError> {
+        match self.data.get_var(var_name).map(Val::as_number) {
+            Some(Some(num)) => Ok(num),
+            Some(None) => Err(LbError::TypeMismatch { command, var: var_name, expected: "number" }),
+            None => Err(self.missing_var(command, var_name)),
+        }
+    }
+
+    /// Gets the number stored in the given variable as a 64-bit integer, for bitwise ops.
+    /// Fractions are truncated, and numbers outside the range of an i64 are an error.
+    fn integer_var(&mut self, var_name: char) -> Result<i64, LbError> {
+        let num = self.number_var('B', var_name)?.trunc();
+        // i64::MIN is -2^63, and 2^63 is just past i64::MAX
+        if !(-9223372036854775808.0..9223372036854775808.0).contains(&num) {
+            return Err(LbError::BitwiseOutOfRange { var: var_name, value: num });
+        }
+        Ok(num as i64)
+    }
+
+    /// Gets a copy of the value stored in the given variable.
+    /// `command` is the letter of the calling command, which error messages name.
+    fn value_var(&mut self, command: char, var_name: char) -> Result<Val, LbError> {
+        match self.data.get_var(var_name) {
+            Some(val) => Ok(val.clone()),
+            None => Err(self.missing_var(command, var_name)),
+        }
+    }
+
+    /// Gets the truthiness of the value stored in the given variable. See [LbStorage::var_as_bool].
+    /// `command` is the letter of the calling command, which error messages name.
+    fn bool_var(&mut self, command: char, var_name: char) -> Result<bool, LbError> {
+        match self.data.var_as_bool(var_name) {
+            Some(truthy) => Ok(truthy),
+            None => Err(self.missing_var(command, var_name)),
+        }
+    }
+
+    /// Builds the error for a variable that couldn't be read: either the name isn't a variable,
+    /// or nothing has been stored in it and the storage's [storage::DefaultPolicy] is `Error`.
+    /// The position is that of the current top-level instruction, as for assertions.
+    fn missing_var(&self, command: char, var_name: char) -> LbError {
+        if !storage::is_var(&var_name) {
+            return LbError::InvalidVariable { command, var: var_name };
+        }
+        LbError::UnsetVariable {
+            var: var_name,
+            position: self.positions.get(self.program_counter).copied(),
+        }
+    }
+
+    /// Gets the list stored in the given variable, so it can be changed in place.
+    /// `command` is the letter of the calling command, which error messages name.
+    fn list_var_mut(&mut self, command: char, var_name: char) -> Result<&mut Vec<Val>, LbError> {
+        let missing = self.missing_var(command, var_name);
+        match self.data.get_var_mut(var_name) {
+            Some(Val::List(list)) => Ok(list),
+            Some(_) => Err(LbError::TypeMismatch { command, var: var_name, expected: "list" }),
+            None => Err(missing),
+        }
+    }
+
+    /// Gets the map stored in the given variable, so it can be changed in place.
+    fn map_var_mut(&mut self, var_name: char) -> Result<&mut BTreeMap<String, Val>, LbError> {
+        let missing = self.missing_var('H', var_name);
+        match self.data.get_var_mut(var_name) {
+            Some(Val::Map(map)) => Ok(map),
+            Some(_) => Err(LbError::TypeMismatch { command: 'H', var: var_name, expected: "map" }),
+            None => Err(missing),
+        }
+    }
+
+    /// Gets the value of the given variable as a string, formatting numbers as they'd be printed.
+    /// `command` is the letter of the calling command, which error messages name.
+    fn text_var(&mut self, command: char, var_name: char) -> Result<String, LbError> {
+        let exact = self.exact_numbers;
+        match self.data.get_var(var_name) {
+            Some(val) => Ok(val.format(exact)),
+            None => Err(self.missing_var(command, var_name)),
+        }
+    }
+
+    /// Gets the single character stored in the given variable, for use as a delimiter.
+    fn delimiter_var(&mut self, command: char, var_name: char) -> Result<char, LbError> {
+        let text = self.text_var(command, var_name)?;
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(delimiter), None) => Ok(delimiter),
+            _ => Err(LbError::InvalidDelimiter { command, var: var_name }),
+        }
+    }
+
+    /// Used by Jump (`Ja`) and JumpIf (`JIab`).
+    /// 
+    /// Makes the program continue from the given label once the current instruction stops.
+    fn jump(&mut self, label: char) -> Result<(), LbError> {
+        if self.execute_depth > 0 {
+            return Err(LbError::JumpFromExecute(label));
+        }
+        let Some(&target) = self.labels.get(&label) else {
+            return Err(LbError::UndefinedLabel(label));
+        };
+
+        // jumping backwards repeats part of the program, so count it like a loop
+        if target <= self.program_counter {
+            let jumps = self.back_jumps.entry(label).or_insert(0);
+            *jumps += 1;
+            if *jumps > self.loop_limit {
+                return Err(LbError::JumpLimit(label));
+            }
+        }
+
+        self.pending_jump = Some(label);
+        Ok(())
+    }
+
+    /// Used by Assert (`Za`) and AssertSame (`Zab`).
+    /// 
+    /// Builds the error for a failed assertion, with the position of the current instruction.
+    /// Assertions inside blocks, loops, and executed programs
+    /// report the position of the top-level instruction containing them.
+    fn assertion_error(&self, message: String) -> LbError {
+        LbError::AssertionFailed {
+            position: self.positions.get(self.program_counter).copied(),
+            message,
+        }
+    }
+
+    /// Begins running a loop. Returns `None` if the command isn't a loop.
+    /// `span` is where the loop is, for reporting the loop iteration limit.
+    fn start_loop(&mut self, command: &LbToken, span: &Range<usize>) -> Result<Option<LoopState>, LbError> {
+        match command {
+            Loop((times, _)) => {
+                // get number of loops
+                let t = self.number_var('L', *times)?;
+                self.times_loop(t.floor() as usize, span).map(Some)
+            },
+            Repeat((count, _)) => self.times_loop(*count, span).map(Some),
+            WhileLoop((cond, _)) => Ok(Some(LoopState::While(*cond, 0))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Begins running a loop whose body runs the given number of times,
+    /// checking that it is within the limits.
+    fn times_loop(&self, loops: usize, span: &Range<usize>) -> Result<LoopState, LbError> {
+        if loops > self.loop_limit {
+            return Err(LbError::LoopLimit('L'));
+        }
+        if self.max_loop_iterations > 0 && loops as u64 > self.max_loop_iterations {
+            return Err(self.loop_iteration_error('L', span));
+        }
+        Ok(LoopState::Times(loops))
+    }
+
+    /// Returns true if a loop's body should run again,
+    /// and counts the execution that is about to happen.
+    fn loop_continues(&mut self, state: &mut LoopState, span: &Range<usize>) -> Result<bool, LbError> {
+        if self.interrupted() {
+            return Ok(false);
+        }
+
+        match state {
+            LoopState::Times(loops) => {
+                if *loops == 0 {
+                    return Ok(false);
+                }
+                *loops -= 1;
+                self.stats.loop_iterations += 1;
+                Ok(true)
+            },
+            LoopState::While(cond, loops) => {
+                // get condition as bool
+                let c = self.bool_var('W', *cond)?;
+                if !c {
+                    return Ok(false);
+                }
+
+                // be sure to count loops
+                *loops += 1;
+                if *loops > self.loop_limit {
+                    return Err(LbError::LoopLimit('W'));
+                }
+                if self.max_loop_iterations > 0 && *loops as u64 > self.max_loop_iterations {
+                    return Err(self.loop_iteration_error('W', span));
+                }
+                self.stats.loop_iterations += 1;
+                Ok(true)
+            },
+        }
+    }
+
+    /// Returns the error for a loop that would run more than [LbProgram::set_max_loop_iterations] allows,
+    /// at the given byte range of the innermost running source.
+    fn loop_iteration_error(&self, command: char, span: &Range<usize>) -> LbError {
+        let (line, column) = self.sources.last()
+            .map_or((0, 0), |(source, _)| line_columns(source, &[span.start])[0]);
+        LbError::LoopIterationLimit { command, max: self.max_loop_iterations, span: span.clone(), line, column }
+    }
+
+    /// Moves to the label that was jumped to, or else increments the program counter.
+    fn advance(&mut self) {
+        match self.pending_jump.take() {
+            Some(label) => self.program_counter = self.labels[&label],
+            None => self.increment_counter(),
+        }
+    }
+
+    /// Returns the current value of each of the given variables.
+    fn var_values(&mut self, vars: &[char]) -> Vec<(char, Val)> {
+        vars.iter()
+            .filter_map(|var| self.data.get_var(*var).map(|val| (*var, val.clone())))
+            .collect()
+    }
+
+    /// Returns true if the program has finished or jumped,
+    /// so any loops and blocks that are running should stop.
+    fn interrupted(&self) -> bool {
+        self.finished || self.pending_jump.is_some()
+    }
+
+    /// Finds the index of each label in a program list,
+    /// and checks that every jump goes to one of them.
+    fn find_labels(plist: &[LbToken]) -> Result<HashMap<char, usize>, LbError> {
+        let mut labels = HashMap::new();
+        for (i, token) in plist.iter().enumerate() {
+            if let Label(label) = token {
+                if labels.insert(*label, i).is_some() {
+                    return Err(LbError::DuplicateLabel(*label));
+                }
+            }
+        }
+
+        // check jumps at any depth, including inside loops and blocks
+        let mut tokens: Vec<&LbToken> = plist.iter().collect();
+        while let Some(token) = tokens.pop() {
+            if let Jump(label) | JumpIf((_, label)) = token {
+                if !labels.contains_key(label) {
+                    return Err(LbError::UndefinedLabel(*label));
+                }
+            }
+            tokens.extend(token.subcommands());
+        }
+
+        Ok(labels)
+    }
 
     /// Increment the program counter, which determines which
     /// instruction to execute next.
@@ -457,39 +2343,446 @@ impl<'a> LbProgram<'a> {
             self.finished = true;
         }
     }
+}
 
-    /// Used by Execute (`Xzacbd`).
-    /// 
-    /// Given a string of sequential argument mappings (i.e. "acbd"), and a String containing
-    /// a Letterbox program, replaces each usage of a parameter name with its given variable.
-    /// For the given example, all usages of 'a' will be replaced with 'c' and 'b' will be replaced
-    /// with 'd'.
-    /// 
-    /// This does not affect hardcoded strings being saved or printed in the program, despite the current
-    /// lexer implementation not allowing for strings within strings.
-    fn apply_argmap(raw: String, argmap: String) -> String {
+/// Builds a Letterbox program one command at a time, without writing its source code.
+/// Each method adds a command, like [LbProgramBuilder::save] for `Sa4`.
+/// Loops and conditionals take a function that builds their body as a block.
+/// Arguments are checked as they are added, and the first that isn't valid,
+/// such as a variable name that isn't a letter, is returned by [LbProgramBuilder::build].
+///
+/// ```
+/// use letterbox_lang::prelude::*;
+///
+/// let program = LbProgramBuilder::new()
+///     .save('a', 3.0)
+///     .save('o', 1.0)
+///     .while_loop('a', |body| body.print_var('a').math('S', 'a', 'a', 'o'));
+/// assert_eq!(program.to_source(), Ok(String::from("Sa3 So1 Wa[ Pa MSaao ]")));
+///
+/// let mut data = LbStorage::new();
+/// let mut out = String::new();
+/// let ast = program.build().unwrap();
+/// ast.run_with(&mut data, &Vec::new(), StringOutput::new(&mut out), 1000).unwrap();
+/// assert_eq!(out, "321");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LbProgramBuilder {
+    commands: Vec<LbToken>,
+
+    /// The first problem with the commands added so far, if any.
+    error: Option<LbError>,
+}
 
-        // use this regex to match quotes
-        let rx_quotes = Regex::new(r"'[^']*'").expect("Invalid regex");
+impl LbProgramBuilder {
+    /// Returns a builder for an empty program.
+    pub fn new() -> LbProgramBuilder {
+        LbProgramBuilder::default()
+    }
 
-        // remove all quoted strings from the text
-        let quoted_strings = rx_quotes.find_iter(&raw);
-        let text_no_quotes = rx_quotes.replace_all(&raw, "%%%");
-        let mut replaceable_text = String::from(text_no_quotes);
-        
-        // replace each parameter with its given variable
-        let argvec: Vec<char> = argmap.chars().collect();
-        for i in (0..argmap.len()).step_by(2) {
-            let param = argvec[i];
-            let arg = argvec[i + 1];
-            replaceable_text = replaceable_text.replace(&String::from(param), &String::from(arg));
+    /// Returns the program, or the first problem with its commands.
+    /// Its commands are the ones that were added, not lexed again, and its source code is
+    /// [LbProgramBuilder::to_source], with each command's spans pointing at where it is written there.
+    /// Labels and jumps are checked when the program is created, as with any source code.
+    pub fn build(self) -> Result<LbAst, LbError> {
+        let source = self.to_source()?;
+        let mut instructions = Vec::new();
+        let mut start = 0;
+        for command in self.commands {
+            let end = start + command.to_source().len();
+            let spans = SpanTree::new(&command, &source, start..end);
+            instructions.push(Spanned { node: command, spans });
+            start = end + 1;
         }
-        
-        // put the quotes back
-        for quote in quoted_strings {
-            replaceable_text = replaceable_text.replacen("%%%", quote.as_str(), 1);
+        Ok(LbAst { source, instructions })
+    }
+
+    /// Returns the program's source code, with its commands separated by spaces,
+    /// or the first problem with its commands. See [LbToken::to_source].
+    pub fn to_source(&self) -> Result<String, LbError> {
+        match &self.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(lb_lexer::to_source(&self.commands)),
+        }
+    }
+
+    /// Saves a number in variable a, like `Sa4`.
+    pub fn save(self, a: char, num: f64) -> Self {
+        let command = if num.is_finite() {
+            check_vars('S', &[a]).map(|_| SaveNumber((a, num)))
+        } else {
+            Err(LbError::InvalidNumber(num))
+        };
+        self.add(command)
+    }
+
+    /// Saves text in variable a, like `Sa'hello'`.
+    pub fn save_str(self, a: char, text: &str) -> Self {
+        self.add(check_vars('S', &[a]).map(|_| SaveStr((a, text.to_string()))))
+    }
+
+    /// Saves a list in variable a, like `Sa[1 'two' [3]]`.
+    pub fn save_list(self, a: char, items: &[ListItem]) -> Self {
+        let command = match non_finite_item(items) {
+            Some(num) => Err(LbError::InvalidNumber(num)),
+            None => check_vars('S', &[a]).map(|_| SaveList((a, items.to_vec()))),
+        };
+        self.add(command)
+    }
+
+    /// Copies the value of variable b into variable a, like `Cab`.
+    pub fn copy(self, a: char, b: char) -> Self {
+        self.add(check_vars('C', &[a, b]).map(|_| Copy((a, b))))
+    }
+
+    /// Appends the value of variable b to variable a, like `Aab`.
+    pub fn append(self, a: char, b: char) -> Self {
+        self.add(check_vars('A', &[a, b]).map(|_| Append((a, b))))
+    }
+
+    /// Prints the value of variable a, like `Pa`.
+    pub fn print_var(self, a: char) -> Self {
+        self.add(check_vars('P', &[a]).map(|_| PrintVar(a)))
+    }
+
+    /// Prints text, like `P'hello'`.
+    pub fn print_str(self, text: &str) -> Self {
+        self.add(Ok(PrintStr(text.to_string())))
+    }
+
+    /// Prints the value of variable a and a newline, like `Oa`.
+    pub fn print_line_var(self, a: char) -> Self {
+        self.add(check_vars('O', &[a]).map(|_| PrintLineVar(a)))
+    }
+
+    /// Prints text and a newline, like `O'hello'`.
+    pub fn print_line_str(self, text: &str) -> Self {
+        self.add(Ok(PrintLineStr(text.to_string())))
+    }
+
+    /// Performs a math op on b and c, storing the result in a, like `MAabc`.
+    pub fn math(self, op: char, a: char, b: char, c: char) -> Self {
+        let command = check_op('M', op, lb_lexer::MATH_OPS)
+            .and_then(|_| check_vars('M', &[a, b, c]))
+            .map(|_| MathOp((op, a, b, c)));
+        self.add(command)
+    }
+
+    /// Performs a math op on b, storing the result in a, like `MFab`.
+    pub fn unary_math(self, op: char, a: char, b: char) -> Self {
+        let command = check_op('M', op, lb_lexer::UNARY_MATH_OPS)
+            .and_then(|_| check_vars('M', &[a, b]))
+            .map(|_| UnaryMathOp((op, a, b)));
+        self.add(command)
+    }
+
+    /// Performs a boolean op on b and c, storing the result in a, like `BAabc`.
+    pub fn bool_op(self, op: char, a: char, b: char, c: char) -> Self {
+        let command = check_op('B', op, lb_lexer::BOOL_OPS)
+            .and_then(|_| check_vars('B', &[a, b, c]))
+            .map(|_| BoolOp((op, a, b, c)));
+        self.add(command)
+    }
+
+    /// Performs a boolean op on b, storing the result in a, like `BNab`.
+    pub fn unary_bool(self, op: char, a: char, b: char) -> Self {
+        let command = check_op('B', op, lb_lexer::UNARY_BOOL_OPS)
+            .and_then(|_| check_vars('B', &[a, b]))
+            .map(|_| UnaryBoolOp((op, a, b)));
+        self.add(command)
+    }
+
+    /// Performs a text op on the given variables, like `TSdsil`.
+    pub fn text(self, op: char, vars: &str) -> Self {
+        self.add(op_args('T', op, vars).map(|vars| TextOp((op, vars))))
+    }
+
+    /// Performs a list op on the given variables, like `VSliv`.
+    pub fn list(self, op: char, vars: &str) -> Self {
+        self.add(op_args('V', op, vars).map(|vars| ListOp((op, vars))))
+    }
+
+    /// Performs a map op on the given variables, like `HSmkv`.
+    pub fn map(self, op: char, vars: &str) -> Self {
+        self.add(op_args('H', op, vars).map(|vars| MapOp((op, vars))))
+    }
+
+    /// Runs the commands built by `body` the number of times in variable a, like `La[ X Y ]`.
+    pub fn repeat(self, a: char, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        let body = build_block(body);
+        self.add(check_vars('L', &[a]).and(body).map(|body| Loop((a, Box::new(body)))))
+    }
+
+    /// Runs the commands built by `body` the given number of times, like `L5[ X Y ]`.
+    pub fn repeat_times(self, count: usize, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        self.add(build_block(body).map(|body| Repeat((count, Box::new(body)))))
+    }
+
+    /// Runs the commands built by `body` while variable a is nonzero, like `Wa[ X Y ]`.
+    pub fn while_loop(self, a: char, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        let body = build_block(body);
+        self.add(check_vars('W', &[a]).and(body).map(|body| WhileLoop((a, Box::new(body)))))
+    }
+
+    /// Runs the commands built by `body` if variable a is nonzero, like `Ia[ X Y ]`.
+    pub fn if_then(self, a: char, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        let body = build_block(body);
+        self.add(check_vars('I', &[a]).and(body).map(|body| IfStatement((a, Box::new(body), None))))
+    }
+
+    /// Runs the commands built by `body` if variable a is nonzero,
+    /// or those built by `otherwise` if not, like `Ia[ X ]:[ Y ]`.
+    pub fn if_else(self, a: char,
+        body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder,
+        otherwise: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder,
+    ) -> Self {
+        let branches = build_block(body).and_then(|body| Ok((body, build_block(otherwise)?)));
+        let command = check_vars('I', &[a])
+            .and(branches)
+            .map(|(body, otherwise)| IfStatement((a, Box::new(body), Some(Box::new(otherwise)))));
+        self.add(command)
+    }
+
+    /// Runs the commands built by `body` if variable a is zero, like `Ua[ X Y ]`.
+    pub fn unless(self, a: char, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        let body = build_block(body);
+        self.add(check_vars('U', &[a]).and(body).map(|body| Unless((a, Box::new(body), None))))
+    }
+
+    /// Runs the commands built by `body` if variable a is zero,
+    /// or those built by `otherwise` if not, like `Ua[ X ]:[ Y ]`.
+    pub fn unless_else(self, a: char,
+        body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder,
+        otherwise: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder,
+    ) -> Self {
+        let branches = build_block(body).and_then(|body| Ok((body, build_block(otherwise)?)));
+        let command = check_vars('U', &[a])
+            .and(branches)
+            .map(|(body, otherwise)| Unless((a, Box::new(body), Some(Box::new(otherwise)))));
+        self.add(command)
+    }
+
+    /// Runs the commands built by `body` in a block, like `[ X Y ]`.
+    pub fn block(self, body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Self {
+        let body = build_block(body);
+        self.add(body)
+    }
+
+    /// Resets variable a to 0, like `Ra`.
+    pub fn reset(self, a: char) -> Self {
+        let command = check_vars('R', &[a]).map(|_| ResetVar(a));
+        self.add(command)
+    }
+
+    /// Resets every variable in the active bank, like `R*`.
+    pub fn reset_all(self) -> Self {
+        self.add(Ok(ResetAll))
+    }
+
+    /// Resets every variable in every bank, like `R#`.
+    pub fn reset_all_banks(self) -> Self {
+        self.add(Ok(ResetAllBanks))
+    }
+
+    /// Switches to the given bank of variables, like `#1`.
+    pub fn switch_bank(self, bank: u8) -> Self {
+        if usize::from(bank) >= storage::BANK_COUNT {
+            return self.add(Err(LbError::InvalidBank(usize::from(bank))));
+        }
+        self.add(Ok(SwitchBank(bank)))
+    }
+
+    /// Stores the input at the given index in variable a, as a number if the op is `N`
+    /// or text if it is `S`, like `GNa0`.
+    pub fn get_input(self, op: char, a: char, index: usize) -> Self {
+        let command = check_op('G', op, "NS")
+            .and_then(|_| check_vars('G', &[a]))
+            .map(|_| GetInput((op, a, index as f64)));
+        self.add(command)
+    }
+
+    /// Stores the input at the index in variable b in variable a, like `GNab`.
+    pub fn get_input_at(self, op: char, a: char, b: char) -> Self {
+        let command = check_op('G', op, "NS")
+            .and_then(|_| check_vars('G', &[a, b]))
+            .map(|_| GetInputAt((op, a, b)));
+        self.add(command)
+    }
+
+    /// Stores the number of inputs in variable a, like `G#a`.
+    pub fn input_count(self, a: char) -> Self {
+        self.add(check_vars('G', &[a]).map(|_| InputCount(a)))
+    }
+
+    /// Asks the host for input and stores it in variable a, like `GQaN`.
+    pub fn query_input(self, a: char, op: char) -> Self {
+        let command = check_op('G', op, "NS")
+            .and_then(|_| check_vars('G', &[a]))
+            .map(|_| QueryInput((a, op)));
+        self.add(command)
+    }
+
+    /// Sets variable a to 1 if it is 0, or to 0 otherwise, like `Na`.
+    pub fn negate(self, a: char) -> Self {
+        self.add(check_vars('N', &[a]).map(|_| Negate(a)))
+    }
+
+    /// Pushes the value of variable a onto the stack, like `K+a`.
+    pub fn push(self, a: char) -> Self {
+        self.add(check_vars('K', &[a]).map(|_| Push(a)))
+    }
+
+    /// Pops the top of the stack into variable a, like `K-a`.
+    pub fn pop(self, a: char) -> Self {
+        self.add(check_vars('K', &[a]).map(|_| Pop(a)))
+    }
+
+    /// Stores the number of values on the stack in variable a, like `K#a`.
+    pub fn stack_depth(self, a: char) -> Self {
+        self.add(check_vars('K', &[a]).map(|_| StackDepth(a)))
+    }
+
+    /// Stores a random number in variable a, like `Da`.
+    pub fn random(self, a: char) -> Self {
+        self.add(check_vars('D', &[a]).map(|_| Random(a)))
+    }
+
+    /// Pauses for the number of milliseconds in variable a, like `Qa`.
+    pub fn sleep(self, a: char) -> Self {
+        self.add(check_vars('Q', &[a]).map(|_| Sleep(a)))
+    }
+
+    /// Stores the milliseconds since the program started in variable a, like `Ea`.
+    pub fn elapsed(self, a: char) -> Self {
+        self.add(check_vars('E', &[a]).map(|_| Elapsed(a)))
+    }
+
+    /// Fails the program if variable a is false, like `Za`.
+    pub fn assert(self, a: char) -> Self {
+        self.add(check_vars('Z', &[a]).map(|_| Assert(a)))
+    }
+
+    /// Fails the program if variables a and b aren't the same value, like `Zab`.
+    pub fn assert_same(self, a: char, b: char) -> Self {
+        self.add(check_vars('Z', &[a, b]).map(|_| AssertSame((a, b))))
+    }
+
+    /// Finishes the program, like `F`.
+    pub fn finish(self) -> Self {
+        self.add(Ok(Finish))
+    }
+
+    /// Finishes the program with the exit status in variable a, like `Fa`.
+    pub fn finish_with_status(self, a: char) -> Self {
+        self.add(check_vars('F', &[a]).map(|_| FinishWithStatus(a)))
+    }
+
+    /// Marks a place that jumps can go to, like `:a`. Labels are lowercase letters.
+    pub fn label(self, label: char) -> Self {
+        self.add(check_label(':', label).map(|_| Label(label)))
+    }
+
+    /// Continues the program from a label, like `Ja`.
+    pub fn jump(self, label: char) -> Self {
+        self.add(check_label('J', label).map(|_| Jump(label)))
+    }
+
+    /// Continues the program from a label if variable a is nonzero, like `JIab`.
+    pub fn jump_if(self, a: char, label: char) -> Self {
+        let command = check_vars('J', &[a])
+            .and_then(|_| check_label('J', label))
+            .map(|_| JumpIf((a, label)));
+        self.add(command)
+    }
+
+    /// Runs the program in variable z, with each pair's first variable replaced by its second,
+    /// and stores any value it returns in `dest`, like `Xzacbd>r`.
+    pub fn execute(self, z: char, args: &[(char, char)], dest: Option<char>) -> Self {
+        let command = check_vars('X', &[z])
+            .and_then(|_| execute_args(args, dest))
+            .map(|args| Execute((z, args, dest)));
+        self.add(command)
+    }
+
+    /// Runs the given program like [LbProgramBuilder::execute], like `X'Pa'acbd>r`.
+    pub fn execute_str(self, program: &str, args: &[(char, char)], dest: Option<char>) -> Self {
+        let command = execute_args(args, dest).map(|args| ExecuteStr((program.to_string(), args, dest)));
+        self.add(command)
+    }
+
+    /// Stops an executed program, returning the value of variable a, like `Ya`.
+    pub fn return_var(self, a: char) -> Self {
+        self.add(check_vars('Y', &[a]).map(|_| Return(a)))
+    }
+
+    /// Adds a command, or records the problem with it if there isn't already one.
+    fn add(mut self, command: Result<LbToken, LbError>) -> Self {
+        match command {
+            Ok(command) => self.commands.push(command),
+            Err(error) => {
+                self.error.get_or_insert(error);
+            },
         }
+        self
+    }
+}
+
+/// Builds the body of a loop, conditional, or block.
+fn build_block(body: impl FnOnce(LbProgramBuilder) -> LbProgramBuilder) -> Result<LbToken, LbError> {
+    let builder = body(LbProgramBuilder::new());
+    match builder.error {
+        Some(error) => Err(error),
+        None => Ok(Block(builder.commands)),
+    }
+}
+
+/// Checks that each of the given characters is a variable name.
+fn check_vars(command: char, vars: &[char]) -> Result<(), LbError> {
+    match vars.iter().find(|var| !storage::is_var(var)) {
+        Some(var) => Err(LbError::InvalidVariable { command, var: *var }),
+        None => Ok(()),
+    }
+}
+
+/// Finds a number in the items of a list literal, at any depth, that isn't finite.
+fn non_finite_item(items: &[ListItem]) -> Option<f64> {
+    items.iter().find_map(|item| match item {
+        ListItem::Number(num) if !num.is_finite() => Some(*num),
+        ListItem::List(items) => non_finite_item(items),
+        _ => None,
+    })
+}
+
+/// Checks that an op is one of the given ops.
+fn check_op(command: char, op: char, ops: &str) -> Result<(), LbError> {
+    if !ops.contains(op) {
+        return Err(LbError::InvalidOp { command, op });
+    }
+    Ok(())
+}
+
+/// Checks that a label is a lowercase letter.
+fn check_label(command: char, label: char) -> Result<(), LbError> {
+    if !label.is_ascii_lowercase() {
+        return Err(LbError::InvalidVariable { command, var: label });
+    }
+    Ok(())
+}
 
-        return replaceable_text;
+/// Checks the variables of a text, list, or map op, and returns them as a string.
+fn op_args(command: char, op: char, vars: &str) -> Result<String, LbError> {
+    let expected = lb_lexer::op_arity(command, op).ok_or(LbError::InvalidOp { command, op })?;
+    let vars: Vec<char> = vars.chars().collect();
+    if vars.len() != expected {
+        return Err(LbError::WrongArgCount { command, op, expected, found: vars.len() });
     }
-}
\ No newline at end of file
+    check_vars(command, &vars)?;
+    Ok(vars.into_iter().collect())
+}
+
+/// Checks the argument pairs and destination of an Execute command, and returns the pairs as a string.
+fn execute_args(args: &[(char, char)], dest: Option<char>) -> Result<String, LbError> {
+    let vars: Vec<char> = args.iter().flat_map(|(param, var)| [*param, *var]).chain(dest).collect();
+    check_vars('X', &vars)?;
+    Ok(args.iter().flat_map(|(param, var)| [*param, *var]).collect())
+}
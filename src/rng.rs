@@ -0,0 +1,40 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A small pseudorandom number generator (SplitMix64).
+/// Each [crate::program::LbProgram] owns one, so programs sharing
+/// a [crate::storage::LbStorage] never interfere with each other's randomness.
+#[derive(Debug, Clone)]
+pub struct LbRng {
+    state: u64,
+}
+
+impl LbRng {
+    /// Returns a generator that always produces the same sequence for the same seed.
+    pub fn new(seed: u64) -> LbRng {
+        LbRng { state: seed }
+    }
+
+    /// Returns a generator seeded from the system clock.
+    pub fn from_time() -> LbRng {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        LbRng::new(nanos)
+    }
+
+    /// Returns the next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a uniformly distributed float in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        // keep the 53 bits an f64 can represent exactly
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
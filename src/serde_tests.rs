@@ -0,0 +1,318 @@
+// Tests for the serde feature, run with `cargo test --features serde`.
+// Commands are serialized into a small tree of values that keeps every detail serde gives it,
+// so these tests don't depend on any particular format.
+
+use std::fmt;
+use std::vec;
+
+use serde::de::{self, DeserializeSeed, EnumAccess, IntoDeserializer, SeqAccess, VariantAccess, Visitor};
+use serde::ser::{self, Impossible, SerializeSeq, SerializeTuple};
+use serde::{forward_to_deserialize_any, Deserialize, Serialize};
+
+use crate::lb_lexer::LbToken;
+use logos::Logos;
+
+/// A serialized value.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Uint(u64),
+    Char(char),
+    Text(String),
+    Option(Option<Box<Value>>),
+    Seq(Vec<Value>),
+    /// An enum variant, with its contents if it has any.
+    Variant(&'static str, Option<Box<Value>>),
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Value {
+    value.serialize(Serializer).expect("Serializing failed")
+}
+
+fn from_value<'de, T: Deserialize<'de>>(value: Value) -> Result<T, String> {
+    T::deserialize(value).map_err(|e| e.0)
+}
+
+struct Serializer;
+
+/// Collects the elements of a sequence or tuple.
+struct SeqSerializer(Vec<Value>);
+
+fn unsupported<T>(kind: &str) -> Result<T, Error> {
+    Err(Error(format!("{kind} can't be serialized")))
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SeqSerializer;
+    type SerializeTuple = SeqSerializer;
+    type SerializeTupleStruct = Impossible<Value, Error>;
+    type SerializeTupleVariant = Impossible<Value, Error>;
+    type SerializeMap = Impossible<Value, Error>;
+    type SerializeStruct = Impossible<Value, Error>;
+    type SerializeStructVariant = Impossible<Value, Error>;
+
+    fn serialize_bool(self, _: bool) -> Result<Value, Error> { unsupported("bool") }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> { self.serialize_i64(v.into()) }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> { self.serialize_i64(v.into()) }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> { self.serialize_i64(v.into()) }
+    fn serialize_i64(self, _: i64) -> Result<Value, Error> { unsupported("i64") }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> { self.serialize_u64(v.into()) }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> { self.serialize_u64(v.into()) }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> { self.serialize_u64(v.into()) }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> { Ok(Value::Uint(v)) }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> { self.serialize_f64(v.into()) }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> { Ok(Value::Number(v)) }
+    fn serialize_char(self, v: char) -> Result<Value, Error> { Ok(Value::Char(v)) }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> { Ok(Value::Text(v.to_string())) }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Value, Error> { unsupported("bytes") }
+    fn serialize_none(self) -> Result<Value, Error> { Ok(Value::Option(None)) }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, Error> {
+        Ok(Value::Option(Some(Box::new(value.serialize(self)?))))
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> { unsupported("unit") }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value, Error> { unsupported("unit struct") }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<Value, Error> {
+        Ok(Value::Variant(variant, None))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _: &'static str, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self, _: &'static str, _: u32, variant: &'static str, value: &T,
+    ) -> Result<Value, Error> {
+        Ok(Value::Variant(variant, Some(Box::new(value.serialize(self)?))))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, Error> {
+        Ok(SeqSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Error> {
+        unsupported("tuple struct")
+    }
+
+    fn serialize_tuple_variant(
+        self, _: &'static str, _: u32, _: &'static str, _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        unsupported("tuple variant")
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> { unsupported("map") }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        unsupported("struct")
+    }
+
+    fn serialize_struct_variant(
+        self, _: &'static str, _: u32, _: &'static str, _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        unsupported("struct variant")
+    }
+}
+
+impl SerializeSeq for SeqSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.0.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Seq(self.0))
+    }
+}
+
+impl SerializeTuple for SeqSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Number(num) => visitor.visit_f64(num),
+            Value::Uint(num) => visitor.visit_u64(num),
+            Value::Char(c) => visitor.visit_char(c),
+            Value::Text(text) => visitor.visit_string(text),
+            Value::Option(None) => visitor.visit_none(),
+            Value::Option(Some(value)) => visitor.visit_some(*value),
+            Value::Seq(values) => visitor.visit_seq(SeqDeserializer(values.into_iter())),
+            variant @ Value::Variant(..) => visitor.visit_enum(variant),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct SeqDeserializer(vec::IntoIter<Value>);
+
+impl<'de> SeqAccess<'de> for SeqDeserializer {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        self.0.next().map(|value| seed.deserialize(value)).transpose()
+    }
+}
+
+impl<'de> EnumAccess<'de> for Value {
+    type Error = Error;
+    type Variant = Contents;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Contents), Error> {
+        let Value::Variant(name, contents) = self else {
+            return Err(Error(String::from("expected a variant")));
+        };
+        let variant = seed.deserialize(IntoDeserializer::<Error>::into_deserializer(name))?;
+        Ok((variant, Contents(contents)))
+    }
+}
+
+/// The contents of an enum variant, if it has any.
+struct Contents(Option<Box<Value>>);
+
+impl<'de> VariantAccess<'de> for Contents {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.0 {
+            None => Ok(()),
+            Some(_) => Err(Error(String::from("expected a unit variant"))),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let value = self.0.ok_or_else(|| Error(String::from("expected a newtype variant")))?;
+        seed.deserialize(*value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, _: V) -> Result<V::Value, Error> {
+        Err(Error(String::from("tuple variants aren't supported")))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _: &'static [&'static str], _: V) -> Result<V::Value, Error> {
+        Err(Error(String::from("struct variants aren't supported")))
+    }
+}
+
+#[test]
+fn token_round_trips() {
+    // every kind of command but Error, with the bodies of loops and conditionals nested in each other
+    let source = "Sa4 Sb-1.5 Sc'text' Sd[1 'x' [2 []]] Cab Aab Pa P'hi' Oa O'hi' MAcab MFab BXcab B&cab BNab \
+        TSdsil TUds VNl VSlia HNm HGvmk LaPa L3[ Pa Pb ] WaMSaab IaPb:Ob UaIbPc:Pd Ia[ IbPc:Pd ] Ra R* R# #1 \
+        GNa0 GSab G#a GQaN Na K+a K-a K#a Da Qa Ea Za Zab F Fa :a Ja JIab Xzab Xzabcd>e X'Pa'ab Ya [ ] [ Pa [ Pb ] ]";
+    let tokens: Vec<LbToken> = LbToken::lexer(source).collect();
+    assert_eq!(tokens.len(), 55);
+    assert!(!tokens.contains(&LbToken::Error));
+    for token in tokens {
+        let value = to_value(&token);
+        assert_eq!(from_value::<LbToken>(value), Ok(token));
+    }
+
+    // commands keep their nesting
+    let token = LbToken::Loop(('a', Box::new(LbToken::PrintVar('b'))));
+    assert_eq!(to_value(&token), Value::Variant("Loop", Some(Box::new(Value::Seq(vec![
+        Value::Char('a'),
+        Value::Variant("PrintVar", Some(Box::new(Value::Char('b')))),
+    ])))));
+
+    // a whole program
+    let program: Vec<LbToken> = LbToken::lexer("Sa2 La[ Pa Ib[ Pb ]:Pc ]").collect();
+    assert_eq!(from_value::<Vec<LbToken>>(to_value(&program)), Ok(program));
+}
+
+#[test]
+fn invalid_tokens() {
+    let variant = |name, value| Value::Variant(name, Some(Box::new(value)));
+    let seq = Value::Seq;
+    let c = Value::Char;
+    let text = |text: &str| Value::Text(text.to_string());
+
+    assert_eq!(from_value::<LbToken>(variant("PrintVar", c('4'))), Err(String::from("P: 4 is not a variable name")));
+    assert_eq!(
+        from_value::<LbToken>(variant("MathOp", seq(vec![c('Z'), c('a'), c('b'), c('c')]))),
+        Err(String::from("M: Invalid op Z")),
+    );
+    assert_eq!(
+        from_value::<LbToken>(variant("UnaryMathOp", seq(vec![c('B'), c('a'), c('b')]))),
+        Err(String::from("M: Invalid op B")),
+    );
+    assert_eq!(
+        from_value::<LbToken>(variant("TextOp", seq(vec![c('S'), text("dsi")]))),
+        Err(String::from("T: Op S takes 4 variables, but was given 3")),
+    );
+    assert_eq!(
+        from_value::<LbToken>(variant("ListOp", seq(vec![c('Z'), text("l")]))),
+        Err(String::from("V: Invalid op Z")),
+    );
+    assert_eq!(
+        from_value::<LbToken>(variant("GetInput", seq(vec![c('N'), c('a'), Value::Number(-1.0)]))),
+        Err(String::from("G: -1 is not an input index")),
+    );
+    assert_eq!(from_value::<LbToken>(variant("Jump", c('A'))), Err(String::from("J: A is not a label name")));
+    assert_eq!(
+        from_value::<LbToken>(variant("Execute", seq(vec![c('z'), text("abc"), Value::Option(None)]))),
+        Err(String::from("X: Parameter c isn't given a variable")),
+    );
+    assert_eq!(
+        from_value::<LbToken>(variant("Execute", seq(vec![c('z'), text("a1"), Value::Option(None)]))),
+        Err(String::from("X: 1 is not a variable name")),
+    );
+    assert_eq!(from_value::<LbToken>(Value::Variant("Error", None)), Err(String::from("Unrecognized instruction")));
+
+    // nested commands are checked too
+    let body = variant("Block", seq(vec![variant("PrintVar", c('a')), variant("Negate", c(' '))]));
+    assert_eq!(
+        from_value::<LbToken>(variant("Loop", seq(vec![c('a'), body]))),
+        Err(String::from("N:   is not a variable name")),
+    );
+}
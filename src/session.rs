@@ -0,0 +1,78 @@
+use std::ops::Range;
+
+use crate::ast::LbAst;
+use crate::error::LbError;
+use crate::lb_lexer::{LbToken, SpanTree};
+use crate::program::{LbProgram, StringOutput};
+use crate::storage::LbStorage;
+
+/// Runs snippets of Letterbox one after another against the same storage, like a REPL.
+/// Each snippet is its own program, so `F` only finishes that snippet,
+/// and labels and output don't carry over to the next one.
+///
+/// ```
+/// use letterbox_lang::prelude::*;
+///
+/// let mut session = LbSession::new(1000);
+/// assert_eq!(session.eval("Sa2 Sb'apples'"), Ok(String::new()));
+/// assert_eq!(session.eval("MMcaa Pc P' ' Ob F Pa"), Ok(String::from("4 apples\n")));
+/// assert_eq!(session.eval("Pa ~").map_err(|e| e.to_string()), Err(String::from("Unrecognized instruction at 3..4")));
+/// ```
+pub struct LbSession {
+    data: LbStorage,
+
+    /// The inputs that `GNa1`, `GSab`, and `G#a` commands read in every snippet.
+    pub inputs: Vec<String>,
+
+    /// The maximum number of times a loop can run in each snippet. See [LbProgram::loop_limit].
+    pub loop_limit: usize,
+}
+
+impl LbSession {
+    /// Returns a new session with empty storage and no inputs.
+    pub fn new(loop_limit: usize) -> LbSession {
+        LbSession { data: LbStorage::new(), inputs: Vec::new(), loop_limit }
+    }
+
+    /// Returns the storage that every snippet shares.
+    pub fn storage(&self) -> &LbStorage {
+        &self.data
+    }
+
+    /// Returns the storage that every snippet shares, so it can be changed between snippets.
+    pub fn storage_mut(&mut self) -> &mut LbStorage {
+        &mut self.data
+    }
+
+    /// Runs a snippet and returns what it printed.
+    /// If any part of the snippet can't be parsed, none of it runs.
+    /// If it fails partway through, the changes it made to storage are kept.
+    pub fn eval(&mut self, source: &str) -> Result<String, LbError> {
+        let ast = LbAst::lex(source);
+        let trees: Vec<(&LbToken, &SpanTree)> = ast.instructions.iter()
+            .map(|instruction| (&instruction.node, &instruction.spans))
+            .collect();
+        if let Some(span) = find_error(&trees) {
+            return Err(LbError::LexError { span });
+        }
+
+        let mut out = String::new();
+        let mut program = LbProgram::from_ast(&ast, &mut self.data, &self.inputs, StringOutput::new(&mut out), self.loop_limit)?;
+        program.run()?;
+        drop(program);
+        Ok(out)
+    }
+}
+
+/// Returns where the first part of a program that couldn't be parsed is, at any depth.
+fn find_error(instructions: &[(&LbToken, &SpanTree)]) -> Option<Range<usize>> {
+    instructions.iter().find_map(|(token, tree)| {
+        if **token == LbToken::Error {
+            return Some(tree.span.clone());
+        }
+        let nested: Vec<(&LbToken, &SpanTree)> = token.subcommands().into_iter()
+            .zip(tree.children.iter())
+            .collect();
+        find_error(&nested)
+    })
+}
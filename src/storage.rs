@@ -1,7 +1,15 @@
-use std::collections::HashMap;
-use crate::program::Val;
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use crate::program::{self, Val};
+use crate::error::LbError;
+use crate::lb_lexer;
 
-const VALID_VARS: &str = "abcdefghijklmnopqrstuvwxyz";
+/// How many banks of variables an [LbStorage] has. See [LbStorage::set_bank].
+pub const BANK_COUNT: usize = 10;
+
+const VALID_VARS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
 
 /// Returns true if and only if the given character is a valid
 /// name for a [LbStorage] variable.
@@ -9,71 +17,688 @@ pub fn is_var(c: &char) -> bool {
     VALID_VARS.contains(*c)
 }
 
+/// The value of a variable that nothing has been stored in.
+static ZERO: Val = Val::Number(0.0);
+
+/// The variables of a bank that nothing has been stored in.
+static EMPTY_BANK: BTreeMap<char, Val> = BTreeMap::new();
+
+/// What a variable that nothing has been stored in holds. See [LbStorage::set_default_policy].
+#[derive(Debug, Clone, Default)]
+pub enum DefaultPolicy {
+    /// Unset variables hold `0`.
+    #[default]
+    Zero,
+
+    /// Reading an unset variable is an error, which catches misspelled variable names.
+    /// Programs fail with [LbError::UnsetVariable].
+    Error,
+
+    /// Unset variables hold the given value.
+    Value(Val),
+}
+
+/// A function that is told about each change to a variable. See [LbStorage::set_observer].
+type Observer = dyn FnMut(char, &Val, Option<&Val>) + Send;
 
 /// A data storage struct that can be operated upon by a [crate::program::LbProgram].
-/// Represents a bank of 26 variables, one for each lowercase letter
-/// of the alphabet. Each variable can store a single number (an f64) or String.
+/// Represents 52 variables, one for each lowercase letter
+/// of the alphabet and one for each uppercase letter. Each variable can store a single number (an f64), String,
+/// list of values, or map from strings to values.
+/// There are [BANK_COUNT] banks of these variables, and one of them is active at a time. See [LbStorage::set_bank].
+/// Also holds a stack of values, shared by every program and bank using this storage.
 pub struct LbStorage {
-    data: HashMap<char, Val>,
+    /// The variables of the active bank.
+    data: BTreeMap<char, Val>,
+    /// The number of the active bank.
+    bank: usize,
+    /// The variables of the other banks that have any, by number.
+    banks: BTreeMap<usize, BTreeMap<char, Val>>,
+    stack: Vec<Val>,
+    observer: Option<Box<Observer>>,
+    default: DefaultPolicy,
+}
+
+impl Default for LbStorage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LbStorage {
     /// Returns a new [LbStorage] instance. It can be used by one or more LbPrograms.
     pub fn new() -> LbStorage {
-        let store = LbStorage {
-            data: HashMap::new(),
-        };
-        return store;
+        LbStorage {
+            data: BTreeMap::new(),
+            bank: 0,
+            banks: BTreeMap::new(),
+            stack: Vec::new(),
+            observer: None,
+            default: DefaultPolicy::Zero,
+        }
+    }
+
+    /// Sets what variables that nothing has been stored in hold when they are read,
+    /// including variables that have been reset. By default they hold `0`.
+    /// With [DefaultPolicy::Error], reading them gives `None` and programs fail.
+    pub fn set_default_policy(&mut self, policy: DefaultPolicy) {
+        self.default = policy;
+    }
+
+    /// Returns what variables that nothing has been stored in hold. See [LbStorage::set_default_policy].
+    pub fn default_policy(&self) -> &DefaultPolicy {
+        &self.default
+    }
+
+    /// Gets the value stored under the given variable name, or the default value if nothing is.
+    /// Returns `None` if the name is invalid, or if nothing is stored and unset variables can't be read.
+    fn lookup(&self, var_name: char) -> Option<&Val> {
+        if !is_var(&var_name) {
+            return None;
+        }
+        match (self.data.get(&var_name), &self.default) {
+            (Some(val), _) => Some(val),
+            (None, DefaultPolicy::Zero) => Some(&ZERO),
+            (None, DefaultPolicy::Value(val)) => Some(val),
+            (None, DefaultPolicy::Error) => None,
+        }
     }
 
     /// Gets the value stored under the given variable name.
     /// If the name is invalid, returns `None`.
-    /// If nothing has been stored under the valid name, returns the default value of `0`.
+    /// If nothing has been stored under the valid name, returns the default value,
+    /// `0` unless [LbStorage::set_default_policy] says otherwise, without storing it.
     pub fn get_var(&mut self, var_name: char) -> Option<&Val> {
-        if !is_var(&var_name) {
-            return None;
+        self.lookup(var_name)
+    }
+
+    /// Gets a copy of the value stored under the given variable name,
+    /// without needing to change the storage.
+    /// If the name is invalid, returns `None`.
+    /// If nothing has been stored under the valid name, returns the default value, as [LbStorage::get_var] does.
+    pub fn peek_var(&self, var_name: char) -> Option<Val> {
+        self.lookup(var_name).cloned()
+    }
+
+    /// Gets a mutable reference to the value stored under the given variable name,
+    /// so it can be changed in place.
+    /// If the name is invalid, returns `None`.
+    /// If nothing has been stored under the valid name, the default value is stored first,
+    /// as [LbStorage::get_var] gives it.
+    pub fn get_var_mut(&mut self, var_name: char) -> Option<&mut Val> {
+        if !self.data.contains_key(&var_name) {
+            let default = self.lookup(var_name)?.clone();
+            self.data.insert(var_name, default);
+        }
+        self.data.get_mut(&var_name)
+    }
+
+    /// Sets a function to call whenever [LbStorage::set_var], [LbStorage::copy],
+    /// [LbStorage::reset_var], or [LbStorage::reset_all] changes a variable.
+    /// It is called after the change with the variable, its new value,
+    /// and its previous value, or `None` if nothing was stored in it.
+    /// [LbStorage::reset_all] calls it once for each variable that held a value, sorted by name.
+    /// Values changed in place through [LbStorage::get_var_mut], like lists that are appended to,
+    /// aren't reported. It must be [Send] so the storage can be moved to another thread,
+    /// such as when it is made into [SharedStorage].
+    pub fn set_observer<F: FnMut(char, &Val, Option<&Val>) + Send + 'static>(&mut self, observer: F) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Tells the observer, if there is one, that a variable changed.
+    fn notify(&mut self, var_name: char, new_value: &Val, previous: Option<&Val>) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(var_name, new_value, previous);
         }
-        let val = self.data.entry(var_name)
-            .or_insert(Val::zero());
-        Some(val)
     }
 
     /// Store a value under the given variable name.
-    /// Returns `Ok(())` if the value has been stored.
-    pub fn set_var(&mut self, var_name: char, new_value: &Val) -> Result<(), String> {
-        self.data.insert(var_name, (*new_value).clone());
+    /// Returns `Ok(())` if the value has been stored, or [LbError::NotAVariable]
+    /// if the name isn't a letter, as it could never be read back.
+    pub fn set_var(&mut self, var_name: char, new_value: &Val) -> Result<(), LbError> {
+        if !is_var(&var_name) {
+            return Err(LbError::NotAVariable(var_name));
+        }
+        let previous = self.data.insert(var_name, (*new_value).clone());
+        self.notify(var_name, new_value, previous.as_ref());
+        Ok(())
+    }
+
+    /// Stores anything that can be converted to a value, like a number, string, or bool,
+    /// under the given variable name. See [LbStorage::set_var].
+    ///
+    /// ```
+    /// use letterbox_lang::prelude::*;
+    ///
+    /// let mut data = LbStorage::new();
+    /// data.set('n', 3).unwrap();
+    /// data.set('g', "Hello").unwrap();
+    /// let inputs: Vec<Val> = vec![Val::from(2.5), Val::from(true)];
+    /// let mut out = String::new();
+    /// let lex = LbToken::lexer("GNa0 GNb1 MAsna MAssb Sr'' Arg Pr");
+    /// let mut program = LbProgram::with_io(lex, &mut data, inputs, StringOutput::new(&mut out), 1000).unwrap();
+    /// program.run().unwrap();
+    ///
+    /// assert_eq!(data.get_number('s'), Ok(6.5));
+    /// assert_eq!(data.get_text('r'), Ok("Hello"));
+    /// assert!(data.get_number('r').is_err());
+    /// assert_eq!(data.get_var('s').cloned().map(f64::try_from), Some(Ok(6.5)));
+    /// ```
+    pub fn set(&mut self, var_name: char, new_value: impl Into<Val>) -> Result<(), LbError> {
+        self.set_var(var_name, &new_value.into())
+    }
+
+    /// Gets the number stored under the given variable name, without needing to change the storage.
+    /// If nothing has been stored under the name, gives the default value, as [LbStorage::get_var] does.
+    /// A bool is read as 1 or 0. Fails with [LbError::VarTypeMismatch] if the variable holds something else,
+    /// and text isn't read as a number; see [LbStorage::get_number_lossy] for that.
+    pub fn get_number(&self, var_name: char) -> Result<f64, LbError> {
+        let val = self.read(var_name)?;
+        val.as_number().ok_or(LbError::VarTypeMismatch { var: var_name, expected: "number", found: val.type_name() })
+    }
+
+    /// Gets the number stored under the given variable name, like [LbStorage::get_number],
+    /// but reads text as a number as `TNdsf` does, ignoring surrounding whitespace.
+    /// Fails with [LbError::VarTypeMismatch] if the variable holds text that isn't a number, a list, or a map.
+    pub fn get_number_lossy(&self, var_name: char) -> Result<f64, LbError> {
+        let val = self.read(var_name)?;
+        let num = match val {
+            Val::Text(text) => program::parse_number(text),
+            other => other.as_number(),
+        };
+        num.ok_or(LbError::VarTypeMismatch { var: var_name, expected: "number", found: val.type_name() })
+    }
+
+    /// Gets the text stored under the given variable name, without needing to change the storage.
+    /// If nothing has been stored under the name, gives the default value, as [LbStorage::get_var] does.
+    /// Fails with [LbError::VarTypeMismatch] if the variable holds something else.
+    /// Numbers aren't formatted as text; use [Val::format] for that.
+    pub fn get_text(&self, var_name: char) -> Result<&str, LbError> {
+        let val = self.read(var_name)?;
+        val.as_text().ok_or(LbError::VarTypeMismatch { var: var_name, expected: "string", found: val.type_name() })
+    }
+
+    /// Gets the value stored under the given variable name, or the default value, for reading from Rust.
+    /// Fails if the name is invalid, or if nothing is stored and unset variables can't be read.
+    fn read(&self, var_name: char) -> Result<&Val, LbError> {
+        match self.lookup(var_name) {
+            Some(val) => Ok(val),
+            None if is_var(&var_name) => Err(LbError::UnsetVariable { var: var_name, position: None }),
+            None => Err(LbError::NotAVariable(var_name)),
+        }
+    }
+
+    /// Resets the value under the given name to the default value,
+    /// as if nothing had been stored in it.
+    /// Fails with [LbError::NotAVariable] if the name isn't a letter.
+    pub fn reset_var(&mut self, var_name: char) -> Result<(), LbError> {
+        if !is_var(&var_name) {
+            return Err(LbError::NotAVariable(var_name));
+        }
+        let previous = self.data.remove(&var_name);
+        self.notify(var_name, &Val::zero(), previous.as_ref());
         Ok(())
     }
 
-    /// Resets the value under the given name to the default value of `0`.
-    pub fn reset_var(&mut self, var_name: char) -> Result<(), String> {
-        self.data.remove(&var_name);
+    /// Resets ALL variables in the active bank to `0` and empties the stack.
+    /// Other banks are left as they are.
+    pub fn reset_all(&mut self) -> Result<(), LbError> {
+        let cleared = std::mem::take(&mut self.data);
+        for (var_name, previous) in cleared {
+            self.notify(var_name, &Val::zero(), Some(&previous));
+        }
+        self.stack.clear();
         Ok(())
     }
 
-    /// Resets ALL variables to `0`. Thw resulting storage is equivalent to `LbStorage::new()`.
-    pub fn reset_all(&mut self) -> Result<(), String> {
-        self.data.clear();
+    /// Resets ALL variables in every bank to `0` and empties the stack, keeping the same bank active.
+    /// The observer is only told about the variables of the active bank.
+    pub fn reset_all_banks(&mut self) -> Result<(), LbError> {
+        self.banks.clear();
+        self.reset_all()
+    }
+
+    /// Returns the number of the active bank, whose variables are read and written.
+    pub fn bank(&self) -> usize {
+        self.bank
+    }
+
+    /// Makes the given bank active, so its variables are read and written until another bank is.
+    /// Banks are numbered from 0 to [BANK_COUNT] - 1, and bank 0 is active to begin with.
+    /// Fails if there is no such bank.
+    pub fn set_bank(&mut self, bank: usize) -> Result<(), LbError> {
+        if bank >= BANK_COUNT {
+            return Err(LbError::InvalidBank(bank));
+        }
+        if bank != self.bank {
+            let previous = std::mem::replace(&mut self.data, self.banks.remove(&bank).unwrap_or_default());
+            if !previous.is_empty() {
+                self.banks.insert(self.bank, previous);
+            }
+            self.bank = bank;
+        }
         Ok(())
     }
 
+    /// Returns each variable that a value has been stored in within the given bank, as [LbStorage::iter] does
+    /// for the active bank. Gives nothing if there is no such bank.
+    pub fn bank_iter(&self, bank: usize) -> StorageIter<'_> {
+        StorageIter(self.bank_vars(bank).iter())
+    }
+
+    /// Returns the variables of the given bank, active or not.
+    fn bank_vars(&self, bank: usize) -> &BTreeMap<char, Val> {
+        if bank == self.bank {
+            return &self.data;
+        }
+        self.banks.get(&bank).unwrap_or(&EMPTY_BANK)
+    }
+
+    /// Returns each variable in the active bank that a value has been stored in, with its value,
+    /// sorted by name with uppercase letters first. Variables that have only been read,
+    /// or have been reset, are left out, and reading them doesn't store anything.
+    pub fn iter(&self) -> StorageIter<'_> {
+        StorageIter(self.data.iter())
+    }
+
+    /// Returns the number of variables in the active bank that a value has been stored in.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if no value has been stored in any variable of the active bank.
+    /// The stack isn't counted.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns true if a value has been stored in the given variable,
+    /// even if that value is `0`.
+    pub fn contains_var(&self, var_name: char) -> bool {
+        self.data.contains_key(&var_name)
+    }
+
+    /// Captures the variables of every bank and the stack as they are now, to be put back later with [LbStorage::restore].
+    pub fn snapshot(&self) -> LbSnapshot {
+        LbSnapshot {
+            data: Arc::new(self.data.clone()),
+            bank: self.bank,
+            banks: Arc::new(self.banks.clone()),
+            stack: Arc::new(self.stack.clone()),
+        }
+    }
+
+    /// Puts the variables of every bank, the active bank, and the stack back as they were when the snapshot was taken.
+    /// Variables stored in since then are reset, and the observer is kept but isn't told about the changes.
+    pub fn restore(&mut self, snapshot: &LbSnapshot) {
+        self.data = (*snapshot.data).clone();
+        self.bank = snapshot.bank;
+        self.banks = (*snapshot.banks).clone();
+        self.stack = (*snapshot.stack).clone();
+    }
+
+    /// Compares every variable in the active bank with the same variable in the other storage's active bank,
+    /// and returns those that differ, sorted by name. Unset variables hold `0`,
+    /// so a variable set to `0` is the same as one that was never set.
+    /// Values are compared as `BSab` compares them, so NaN always differs, and the stacks aren't compared.
+    pub fn diff(&self, other: &LbStorage) -> Vec<VarDiff> {
+        diff_vars(&self.data, &other.data)
+    }
+
+    /// Returns each variable that a value has been stored in, as [LbStorage] displays them.
+    pub fn dump(&self) -> String {
+        self.to_string()
+    }
+
+    /// Pushes a value onto the top of the stack.
+    pub fn push(&mut self, value: &Val) {
+        self.stack.push(value.clone());
+    }
+
+    /// Removes the value on top of the stack and returns it,
+    /// or returns `None` if the stack is empty.
+    pub fn pop(&mut self) -> Option<Val> {
+        self.stack.pop()
+    }
+
+    /// Returns the number of values on the stack.
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
     /// Copies a value from one variable to another.
     /// Does not affect the original value.
-    /// Returns `Ok(())` if the value was cloned successfully.
-    pub fn copy(&mut self, from_var: char, to_var: char) -> Result<(), String> {
-        let x = self.get_var(from_var).expect("Couldn't find variable");
+    /// Returns `Ok(())` if the value was cloned successfully, or an error
+    /// if the variable to copy from can't be read.
+    pub fn copy(&mut self, from_var: char, to_var: char) -> Result<(), LbError> {
+        let Some(x) = self.lookup(from_var) else {
+            if is_var(&from_var) {
+                return Err(LbError::UnsetVariable { var: from_var, position: None });
+            }
+            return Err(LbError::InvalidVariable { command: 'C', var: from_var });
+        };
         let y = (*x).clone();
         self.set_var(to_var, &y)
     }
 
-    /// Returns `Some(false)` if and only if the value under the given name is 0.
-    /// Otherwise, returns `Some(true)`.
-    /// If the given variable name is invalid, returns `None`.
+    /// Returns `Some(false)` if and only if the value under the given name is 0, false,
+    /// an empty list, or an empty map. Otherwise, returns `Some(true)`.
+    /// If the given variable name is invalid, or it is unset and can't be read, returns `None`.
     pub fn var_as_bool(&mut self, var_name: char) -> Option<bool> {
-        let x = self.get_var(var_name).expect("Couldn't find variable");
-        return match x {
+        let x = self.get_var(var_name)?;
+        match x {
             Val::Number(n) => Some(*n != 0.0),
+            Val::Bool(b) => Some(*b),
             Val::Text(_) => Some(true),
-        };
+            Val::List(list) => Some(!list.is_empty()),
+            Val::Map(map) => Some(!map.is_empty()),
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Returns each variable that differs between two banks of variables, for [LbStorage::diff].
+fn diff_vars(left: &BTreeMap<char, Val>, right: &BTreeMap<char, Val>) -> Vec<VarDiff> {
+    let mut vars: Vec<char> = left.keys().chain(right.keys()).copied().collect();
+    vars.sort();
+    vars.dedup();
+    vars.into_iter()
+        .map(|var| VarDiff {
+            var,
+            left: left.get(&var).unwrap_or(&ZERO).clone(),
+            right: right.get(&var).unwrap_or(&ZERO).clone(),
+        })
+        .filter(|diff| diff.left != diff.right)
+        .collect()
+}
+
+impl Clone for LbStorage {
+    /// Copies the variables of every bank, the active bank, the stack, and the default policy.
+    /// Observers can't be copied, so the clone has none.
+    fn clone(&self) -> Self {
+        LbStorage {
+            data: self.data.clone(),
+            bank: self.bank,
+            banks: self.banks.clone(),
+            stack: self.stack.clone(),
+            observer: None,
+            default: self.default.clone(),
+        }
+    }
+}
+
+impl PartialEq for LbStorage {
+    /// Storages are equal if every variable in every bank is the same, as [LbStorage::diff] compares them,
+    /// and they have the same active bank and the same stack.
+    /// Unset variables hold `0` here whatever the default policy is, and observers aren't compared.
+    fn eq(&self, other: &LbStorage) -> bool {
+        self.bank == other.bank
+            && self.stack == other.stack
+            && (0..BANK_COUNT).all(|bank| diff_vars(self.bank_vars(bank), other.bank_vars(bank)).is_empty())
+    }
+}
+
+impl fmt::Display for LbStorage {
+    /// Writes each variable that a value has been stored in, one per line and sorted by name,
+    /// like `a: 4`. Text is quoted as in source code, like `b: 'it\'s'`,
+    /// and numbers, lists, and maps are written as `Pa` prints them.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<String> = self.iter().map(|(var_name, val)| format!("{var_name}: {}", show(val))).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl fmt::Debug for LbStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LbStorage")
+            .field("vars", &self.data)
+            .field("bank", &self.bank)
+            .field("other_banks", &self.banks)
+            .field("stack", &self.stack)
+            .field("observer", &self.observer.is_some())
+            .finish()
+    }
+}
+
+/// Writes a value for [LbStorage] and [VarDiff] to display, quoting text
+/// so it can be told apart from numbers.
+fn show(val: &Val) -> String {
+    match val {
+        Val::Text(text) => lb_lexer::quote(text),
+        other => other.format(false),
+    }
+}
+
+impl<'a> IntoIterator for &'a LbStorage {
+    type Item = (char, &'a Val);
+    type IntoIter = StorageIter<'a>;
+
+    fn into_iter(self) -> StorageIter<'a> {
+        self.iter()
+    }
+}
+
+/// A variable that holds different values in two storages. See [LbStorage::diff].
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarDiff {
+    /// The name of the variable.
+    pub var: char,
+
+    /// Its value in the storage `diff` was called on.
+    pub left: Val,
+
+    /// Its value in the storage passed to `diff`.
+    pub right: Val,
+}
+
+impl fmt::Display for VarDiff {
+    /// Writes the variable and both values, with text in quotes, like `b: 7 != '7'`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} != {}", self.var, show(&self.left), show(&self.right))
+    }
+}
+
+/// Asserts that two [LbStorage]s hold the same variables, as [LbStorage::diff] compares them.
+/// On failure, the panic message lists each variable that differs.
+///
+/// ```should_panic
+/// use letterbox_lang::prelude::*;
+/// use letterbox_lang::assert_storage_eq;
+///
+/// let mut left = LbStorage::new();
+/// let mut right = LbStorage::new();
+/// left.set('a', 0).unwrap();
+/// assert_storage_eq!(left, right);
+///
+/// // panics with "storages differ:\n  b: 7 != 5"
+/// left.set('b', 7).unwrap();
+/// right.set('b', 5).unwrap();
+/// assert_storage_eq!(left, right);
+/// ```
+#[macro_export]
+macro_rules! assert_storage_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        let diffs = $left.diff(&$right);
+        if !diffs.is_empty() {
+            let lines: Vec<String> = diffs.iter().map(|diff| format!("  {diff}")).collect();
+            panic!("storages differ:\n{}", lines.join("\n"));
+        }
+    };
+}
+
+/// The variables and stack of an [LbStorage] at one point in time. See [LbStorage::snapshot].
+/// Cloning a snapshot is cheap, since clones share the same values.
+#[derive(Debug, Clone)]
+pub struct LbSnapshot {
+    data: Arc<BTreeMap<char, Val>>,
+    bank: usize,
+    banks: Arc<BTreeMap<usize, BTreeMap<char, Val>>>,
+    stack: Arc<Vec<Val>>,
+}
+
+/// An iterator over the variables that values have been stored in. See [LbStorage::iter].
+#[derive(Debug, Clone)]
+pub struct StorageIter<'a>(btree_map::Iter<'a, char, Val>);
+
+impl<'a> Iterator for StorageIter<'a> {
+    type Item = (char, &'a Val);
+
+    fn next(&mut self) -> Option<(char, &'a Val)> {
+        self.0.next().map(|(var_name, val)| (*var_name, val))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+/// An [LbStorage] that programs on different threads can share, so they can
+/// communicate through the same variables and stack. Cloning it gives another handle to the same storage.
+///
+/// A program using shared storage takes it for each instruction it runs, so other programs
+/// never see an instruction half done. Loops, conditionals, blocks, and executed programs
+/// let go of it between the instructions inside them, so programs can take turns within a loop,
+/// and `Qa` lets go of it while it sleeps. Any two instructions from the same program may have
+/// other programs' instructions run between them, so a value read by one instruction
+/// may have changed by the next. Programs waiting for the storage get it in the order they asked for it,
+/// so a program in a tight loop can't keep it from the others.
+#[derive(Clone)]
+pub struct SharedStorage {
+    /// Who has the storage, and a signal for those waiting for it to be given back.
+    inner: Arc<(Mutex<Turns>, Condvar)>,
+}
+
+/// The storage, and whose turn it is to take it.
+struct Turns {
+    /// The storage, or `None` while it is taken.
+    storage: Option<LbStorage>,
+
+    /// The turn given to the next one to ask for the storage.
+    next: u64,
+
+    /// The turn of the one that may take the storage now.
+    serving: u64,
+}
+
+impl SharedStorage {
+    /// Returns shared storage starting with the given storage's values, stack, and observer.
+    pub fn new(storage: LbStorage) -> SharedStorage {
+        let turns = Turns { storage: Some(storage), next: 0, serving: 0 };
+        SharedStorage { inner: Arc::new((Mutex::new(turns), Condvar::new())) }
+    }
+
+    /// Runs a function with the storage, once no program is in the middle of an instruction,
+    /// and returns its result. Programs wait for the function to finish.
+    pub fn with<R>(&self, f: impl FnOnce(&mut LbStorage) -> R) -> R {
+        let mut storage = ProgramStorage::from(self.clone());
+        storage.acquire();
+        f(&mut storage)
+    }
+
+    /// Waits for the storage to be free and takes it.
+    fn take(&self) -> LbStorage {
+        let (lock, freed) = &*self.inner;
+        let mut turns = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let turn = turns.next;
+        turns.next += 1;
+        let mut turns = freed.wait_while(turns, |turns| turns.serving != turn)
+            .unwrap_or_else(PoisonError::into_inner);
+        turns.storage.take().expect("Shared storage taken out of turn")
+    }
+
+    /// Gives back storage that was taken, for the next one waiting for it.
+    fn give_back(&self, storage: LbStorage) {
+        let (lock, freed) = &*self.inner;
+        let mut turns = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        turns.storage = Some(storage);
+        turns.serving += 1;
+        freed.notify_all();
+    }
+}
+
+/// The storage an [crate::program::LbProgram] runs against:
+/// either an [LbStorage] it borrows for as long as it exists, or a [SharedStorage].
+/// Created from either one with `into()`, so `&mut storage` and `shared.clone()`
+/// can both be passed to the program's constructors.
+pub struct ProgramStorage<'a>(Binding<'a>);
+
+enum Binding<'a> {
+    Exclusive(&'a mut LbStorage),
+    Shared(SharedTurn),
+}
+
+/// A handle to shared storage, and the storage itself while it is taken.
+struct SharedTurn {
+    shared: SharedStorage,
+    held: Option<LbStorage>,
+}
+
+impl ProgramStorage<'_> {
+    /// Takes shared storage, waiting for it if another program has it.
+    /// Does nothing if it's already taken or isn't shared.
+    pub(crate) fn acquire(&mut self) {
+        if let Binding::Shared(SharedTurn { shared, held: held @ None }) = &mut self.0 {
+            *held = Some(shared.take());
+        }
+    }
+
+    /// Gives back shared storage so other programs can use it.
+    /// Returns true if it was taken, so it can be taken again afterwards.
+    pub(crate) fn release(&mut self) -> bool {
+        match &mut self.0 {
+            Binding::Shared(turn) => turn.release(),
+            Binding::Exclusive(_) => false,
+        }
+    }
+}
+
+impl SharedTurn {
+    /// Gives back the storage if it is taken, and returns whether it was.
+    fn release(&mut self) -> bool {
+        match self.held.take() {
+            Some(storage) => {
+                self.shared.give_back(storage);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+impl Drop for SharedTurn {
+    /// Gives back storage that is still taken, even if a program panics.
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+impl<'a> From<&'a mut LbStorage> for ProgramStorage<'a> {
+    fn from(storage: &'a mut LbStorage) -> Self {
+        ProgramStorage(Binding::Exclusive(storage))
+    }
+}
+
+impl From<SharedStorage> for ProgramStorage<'_> {
+    fn from(shared: SharedStorage) -> Self {
+        ProgramStorage(Binding::Shared(SharedTurn { shared, held: None }))
+    }
+}
+
+impl Deref for ProgramStorage<'_> {
+    type Target = LbStorage;
+
+    fn deref(&self) -> &LbStorage {
+        match &self.0 {
+            Binding::Exclusive(storage) => storage,
+            Binding::Shared(turn) => turn.held.as_ref().expect("Shared storage used before it was taken"),
+        }
+    }
+}
+
+impl DerefMut for ProgramStorage<'_> {
+    fn deref_mut(&mut self) -> &mut LbStorage {
+        match &mut self.0 {
+            Binding::Exclusive(storage) => storage,
+            Binding::Shared(turn) => turn.held.as_mut().expect("Shared storage used before it was taken"),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the letterbox binary with the given arguments and program on standard input.
+fn letterbox(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_letterbox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Binary should start");
+    child.stdin.take().expect("Stdin should be piped").write_all(stdin.as_bytes()).expect("Stdin write failed");
+    child.wait_with_output().expect("Binary should finish")
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[test]
+fn runs_programs() {
+    // inputs follow the program
+    let out = letterbox(&["-", "3", "4"], "GNa0 GNb1 MAcab Oc");
+    assert!(out.status.success());
+    assert_eq!(text(&out.stdout), "7\n");
+
+    let path = std::env::temp_dir().join(format!("letterbox-cli-{}.lb", std::process::id()));
+    std::fs::write(&path, "P'from a file'").expect("Program write failed");
+    let out = letterbox(&[path.to_str().unwrap()], "");
+    assert_eq!(text(&out.stdout), "from a file");
+
+    // a program from a file reads missing inputs from standard input
+    std::fs::write(&path, "GNa0 GNb1 MAcab Oc").expect("Program write failed");
+    let out = letterbox(&[path.to_str().unwrap(), "3"], "4\n");
+    assert_eq!(text(&out.stdout), "7\n");
+    let out = letterbox(&[path.to_str().unwrap()], "3\n");
+    assert!(text(&out.stderr).contains("no input at index 1"));
+    std::fs::remove_file(&path).ok();
+
+    // Fa sets the exit status
+    let out = letterbox(&["-"], "Sa3 Pa Fa");
+    assert_eq!(out.status.code(), Some(3));
+    assert_eq!(text(&out.stdout), "3");
+}
+
+#[test]
+fn includes_files() {
+    let dir = std::env::temp_dir().join(format!("letterbox-cli-includes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Directory creation failed");
+    std::fs::write(dir.join("main.lb"), "Sa6 Sb0\n!#include \"divide.lb\"\nPc").expect("Program write failed");
+    std::fs::write(dir.join("divide.lb"), "!#include \"half.lb\"\n\nMQcab").expect("Program write failed");
+    std::fs::write(dir.join("half.lb"), "Sb2").expect("Program write failed");
+    let main = dir.join("main.lb");
+    let out = letterbox(&[main.to_str().unwrap()], "");
+    assert_eq!(text(&out.stdout), "3");
+
+    // errors are reported in the file they are in
+    std::fs::write(dir.join("half.lb"), "Sb0").expect("Program write failed");
+    let out = letterbox(&[main.to_str().unwrap()], "");
+    assert_eq!(text(&out.stderr), "letterbox: M: Division by zero in MQcab\n  at line 3, column 1 of divide.lb\n");
+    std::fs::write(dir.join("half.lb"), "Sb2 ~").expect("Program write failed");
+    let out = letterbox(&[main.to_str().unwrap()], "");
+    assert_eq!(text(&out.stderr), "letterbox: Program can't be parsed:\n  `~` at line 1, column 5 of half.lb\n");
+
+    std::fs::write(dir.join("half.lb"), "!#include \"divide.lb\"").expect("Program write failed");
+    let out = letterbox(&[main.to_str().unwrap()], "");
+    std::fs::remove_dir_all(&dir).ok();
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(text(&out.stderr), "letterbox: Include cycle: divide.lb -> half.lb -> divide.lb\n");
+}
+
+#[test]
+fn reports_errors() {
+    // output printed before the error is kept, and the error names where it happened
+    let out = letterbox(&["-"], "Pa\n  Sb0 MQcab");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(text(&out.stdout), "0");
+    assert_eq!(text(&out.stderr), "letterbox: M: Division by zero in MQcab\n  at line 2, column 7\n");
+
+    // typos fail before anything runs, even where they wouldn't be reached
+    let out = letterbox(&["-"], "Pa F ~");
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(text(&out.stdout), "");
+    assert!(text(&out.stderr).contains("`~` at line 1, column 6"));
+
+    let out = letterbox(&["--max-instructions", "50", "-"],"Sa1 WaPa");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(text(&out.stderr).contains("Instruction limit of 50 exceeded"));
+
+    let out = letterbox(&["--frobnicate", "-"], "");
+    assert_eq!(out.status.code(), Some(2));
+    let out = letterbox(&["no-such-file.lb"], "");
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn traces_instructions() {
+    let out = letterbox(&["--trace", "-"], "Sa1 Sb2 MAcab");
+    assert!(out.status.success());
+    assert_eq!(text(&out.stderr).lines().last(), Some("1:9 MAcab c=0 a=1 b=2 -> c=3 a=1 b=2"));
+}